@@ -1,29 +1,307 @@
-fn main() {
-    let mut args = std::env::args();
-    if args.len() < 3 {
-        panic!("Wrong arguments number! Need 2: kernel file path and bootable img file path");
+use std::path::{Path, PathBuf};
+
+/// Kernel section the command line is written to, kernel/src/cmdline.rs
+const CMDLINE_SECTION: &str = ".cmdline";
+
+/// Firmware the image is created for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum BootFormat {
+    Bios,
+    Uefi,
+    /// Two images: BIOS at the given path, UEFI next to it with "-uefi" suffix
+    Both,
+}
+
+impl BootFormat {
+    fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "bios" => Some(Self::Bios),
+            "uefi" => Some(Self::Uefi),
+            "both" => Some(Self::Both),
+            _ => None,
+        }
     }
+}
+
+const USAGE: &str =
+    "Usage: <kernel file path> <bootable img file path> [bios|uefi|both] [--cmdline \"...\"] [--initrd <CPIO newc archive path>]";
+
+fn main() {
     // Skip program name
-    args.next();
+    let mut args = std::env::args().skip(1);
+    let mut positional_args = Vec::new();
+    let mut cmdline = None;
+    let mut initrd_file_path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--cmdline" {
+            cmdline = Some(
+                args.next()
+                    .unwrap_or_else(|| panic!("--cmdline needs a value\n{USAGE}")),
+            );
+        } else if arg == "--initrd" {
+            initrd_file_path =
+                Some(PathBuf::from(args.next().unwrap_or_else(|| {
+                    panic!("--initrd needs a value\n{USAGE}")
+                })));
+        } else {
+            positional_args.push(arg);
+        }
+    }
+    if !(2..=3).contains(&positional_args.len()) {
+        panic!("Wrong arguments number!\n{USAGE}");
+    }
 
-    let kernel_file_path = args.next().unwrap();
-    let kernel_file_path = std::path::Path::new(&kernel_file_path);
-    let bootable_img_file_path = args.next().unwrap();
-    let bootable_img_file_path = std::path::Path::new(&bootable_img_file_path);
+    let kernel_file_path = Path::new(&positional_args[0]);
+    let bootable_img_file_path = Path::new(&positional_args[1]);
+    let boot_format = match positional_args.get(2) {
+        Some(arg) => BootFormat::parse(arg)
+            .unwrap_or_else(|| panic!("Unknown boot format {arg:?}, need bios, uefi or both")),
+        None => BootFormat::Bios,
+    };
 
-    if !std::path::Path::new(&kernel_file_path).exists() {
+    if !kernel_file_path.exists() {
         panic!("Failed to find kernel file");
     }
+    if let Some(ref initrd_file_path) = initrd_file_path {
+        if !initrd_file_path.exists() {
+            panic!("Failed to find initrd file");
+        }
+    }
+
+    // Kernel with command line is written next to the image, the original is not changed
+    let patched_kernel_file_path = cmdline.map(|cmdline| {
+        let patched_kernel_file_path = bootable_img_file_path.with_extension("kernel");
+        embed_cmdline(kernel_file_path, &cmdline, &patched_kernel_file_path);
+        println!("Command line embedded: {cmdline:?}");
+        patched_kernel_file_path
+    });
+    let kernel_file_path = patched_kernel_file_path
+        .as_deref()
+        .unwrap_or(kernel_file_path);
+
+    if let Some(ref initrd_file_path) = initrd_file_path {
+        println!("Initrd embedded as ramdisk: {initrd_file_path:?}");
+    }
 
     // Create bootable img
     // Boot config
     let boot_config = bootloader::BootConfig::default();
 
-    let mut bootable_img = bootloader::BiosBoot::new(kernel_file_path);
-    bootable_img.set_boot_config(&boot_config);
-    let result = bootable_img.create_disk_image(bootable_img_file_path);
-    if let Err(error) = result {
-        panic!("Failed to create bootable img: {error}");
+    if boot_format != BootFormat::Uefi {
+        let mut bootable_img = bootloader::BiosBoot::new(kernel_file_path);
+        bootable_img.set_boot_config(&boot_config);
+        if let Some(ref initrd_file_path) = initrd_file_path {
+            bootable_img.set_ramdisk(initrd_file_path);
+        }
+        let result = bootable_img.create_disk_image(bootable_img_file_path);
+        if let Err(error) = result {
+            panic!("Failed to create BIOS bootable img: {error}");
+        }
+        println!("BIOS bootable img created: {bootable_img_file_path:?}");
+    }
+    if boot_format != BootFormat::Bios {
+        let uefi_img_file_path = if boot_format == BootFormat::Both {
+            uefi_img_file_path(bootable_img_file_path)
+        } else {
+            bootable_img_file_path.to_path_buf()
+        };
+        let mut bootable_img = bootloader::UefiBoot::new(kernel_file_path);
+        bootable_img.set_boot_config(&boot_config);
+        if let Some(ref initrd_file_path) = initrd_file_path {
+            bootable_img.set_ramdisk(initrd_file_path);
+        }
+        let result = bootable_img.create_disk_image(&uefi_img_file_path);
+        if let Err(error) = result {
+            panic!("Failed to create UEFI bootable img: {error}");
+        }
+        println!("UEFI bootable img created: {uefi_img_file_path:?}");
+    }
+
+    if let Some(patched_kernel_file_path) = patched_kernel_file_path {
+        std::fs::remove_file(patched_kernel_file_path).expect("Failed to remove patched kernel");
+    }
+}
+
+/// "bootable.img" -> "bootable-uefi.img"
+fn uefi_img_file_path(bios_img_file_path: &Path) -> PathBuf {
+    let mut file_name = bios_img_file_path
+        .file_stem()
+        .expect("Bootable img file path has no file name")
+        .to_os_string();
+    file_name.push("-uefi");
+    if let Some(extension) = bios_img_file_path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    bios_img_file_path.with_file_name(file_name)
+}
+
+/// Copies kernel ELF with command line written to [CMDLINE_SECTION]
+///
+/// Command line must leave room for terminating NUL
+fn embed_cmdline(kernel_file_path: &Path, cmdline: &str, output_file_path: &Path) {
+    let mut kernel = std::fs::read(kernel_file_path).expect("Failed to read kernel file");
+    let (section_offset, section_size) = find_elf_section(&kernel, CMDLINE_SECTION)
+        .unwrap_or_else(|| panic!("Kernel has no {CMDLINE_SECTION} section"));
+    if cmdline.len() >= section_size {
+        panic!(
+            "Command line is too long: {} bytes, max {}",
+            cmdline.len(),
+            section_size - 1
+        );
+    }
+    let section = &mut kernel[section_offset..section_offset + section_size];
+    section.fill(0);
+    section[..cmdline.len()].copy_from_slice(cmdline.as_bytes());
+    std::fs::write(output_file_path, kernel).expect("Failed to write patched kernel file");
+}
+
+/// Returns file offset and size of ELF64 little-endian section
+fn find_elf_section(elf: &[u8], name: &str) -> Option<(usize, usize)> {
+    let u16_at = |offset: usize| {
+        Some(u16::from_le_bytes(
+            elf.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let u32_at = |offset: usize| {
+        Some(u32::from_le_bytes(
+            elf.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let u64_at = |offset: usize| {
+        Some(u64::from_le_bytes(
+            elf.get(offset..offset + 8)?.try_into().ok()?,
+        ))
+    };
+    // ELF magic, 64-bit, little-endian
+    if elf.get(..6)? != b"\x7fELF\x02\x01" {
+        return None;
+    }
+    let section_headers_offset = u64_at(0x28)? as usize;
+    let section_header_size = u16_at(0x3A)? as usize;
+    let sections_number = u16_at(0x3C)? as usize;
+    let section_names_index = u16_at(0x3E)? as usize;
+    let section_header = |index: usize| section_headers_offset + index * section_header_size;
+    // sh_offset and sh_size
+    let section_range = |index: usize| -> Option<(usize, usize)> {
+        let header = section_header(index);
+        Some((
+            u64_at(header + 0x18)? as usize,
+            u64_at(header + 0x20)? as usize,
+        ))
+    };
+    let (names_offset, names_size) = section_range(section_names_index)?;
+    let names = elf.get(names_offset..names_offset + names_size)?;
+    (0..sections_number).find_map(|index| {
+        let name_offset = u32_at(section_header(index))? as usize;
+        let section_name = names.get(name_offset..)?.split(|&byte| byte == 0).next()?;
+        if section_name != name.as_bytes() {
+            return None;
+        }
+        let (offset, size) = section_range(index)?;
+        elf.get(offset..offset + size)?;
+        Some((offset, size))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Offset and size of .cmdline section in [fixture_elf]
+    const FIXTURE_CMDLINE: (usize, usize) = (0x60, 16);
+
+    /// Small ELF64 with sections: null, .shstrtab, .cmdline (filled with 0xAA)
+    fn fixture_elf() -> Vec<u8> {
+        const SECTION_HEADERS_OFFSET: usize = 0x70;
+        const SECTION_HEADER_SIZE: usize = 64;
+        let mut elf = vec![0; SECTION_HEADERS_OFFSET + 3 * SECTION_HEADER_SIZE];
+        elf[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+        elf[0x28..0x30].copy_from_slice(&(SECTION_HEADERS_OFFSET as u64).to_le_bytes());
+        elf[0x3A..0x3C].copy_from_slice(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+        // Sections number and .shstrtab index
+        elf[0x3C..0x3E].copy_from_slice(&3u16.to_le_bytes());
+        elf[0x3E..0x40].copy_from_slice(&1u16.to_le_bytes());
+
+        let names = b"\0.shstrtab\0.cmdline\0";
+        elf[0x40..0x40 + names.len()].copy_from_slice(names);
+        let (cmdline_offset, cmdline_size) = FIXTURE_CMDLINE;
+        elf[cmdline_offset..cmdline_offset + cmdline_size].fill(0xAA);
+
+        // sh_name, sh_offset, sh_size
+        for (index, name_offset, offset, size) in [
+            (1, 1, 0x40, names.len()),
+            (2, 11, cmdline_offset, cmdline_size),
+        ] {
+            let header = SECTION_HEADERS_OFFSET + index * SECTION_HEADER_SIZE;
+            elf[header..header + 4].copy_from_slice(&(name_offset as u32).to_le_bytes());
+            elf[header + 0x18..header + 0x20].copy_from_slice(&(offset as u64).to_le_bytes());
+            elf[header + 0x20..header + 0x28].copy_from_slice(&(size as u64).to_le_bytes());
+        }
+        elf
+    }
+
+    /// Writes fixture kernel to temporary directory, returns kernel and output paths
+    fn fixture_files(test_name: &str) -> (PathBuf, PathBuf) {
+        let directory = std::env::temp_dir().join(format!(
+            "bootable-img-builder-{}-{test_name}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let kernel_file_path = directory.join("kernel");
+        std::fs::write(&kernel_file_path, fixture_elf()).unwrap();
+        (kernel_file_path, directory.join("kernel-patched"))
+    }
+
+    #[test]
+    fn finds_elf_section() {
+        let elf = fixture_elf();
+        assert_eq!(
+            find_elf_section(&elf, CMDLINE_SECTION),
+            Some(FIXTURE_CMDLINE)
+        );
+        assert_eq!(find_elf_section(&elf, ".shstrtab"), Some((0x40, 20)));
+        assert_eq!(find_elf_section(&elf, ".cmd"), None);
+        assert_eq!(find_elf_section(&elf, ".text"), None);
+    }
+
+    #[test]
+    fn rejects_broken_elf() {
+        let elf = fixture_elf();
+        // Section headers are cut off
+        assert_eq!(find_elf_section(&elf[..0x80], CMDLINE_SECTION), None);
+        // 32-bit
+        let mut elf32 = elf.clone();
+        elf32[4] = 1;
+        assert_eq!(find_elf_section(&elf32, CMDLINE_SECTION), None);
+        assert_eq!(find_elf_section(b"\x7fEL", CMDLINE_SECTION), None);
+    }
+
+    #[test]
+    fn embeds_cmdline() {
+        let (kernel_file_path, output_file_path) = fixture_files("embeds_cmdline");
+        embed_cmdline(&kernel_file_path, "log=debug", &output_file_path);
+
+        let original = fixture_elf();
+        let patched = std::fs::read(&output_file_path).unwrap();
+        let (offset, size) = FIXTURE_CMDLINE;
+        let mut expected_section = [0; FIXTURE_CMDLINE.1];
+        expected_section[..9].copy_from_slice(b"log=debug");
+        assert_eq!(patched[offset..offset + size], expected_section);
+        assert_eq!(patched[..offset], original[..offset]);
+        assert_eq!(patched[offset + size..], original[offset + size..]);
+        // Original kernel is not changed
+        assert_eq!(std::fs::read(&kernel_file_path).unwrap(), original);
+    }
+
+    #[test]
+    #[should_panic(expected = "Command line is too long")]
+    fn rejects_cmdline_without_room_for_nul() {
+        let (kernel_file_path, output_file_path) = fixture_files("rejects_long_cmdline");
+        embed_cmdline(
+            &kernel_file_path,
+            &"a".repeat(FIXTURE_CMDLINE.1),
+            &output_file_path,
+        );
     }
-    println!("Bootable img created: {bootable_img_file_path:?}");
 }
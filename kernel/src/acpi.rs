@@ -1,14 +1,28 @@
+pub mod aml;
+pub mod events;
+pub mod mcfg;
+pub mod power;
+
+pub use aml::evaluate;
+
 use crate::memory_management::general_purpose_allocator::GeneralPurposeAllocator;
 use crate::memory_management::virtual_memory_manager;
 use crate::memory_management::PAGE_SIZE;
+use crate::sync::IrqMutex;
 use acpi_lib::{AcpiTables, PhysicalMapping, PlatformInfo};
 use bootloader_api::BootInfo;
 use core::ptr::NonNull;
-use spin::{Mutex, Once};
+use spin::Once;
 use x86_64::PhysAddr;
 
-pub static ACPI_TABLES: Once<Mutex<AcpiTables<BaseAcpiHandler>>> = Once::new();
+/// ACPI tables
+///
+/// IRQ-safe, so tables can be read from interrupt handlers (e.g. ACPI event callbacks)
+pub static ACPI_TABLES: Once<IrqMutex<AcpiTables<BaseAcpiHandler>>> = Once::new();
 
+/// Platform info collected from MADT once at [init]
+///
+/// Consumers must use it instead of calling platform_info_in again
 pub static PLATFORM_INFO: Once<PlatformInfo<'static, GeneralPurposeAllocator>> = Once::new();
 
 /// Gets ACPI tables
@@ -34,7 +48,7 @@ pub fn init(boot_info: &BootInfo) {
             .expect("Failed to get ACPI tables")
     };
 
-    ACPI_TABLES.call_once(|| Mutex::new(acpi_tables));
+    ACPI_TABLES.call_once(|| IrqMutex::new(acpi_tables));
 
     // Collect PlatformInfo
     let acpi_tables_mutex_guard = ACPI_TABLES.get().unwrap().lock();
@@ -49,6 +63,19 @@ pub fn init(boot_info: &BootInfo) {
     };
 
     PLATFORM_INFO.call_once(|| static_platform_info);
+    drop(acpi_tables_mutex_guard);
+
+    // Read PCI Express configuration regions
+    mcfg::init();
+
+    // Parse AML (DSDT and SSDTs)
+    aml::init();
+
+    // Read PM1 registers for fixed events
+    events::init();
+
+    // Read PM1 control registers and S5 sleep type for power off
+    power::init();
 }
 
 #[derive(Debug, Clone)]
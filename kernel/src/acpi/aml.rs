@@ -0,0 +1,304 @@
+use super::ACPI_TABLES;
+use crate::memory_management::virtual_memory_manager;
+use alloc::boxed::Box;
+use aml_lib::pci_routing::{PciRoutingTable, Pin};
+use aml_lib::value::Args;
+use aml_lib::{AmlContext, AmlError, AmlName, AmlValue, DebugVerbosity};
+use spin::{Mutex, Once};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+/// AML interpreter with parsed DSDT and SSDTs
+///
+/// None if AML parsing failed
+static AML_CONTEXT: Once<Option<Mutex<AmlContext>>> = Once::new();
+
+/// PCI interrupt routing of root bridge (bus 0), from \_SB.PCI0._PRT
+///
+/// None if AML parsing failed or _PRT not found (MSI-only devices still work)
+static PCI_ROUTING_TABLE: Once<Option<PciRoutingTable>> = Once::new();
+
+/// Path to _PRT of root PCI bridge
+const ROOT_BRIDGE_PRT_PATH: &str = "\\_SB.PCI0._PRT";
+
+/// Parses DSDT and SSDTs, collects PCI interrupt routing
+///
+/// Failures are logged, kernel continues without AML
+pub fn init() {
+    AML_CONTEXT.call_once(|| {
+        let mut aml_context = AmlContext::new(Box::new(AmlHandler), DebugVerbosity::None);
+        let acpi_tables = ACPI_TABLES.get().unwrap().lock();
+
+        let dsdt = match acpi_tables.dsdt() {
+            Ok(dsdt) => dsdt,
+            Err(err) => {
+                log::warn!("Failed to get DSDT, AML not available: {err:?}");
+                return None;
+            }
+        };
+        if let Err(err) = aml_context.parse_table(aml_table_stream(dsdt.address, dsdt.length)) {
+            log::warn!("Failed to parse DSDT, AML not available: {err:?}");
+            return None;
+        }
+        for ssdt in acpi_tables.ssdts() {
+            if let Err(err) = aml_context.parse_table(aml_table_stream(ssdt.address, ssdt.length)) {
+                // DSDT is enough for the most things
+                log::warn!("Failed to parse SSDT: {err:?}");
+            }
+        }
+        // Run _STA and _INI of devices
+        if let Err(err) = aml_context.initialize_objects() {
+            log::warn!("Failed to initialize AML objects: {err:?}");
+        }
+        log::info!("AML namespace initialized");
+        Some(Mutex::new(aml_context))
+    });
+
+    PCI_ROUTING_TABLE.call_once(|| {
+        let mut aml_context = AML_CONTEXT.get().unwrap().as_ref()?.lock();
+        let prt_path = AmlName::from_str(ROOT_BRIDGE_PRT_PATH).unwrap();
+        match PciRoutingTable::from_prt_path(&prt_path, &mut aml_context) {
+            Ok(pci_routing_table) => Some(pci_routing_table),
+            Err(err) => {
+                log::warn!("Failed to get PCI routing table from {ROOT_BRIDGE_PRT_PATH}: {err:?}");
+                None
+            }
+        }
+    });
+}
+
+/// Returns AML stream of table in Complete Physical Memory Mapping
+fn aml_table_stream(phys_addr: usize, length: u32) -> &'static [u8] {
+    let virt_addr =
+        virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(PhysAddr::new(phys_addr as u64));
+    unsafe { core::slice::from_raw_parts(virt_addr.as_ptr(), length as usize) }
+}
+
+/// Returns PCI interrupt routing table of root bridge (bus 0)
+pub fn pci_routing() -> Option<&'static PciRoutingTable> {
+    PCI_ROUTING_TABLE.get()?.as_ref()
+}
+
+/// Returns GSI of PCI device INTx pin on root bridge (bus 0)
+///
+/// None if AML not available or the pin is not routed
+pub fn pci_interrupt_gsi(device: u16, function: u16, pin: Pin) -> Option<u32> {
+    let pci_routing_table = pci_routing()?;
+    let mut aml_context = AML_CONTEXT.get()?.as_ref()?.lock();
+    pci_routing_table
+        .route(device, function, pin, &mut aml_context)
+        .ok()
+        .map(|irq_descriptor| irq_descriptor.irq)
+}
+
+/// Error of AML control method evaluation
+#[derive(Debug)]
+pub enum AmlEvaluateError {
+    /// AML parsing failed at init
+    NotAvailable,
+    /// Invalid path
+    InvalidPath(AmlError),
+    /// Interpreter error
+    Aml(AmlError),
+}
+
+/// Evaluates named control method (or object), e.g. `\_S5_`
+pub fn evaluate(path: &str, args: Args) -> Result<AmlValue, AmlEvaluateError> {
+    let mut aml_context = AML_CONTEXT
+        .get()
+        .and_then(Option::as_ref)
+        .ok_or(AmlEvaluateError::NotAvailable)?
+        .lock();
+    let path = AmlName::from_str(path).map_err(AmlEvaluateError::InvalidPath)?;
+    aml_context
+        .invoke_method(&path, args)
+        .map_err(AmlEvaluateError::Aml)
+}
+
+/// Gives AML interpreter access to memory, IO ports and PCI configuration space
+struct AmlHandler;
+
+impl AmlHandler {
+    /// Legacy PCI configuration space access mechanism #1
+    ///
+    /// Only segment 0 is supported
+    fn pci_config_address(segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        assert_eq!(segment, 0, "Only PCI segment 0 supported");
+        (1 << 31)
+            | (bus as u32) << 16
+            | (device as u32) << 11
+            | (function as u32) << 8
+            | (offset as u32 & 0xFC)
+    }
+
+    fn read_pci_dword(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        let address = Self::pci_config_address(segment, bus, device, function, offset);
+        unsafe {
+            Port::<u32>::new(0xCF8).write(address);
+            Port::<u32>::new(0xCFC).read()
+        }
+    }
+
+    fn write_pci_dword(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
+    ) {
+        let address = Self::pci_config_address(segment, bus, device, function, offset);
+        unsafe {
+            Port::<u32>::new(0xCF8).write(address);
+            Port::<u32>::new(0xCFC).write(value);
+        }
+    }
+
+    /// Read-modify-write of a part of PCI configuration dword
+    #[allow(clippy::too_many_arguments)]
+    fn write_pci_part(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
+        bits: u32,
+    ) {
+        let shift = (offset as u32 & 0b11) * 8;
+        let mask = (u32::MAX >> (32 - bits)) << shift;
+        let dword = self.read_pci_dword(segment, bus, device, function, offset);
+        let dword = (dword & !mask) | ((value << shift) & mask);
+        self.write_pci_dword(segment, bus, device, function, offset, dword);
+    }
+
+    #[inline]
+    fn memory_ptr<T>(address: usize) -> *mut T {
+        virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(PhysAddr::new(address as u64))
+            .as_mut_ptr()
+    }
+}
+
+impl aml_lib::Handler for AmlHandler {
+    fn read_u8(&self, address: usize) -> u8 {
+        unsafe { Self::memory_ptr::<u8>(address).read_volatile() }
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        unsafe { Self::memory_ptr::<u16>(address).read_volatile() }
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        unsafe { Self::memory_ptr::<u32>(address).read_volatile() }
+    }
+
+    fn read_u64(&self, address: usize) -> u64 {
+        unsafe { Self::memory_ptr::<u64>(address).read_volatile() }
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) {
+        unsafe { Self::memory_ptr::<u8>(address).write_volatile(value) }
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) {
+        unsafe { Self::memory_ptr::<u16>(address).write_volatile(value) }
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) {
+        unsafe { Self::memory_ptr::<u32>(address).write_volatile(value) }
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) {
+        unsafe { Self::memory_ptr::<u64>(address).write_volatile(value) }
+    }
+
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        let dword = self.read_pci_dword(segment, bus, device, function, offset);
+        (dword >> ((offset & 0b11) * 8)) as u8
+    }
+
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        let dword = self.read_pci_dword(segment, bus, device, function, offset);
+        (dword >> ((offset & 0b10) * 8)) as u16
+    }
+
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        self.read_pci_dword(segment, bus, device, function, offset)
+    }
+
+    fn write_pci_u8(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u8,
+    ) {
+        self.write_pci_part(segment, bus, device, function, offset, value as u32, 8);
+    }
+
+    fn write_pci_u16(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u16,
+    ) {
+        self.write_pci_part(segment, bus, device, function, offset, value as u32, 16);
+    }
+
+    fn write_pci_u32(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
+    ) {
+        self.write_pci_dword(segment, bus, device, function, offset, value);
+    }
+
+    fn stall(&self, microseconds: u64) {
+        // Timers are not initialized yet when AML is parsed
+        // Write to POST port 0x80 takes ~1 microsecond
+        for _ in 0..microseconds {
+            unsafe {
+                Port::<u8>::new(0x80).write(0);
+            }
+        }
+    }
+
+    fn sleep(&self, milliseconds: u64) {
+        self.stall(milliseconds * 1000);
+    }
+}
@@ -0,0 +1,296 @@
+//! ACPI fixed events (power button, sleep button, RTC alarm, etc.)
+//!
+//! Fixed events are signaled through SCI, their status and enable bits are in PM1 event registers.
+//! Events can only be enabled after [enable_acpi_mode].
+use super::ACPI_TABLES;
+use crate::interrupts::idt::{self, IO_APIC_ISA_IRQ_VECTORS_RANGE};
+use acpi_lib::address::{AddressSpace, GenericAddress};
+use acpi_lib::fadt::Fadt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::port::Port;
+
+/// ACPI fixed event, value is the bit in PM1 status and enable registers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AcpiEvent {
+    /// PM timer carry
+    Timer = 0,
+    /// Global lock release
+    GlobalLock = 5,
+    /// Power button
+    PowerButton = 8,
+    /// Sleep button
+    SleepButton = 9,
+    /// RTC alarm
+    RtcAlarm = 10,
+    /// PCI Express wake
+    PciExpressWake = 14,
+}
+
+impl AcpiEvent {
+    const ALL: [AcpiEvent; 6] = [
+        AcpiEvent::Timer,
+        AcpiEvent::GlobalLock,
+        AcpiEvent::PowerButton,
+        AcpiEvent::SleepButton,
+        AcpiEvent::RtcAlarm,
+        AcpiEvent::PciExpressWake,
+    ];
+
+    #[inline]
+    fn mask(self) -> u16 {
+        1 << self as u8
+    }
+}
+
+/// ACPI events error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AcpiEventError {
+    /// FADT not found or PM1 registers are not in System IO space
+    NotSupported,
+    /// Firmware didn't switch to ACPI mode
+    AcpiModeTimeout,
+    /// [enable_acpi_mode] was not called
+    AcpiModeDisabled,
+}
+
+/// Callback of ACPI event, called from SCI handler
+pub type AcpiEventHandler = fn(AcpiEvent);
+
+/// PM1 event and control registers ports (from FADT)
+#[derive(Debug)]
+struct FixedEventRegisters {
+    /// ISA IRQ of SCI
+    sci_interrupt: u16,
+    smi_cmd_port: u16,
+    acpi_enable: u8,
+    /// PM1a status register port, enable register follows it
+    pm1a_event_port: u16,
+    pm1b_event_port: Option<u16>,
+    /// Length of PM1 status (and enable) register in bytes
+    pm1_register_length: u16,
+    pm1a_control_port: u16,
+    pm1b_control_port: Option<u16>,
+}
+
+static FIXED_EVENT_REGISTERS: Once<Option<FixedEventRegisters>> = Once::new();
+
+static ACPI_MODE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+static EVENT_HANDLERS: Mutex<[Option<AcpiEventHandler>; 16]> = Mutex::new([None; 16]);
+
+/// SCI_EN bit of PM1 control register
+pub(super) const PM1_CONTROL_SCI_EN: u16 = 1;
+
+/// How many times PM1 control register is polled after ACPI enable command
+const ACPI_MODE_ENABLE_POLLS: usize = 1_000_000;
+
+/// Reads PM1 registers location from FADT
+pub fn init() {
+    FIXED_EVENT_REGISTERS.call_once(|| {
+        let acpi_tables = ACPI_TABLES.get().unwrap().lock();
+        let fadt = match acpi_tables.find_table::<Fadt>() {
+            Ok(fadt) => fadt,
+            Err(err) => {
+                log::warn!("Failed to get FADT, ACPI events not supported: {err:?}");
+                return None;
+            }
+        };
+        let fixed_event_registers = FixedEventRegisters::from_fadt(&fadt);
+        if fixed_event_registers.is_none() {
+            log::warn!("PM1 registers are not in System IO space, ACPI events not supported");
+        }
+        fixed_event_registers
+    });
+    if let Some(sci_vector) = sci_vector() {
+        if let Err(err) = idt::register_handler(sci_vector, |_| {
+            handle_sci();
+        }) {
+            log::warn!("Failed to register SCI handler for vector {sci_vector}: {err:?}");
+        }
+    }
+}
+
+impl FixedEventRegisters {
+    fn from_fadt(fadt: &Fadt) -> Option<Self> {
+        let pm1a_event_block = fadt.pm1a_event_block().ok()?;
+        let pm1b_event_block = fadt.pm1b_event_block().ok()?;
+        let pm1a_control_block = fadt.pm1a_control_block().ok()?;
+        let pm1b_control_block = fadt.pm1b_control_block().ok()?;
+        Some(Self {
+            sci_interrupt: fadt.sci_interrupt,
+            smi_cmd_port: u16::try_from(fadt.smi_cmd_port).ok()?,
+            acpi_enable: fadt.acpi_enable,
+            pm1a_event_port: io_port(&pm1a_event_block)?,
+            pm1b_event_port: optional_io_port(pm1b_event_block.as_ref())?,
+            // Event block consists of status and enable registers of equal length
+            pm1_register_length: pm1a_event_block.bit_width as u16 / 16,
+            pm1a_control_port: io_port(&pm1a_control_block)?,
+            pm1b_control_port: optional_io_port(pm1b_control_block.as_ref())?,
+        })
+    }
+
+    fn event_ports(&self) -> impl Iterator<Item = u16> {
+        core::iter::once(self.pm1a_event_port).chain(self.pm1b_event_port)
+    }
+}
+
+/// Returns IO port of System IO generic address
+pub(super) fn io_port(generic_address: &GenericAddress) -> Option<u16> {
+    if generic_address.address_space != AddressSpace::SystemIo {
+        return None;
+    }
+    u16::try_from(generic_address.address).ok()
+}
+
+/// Returns Some(None) if there is no block, None if the block is not in System IO space
+pub(super) fn optional_io_port(generic_address: Option<&GenericAddress>) -> Option<Option<u16>> {
+    match generic_address {
+        Some(generic_address) => io_port(generic_address).map(Some),
+        None => Some(None),
+    }
+}
+
+#[inline]
+fn fixed_event_registers() -> Result<&'static FixedEventRegisters, AcpiEventError> {
+    FIXED_EVENT_REGISTERS
+        .get()
+        .and_then(Option::as_ref)
+        .ok_or(AcpiEventError::NotSupported)
+}
+
+/// Returns ISA IRQ of SCI (System Control Interrupt)
+pub fn sci_interrupt() -> Option<u16> {
+    fixed_event_registers()
+        .ok()
+        .map(|registers| registers.sci_interrupt)
+}
+
+/// Switches firmware from legacy (SMM) mode to ACPI mode, after it SCI is generated for events
+pub fn enable_acpi_mode() -> Result<(), AcpiEventError> {
+    let registers = fixed_event_registers()?;
+    let sci_enabled = || unsafe {
+        Port::<u16>::new(registers.pm1a_control_port).read() & PM1_CONTROL_SCI_EN != 0
+    };
+
+    // SMI command port 0 means hardware-reduced or always in ACPI mode
+    if !sci_enabled() && registers.smi_cmd_port != 0 && registers.acpi_enable != 0 {
+        unsafe {
+            Port::<u8>::new(registers.smi_cmd_port).write(registers.acpi_enable);
+        }
+        if !(0..ACPI_MODE_ENABLE_POLLS).any(|_| sci_enabled()) {
+            return Err(AcpiEventError::AcpiModeTimeout);
+        }
+    }
+
+    // Disable all events and clear their status, so only requested events fire
+    for port in registers.event_ports() {
+        unsafe {
+            Port::<u16>::new(port + registers.pm1_register_length).write(0);
+            Port::<u16>::new(port).write(u16::MAX);
+        }
+    }
+    ACPI_MODE_ENABLED.store(true, Ordering::Release);
+    log::info!(
+        "ACPI mode enabled, SCI is ISA IRQ {}",
+        registers.sci_interrupt
+    );
+    Ok(())
+}
+
+/// Sets event enable bit in PM1 enable registers
+pub fn enable_event(event: AcpiEvent) -> Result<(), AcpiEventError> {
+    update_event_enable(event, true)
+}
+
+/// Clears event enable bit in PM1 enable registers
+pub fn disable_event(event: AcpiEvent) -> Result<(), AcpiEventError> {
+    update_event_enable(event, false)
+}
+
+fn update_event_enable(event: AcpiEvent, enable: bool) -> Result<(), AcpiEventError> {
+    let registers = fixed_event_registers()?;
+    if !ACPI_MODE_ENABLED.load(Ordering::Acquire) {
+        return Err(AcpiEventError::AcpiModeDisabled);
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for port in registers.event_ports() {
+            let mut enable_register = Port::<u16>::new(port + registers.pm1_register_length);
+            unsafe {
+                let value = enable_register.read();
+                if enable {
+                    // Clear stale status first, status bits are write-1-to-clear
+                    Port::<u16>::new(port).write(event.mask());
+                    enable_register.write(value | event.mask());
+                } else {
+                    enable_register.write(value & !event.mask());
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Registers callback of event, replaces previous one
+///
+/// Callback is called from SCI handler with interrupts disabled
+pub fn register_event_handler(event: AcpiEvent, handler: AcpiEventHandler) {
+    EVENT_HANDLERS.lock()[event as usize] = Some(handler);
+}
+
+/// Removes callback of event
+pub fn unregister_event_handler(event: AcpiEvent) {
+    EVENT_HANDLERS.lock()[event as usize] = None;
+}
+
+/// Returns IDT vector of SCI if it's ISA IRQ
+pub fn sci_vector() -> Option<u8> {
+    let sci_interrupt = u8::try_from(sci_interrupt()?).ok()?;
+    let vector = IO_APIC_ISA_IRQ_VECTORS_RANGE.start() + sci_interrupt;
+    IO_APIC_ISA_IRQ_VECTORS_RANGE
+        .contains(&vector)
+        .then_some(vector)
+}
+
+/// SCI handler, reads PM1 status, clears it and dispatches enabled events to callbacks
+///
+/// Returns false if SCI was not caused by a fixed event (e.g. GPE)
+pub fn handle_sci() -> bool {
+    let Ok(registers) = fixed_event_registers() else {
+        return false;
+    };
+    if !ACPI_MODE_ENABLED.load(Ordering::Acquire) {
+        return false;
+    }
+    let mut fired_events: u16 = 0;
+    for port in registers.event_ports() {
+        unsafe {
+            let status = Port::<u16>::new(port).read();
+            let enabled = Port::<u16>::new(port + registers.pm1_register_length).read();
+            let pending = status & enabled;
+            // Write-1-to-clear
+            Port::<u16>::new(port).write(pending);
+            fired_events |= pending;
+        }
+    }
+    if fired_events == 0 {
+        return false;
+    }
+
+    // Handler may be registered at this moment
+    let Some(event_handlers) = EVENT_HANDLERS.try_lock() else {
+        log::warn!("ACPI events {fired_events:#06X} dropped, handlers are being registered");
+        return true;
+    };
+    for event in AcpiEvent::ALL {
+        if fired_events & event.mask() == 0 {
+            continue;
+        }
+        match event_handlers[event as usize] {
+            Some(handler) => handler(event),
+            None => log::info!("Unhandled ACPI event {event:?}"),
+        }
+    }
+    true
+}
@@ -0,0 +1,82 @@
+//! MCFG: PCI Express memory-mapped configuration space (ECAM) regions
+//!
+//! Regions are accessed through Complete Physical Memory Mapping,
+//! firmware MTRRs make them uncacheable (like other MMIO below 4 GB).
+use super::ACPI_TABLES;
+use crate::memory_management::general_purpose_allocator::GeneralPurposeAllocator;
+use crate::memory_management::virtual_memory_manager;
+use acpi_lib::mcfg::Mcfg;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// ECAM region of PCI segment group
+#[derive(Debug, Clone)]
+pub struct PcieConfigRegion {
+    pub segment_group: u16,
+    pub bus_range: RangeInclusive<u8>,
+    /// Physical address of bus 0 configuration space (even if bus range doesn't start from 0)
+    pub base_phys_addr: PhysAddr,
+}
+
+/// Regions from MCFG, empty if MCFG is not present
+static REGIONS: Once<Vec<PcieConfigRegion, GeneralPurposeAllocator>> = Once::new();
+
+/// Configuration space size of a function
+pub const FUNCTION_CONFIG_SPACE_SIZE: u64 = 4096;
+
+/// Reads MCFG
+///
+/// Regions not covered by Complete Physical Memory Mapping are skipped
+pub fn init() {
+    REGIONS.call_once(|| {
+        let mut regions = Vec::new_in(GeneralPurposeAllocator);
+        let acpi_tables = ACPI_TABLES.get().unwrap().lock();
+        let Ok(mcfg) = acpi_tables.find_table::<Mcfg>() else {
+            log::info!("MCFG not found, PCI Express configuration space is not accessible");
+            return regions;
+        };
+        for entry in mcfg.entries() {
+            let region = PcieConfigRegion {
+                segment_group: entry.pci_segment_group,
+                bus_range: entry.bus_number_start..=entry.bus_number_end,
+                base_phys_addr: PhysAddr::new(entry.base_address),
+            };
+            let region_end_phys_addr =
+                region.base_phys_addr + ((*region.bus_range.end() as u64 + 1) << 20) - 1u64;
+            let is_mapped = virtual_memory_manager::translate(
+                virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(region_end_phys_addr),
+            )
+            .is_some();
+            if !is_mapped {
+                log::warn!("PCI Express configuration region {region:X?} is not in CPMM, skipped");
+                continue;
+            }
+            log::info!("PCI Express configuration region {region:X?}");
+            regions.push(region);
+        }
+        regions
+    });
+}
+
+/// Returns regions from MCFG
+pub fn regions() -> &'static [PcieConfigRegion] {
+    REGIONS.get().expect("MCFG is not read")
+}
+
+/// Returns virtual address of function configuration space, None if no region covers it
+pub fn function_config_space(
+    segment_group: u16,
+    bus: u8,
+    device: u8,
+    function: u8,
+) -> Option<VirtAddr> {
+    let region = regions()
+        .iter()
+        .find(|region| region.segment_group == segment_group && region.bus_range.contains(&bus))?;
+    let offset = ((bus as u64) << 20) | ((device as u64) << 15) | ((function as u64) << 12);
+    Some(virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(
+        region.base_phys_addr + offset,
+    ))
+}
@@ -0,0 +1,228 @@
+//! ACPI power management: soft off (S5) and reset
+//!
+//! Registers and sleep type values are collected at [init], so power off and reboot don't lock anything
+//! and can be used from panic handler.
+use super::events::{self, io_port, optional_io_port, PM1_CONTROL_SCI_EN};
+use super::ACPI_TABLES;
+use crate::memory_management::virtual_memory_manager;
+use crate::shutdown;
+use acpi_lib::address::AddressSpace;
+use acpi_lib::fadt::Fadt;
+use aml_lib::value::Args;
+use aml_lib::AmlValue;
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+/// PM1 control registers and S5 sleep type values
+#[derive(Debug)]
+struct SoftOffRegisters {
+    pm1a_control_port: u16,
+    pm1b_control_port: Option<u16>,
+    /// SLP_TYPa value of S5
+    sleep_type_a: u16,
+    /// SLP_TYPb value of S5
+    sleep_type_b: u16,
+}
+
+static SOFT_OFF_REGISTERS: Once<Option<SoftOffRegisters>> = Once::new();
+
+/// FADT RESET_REG location
+#[derive(Debug, Copy, Clone)]
+enum ResetRegisterAddress {
+    Io(u16),
+    Memory(PhysAddr),
+}
+
+/// FADT RESET_REG and RESET_VALUE
+#[derive(Debug)]
+struct ResetRegister {
+    address: ResetRegisterAddress,
+    value: u8,
+}
+
+/// None if FADT doesn't support reset register
+static RESET_REGISTER: Once<Option<ResetRegister>> = Once::new();
+
+/// 8042 keyboard controller command port
+const KEYBOARD_CONTROLLER_COMMAND_PORT: u16 = 0x64;
+
+/// 8042 command pulsing CPU reset line
+const KEYBOARD_CONTROLLER_RESET_PULSE: u8 = 0xFE;
+
+/// Path to S5 (soft off) sleep type package
+const S5_PATH: &str = "\\_S5_";
+
+/// SLP_TYPx values of S5 in QEMU DSDT (both PIIX4 and ICH9), used if AML is not available
+const QEMU_S5_SLEEP_TYPE: u16 = 0;
+
+/// SLP_TYPx field of PM1 control register
+const PM1_CONTROL_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CONTROL_SLP_TYP_MASK: u16 = 0b111 << PM1_CONTROL_SLP_TYP_SHIFT;
+
+/// SLP_EN bit of PM1 control register
+const PM1_CONTROL_SLP_EN: u16 = 1 << 13;
+
+/// How many times the core spins after SLP_EN or reset is written before it is considered failed
+const POWER_OFF_SPINS: usize = 100_000_000;
+
+/// Reads PM1 control registers and reset register from FADT and S5 sleep type from AML
+///
+/// AML must be parsed before
+pub fn init() {
+    RESET_REGISTER.call_once(|| {
+        let acpi_tables = ACPI_TABLES.get().unwrap().lock();
+        let fadt = acpi_tables.find_table::<Fadt>().ok()?;
+        let flags = fadt.flags;
+        if !flags.supports_system_reset_via_fadt() {
+            log::info!("FADT reset register not supported, 8042 reset is used");
+            return None;
+        }
+        let reset_register = fadt.reset_register().ok()?;
+        let address = match reset_register.address_space {
+            AddressSpace::SystemIo => {
+                ResetRegisterAddress::Io(u16::try_from(reset_register.address).ok()?)
+            }
+            AddressSpace::SystemMemory => {
+                ResetRegisterAddress::Memory(PhysAddr::new(reset_register.address))
+            }
+            address_space => {
+                log::warn!(
+                    "FADT reset register in {address_space:?} space not supported, 8042 reset is used"
+                );
+                return None;
+            }
+        };
+        let value = fadt.reset_value;
+        log::info!("ACPI reset: register {address:X?}, value {value:#04X}");
+        Some(ResetRegister { address, value })
+    });
+
+    SOFT_OFF_REGISTERS.call_once(|| {
+        let acpi_tables = ACPI_TABLES.get().unwrap().lock();
+        let fadt = match acpi_tables.find_table::<Fadt>() {
+            Ok(fadt) => fadt,
+            Err(err) => {
+                log::warn!("Failed to get FADT, ACPI power off not supported: {err:?}");
+                return None;
+            }
+        };
+        let pm1a_control_block = fadt.pm1a_control_block().ok();
+        let pm1b_control_block = fadt.pm1b_control_block().ok();
+        drop(acpi_tables);
+        let (Some(pm1a_control_port), Some(pm1b_control_port)) = (
+            pm1a_control_block.as_ref().and_then(io_port),
+            pm1b_control_block.and_then(|block| optional_io_port(block.as_ref())),
+        ) else {
+            log::warn!(
+                "PM1 control registers are not in System IO space, ACPI power off not supported"
+            );
+            return None;
+        };
+
+        let (sleep_type_a, sleep_type_b) = s5_sleep_type().unwrap_or_else(|| {
+            log::warn!("Failed to get {S5_PATH} from AML, QEMU sleep type values are used");
+            (QEMU_S5_SLEEP_TYPE, QEMU_S5_SLEEP_TYPE)
+        });
+        log::info!("ACPI power off: S5 SLP_TYPa {sleep_type_a}, SLP_TYPb {sleep_type_b}");
+        Some(SoftOffRegisters {
+            pm1a_control_port,
+            pm1b_control_port,
+            sleep_type_a,
+            sleep_type_b,
+        })
+    });
+}
+
+/// Returns SLP_TYPa and SLP_TYPb of S5 from \_S5_ package
+///
+/// Package is { SLP_TYPa, SLP_TYPb, reserved, reserved }, some firmware puts both values into first element
+fn s5_sleep_type() -> Option<(u16, u16)> {
+    let AmlValue::Package(elements) = super::evaluate(S5_PATH, Args::EMPTY).ok()? else {
+        return None;
+    };
+    let integer = |element: Option<&AmlValue>| match element? {
+        AmlValue::Integer(value) => Some(*value as u16),
+        _ => None,
+    };
+    let sleep_type_a = integer(elements.first())?;
+    let sleep_type_b = integer(elements.get(1)).unwrap_or(sleep_type_a);
+    Some((sleep_type_a & 0b111, sleep_type_b & 0b111))
+}
+
+/// Powers the machine off (enters S5)
+///
+/// Doesn't flush anything, use [shutdown::shutdown] for orderly stop.
+/// Halts forever if ACPI power off is not supported or failed.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+    let Some(registers) = SOFT_OFF_REGISTERS.get().and_then(Option::as_ref) else {
+        crate::serial_println_lock_free!("ACPI power off not supported");
+        shutdown::halt_forever();
+    };
+
+    // Sleep transitions are ignored in legacy mode
+    let sci_enabled =
+        unsafe { Port::<u16>::new(registers.pm1a_control_port).read() & PM1_CONTROL_SCI_EN != 0 };
+    if !sci_enabled {
+        if let Err(err) = events::enable_acpi_mode() {
+            crate::serial_println_lock_free!("Failed to enable ACPI mode: {err:?}");
+        }
+    }
+
+    // PM1b is written first, the machine may power off right after PM1a write
+    if let Some(pm1b_control_port) = registers.pm1b_control_port {
+        write_sleep_enable(pm1b_control_port, registers.sleep_type_b);
+    }
+    write_sleep_enable(registers.pm1a_control_port, registers.sleep_type_a);
+
+    for _ in 0..POWER_OFF_SPINS {
+        core::hint::spin_loop();
+    }
+    crate::serial_println_lock_free!("ACPI power off failed");
+    shutdown::halt_forever()
+}
+
+/// Writes SLP_TYPx and SLP_EN to PM1 control register, preserving other bits
+fn write_sleep_enable(pm1_control_port: u16, sleep_type: u16) {
+    let mut pm1_control = Port::<u16>::new(pm1_control_port);
+    unsafe {
+        let value = pm1_control.read() & !(PM1_CONTROL_SLP_TYP_MASK | PM1_CONTROL_SLP_EN);
+        pm1_control.write(value | sleep_type << PM1_CONTROL_SLP_TYP_SHIFT | PM1_CONTROL_SLP_EN);
+    }
+}
+
+/// Resets the machine
+///
+/// Uses FADT reset register if supported, otherwise pulses reset line through 8042 keyboard controller.
+/// Doesn't flush anything, halts forever if both methods failed.
+///
+/// Not called at boot, reset ends the run, check it manually (e.g. QEMU without `-no-reboot` restarts the kernel).
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    if let Some(reset_register) = RESET_REGISTER.get().and_then(Option::as_ref) {
+        match reset_register.address {
+            ResetRegisterAddress::Io(port) => unsafe {
+                Port::<u8>::new(port).write(reset_register.value);
+            },
+            ResetRegisterAddress::Memory(phys_addr) => unsafe {
+                virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(phys_addr)
+                    .as_mut_ptr::<u8>()
+                    .write_volatile(reset_register.value);
+            },
+        }
+        for _ in 0..POWER_OFF_SPINS {
+            core::hint::spin_loop();
+        }
+        crate::serial_println_lock_free!("ACPI reset failed, trying 8042 reset");
+    }
+
+    unsafe {
+        Port::<u8>::new(KEYBOARD_CONTROLLER_COMMAND_PORT).write(KEYBOARD_CONTROLLER_RESET_PULSE);
+    }
+    for _ in 0..POWER_OFF_SPINS {
+        core::hint::spin_loop();
+    }
+    crate::serial_println_lock_free!("8042 reset failed");
+    shutdown::halt_forever()
+}
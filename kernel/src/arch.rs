@@ -0,0 +1,105 @@
+use crate::cpu;
+use spin::Once;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{FsBase, GsBase, KernelGsBase};
+use x86_64::registers::segmentation::{Segment64, FS, GS};
+use x86_64::VirtAddr;
+
+/// Whether FSGSBASE instructions (rdfsbase, wrfsbase, rdgsbase, wrgsbase) are supported and enabled
+static FSGSBASE_ENABLED: Once<bool> = Once::new();
+
+/// Enables FSGSBASE instructions if CPU supports them
+pub fn init() {
+    FSGSBASE_ENABLED.call_once(|| {
+        let has_fsgsbase = cpu::features().fsgsbase;
+        if has_fsgsbase {
+            unsafe {
+                Cr4::update(|flags| flags.insert(Cr4Flags::FSGSBASE));
+            }
+            log::info!("FSGSBASE instructions enabled");
+        } else {
+            log::info!("FSGSBASE instructions not supported, MSRs will be used");
+        }
+        has_fsgsbase
+    });
+}
+
+#[inline]
+fn fsgsbase_enabled() -> bool {
+    FSGSBASE_ENABLED.get().copied().unwrap_or(false)
+}
+
+/// Sets FS base (IA32_FS_BASE MSR 0xC0000100)
+///
+/// Used for thread-local storage
+#[inline]
+pub fn set_fs_base(addr: VirtAddr) {
+    if fsgsbase_enabled() {
+        unsafe {
+            FS::write_base(addr);
+        }
+    } else {
+        FsBase::write(addr);
+    }
+}
+
+/// Gets FS base (IA32_FS_BASE MSR 0xC0000100)
+#[inline]
+pub fn get_fs_base() -> VirtAddr {
+    if fsgsbase_enabled() {
+        FS::read_base()
+    } else {
+        FsBase::read()
+    }
+}
+
+/// Sets GS base (IA32_GS_BASE MSR 0xC0000101)
+///
+/// Used for per-CPU data
+#[inline]
+pub fn set_gs_base(addr: VirtAddr) {
+    if fsgsbase_enabled() {
+        unsafe {
+            GS::write_base(addr);
+        }
+    } else {
+        GsBase::write(addr);
+    }
+}
+
+/// Gets GS base (IA32_GS_BASE MSR 0xC0000101)
+#[inline]
+pub fn get_gs_base() -> VirtAddr {
+    if fsgsbase_enabled() {
+        GS::read_base()
+    } else {
+        GsBase::read()
+    }
+}
+
+/// Sets kernel GS base (IA32_KERNEL_GS_BASE MSR 0xC0000102)
+///
+/// It is swapped with GS base by swapgs
+#[inline]
+pub fn set_kernel_gs_base(addr: VirtAddr) {
+    KernelGsBase::write(addr);
+}
+
+/// Gets kernel GS base (IA32_KERNEL_GS_BASE MSR 0xC0000102)
+#[inline]
+pub fn get_kernel_gs_base() -> VirtAddr {
+    KernelGsBase::read()
+}
+
+/// Swaps GS base and kernel GS base (swapgs)
+///
+/// Must be called at syscall entry and exit (and at interrupt entry and exit from user mode)
+///
+/// # Safety
+/// Caller must track which GS base is active, double swap gives user GS base to kernel code
+#[inline]
+pub unsafe fn swapgs() {
+    unsafe {
+        GS::swap();
+    }
+}
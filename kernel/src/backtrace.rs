@@ -0,0 +1,76 @@
+//! Stack backtrace using frame pointers
+//!
+//! Kernel is built with `-C force-frame-pointers=yes` (kernel/config.toml), so every frame starts with saved RBP
+//! followed by the return address. Addresses are printed raw, resolve them with `addr2line -e <kernel elf>`.
+use crate::memory_management::virtual_memory_manager;
+use x86_64::VirtAddr;
+
+/// Frames deeper than this are not walked
+const MAX_FRAMES: usize = 64;
+
+/// Frame pointers must stay this close above the stack pointer, BSP kernel stack (128 KB) is the largest
+const MAX_STACK_SIZE: u64 = 128 * 1024;
+
+/// Calls visitor with return address of every frame, starting from the caller
+///
+/// Stops at [MAX_FRAMES], when frame pointer leaves the stack, isn't mapped or doesn't go up the stack.
+/// Doesn't lock anything, can be used in panic handler.
+#[inline(never)]
+pub fn walk(mut visitor: impl FnMut(VirtAddr)) {
+    let stack_pointer: u64;
+    let mut frame_pointer: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, rsp",
+            "mov {}, rbp",
+            out(reg) stack_pointer,
+            out(reg) frame_pointer,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    let stack_end = stack_pointer.saturating_add(MAX_STACK_SIZE);
+
+    for _ in 0..MAX_FRAMES {
+        // Saved RBP and return address
+        if frame_pointer < stack_pointer
+            || frame_pointer.saturating_add(16) > stack_end
+            || frame_pointer % 8 != 0
+            || !is_mapped(frame_pointer)
+            || !is_mapped(frame_pointer + 8)
+        {
+            break;
+        }
+        let (saved_frame_pointer, return_address) = unsafe {
+            let frame = frame_pointer as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if return_address == 0 {
+            break;
+        }
+        visitor(VirtAddr::new_truncate(return_address));
+        if saved_frame_pointer <= frame_pointer {
+            break;
+        }
+        frame_pointer = saved_frame_pointer;
+    }
+}
+
+/// Prints backtrace to COM1 without lock
+pub fn print() {
+    crate::serial_println_lock_free!("Backtrace:");
+    let mut frames = 0;
+    walk(|return_address| {
+        crate::serial_println_lock_free!("  #{frames:<2} 0x{:016X}", return_address.as_u64());
+        frames += 1;
+    });
+    if frames == 0 {
+        crate::serial_println_lock_free!("  no frames (frame pointers are not available)");
+    }
+}
+
+fn is_mapped(virt_addr: u64) -> bool {
+    VirtAddr::try_new(virt_addr)
+        .ok()
+        .and_then(virtual_memory_manager::translate)
+        .is_some()
+}
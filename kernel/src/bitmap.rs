@@ -0,0 +1,134 @@
+//! Bitmap for allocator tracking (vectors, comparators, pages, etc.)
+//!
+//! Not synchronized, wrap it in a Mutex if it's shared.
+
+/// Bitmap backed by u64 words, a fixed array ([ArrayBitmap]) or a borrowed slice
+///
+/// All operations check bounds and panic on out of range index
+pub struct Bitmap<S: AsRef<[u64]> + AsMut<[u64]>> {
+    words: S,
+    /// Number of bits in use, the rest bits of the last word are ignored
+    bits: usize,
+}
+
+/// Bitmap with WORDS * 64 bits stored inline
+pub type ArrayBitmap<const WORDS: usize> = Bitmap<[u64; WORDS]>;
+
+impl<const WORDS: usize> Bitmap<[u64; WORDS]> {
+    /// Creates bitmap with all WORDS * 64 bits cleared
+    pub const fn new_array() -> Self {
+        Self {
+            words: [0; WORDS],
+            bits: WORDS * u64::BITS as usize,
+        }
+    }
+}
+
+impl<'a> Bitmap<&'a mut [u64]> {
+    /// Creates bitmap of bits bits over words, clears them
+    ///
+    /// words must contain at least bits bits
+    pub fn new_in(words: &'a mut [u64], bits: usize) -> Self {
+        assert!(
+            bits <= words.len() * u64::BITS as usize,
+            "Bitmap storage is too small"
+        );
+        words.fill(0);
+        Self { words, bits }
+    }
+}
+
+impl<S: AsRef<[u64]> + AsMut<[u64]>> Bitmap<S> {
+    /// Returns number of bits
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bits
+    }
+
+    #[inline]
+    fn check_index(&self, index: usize) {
+        assert!(
+            index < self.bits,
+            "Bitmap index {index} out of range {}",
+            self.bits
+        );
+    }
+
+    /// Sets bit
+    #[inline]
+    pub fn set(&mut self, index: usize) {
+        self.check_index(index);
+        self.words.as_mut()[index / 64] |= 1 << (index % 64);
+    }
+
+    /// Clears bit
+    #[inline]
+    pub fn clear(&mut self, index: usize) {
+        self.check_index(index);
+        self.words.as_mut()[index / 64] &= !(1 << (index % 64));
+    }
+
+    /// Returns true if bit is set
+    #[inline]
+    pub fn test(&self, index: usize) -> bool {
+        self.check_index(index);
+        self.words.as_ref()[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Sets bits in range start..start + count
+    pub fn set_range(&mut self, start: usize, count: usize) {
+        for index in start..start + count {
+            self.set(index);
+        }
+    }
+
+    /// Clears bits in range start..start + count
+    pub fn clear_range(&mut self, start: usize, count: usize) {
+        for index in start..start + count {
+            self.clear(index);
+        }
+    }
+
+    /// Returns index of the first clear bit
+    pub fn find_first_clear(&self) -> Option<usize> {
+        self.words
+            .as_ref()
+            .iter()
+            .enumerate()
+            .find(|(_, word)| **word != u64::MAX)
+            .map(|(word_index, word)| word_index * 64 + word.trailing_ones() as usize)
+            .filter(|index| *index < self.bits)
+    }
+
+    /// Returns index of the first run of run_length clear bits
+    ///
+    /// run_length must be not zero
+    pub fn find_first_clear_run(&self, run_length: usize) -> Option<usize> {
+        assert_ne!(run_length, 0, "Zero run length");
+        let words = self.words.as_ref();
+        let mut run_start = 0;
+        let mut index = 0;
+        while index < self.bits {
+            let word = words[index / 64];
+            // Whole set word breaks the run, whole clear word continues it
+            if index % 64 == 0 && word == u64::MAX {
+                index += 64;
+                run_start = index;
+                continue;
+            }
+            if index % 64 == 0 && word == 0 {
+                index += 64;
+            } else if word & (1 << (index % 64)) != 0 {
+                index += 1;
+                run_start = index;
+                continue;
+            } else {
+                index += 1;
+            }
+            if index.min(self.bits) - run_start >= run_length {
+                return Some(run_start);
+            }
+        }
+        None
+    }
+}
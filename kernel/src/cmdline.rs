@@ -0,0 +1,50 @@
+//! Kernel command line
+//!
+//! Bootloader doesn't pass a command line, so the image builder (`--cmdline`) writes it
+//! into [CMDLINE_SECTION] of the kernel file before the image is created.
+use core::cell::UnsafeCell;
+use spin::Once;
+
+/// Section name, the image builder looks for it
+pub const CMDLINE_SECTION: &str = ".cmdline";
+
+/// Section size, command line is NUL-terminated
+pub const CMDLINE_SIZE: usize = 256;
+
+/// Written by the image builder, UnsafeCell keeps the compiler from folding zeros
+#[repr(transparent)]
+struct CmdlineBuffer(UnsafeCell<[u8; CMDLINE_SIZE]>);
+
+unsafe impl Sync for CmdlineBuffer {}
+
+#[used]
+#[link_section = ".cmdline"]
+static CMDLINE: CmdlineBuffer = CmdlineBuffer(UnsafeCell::new([0; CMDLINE_SIZE]));
+
+static CMDLINE_STR: Once<&'static str> = Once::new();
+
+/// Returns the command line, empty if it's not set or not UTF-8
+pub fn get() -> &'static str {
+    CMDLINE_STR.call_once(|| {
+        // SAFETY: the buffer is never written by the kernel
+        let bytes = unsafe { &*CMDLINE.0.get() };
+        let length = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(CMDLINE_SIZE);
+        core::str::from_utf8(&bytes[..length])
+            .unwrap_or_else(|err| {
+                log::warn!("Command line is not UTF-8: {err}");
+                ""
+            })
+            .trim()
+    })
+}
+
+/// Returns value of `key=value` argument, arguments are separated by whitespace
+pub fn arg(key: &str) -> Option<&'static str> {
+    get().split_whitespace().find_map(|arg| {
+        let (arg_key, value) = arg.split_once('=')?;
+        (arg_key == key).then_some(value)
+    })
+}
@@ -1,33 +1,421 @@
 // COM ports global variables for synchronization
 
-use spin::Mutex;
+use crate::interrupts::idt;
+use crate::sync::ByteRingBuffer;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use spin::{Mutex, MutexGuard};
 use x86_64::instructions::port::Port;
 
 /// COM1 port for printing QEMU logs
 ///
-/// **Don't use in interrupts**<br>
-/// Use [COM1_PORT_LOCK_FREE] instead
-pub static COM1_PORT: Mutex<uart_16550::SerialPort> =
+/// Locked by [SerialChannel::lock], it records the owner CPU<br>
+/// **Don't lock it in interrupts**<br>
+/// Print with [SmartSerialPrinter](crate::serial_debug::serial_printer::SmartSerialPrinter) or [COM1_PORT_LOCK_FREE] instead
+static COM1_PORT: Mutex<uart_16550::SerialPort> =
     unsafe { Mutex::new(uart_16550::SerialPort::new(0x3F8)) };
 
 /// Lock free COM1 port for printing QEMU logs in interrupts
 pub static mut COM1_PORT_LOCK_FREE: uart_16550::SerialPort =
     unsafe { uart_16550::SerialPort::new(0x3F8) };
 
+/// COM2 port, e.g. for a separate log channel
+///
+/// **Don't use in interrupts**
+static COM2_PORT: Mutex<uart_16550::SerialPort> =
+    unsafe { Mutex::new(uart_16550::SerialPort::new(0x2F8)) };
+
+/// COM3 port
+///
+/// **Don't use in interrupts**
+static COM3_PORT: Mutex<uart_16550::SerialPort> =
+    unsafe { Mutex::new(uart_16550::SerialPort::new(0x3E8)) };
+
+/// COM4 port
+///
+/// **Don't use in interrupts**
+static COM4_PORT: Mutex<uart_16550::SerialPort> =
+    unsafe { Mutex::new(uart_16550::SerialPort::new(0x2E8)) };
+
+/// Serial port selector
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SerialChannel {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl SerialChannel {
+    pub const ALL: [SerialChannel; 4] = [
+        SerialChannel::Com1,
+        SerialChannel::Com2,
+        SerialChannel::Com3,
+        SerialChannel::Com4,
+    ];
+
+    /// First IO port of UART registers
+    pub const fn base_port(self) -> u16 {
+        match self {
+            SerialChannel::Com1 => 0x3F8,
+            SerialChannel::Com2 => 0x2F8,
+            SerialChannel::Com3 => 0x3E8,
+            SerialChannel::Com4 => 0x2E8,
+        }
+    }
+
+    /// Locks port and records this CPU as its owner
+    ///
+    /// **Don't use in interrupts**<br>
+    /// Use [SerialChannel::send_lock_free] instead
+    pub fn lock(self) -> PortGuard {
+        // Interrupt between locking and recording the owner would see the port locked by another CPU and wait forever
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let guard = self.port().lock();
+            PORT_OWNERS[self as usize].store(this_cpu_port_owner_id(), Ordering::Relaxed);
+            PortGuard {
+                guard: ManuallyDrop::new(guard),
+                channel: self,
+            }
+        })
+    }
+
+    /// Whether the port is locked by this CPU
+    ///
+    /// Locking it again on this CPU (e.g. from an interrupt handler) would deadlock
+    pub fn is_locked_by_this_cpu(self) -> bool {
+        PORT_OWNERS[self as usize].load(Ordering::Relaxed) == this_cpu_port_owner_id()
+    }
+
+    /// Port mutex, it's locked only by [SerialChannel::lock]
+    fn port(self) -> &'static Mutex<uart_16550::SerialPort> {
+        match self {
+            SerialChannel::Com1 => &COM1_PORT,
+            SerialChannel::Com2 => &COM2_PORT,
+            SerialChannel::Com3 => &COM3_PORT,
+            SerialChannel::Com4 => &COM4_PORT,
+        }
+    }
+
+    /// Sends byte without lock, can be used in interrupts
+    pub fn send_lock_free(self, byte: u8) {
+        // SerialPort only holds port numbers
+        let mut serial_port = unsafe { uart_16550::SerialPort::new(self.base_port()) };
+        serial_port.send(byte);
+    }
+
+    /// Whether the port was detected at [init]
+    ///
+    /// Bytes sent to a missing port are lost
+    pub fn is_present(self) -> bool {
+        PRESENT_CHANNELS.load(Ordering::Relaxed) & (1 << self as u8) != 0
+    }
+
+    /// Enables or disables loopback mode
+    ///
+    /// In loopback mode sent bytes are received back and nothing goes out, so nothing must be printed meanwhile
+    pub fn set_loopback(self, enabled: bool) {
+        let mut modem_control_register = Port::<u8>::new(self.base_port() + 4);
+        unsafe {
+            let value = modem_control_register.read();
+            if enabled {
+                modem_control_register.write(value | MODEM_CONTROL_LOOPBACK);
+            } else {
+                modem_control_register.write(value & !MODEM_CONTROL_LOOPBACK);
+            }
+        }
+    }
+
+    /// Reads received byte directly from the port without waiting
+    ///
+    /// COM1 bytes are taken by IRQ 4 handler, use [try_read_byte] for it
+    pub fn try_receive_raw(self) -> Option<u8> {
+        unsafe {
+            let line_status = Port::<u8>::new(self.base_port() + 5).read();
+            (line_status & LINE_STATUS_DATA_READY != 0)
+                .then(|| Port::<u8>::new(self.base_port()).read())
+        }
+    }
+
+    /// Checks UART presence using scratch register
+    fn probe(self) -> bool {
+        let mut scratch_register = Port::<u8>::new(self.base_port() + 7);
+        unsafe {
+            scratch_register.write(SCRATCH_PROBE_VALUE);
+            scratch_register.read() == SCRATCH_PROBE_VALUE
+        }
+    }
+}
+
+/// Owner id of CPU holding port lock, indexed by [SerialChannel], [NO_PORT_OWNER] if not locked
+static PORT_OWNERS: [AtomicU32; 4] = [const { AtomicU32::new(NO_PORT_OWNER) }; 4];
+
+/// Port is not locked
+const NO_PORT_OWNER: u32 = u32::MAX;
+
+/// Returns port owner id of this CPU: APIC ID + 1, 0 before per-CPU block exists (only the BSP runs then)
+fn this_cpu_port_owner_id() -> u32 {
+    crate::percpu::try_this_cpu().map_or(0, |cpu_local| cpu_local.apic_id + 1)
+}
+
+/// Locked port, the owner is cleared when it's dropped
+pub struct PortGuard {
+    guard: ManuallyDrop<MutexGuard<'static, uart_16550::SerialPort>>,
+    channel: SerialChannel,
+}
+
+impl Deref for PortGuard {
+    type Target = uart_16550::SerialPort;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl DerefMut for PortGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl Drop for PortGuard {
+    fn drop(&mut self) {
+        // Like in SerialChannel::lock, interrupt must not see the port locked without owner
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            PORT_OWNERS[self.channel as usize].store(NO_PORT_OWNER, Ordering::Relaxed);
+            unsafe { ManuallyDrop::drop(&mut self.guard) };
+        });
+    }
+}
+
+/// Bit mask of detected [SerialChannel]s
+static PRESENT_CHANNELS: AtomicU8 = AtomicU8::new(0);
+
+/// Written to scratch register and read back, missing port reads 0xFF
+const SCRATCH_PROBE_VALUE: u8 = 0x5A;
+
 /// Inits COM ports
 pub fn init() {
     #[allow(static_mut_refs)]
     unsafe {
         COM1_PORT_LOCK_FREE.init();
-        // Disable all COM1 interrupts (Input to qemu monitor triggers interrupts from COM1)
+        // Only receive interrupt is enabled (Input to qemu serial console triggers it)
         // Disable DLAB (clear MSB in Line Control Register)
         let mut line_control_register = Port::<u8>::new(0x3F8 + 3);
         let mut line_control_register_value = line_control_register.read();
         line_control_register_value &= 0b0111_1111u8;
         line_control_register.write(line_control_register_value);
 
-        // Disable all interrupts
+        // Enable only Received Data Available interrupt, bytes are taken in IRQ 4 handler without COM1 lock
         let mut interrupt_enable_register = Port::<u8>::new(0x3F8 + 1);
-        interrupt_enable_register.write(0);
+        interrupt_enable_register.write(INTERRUPT_ENABLE_RECEIVED_DATA_AVAILABLE);
     };
+    if let Err(err) = idt::register_handler(
+        idt::IO_APIC_ISA_IRQ_VECTORS_RANGE.start() + COM1_ISA_IRQ,
+        |_| receive_com1(),
+    ) {
+        log::warn!("Failed to register COM1 receive handler: {err:?}");
+    }
+    // COM1 is used even if probe failed, QEMU logs go there
+    PRESENT_CHANNELS.fetch_or(1 << SerialChannel::Com1 as u8, Ordering::Relaxed);
+
+    // Other ports are only used for output, their interrupts are disabled
+    for channel in SerialChannel::ALL.into_iter().skip(1) {
+        if !channel.probe() {
+            continue;
+        }
+        channel.lock().init();
+        unsafe {
+            Port::<u8>::new(channel.base_port() + 1).write(0);
+        }
+        PRESENT_CHANNELS.fetch_or(1 << channel as u8, Ordering::Relaxed);
+    }
+}
+
+/// UART clock divided by 16, baud rate with divisor 1
+const MAX_BAUD_RATE: u32 = 115200;
+
+/// Line Control Register: divisor latch access bit
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+
+/// FIFO Control Register: enable and clear FIFOs, 14 bytes receive trigger level
+const FIFO_CONTROL_ENABLE_CLEAR_14: u8 = 0b1100_0111;
+
+/// Parity bit
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Always 1
+    Mark,
+    /// Always 0
+    Space,
+}
+
+impl Parity {
+    /// Line Control Register bits 3-5
+    fn line_control_bits(self) -> u8 {
+        match self {
+            Parity::None => 0b000 << 3,
+            Parity::Odd => 0b001 << 3,
+            Parity::Even => 0b011 << 3,
+            Parity::Mark => 0b101 << 3,
+            Parity::Space => 0b111 << 3,
+        }
+    }
+}
+
+/// COM port configuration error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConfigureError {
+    /// Baud rate doesn't divide 115200 evenly
+    BaudRateNotDivisor(u32),
+    /// Data bits are not 5-8
+    DataBitsOutOfRange(u8),
+    /// Stop bits are not 1 or 2
+    StopBitsOutOfRange(u8),
+}
+
+/// Returns divisor latch and Line Control Register values of baud rate and framing, see [configure]
+pub fn line_settings(
+    baud: u32,
+    data_bits: u8,
+    parity: Parity,
+    stop_bits: u8,
+) -> Result<(u16, u8), ConfigureError> {
+    if baud == 0 || MAX_BAUD_RATE % baud != 0 {
+        return Err(ConfigureError::BaudRateNotDivisor(baud));
+    }
+    if !(5..=8).contains(&data_bits) {
+        return Err(ConfigureError::DataBitsOutOfRange(data_bits));
+    }
+    if !(1..=2).contains(&stop_bits) {
+        return Err(ConfigureError::StopBitsOutOfRange(stop_bits));
+    }
+    let divisor = (MAX_BAUD_RATE / baud) as u16;
+    let line_control_register_value =
+        (data_bits - 5) | (stop_bits - 1) << 2 | parity.line_control_bits();
+    Ok((divisor, line_control_register_value))
+}
+
+/// Sets COM1 baud rate and framing
+///
+/// Programs divisor latch, Line Control Register and FIFO Control Register.
+/// 2 stop bits mean 1.5 stop bits if data bits is 5.
+/// Baud rate is limited by 115200, QEMU doesn't throttle output by it anyway.
+pub fn configure(
+    baud: u32,
+    data_bits: u8,
+    parity: Parity,
+    stop_bits: u8,
+) -> Result<(), ConfigureError> {
+    let (divisor, line_control_register_value) = line_settings(baud, data_bits, parity, stop_bits)?;
+
+    // Nothing is sent while the divisor is changed
+    let _com1_port_lock = SerialChannel::Com1.lock();
+    flush_com1();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut line_control_register = Port::<u8>::new(0x3F8 + 3);
+        line_control_register.write(LINE_CONTROL_DLAB);
+        // Divisor Latch Low and High bytes are at data and interrupt enable registers with DLAB set
+        Port::<u8>::new(0x3F8).write(divisor as u8);
+        Port::<u8>::new(0x3F8 + 1).write((divisor >> 8) as u8);
+        // DLAB is cleared
+        line_control_register.write(line_control_register_value);
+        Port::<u8>::new(0x3F8 + 2).write(FIFO_CONTROL_ENABLE_CLEAR_14);
+    });
+    Ok(())
+}
+
+/// Reads COM1 divisor latch
+pub fn com1_divisor() -> u16 {
+    let _com1_port_lock = SerialChannel::Com1.lock();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut line_control_register = Port::<u8>::new(0x3F8 + 3);
+        let line_control_register_value = line_control_register.read();
+        line_control_register.write(line_control_register_value | LINE_CONTROL_DLAB);
+        let low = Port::<u8>::new(0x3F8).read();
+        let high = Port::<u8>::new(0x3F8 + 1).read();
+        line_control_register.write(line_control_register_value);
+        u16::from_le_bytes([low, high])
+    })
+}
+
+/// COM1 ISA IRQ
+const COM1_ISA_IRQ: u8 = 4;
+
+/// Interrupt Enable Register: received data available
+const INTERRUPT_ENABLE_RECEIVED_DATA_AVAILABLE: u8 = 1;
+
+/// Line Status Register: data ready
+const LINE_STATUS_DATA_READY: u8 = 1;
+
+/// Modem Control Register: loopback mode, sent bytes are received back
+const MODEM_CONTROL_LOOPBACK: u8 = 1 << 4;
+
+/// Received bytes, pushed by [receive_com1]
+static RECEIVE_BUFFER: ByteRingBuffer<256> = ByteRingBuffer::new();
+
+/// Moves received bytes from COM1 into the ring buffer
+///
+/// Called from IRQ 4 handler, doesn't lock COM1. Bytes are dropped if the buffer is full.
+/// Must not run on two cores at once (IO APIC delivers IRQ 4 to the bootstrap processor only).
+pub fn receive_com1() {
+    let mut line_status_register = Port::<u8>::new(0x3F8 + 5);
+    let mut receiver_buffer_register = Port::<u8>::new(0x3F8);
+    while unsafe { line_status_register.read() } & LINE_STATUS_DATA_READY != 0 {
+        let byte = unsafe { receiver_buffer_register.read() };
+        RECEIVE_BUFFER.push(byte);
+    }
+}
+
+/// Takes received byte from COM1 buffer
+pub fn try_read_byte() -> Option<u8> {
+    RECEIVE_BUFFER.pop()
+}
+
+/// Reads line from COM1 into buf, returns its length
+///
+/// Waits until CR or LF is received or buf is full, line ending is not stored.
+/// Interrupts must be enabled.
+pub fn read_line(buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    while len < buf.len() {
+        match try_read_byte() {
+            Some(b'\r' | b'\n') => break,
+            Some(byte) => {
+                buf[len] = byte;
+                len += 1;
+            }
+            None => core::hint::spin_loop(),
+        }
+    }
+    len
+}
+
+/// Line Status Register: transmitter holding register and shift register are empty
+const LINE_STATUS_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+/// Waits until COM1 sends all written bytes
+///
+/// Doesn't lock COM1, polling is bounded so a missing UART doesn't hang the caller
+pub fn flush_com1() {
+    SerialChannel::Com1.flush();
+}
+
+impl SerialChannel {
+    /// Waits until the port sends all written bytes
+    ///
+    /// Doesn't lock the port, polling is bounded so a missing UART doesn't hang the caller
+    pub fn flush(self) {
+        let mut line_status_register = Port::<u8>::new(self.base_port() + 5);
+        for _ in 0..1_000_000 {
+            if unsafe { line_status_register.read() } & LINE_STATUS_TRANSMITTER_EMPTY != 0 {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
 }
@@ -0,0 +1,162 @@
+//! CPU identification and features
+//!
+//! CPUID is queried once (on the BSP), APs are assumed to have the same features.
+use core::fmt;
+use raw_cpuid::CpuId;
+use spin::Once;
+
+static CPU_FEATURES: Once<CpuFeatures> = Once::new();
+
+/// Features used or reported by the kernel
+#[derive(Debug)]
+pub struct CpuFeatures {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub apic: bool,
+    pub x2apic: bool,
+    /// Local APIC timer TSC-deadline mode
+    pub tsc_deadline: bool,
+    /// TSC runs at constant rate in all P-, C- and T-states
+    pub invariant_tsc: bool,
+    pub nx: bool,
+    pub one_gb_pages: bool,
+    pub pat: bool,
+    pub fsgsbase: bool,
+    pub sse: bool,
+    pub sse2: bool,
+    pub sse3: bool,
+    pub ssse3: bool,
+    pub sse4_1: bool,
+    pub sse4_2: bool,
+    pub avx: bool,
+    pub avx2: bool,
+    pub rdrand: bool,
+    pub rdseed: bool,
+    /// Running under a hypervisor
+    pub hypervisor: bool,
+}
+
+impl CpuFeatures {
+    fn detect() -> Self {
+        let cpuid = CpuId::new();
+        let feature_info = cpuid
+            .get_feature_info()
+            .expect("Failed to get CPUID features!");
+        let extended_feature_info = cpuid.get_extended_feature_info();
+        let extended_processor_feature_info =
+            cpuid.get_extended_processor_and_feature_identifiers();
+
+        let mut vendor = [0; 12];
+        if let Some(vendor_info) = cpuid.get_vendor_info() {
+            copy_str(&mut vendor, vendor_info.as_str());
+        }
+        let mut brand = [0; 48];
+        if let Some(brand_string) = cpuid.get_processor_brand_string() {
+            copy_str(&mut brand, brand_string.as_str().trim());
+        }
+
+        Self {
+            vendor,
+            brand,
+            apic: feature_info.has_apic(),
+            x2apic: feature_info.has_x2apic(),
+            tsc_deadline: feature_info.has_tsc_deadline(),
+            invariant_tsc: cpuid.get_advanced_power_mgmt_info().is_some_and(
+                |advanced_power_mgmt_info| advanced_power_mgmt_info.has_invariant_tsc(),
+            ),
+            nx: extended_processor_feature_info.as_ref().is_some_and(
+                |extended_processor_feature_info| {
+                    extended_processor_feature_info.has_execute_disable()
+                },
+            ),
+            one_gb_pages: extended_processor_feature_info.as_ref().is_some_and(
+                |extended_processor_feature_info| extended_processor_feature_info.has_1gib_pages(),
+            ),
+            pat: feature_info.has_pat(),
+            fsgsbase: extended_feature_info
+                .as_ref()
+                .is_some_and(|extended_feature_info| extended_feature_info.has_fsgsbase()),
+            sse: feature_info.has_sse(),
+            sse2: feature_info.has_sse2(),
+            sse3: feature_info.has_sse3(),
+            ssse3: feature_info.has_ssse3(),
+            sse4_1: feature_info.has_sse41(),
+            sse4_2: feature_info.has_sse42(),
+            avx: feature_info.has_avx(),
+            avx2: extended_feature_info
+                .as_ref()
+                .is_some_and(|extended_feature_info| extended_feature_info.has_avx2()),
+            rdrand: feature_info.has_rdrand(),
+            rdseed: extended_feature_info
+                .as_ref()
+                .is_some_and(|extended_feature_info| extended_feature_info.has_rdseed()),
+            hypervisor: feature_info.has_hypervisor(),
+        }
+    }
+
+    /// Vendor string, e.g. "GenuineIntel"
+    pub fn vendor(&self) -> &str {
+        str_from_bytes(&self.vendor)
+    }
+
+    /// Processor brand string, empty if CPUID doesn't report it
+    pub fn brand(&self) -> &str {
+        str_from_bytes(&self.brand)
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:?}:", self.vendor(), self.brand())?;
+        let features = [
+            ("apic", self.apic),
+            ("x2apic", self.x2apic),
+            ("tsc-deadline", self.tsc_deadline),
+            ("invariant-tsc", self.invariant_tsc),
+            ("nx", self.nx),
+            ("1gb-pages", self.one_gb_pages),
+            ("pat", self.pat),
+            ("fsgsbase", self.fsgsbase),
+            ("sse", self.sse),
+            ("sse2", self.sse2),
+            ("sse3", self.sse3),
+            ("ssse3", self.ssse3),
+            ("sse4.1", self.sse4_1),
+            ("sse4.2", self.sse4_2),
+            ("avx", self.avx),
+            ("avx2", self.avx2),
+            ("rdrand", self.rdrand),
+            ("rdseed", self.rdseed),
+            ("hypervisor", self.hypervisor),
+        ];
+        for (name, _) in features.iter().filter(|(_, supported)| *supported) {
+            write!(f, " {name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies string, truncating it to the buffer
+fn copy_str(buffer: &mut [u8], string: &str) {
+    let length = string.len().min(buffer.len());
+    buffer[..length].copy_from_slice(&string.as_bytes()[..length]);
+}
+
+/// Returns string before the first NUL
+fn str_from_bytes(bytes: &[u8]) -> &str {
+    let length = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..length]).unwrap_or("")
+}
+
+/// Queries CPUID and logs features
+pub fn init() {
+    log::info!("CPU: {}", features());
+}
+
+/// Returns features of the BSP, CPUID is queried on the first call
+pub fn features() -> &'static CpuFeatures {
+    CPU_FEATURES.call_once(CpuFeatures::detect)
+}
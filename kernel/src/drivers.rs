@@ -0,0 +1,4 @@
+//! Device drivers
+pub mod keyboard;
+pub mod pci;
+pub mod rtc;
@@ -0,0 +1,230 @@
+//! PS/2 keyboard on ISA IRQ 1
+//!
+//! IRQ handler only moves scancodes from the controller into a ring buffer,
+//! they are decoded (Scancode Set 1, translated by the controller) in [poll].
+use crate::interrupts::apic::ioapic;
+use crate::interrupts::idt;
+use crate::sync::ByteRingBuffer;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// PS/2 controller data port
+const DATA_PORT: u16 = 0x60;
+
+/// PS/2 controller status register
+const STATUS_PORT: u16 = 0x64;
+
+/// Status Register: output buffer full, data port has a byte
+const STATUS_OUTPUT_FULL: u8 = 1;
+
+/// Keyboard ISA IRQ
+const KEYBOARD_ISA_IRQ: u8 = 1;
+
+/// How many bytes are read from the data port at once, a missing controller reads 0xFF forever
+const MAX_BYTES_PER_READ: usize = 16;
+
+/// Raw scancodes, pushed by IRQ 1 handler
+static SCANCODES: ByteRingBuffer<128> = ByteRingBuffer::new();
+
+/// Decoder of [SCANCODES], taken by [poll]
+static DECODER: Mutex<ScancodeDecoder> = Mutex::new(ScancodeDecoder::new());
+
+/// Key, printable keys are unshifted ASCII characters
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyCode {
+    Char(char),
+    Escape,
+    Backspace,
+    Tab,
+    Enter,
+    LeftCtrl,
+    RightCtrl,
+    LeftShift,
+    RightShift,
+    LeftAlt,
+    RightAlt,
+    CapsLock,
+    /// F1-F12
+    Function(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+}
+
+/// Key press or release
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+}
+
+/// Scancode Set 1 decoder
+///
+/// Keeps the extended (0xE0) prefix between bytes
+#[derive(Debug)]
+pub struct ScancodeDecoder {
+    extended: bool,
+    /// Remaining bytes of Pause sequence (E1 1D 45 E1 9D C5), it has no release
+    pause_bytes_left: u8,
+}
+
+/// Extended scancode prefix
+const EXTENDED_PREFIX: u8 = 0xE0;
+
+/// Pause key sequence prefix
+const PAUSE_PREFIX: u8 = 0xE1;
+
+/// Release scancode is make code with this bit set
+const RELEASE_BIT: u8 = 0x80;
+
+impl ScancodeDecoder {
+    pub const fn new() -> Self {
+        Self {
+            extended: false,
+            pause_bytes_left: 0,
+        }
+    }
+
+    /// Feeds scancode byte, returns event when a key sequence is complete
+    ///
+    /// Unknown keys and fake shifts around extended keys (E0 2A, E0 AA, ...) give no event
+    pub fn decode(&mut self, scancode: u8) -> Option<KeyEvent> {
+        if self.pause_bytes_left != 0 {
+            self.pause_bytes_left -= 1;
+            return None;
+        }
+        match scancode {
+            EXTENDED_PREFIX => {
+                self.extended = true;
+                return None;
+            }
+            PAUSE_PREFIX => {
+                self.pause_bytes_left = 5;
+                return None;
+            }
+            _ => {}
+        }
+        let extended = core::mem::take(&mut self.extended);
+        let pressed = scancode & RELEASE_BIT == 0;
+        let make_code = scancode & !RELEASE_BIT;
+        let key = if extended {
+            extended_key(make_code)
+        } else {
+            key(make_code)
+        }?;
+        Some(KeyEvent { key, pressed })
+    }
+}
+
+impl Default for ScancodeDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Scancode Set 1 make codes 0x00-0x3A
+const MAIN_BLOCK: [Option<char>; 0x3A] = {
+    let chars = *b"\0\x001234567890-=\0\0qwertyuiop[]\0\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+    let mut main_block = [None; 0x3A];
+    let mut i = 0;
+    while i < 0x3A {
+        if chars[i] != 0 {
+            main_block[i] = Some(chars[i] as char);
+        }
+        i += 1;
+    }
+    main_block
+};
+
+fn key(make_code: u8) -> Option<KeyCode> {
+    let key = match make_code {
+        0x01 => KeyCode::Escape,
+        0x0E => KeyCode::Backspace,
+        0x0F => KeyCode::Tab,
+        0x1C => KeyCode::Enter,
+        0x1D => KeyCode::LeftCtrl,
+        0x2A => KeyCode::LeftShift,
+        0x36 => KeyCode::RightShift,
+        0x38 => KeyCode::LeftAlt,
+        0x3A => KeyCode::CapsLock,
+        0x3B..=0x44 => KeyCode::Function(make_code - 0x3B + 1),
+        0x57 => KeyCode::Function(11),
+        0x58 => KeyCode::Function(12),
+        _ => KeyCode::Char((*MAIN_BLOCK.get(make_code as usize)?)?),
+    };
+    Some(key)
+}
+
+fn extended_key(make_code: u8) -> Option<KeyCode> {
+    let key = match make_code {
+        0x1C => KeyCode::Enter,
+        0x1D => KeyCode::RightCtrl,
+        0x35 => KeyCode::Char('/'),
+        0x38 => KeyCode::RightAlt,
+        0x47 => KeyCode::Home,
+        0x48 => KeyCode::Up,
+        0x49 => KeyCode::PageUp,
+        0x4B => KeyCode::Left,
+        0x4D => KeyCode::Right,
+        0x4F => KeyCode::End,
+        0x50 => KeyCode::Down,
+        0x51 => KeyCode::PageDown,
+        0x52 => KeyCode::Insert,
+        0x53 => KeyCode::Delete,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Registers IRQ 1 handler and unmasks its IO APIC pin
+///
+/// IO APIC must be inited, the pin is found by Interrupt Source Override
+pub fn init() {
+    let gsi = ioapic::isa_irq_gsi(KEYBOARD_ISA_IRQ);
+    ioapic::set_mask(gsi, true);
+    // Bytes left by firmware would block the next IRQ
+    read_scancodes(|_| {});
+    if let Err(err) = idt::register_handler(
+        idt::IO_APIC_ISA_IRQ_VECTORS_RANGE.start() + KEYBOARD_ISA_IRQ,
+        |_| {
+            read_scancodes(|scancode| {
+                SCANCODES.push(scancode);
+            })
+        },
+    ) {
+        log::warn!("Failed to register keyboard IRQ handler: {err:?}");
+        return;
+    }
+    ioapic::set_mask(gsi, false);
+    log::info!("PS/2 keyboard on GSI {gsi}");
+}
+
+/// Reads bytes available in the controller
+fn read_scancodes(mut handle_scancode: impl FnMut(u8)) {
+    let mut status_port = Port::<u8>::new(STATUS_PORT);
+    let mut data_port = Port::<u8>::new(DATA_PORT);
+    for _ in 0..MAX_BYTES_PER_READ {
+        if unsafe { status_port.read() } & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        handle_scancode(unsafe { data_port.read() });
+    }
+}
+
+/// Returns next key event
+pub fn poll() -> Option<KeyEvent> {
+    let mut decoder = DECODER.lock();
+    while let Some(scancode) = SCANCODES.pop() {
+        if let Some(key_event) = decoder.decode(scancode) {
+            return Some(key_event);
+        }
+    }
+    None
+}
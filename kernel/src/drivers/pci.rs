@@ -0,0 +1,292 @@
+//! PCI devices enumeration
+//!
+//! Configuration space is accessed through MCFG regions (PCI Express, 4096 bytes per function) if ACPI reports them,
+//! otherwise through configuration mechanism #1 (0xCF8/0xCFC I/O ports, segment 0, 256 bytes per function).
+//! Devices are not configured.
+use crate::acpi::mcfg;
+use crate::memory_management::general_purpose_allocator::GeneralPurposeAllocator;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::RangeInclusive;
+use spin::{Mutex, Once};
+use x86_64::instructions::port::Port;
+
+/// Configuration address port
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+
+/// Configuration data port, dword selected by [CONFIG_ADDRESS_PORT]
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+
+/// Configuration address enable bit
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+/// Configuration space size reachable through I/O ports
+const LEGACY_CONFIG_SPACE_SIZE: u16 = 256;
+
+/// Vendor ID of nonexistent function
+const INVALID_VENDOR_ID: u16 = 0xFFFF;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+/// Configuration space offsets
+const VENDOR_ID_OFFSET: u8 = 0x00;
+const CLASS_OFFSET: u8 = 0x08;
+const HEADER_TYPE_OFFSET: u8 = 0x0C;
+const BAR0_OFFSET: u8 = 0x10;
+
+/// Header type bit 7: device has multiple functions
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+
+/// Header type without multi-function bit
+const HEADER_TYPE_MASK: u8 = 0x7F;
+
+/// General device header, 6 BARs
+const HEADER_TYPE_GENERAL: u8 = 0x00;
+
+/// PCI-to-PCI bridge header, 2 BARs
+const HEADER_TYPE_PCI_TO_PCI_BRIDGE: u8 = 0x01;
+
+/// BAR bit 0: I/O space
+const BAR_IO_SPACE: u32 = 1;
+
+/// Memory BAR type bits 1-2: 64-bit
+const BAR_MEMORY_TYPE_MASK: u32 = 0b110;
+const BAR_MEMORY_TYPE_64: u32 = 0b100;
+
+/// Memory BAR bit 3: prefetchable
+const BAR_MEMORY_PREFETCHABLE: u32 = 1 << 3;
+
+/// Address and data ports pair is not atomic
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Devices found by [init]
+static DEVICES: Once<Vec<PciDevice, GeneralPurposeAllocator>> = Once::new();
+
+/// Segment group, bus, device, function
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// Base Address Register
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Bar {
+    Io { port: u32 },
+    Memory { address: u64, prefetchable: bool },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    /// Without multi-function bit
+    pub header_type: u8,
+    /// None for unimplemented BARs and upper halves of 64-bit BARs
+    pub bars: [Option<Bar>; 6],
+}
+
+/// Scans PCI buses and saves found devices
+///
+/// Memory management must be inited
+pub fn init() {
+    let devices = DEVICES.call_once(enumerate);
+    for device in devices {
+        log::info!(
+            "PCI {}: {:04x}:{:04x} class {:02x}:{:02x}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass
+        );
+    }
+}
+
+/// Returns devices found by [init]
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().expect("PCI is not inited")
+}
+
+/// Returns devices with class and subclass
+pub fn find_by_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| device.class == class && device.subclass == subclass)
+}
+
+/// Scans all buses of MCFG regions, or all buses of segment 0 if there are no MCFG regions
+///
+/// Brute force, bridges are not followed
+pub fn enumerate() -> Vec<PciDevice, GeneralPurposeAllocator> {
+    let mut devices = Vec::new_in(GeneralPurposeAllocator);
+    for (segment, bus) in
+        bus_ranges().flat_map(|(segment, buses)| buses.map(move |bus| (segment, bus)))
+    {
+        for device in 0..DEVICES_PER_BUS {
+            let Some(function_0) = read_function(PciAddress {
+                segment,
+                bus,
+                device,
+                function: 0,
+            }) else {
+                continue;
+            };
+            devices.push(function_0);
+            let header_type = read_header_u8(function_0.address, HEADER_TYPE_OFFSET + 2);
+            if header_type & HEADER_TYPE_MULTI_FUNCTION == 0 {
+                continue;
+            }
+            devices.extend((1..FUNCTIONS_PER_DEVICE).filter_map(|function| {
+                read_function(PciAddress {
+                    segment,
+                    bus,
+                    device,
+                    function,
+                })
+            }));
+        }
+    }
+    devices
+}
+
+/// Returns segment groups and their bus ranges to scan
+fn bus_ranges() -> impl Iterator<Item = (u16, RangeInclusive<u8>)> {
+    let regions = mcfg::regions();
+    let legacy_bus_range = regions.is_empty().then_some((0, 0..=u8::MAX));
+    regions
+        .iter()
+        .map(|region| (region.segment_group, region.bus_range.clone()))
+        .chain(legacy_bus_range)
+}
+
+/// Reads function header, None if function doesn't exist
+fn read_function(address: PciAddress) -> Option<PciDevice> {
+    let id = read_header_u32(address, VENDOR_ID_OFFSET);
+    let vendor_id = id as u16;
+    if vendor_id == INVALID_VENDOR_ID {
+        return None;
+    }
+    let class = read_header_u32(address, CLASS_OFFSET);
+    let header_type = read_header_u8(address, HEADER_TYPE_OFFSET + 2) & HEADER_TYPE_MASK;
+    let bars_number = match header_type {
+        HEADER_TYPE_GENERAL => 6,
+        HEADER_TYPE_PCI_TO_PCI_BRIDGE => 2,
+        _ => 0,
+    };
+    Some(PciDevice {
+        address,
+        vendor_id,
+        device_id: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        header_type,
+        bars: read_bars(address, bars_number),
+    })
+}
+
+/// Reads BARs addresses, BARs are not sized
+fn read_bars(address: PciAddress, bars_number: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let mut index = 0;
+    while index < bars_number {
+        let offset = BAR0_OFFSET + index as u8 * 4;
+        let bar = read_header_u32(address, offset);
+        if bar & BAR_IO_SPACE != 0 {
+            let port = bar & !0b11;
+            bars[index] = (port != 0).then_some(Bar::Io { port });
+            index += 1;
+            continue;
+        }
+        let prefetchable = bar & BAR_MEMORY_PREFETCHABLE != 0;
+        let mut memory_address = (bar & !0xF) as u64;
+        let is_64_bit = bar & BAR_MEMORY_TYPE_MASK == BAR_MEMORY_TYPE_64;
+        if is_64_bit && index + 1 < bars_number {
+            memory_address |= (read_header_u32(address, offset + 4) as u64) << 32;
+        }
+        bars[index] = (memory_address != 0).then_some(Bar::Memory {
+            address: memory_address,
+            prefetchable,
+        });
+        // Upper half of 64-bit BAR is not a BAR
+        index += if is_64_bit { 2 } else { 1 };
+    }
+    bars
+}
+
+/// Reads dword from configuration space, offset must be dword aligned
+///
+/// Uses MCFG region if it covers the function, otherwise I/O ports.
+/// Returns None if the offset is not reachable (I/O ports reach only 256 bytes of segment 0).
+pub fn read_config_u32(address: PciAddress, offset: u16) -> Option<u32> {
+    assert!(
+        address.device < DEVICES_PER_BUS && address.function < FUNCTIONS_PER_DEVICE,
+        "Invalid PCI address {address}"
+    );
+    assert!(
+        offset % 4 == 0 && (offset as u64) < mcfg::FUNCTION_CONFIG_SPACE_SIZE,
+        "Invalid PCI configuration space offset {offset:#X}"
+    );
+    if let Some(config_space) = mcfg::function_config_space(
+        address.segment,
+        address.bus,
+        address.device,
+        address.function,
+    ) {
+        let value = unsafe {
+            config_space
+                .as_ptr::<u32>()
+                .byte_add(offset as usize)
+                .read_volatile()
+        };
+        return Some(value);
+    }
+    if address.segment != 0 || offset >= LEGACY_CONFIG_SPACE_SIZE {
+        return None;
+    }
+    Some(read_legacy_config_u32(address, offset as u8))
+}
+
+/// Reads dword through configuration mechanism #1
+fn read_legacy_config_u32(address: PciAddress, offset: u8) -> u32 {
+    let config_address = CONFIG_ADDRESS_ENABLE
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | offset as u32;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _config_guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS_PORT).write(config_address);
+            Port::<u32>::new(CONFIG_DATA_PORT).read()
+        }
+    })
+}
+
+/// Reads dword from function header, the function is reachable (it was found by scan)
+fn read_header_u32(address: PciAddress, offset: u8) -> u32 {
+    read_config_u32(address, offset as u16).expect("PCI function header is not reachable")
+}
+
+/// Reads byte from function header
+fn read_header_u8(address: PciAddress, offset: u8) -> u8 {
+    (read_header_u32(address, offset & !0b11) >> ((offset & 0b11) * 8)) as u8
+}
@@ -0,0 +1,256 @@
+//! CMOS real-time clock
+//!
+//! RTC is read directly every time, there is no update interrupt.
+//! Time is assumed to be UTC (QEMU default, `-rtc base=utc`).
+use crate::acpi::ACPI_TABLES;
+use acpi_lib::fadt::Fadt;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::instructions::port::Port;
+
+/// CMOS register index port, bit 7 disables NMI
+const INDEX_PORT: u16 = 0x70;
+
+/// CMOS register data port
+const DATA_PORT: u16 = 0x71;
+
+/// NMI is disabled while a register is selected, it's enabled back after the read
+const NMI_DISABLE: u8 = 1 << 7;
+
+const SECONDS_REGISTER: u8 = 0x00;
+const MINUTES_REGISTER: u8 = 0x02;
+const HOURS_REGISTER: u8 = 0x04;
+const DAY_OF_MONTH_REGISTER: u8 = 0x07;
+const MONTH_REGISTER: u8 = 0x08;
+const YEAR_REGISTER: u8 = 0x09;
+const STATUS_A_REGISTER: u8 = 0x0A;
+const STATUS_B_REGISTER: u8 = 0x0B;
+
+/// Status Register A: update in progress, registers may be inconsistent
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+
+/// Status Register B: hours are in 24-hour format
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+
+/// Status Register B: values are binary, not BCD
+const STATUS_B_BINARY: u8 = 1 << 2;
+
+/// Hours register in 12-hour format: PM
+const HOURS_PM: u8 = 1 << 7;
+
+/// Used if FADT has no century register, RTC year is 2 digits
+const DEFAULT_CENTURY: u16 = 20;
+
+/// Earlier RTC year means that the clock was never set or CMOS battery is dead
+const MIN_PLAUSIBLE_YEAR: u16 = 2025;
+
+/// How many times registers are read until two reads match
+const MAX_READ_ATTEMPTS: usize = 16;
+
+/// CMOS index/data pair is not atomic
+static CMOS_LOCK: Mutex<()> = Mutex::new(());
+
+/// FADT CENTURY (CMOS register index), None if FADT doesn't report it
+static CENTURY_REGISTER: Once<Option<u8>> = Once::new();
+
+/// Date and time read from RTC
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    /// 0-23
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns seconds since 1970-01-01 00:00:00 UTC
+    ///
+    /// None if a field is out of range or date is earlier than epoch (e.g. unset or corrupted CMOS)
+    pub fn to_unix_seconds(self) -> Option<u64> {
+        if self.hour > 23 || self.minute > 59 || self.second > 59 {
+            return None;
+        }
+        Some(
+            days_since_unix_epoch(self.year, self.month, self.day)? * 86400
+                + self.hour as u64 * 3600
+                + self.minute as u64 * 60
+                + self.second as u64,
+        )
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Gets century register from FADT
+///
+/// ACPI must be inited
+pub fn init() {
+    let century_register = *CENTURY_REGISTER.call_once(|| {
+        let acpi_tables = ACPI_TABLES.get()?.lock();
+        let century = acpi_tables.find_table::<Fadt>().ok()?.century;
+        // 0 - not supported
+        (century != 0).then_some(century)
+    });
+    match century_register {
+        Some(century_register) => {
+            log::info!("RTC century register {century_register:#04X}")
+        }
+        None => log::info!("RTC century register not supported, {DEFAULT_CENTURY}xx is assumed"),
+    }
+    // Wrong wall-clock time is not fatal, it's only reported
+    let date_time = read();
+    if date_time.to_unix_seconds().is_none() {
+        log::warn!("RTC time is invalid: {date_time:?}");
+    } else if date_time.year < MIN_PLAUSIBLE_YEAR {
+        log::warn!("RTC time {date_time} is before {MIN_PLAUSIBLE_YEAR}, clock is unset or CMOS battery is dead");
+    } else {
+        log::info!("RTC time: {date_time}");
+    }
+}
+
+/// Converts BCD byte to binary
+pub const fn bcd_to_binary(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0xF)
+}
+
+/// Whether year is leap in Gregorian calendar
+pub const fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+/// Returns number of days in month (1-12), None if month is out of range
+pub const fn days_in_month(year: u16, month: u8) -> Option<u8> {
+    const DAYS_IN_MONTH: [u8; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    match month {
+        2 if is_leap_year(year) => Some(29),
+        1..=12 => Some(DAYS_IN_MONTH[month as usize - 1]),
+        _ => None,
+    }
+}
+
+/// Returns number of days from 1970-01-01 to date
+///
+/// None if date is invalid or earlier than epoch
+pub fn days_since_unix_epoch(year: u16, month: u8, day: u8) -> Option<u64> {
+    const DAYS_BEFORE_MONTH: [u64; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    if year < 1970 || day == 0 || day > days_in_month(year, month)? {
+        return None;
+    }
+    let years_days: u64 = (1970..year)
+        .map(|year| if is_leap_year(year) { 366 } else { 365 })
+        .sum();
+    let leap_day = (month > 2 && is_leap_year(year)) as u64;
+    Some(years_days + DAYS_BEFORE_MONTH[month as usize - 1] + leap_day + day as u64 - 1)
+}
+
+/// Returns seconds since Unix epoch, None if RTC time is invalid
+pub fn now() -> Option<u64> {
+    read().to_unix_seconds()
+}
+
+/// Reads RTC date and time
+///
+/// Registers are read after update-in-progress is clear until two reads match,
+/// otherwise update could happen between registers reads.
+pub fn read() -> DateTime {
+    let century_register = CENTURY_REGISTER.get().copied().flatten();
+    let raw = x86_64::instructions::interrupts::without_interrupts(|| {
+        let _cmos_guard = CMOS_LOCK.lock();
+        let mut previous = read_raw(century_register);
+        for _ in 0..MAX_READ_ATTEMPTS {
+            let current = read_raw(century_register);
+            if current == previous {
+                break;
+            }
+            previous = current;
+        }
+        previous
+    });
+    raw.decode()
+}
+
+/// Register values as they are in CMOS
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct RawDateTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+    status_b: u8,
+}
+
+impl RawDateTime {
+    fn decode(self) -> DateTime {
+        let binary = self.status_b & STATUS_B_BINARY != 0;
+        let to_binary = |value: u8| {
+            if binary {
+                value
+            } else {
+                bcd_to_binary(value)
+            }
+        };
+        let mut hour = to_binary(self.hour & !HOURS_PM);
+        if self.status_b & STATUS_B_24_HOUR == 0 {
+            // 12 AM is 0, 12 PM is 12
+            hour %= 12;
+            if self.hour & HOURS_PM != 0 {
+                hour += 12;
+            }
+        }
+        let century = self
+            .century
+            .map_or(DEFAULT_CENTURY, |century| to_binary(century) as u16);
+        DateTime {
+            year: century * 100 + to_binary(self.year) as u16,
+            month: to_binary(self.month),
+            day: to_binary(self.day),
+            hour,
+            minute: to_binary(self.minute),
+            second: to_binary(self.second),
+        }
+    }
+}
+
+/// Waits out update in progress and reads registers, CMOS lock must be taken
+fn read_raw(century_register: Option<u8>) -> RawDateTime {
+    while read_register(STATUS_A_REGISTER) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawDateTime {
+        second: read_register(SECONDS_REGISTER),
+        minute: read_register(MINUTES_REGISTER),
+        hour: read_register(HOURS_REGISTER),
+        day: read_register(DAY_OF_MONTH_REGISTER),
+        month: read_register(MONTH_REGISTER),
+        year: read_register(YEAR_REGISTER),
+        century: century_register.map(read_register),
+        status_b: read_register(STATUS_B_REGISTER),
+    }
+}
+
+/// Reads register with NMI disabled, then enables NMI back
+fn read_register(register: u8) -> u8 {
+    let mut index_port = Port::<u8>::new(INDEX_PORT);
+    unsafe {
+        index_port.write(NMI_DISABLE | register);
+        let value = Port::<u8>::new(DATA_PORT).read();
+        index_port.write(register);
+        value
+    }
+}
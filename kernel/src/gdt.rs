@@ -1,55 +1,133 @@
+use alloc::boxed::Box;
 use x86_64::instructions::segmentation::Segment;
 use x86_64::registers::segmentation::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
-use x86_64::PrivilegeLevel;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
 
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// IST index of Double Fault handler stack
+///
+/// Double Fault caused by kernel stack overflow can't push its frame to the overflowed stack,
+/// so it switches to a known good stack
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+/// Size of Double Fault handler stack
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 5 * 4096;
+
+#[repr(C, align(16))]
+struct Stack([u8; DOUBLE_FAULT_STACK_SIZE]);
+
+/// Double Fault handler stack of the BSP, APs allocate theirs in [init_ap]
+static mut DOUBLE_FAULT_STACK: Stack = Stack([0; DOUBLE_FAULT_STACK_SIZE]);
+
 /// Creates and loads GDT
 #[allow(static_mut_refs)]
 pub fn init() {
     unsafe {
-        // Null Descriptor already in GDT
-        // GDT[1] Kernel Code
-        GDT.append(Descriptor::kernel_code_segment());
-        // GDT[2] Kernel Data
-        GDT.append(Descriptor::kernel_data_segment());
-        // GDT[3] User Code
-        GDT.append(Descriptor::user_code_segment());
-        // GDT[4] User Data
-        GDT.append(Descriptor::user_data_segment());
-        // Info about I/O Permission Bit Map in TSS:
-        // "For I/O Permission Bit Map
-        // If the I/O bit map base address is greater than or equal to the TSS segment limit, there is no I/O permission map,
-        // and all I/O instructions generate exceptions when the CPL is greater than the current IOPL."
-        //
-        // How to forbid all user level IO operations?
-        // IOPB in TSS must be set to 0xFFFF
-        // !!!
-        // The x86_64 library setting the System Segment TSS in GDT sets the limit equal to sizeof(TSS) - 1 and IOPB = sizeof(TSS),
-        // so the I/O Permission Bit Map is considered empty.
+        // Stack grows down, so IST entry points to the end of the stack
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            VirtAddr::from_ptr(core::ptr::addr_of!(DOUBLE_FAULT_STACK))
+                + DOUBLE_FAULT_STACK_SIZE as u64;
+        let tss_selector = fill_gdt(&mut GDT, &TSS);
 
         // lgdt
         GDT.load();
+        set_segment_registers();
+
+        // ltr
+        x86_64::instructions::tables::load_tss(tss_selector);
+    }
+}
 
-        // Set segment registers
-        // CS, DS, SS, ES
-        // FS and GS not used
-        x86_64::instructions::segmentation::CS::set_reg(SegmentSelector::new(
-            1,
-            PrivilegeLevel::Ring0,
-        ));
-        x86_64::instructions::segmentation::DS::set_reg(SegmentSelector::new(
-            2,
-            PrivilegeLevel::Ring0,
-        ));
-        x86_64::instructions::segmentation::SS::set_reg(SegmentSelector::new(
-            2,
-            PrivilegeLevel::Ring0,
-        ));
-        x86_64::instructions::segmentation::ES::set_reg(SegmentSelector::new(
-            2,
-            PrivilegeLevel::Ring0,
-        ));
+/// Creates and loads GDT of application processor
+///
+/// TSS descriptor becomes busy after ltr and can't be loaded on another CPU,
+/// so every AP gets its own GDT with the same layout, its own TSS and Double Fault stack.
+/// They are allocated from the heap and never freed.
+pub fn init_ap() {
+    let double_fault_stack = Box::leak(Box::<Stack>::new_uninit());
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(double_fault_stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE as u64;
+    let tss = Box::leak(Box::new(tss));
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let tss_selector = fill_gdt(gdt, tss);
+    unsafe {
+        gdt.load();
+        set_segment_registers();
+        x86_64::instructions::tables::load_tss(tss_selector);
     }
 }
+
+/// Appends segment descriptors and TSS descriptor to empty GDT, returns TSS selector
+fn fill_gdt(gdt: &mut GlobalDescriptorTable, tss: &'static TaskStateSegment) -> SegmentSelector {
+    // Null Descriptor already in GDT
+    // GDT[1] Kernel Code
+    gdt.append(Descriptor::kernel_code_segment());
+    // GDT[2] Kernel Data
+    gdt.append(Descriptor::kernel_data_segment());
+    // GDT[3] User Code
+    gdt.append(Descriptor::user_code_segment());
+    // GDT[4] User Data
+    gdt.append(Descriptor::user_data_segment());
+    // Info about I/O Permission Bit Map in TSS:
+    // "For I/O Permission Bit Map
+    // If the I/O bit map base address is greater than or equal to the TSS segment limit, there is no I/O permission map,
+    // and all I/O instructions generate exceptions when the CPL is greater than the current IOPL."
+    //
+    // How to forbid all user level IO operations?
+    // IOPB in TSS must be set to 0xFFFF
+    // !!!
+    // The x86_64 library setting the System Segment TSS in GDT sets the limit equal to sizeof(TSS) - 1 and IOPB = sizeof(TSS),
+    // so the I/O Permission Bit Map is considered empty.
+
+    // GDT[5], GDT[6] TSS
+    gdt.append(Descriptor::tss_segment(tss))
+}
+
+/// Returns true if this CPU has loaded TSS (task register is not null)
+pub fn tss_loaded() -> bool {
+    let task_register: u16;
+    unsafe {
+        core::arch::asm!("str {0:x}", out(reg) task_register, options(nomem, nostack, preserves_flags));
+    }
+    task_register != 0
+}
+
+/// Returns top of this CPU's Double Fault stack from the loaded TSS, None if TSS isn't loaded
+pub fn double_fault_stack_top() -> Option<VirtAddr> {
+    let task_register: u16;
+    unsafe {
+        core::arch::asm!("str {0:x}", out(reg) task_register, options(nomem, nostack, preserves_flags));
+    }
+    if task_register == 0 {
+        return None;
+    }
+    let gdt = x86_64::instructions::tables::sgdt();
+    // TSS descriptor takes two GDT entries, base is scattered over them
+    let descriptor = gdt.base + (task_register & !0b111) as u64;
+    let (low, high) = unsafe {
+        (
+            descriptor.as_ptr::<u64>().read(),
+            descriptor.as_ptr::<u64>().add(1).read(),
+        )
+    };
+    let base =
+        ((low >> 16) & 0xFF_FFFF) | (((low >> 56) & 0xFF) << 24) | ((high & 0xFFFF_FFFF) << 32);
+    let tss = unsafe { &*(base as *const TaskStateSegment) };
+    Some(tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize])
+}
+
+/// Sets segment registers
+/// CS, DS, SS, ES
+/// FS and GS selectors not used, their bases are managed by crate::arch
+unsafe fn set_segment_registers() {
+    x86_64::instructions::segmentation::CS::set_reg(SegmentSelector::new(1, PrivilegeLevel::Ring0));
+    x86_64::instructions::segmentation::DS::set_reg(SegmentSelector::new(2, PrivilegeLevel::Ring0));
+    x86_64::instructions::segmentation::SS::set_reg(SegmentSelector::new(2, PrivilegeLevel::Ring0));
+    x86_64::instructions::segmentation::ES::set_reg(SegmentSelector::new(2, PrivilegeLevel::Ring0));
+}
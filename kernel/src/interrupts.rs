@@ -1,14 +1,61 @@
 pub mod apic;
 pub mod idt;
+#[cfg(feature = "interrupt-latency")]
+pub mod latency;
 pub mod pic;
 
-/// Fills IDT, inits IO APIC and bootstrap processor's Local APIC, but it doesn't enable interrupts
+// Init sequence (kmain):
+// 1. idt::init      - as early as possible (after GDT), so exceptions during memory and ACPI init are reported
+// 2. acpi::init     - APIC and IO APIC init read PLATFORM_INFO (collected from MADT)
+// 3. interrupts::init:
+//    a. PIC remap and disable - legacy PIC must not deliver interrupts in APIC mode
+//    b. Local APIC, then IO APIC
+// 4. timers::init   - timers route interrupts through IO APIC and calibrate against each other:
+//    a. PIT restarted through IO APIC, its channel 2 busy sleep is the calibration timer if HPET is not available
+//    b. HPET, then Invariant TSC calibrated against it
+//    c. Local APIC Timer calibrated against HPET (or PIT channel 2), calibration polls and doesn't need interrupts
+// 5. Enable interrupts
+//
+// Interrupt controller state only moves forward: PIC (firmware) -> PIC masked -> Local APIC + IO APIC.
+// There is no way back to PIC mode.
+
+/// Switches from legacy PIC to APIC, but it doesn't enable interrupts
+///
+/// IDT must be filled ([idt::init]) and ACPI tables must be parsed ([crate::acpi::init])
 pub fn init() {
+    transition_to_apic();
+}
+
+/// Hands interrupts off from PIC (and PIT) to Local APIC and IO APIC
+///
+/// 1. Masks all PIC interrupts and drains in-service ones
+/// 2. Stops PIT, it is restarted through IO APIC by timers init
+/// 3. Inits Local APIC and IO APIC
+///
+/// CPU interrupts stay disabled, the caller enables them when timers are ready
+pub fn transition_to_apic() {
     x86_64::instructions::interrupts::disable();
+    assert!(
+        crate::acpi::PLATFORM_INFO.get().is_some(),
+        "ACPI must be parsed before APIC init"
+    );
 
     // Init and disable PIC
     pic::init_and_disable();
+    pic::drain();
+    assert_eq!(
+        pic::read_in_service_registers(),
+        [0, 0],
+        "PIC has in-service interrupts after drain"
+    );
+
+    // PIT must not fire until it is routed through IO APIC
+    crate::timers::pit::stop();
 
     // Init Local APIC and IO APIC
     apic::init();
+    assert!(
+        apic::vectors_in(apic::read_isr()).next().is_none(),
+        "Local APIC has in-service interrupts after APIC init"
+    );
 }
@@ -1,19 +1,28 @@
-mod ioapic;
+pub mod ioapic;
+pub mod smp;
+pub mod timer;
 
 use crate::acpi::PLATFORM_INFO;
+use crate::cpu;
 use crate::memory_management::virtual_memory_manager;
+use crate::memory_management::virtual_memory_manager::CacheType;
 use crate::memory_management::PAGE_SIZE;
 use acpi_lib::platform::interrupt::{LocalInterruptLine, NmiProcessor};
 use acpi_lib::InterruptModel;
 use bitfield::bitfield;
-use raw_cpuid::CpuId;
-use x86_64::instructions::tlb;
-use x86_64::structures::paging::page_table::PageTableLevel;
-use x86_64::structures::paging::PageTableFlags;
+use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::{PhysAddr, VirtAddr};
 
 static LOCAL_APIC_VERSION: spin::Once<LocalApicVersion> = spin::Once::new();
 
+/// Number of spurious interrupts, incremented by the spurious vector handler
+pub(super) static SPURIOUS_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns number of spurious interrupts on all CPUs
+pub fn spurious_count() -> u64 {
+    SPURIOUS_INTERRUPTS.load(Ordering::Relaxed)
+}
+
 /// Defined in Local APIC Version Register
 #[derive(Debug, PartialEq)]
 enum LocalApicVersion {
@@ -33,6 +42,9 @@ const BASE_VIRT_ADDR: VirtAddr =
     virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(BASE_PHYS_ADDR);
 
 // Registers
+/// 0x20    Local APIC ID Register
+const ID_REGISTER: *mut u32 = (BASE_VIRT_ADDR.as_u64() + 0x20) as *mut u32;
+
 /// 0x30    Local APIC Version Register
 const VERSION_REGISTER: *mut u32 = (BASE_VIRT_ADDR.as_u64() + 0x30) as *mut u32;
 
@@ -42,6 +54,21 @@ const EOI_REGISTER: *mut u32 = (BASE_VIRT_ADDR.as_u64() + 0xB0) as *mut u32;
 /// 0xF0    Spurious-Interrupt Vector Register
 const SPURIOUS_INTERRUPT_VECTOR_REGISTER: *mut u32 = (BASE_VIRT_ADDR.as_u64() + 0xF0) as *mut u32;
 
+/// 0x100-0x170   In-Service Register (8 registers of 32 bits, 16 bytes apart)
+const IN_SERVICE_REGISTER_BASE: *mut u32 = (BASE_VIRT_ADDR.as_u64() + 0x100) as *mut u32;
+
+/// 0x200-0x270   Interrupt Request Register (8 registers of 32 bits, 16 bytes apart)
+const INTERRUPT_REQUEST_REGISTER_BASE: *mut u32 = (BASE_VIRT_ADDR.as_u64() + 0x200) as *mut u32;
+
+/// 0x280   Error Status Register
+const ERROR_STATUS_REGISTER: *mut u32 = (BASE_VIRT_ADDR.as_u64() + 0x280) as *mut u32;
+
+/// 0x300   Interrupt Command Register (bits 0-31)
+const INTERRUPT_COMMAND_REGISTER_LOW: *mut u32 = (BASE_VIRT_ADDR.as_u64() + 0x300) as *mut u32;
+
+/// 0x310   Interrupt Command Register (bits 32-63)
+const INTERRUPT_COMMAND_REGISTER_HIGH: *mut u32 = (BASE_VIRT_ADDR.as_u64() + 0x310) as *mut u32;
+
 /// 0x320   LVT Timer Register
 const LVT_TIMER_REGISTER: *mut u32 = (BASE_VIRT_ADDR.as_u64() + 0x320) as *mut u32;
 
@@ -69,23 +96,32 @@ pub fn init() {
     x86_64::instructions::interrupts::disable();
 
     // Check APIC support
-    let cpuid = CpuId::new();
-    let cpuid_feature_info = cpuid
-        .get_feature_info()
-        .expect("Failed to get CPUID features!");
-    if !cpuid_feature_info.has_apic() {
+    if !cpu::features().apic {
         panic!("APIC not supported");
     }
 
     // Check APIC base address from MSR (Intel and AMD supported)
-    let ia32_apic_base_msr = unsafe { x86_64::registers::model_specific::Msr::new(0x1B).read() };
-    let apic_base_page_phys_addr_from_msr =
-        x86_64::align_down(ia32_apic_base_msr, PAGE_SIZE as u64);
+    let mut apic_base_msr = ApicBaseMsr::read();
     assert_eq!(
-        apic_base_page_phys_addr_from_msr,
-        BASE_PHYS_ADDR.as_u64(),
+        apic_base_msr.base_phys_addr(),
+        BASE_PHYS_ADDR,
         "The APIC base address is not on the default page!"
     );
+    log::info!(
+        "Local APIC: BSP flag = {}, x2APIC enabled = {}",
+        apic_base_msr.bsp(),
+        apic_base_msr.x2apic_enable()
+    );
+    assert!(
+        apic_base_msr.bsp(),
+        "Local APIC init must be done on bootstrap processor"
+    );
+    // If global enable is clear, APIC is hard-disabled, LVT and SVR writes are ignored
+    if !apic_base_msr.global_enable() {
+        log::warn!("Local APIC is globally disabled, enabling");
+        apic_base_msr.set_global_enable(true);
+        apic_base_msr.write();
+    }
 
     // Make APIC base mapping page uncacheable
     // osdev wiki: Section 11.4.1 of 3rd volume of Intel SDM recommends mapping the base address page as strong uncacheable for correct APIC operation.
@@ -93,13 +129,8 @@ pub fn init() {
     // APIC registers are memory-mapped to a 4-KByte region of the processor’s physical
     // address space with an initial starting address of FEE00000H. For correct APIC operation, this address space must
     // be mapped to an area of memory that has been designated as strong uncacheable (UC)
-    virtual_memory_manager::set_flags_in_page_table(
-        BASE_VIRT_ADDR,
-        PageTableLevel::One,
-        PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
-        true,
-    );
-    tlb::flush(BASE_VIRT_ADDR);
+    virtual_memory_manager::set_cache_type(BASE_VIRT_ADDR, 1, CacheType::Uncacheable)
+        .expect("Failed to make APIC registers uncacheable");
 
     // Determine whether the 82489DX is a discrete APIC or an Integrated APIC using the Local APIC Version Register
     // Version bits 0-7:
@@ -263,6 +294,246 @@ pub fn send_eoi() {
     }
 }
 
+/// Whether this CPU is bootstrap processor
+///
+/// IA32_APIC_BASE MSR BSP flag
+pub fn is_bsp() -> bool {
+    ApicBaseMsr::read().bsp()
+}
+
+/// Returns Local APIC ID of this CPU
+///
+/// ID Register bits 24-31
+pub fn local_apic_id() -> u8 {
+    unsafe { (ID_REGISTER.read_volatile() >> 24) as u8 }
+}
+
+/// Sends fixed interrupt with vector to this CPU (self-IPI)
+///
+/// Allows to test IDT and handlers without real hardware interrupts <br>
+/// Vector                           0-7 = IDT vector <br>
+/// Delivery Mode                    8-10 = 000 - Fixed <br>
+/// Destination Mode                 11 = 0 - Physical (ignored with shorthand) <br>
+/// Delivery Status                  12 = (Read Only) <br>
+/// Level                            14 = 1 - Assert <br>
+/// Trigger Mode                     15 = 0 - Edge <br>
+/// Destination Shorthand            18-19 = 01 - Self <br>
+pub fn send_self_ipi(vector: u8) {
+    send_fixed_ipi(0, vector, 0b01);
+}
+
+/// Sends fixed interrupt with vector to processor with Local APIC ID
+///
+/// Like [send_self_ipi], but Destination Shorthand is 00 - No shorthand, destination is ICR bits 56-63
+pub fn send_ipi(dest_apic_id: u8, vector: u8) {
+    send_fixed_ipi(dest_apic_id, vector, 0b00);
+}
+
+/// Sends fixed interrupt with vector to all processors except this one
+///
+/// Like [send_self_ipi], but Destination Shorthand is 11 - All Excluding Self
+pub fn send_ipi_all_excluding_self(vector: u8) {
+    send_fixed_ipi(0, vector, 0b11);
+}
+
+/// Sends NMI to processor with Local APIC ID
+///
+/// Vector                           0-7 = ignored <br>
+/// Delivery Mode                    8-10 = 100 - NMI <br>
+/// Level                            14 = 1 - Assert <br>
+/// Destination Shorthand            18-19 = 00 - No shorthand <br>
+pub fn send_nmi_ipi(dest_apic_id: u8) {
+    let mut register_value = InterruptCommandRegisterLow(0);
+    register_value.set_delivery_mode(0b100);
+    register_value.set_level(true);
+    send_interrupt_command(dest_apic_id, register_value);
+}
+
+fn send_fixed_ipi(dest_apic_id: u8, vector: u8, destination_shorthand: u32) {
+    assert!(
+        vector >= 32,
+        "Vectors 0-31 are reserved for CPU exceptions, they can't be sent as fixed IPI"
+    );
+    let mut register_value = InterruptCommandRegisterLow(0);
+    register_value.set_vector(vector as u32);
+    register_value.set_level(true);
+    register_value.set_destination_shorthand(destination_shorthand);
+    send_interrupt_command(dest_apic_id, register_value);
+}
+
+/// Writes Interrupt Command Register and waits for delivery
+///
+/// Destination is ignored with shorthand
+fn send_interrupt_command(dest_apic_id: u8, register_value: InterruptCommandRegisterLow) {
+    // Interrupt handler sending IPI between two writes would change destination
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        // Destination field is bits 56-63 of ICR
+        INTERRUPT_COMMAND_REGISTER_HIGH.write_volatile((dest_apic_id as u32) << 24);
+        // Writing low part sends IPI
+        INTERRUPT_COMMAND_REGISTER_LOW.write_volatile(register_value.0);
+        // Wait for delivery
+        while InterruptCommandRegisterLow(INTERRUPT_COMMAND_REGISTER_LOW.read_volatile())
+            .delivery_status()
+        {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// IA32_APIC_BASE MSR
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+
+bitfield! {
+    /// IA32_APIC_BASE MSR value <br>
+    /// BSP flag                 8       = processor is bootstrap processor (Read Only) <br>
+    /// x2APIC enable            10      = x2APIC mode enabled <br>
+    /// APIC global enable       11      = 0 - APIC hard-disabled <br>
+    /// APIC base                12-51   = physical page of APIC registers <br>
+    pub struct ApicBaseMsr(u64);
+    impl Debug;
+    pub bsp, _: 8;
+    pub x2apic_enable, set_x2apic_enable: 10;
+    pub global_enable, set_global_enable: 11;
+    base_page_number, set_base_page_number: 51, 12;
+}
+
+impl ApicBaseMsr {
+    /// Reads IA32_APIC_BASE MSR
+    pub fn read() -> Self {
+        Self(unsafe { x86_64::registers::model_specific::Msr::new(IA32_APIC_BASE_MSR).read() })
+    }
+
+    /// Writes value to IA32_APIC_BASE MSR
+    ///
+    /// Clearing global enable hard-disables APIC, it can only be re-enabled by writing the MSR again
+    pub fn write(&self) {
+        unsafe {
+            x86_64::registers::model_specific::Msr::new(IA32_APIC_BASE_MSR).write(self.0);
+        }
+    }
+
+    /// Returns physical address of APIC registers page
+    pub fn base_phys_addr(&self) -> PhysAddr {
+        PhysAddr::new(self.base_page_number() * PAGE_SIZE as u64)
+    }
+}
+
+/// Reads 256-bit register (ISR, IRR) consisting of 8 32-bit registers 16 bytes apart
+fn read_256_bit_register(base: *mut u32) -> [u32; 8] {
+    // Registers are 16 bytes apart, 4 u32's
+    core::array::from_fn(|i| unsafe { base.add(i * 4).read_volatile() })
+}
+
+/// Reads In-Service Register, bit N is set if vector N is being serviced (EOI not sent yet)
+pub fn read_isr() -> [u32; 8] {
+    read_256_bit_register(IN_SERVICE_REGISTER_BASE)
+}
+
+/// Reads Interrupt Request Register, bit N is set if vector N is accepted, but not yet dispatched to CPU
+pub fn read_irr() -> [u32; 8] {
+    read_256_bit_register(INTERRUPT_REQUEST_REGISTER_BASE)
+}
+
+/// Returns iterator over set vectors of ISR or IRR value
+pub fn vectors_in(register_value: [u32; 8]) -> impl Iterator<Item = u8> {
+    (0..=u8::MAX).filter(move |vector| {
+        register_value[*vector as usize / 32] & (1 << (*vector as usize % 32)) != 0
+    })
+}
+
+/// Logs in-service and pending vectors
+///
+/// Vector stuck in ISR usually means missing EOI
+pub fn log_in_flight_interrupts() {
+    for (register_name, register_value) in [("ISR", read_isr()), ("IRR", read_irr())] {
+        if vectors_in(register_value).next().is_none() {
+            log::info!("Local APIC {register_name}: empty");
+            continue;
+        }
+        for vector in vectors_in(register_value) {
+            log::info!("Local APIC {register_name}: vector {vector}");
+        }
+    }
+}
+
+bitfield! {
+    /// Error Status Register value <br>
+    /// Send Checksum Error         0   (P6 and Pentium only) <br>
+    /// Receive Checksum Error      1   (P6 and Pentium only) <br>
+    /// Send Accept Error           2   (P6 and Pentium only) <br>
+    /// Receive Accept Error        3   (P6 and Pentium only) <br>
+    /// Redirectable IPI            4   lowest priority IPI is not supported <br>
+    /// Send Illegal Vector         5 <br>
+    /// Received Illegal Vector     6 <br>
+    /// Illegal Register Address    7
+    #[derive(Copy, Clone, PartialEq, Eq)]
+    pub struct ApicErrorStatus(u32);
+    impl Debug;
+    pub send_checksum_error, _: 0;
+    pub receive_checksum_error, _: 1;
+    pub send_accept_error, _: 2;
+    pub receive_accept_error, _: 3;
+    pub redirectable_ipi, _: 4;
+    pub send_illegal_vector, _: 5;
+    pub received_illegal_vector, _: 6;
+    pub illegal_register_address, _: 7;
+}
+
+impl From<u32> for ApicErrorStatus {
+    fn from(register_value: u32) -> Self {
+        Self(register_value)
+    }
+}
+
+impl core::fmt::Display for ApicErrorStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let errors = [
+            (self.send_checksum_error(), "send checksum error"),
+            (self.receive_checksum_error(), "receive checksum error"),
+            (self.send_accept_error(), "send accept error"),
+            (self.receive_accept_error(), "receive accept error"),
+            (self.redirectable_ipi(), "redirectable IPI"),
+            (self.send_illegal_vector(), "send illegal vector"),
+            (self.received_illegal_vector(), "received illegal vector"),
+            (self.illegal_register_address(), "illegal register address"),
+        ];
+        let mut errors = errors
+            .into_iter()
+            .filter_map(|(is_set, description)| is_set.then_some(description));
+        match errors.next() {
+            None => write!(f, "no errors ({:#04X})", self.0),
+            Some(first) => {
+                write!(f, "{first}")?;
+                for description in errors {
+                    write!(f, ", {description}")?;
+                }
+                write!(f, " ({:#04X})", self.0)
+            }
+        }
+    }
+}
+
+/// Reads and clears Error Status Register of this CPU
+///
+/// Write updates the register with errors collected since the last write, so it's written before read
+pub fn read_error_status() -> ApicErrorStatus {
+    unsafe {
+        ERROR_STATUS_REGISTER.write_volatile(0);
+        ApicErrorStatus(ERROR_STATUS_REGISTER.read_volatile())
+    }
+}
+
+bitfield! {
+    struct InterruptCommandRegisterLow(u32);
+    vector, set_vector: 7, 0;
+    delivery_mode, set_delivery_mode: 10, 8;
+    destination_mode, set_destination_mode: 11;
+    delivery_status, _: 12;
+    level, set_level: 14;
+    trigger_mode, set_trigger_mode: 15;
+    destination_shorthand, set_destination_shorthand: 19, 18;
+}
+
 bitfield! {
     struct LvtRegister(u32);
     vector, set_vector: 7, 0;
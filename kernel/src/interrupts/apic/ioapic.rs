@@ -1,27 +1,28 @@
 use crate::acpi::PLATFORM_INFO;
 use crate::interrupts::idt::IO_APIC_ISA_IRQ_VECTORS_RANGE;
 use crate::memory_management::general_purpose_allocator::GeneralPurposeAllocator;
-use acpi_lib::madt::Madt;
 use acpi_lib::platform::interrupt::{Polarity, TriggerMode};
-use acpi_lib::{AcpiTable, InterruptModel, ManagedSlice};
+use acpi_lib::{InterruptModel, ManagedSlice};
 use bitfield::bitfield;
 use core::ops::Add;
-use spin::Once;
+use spin::{Mutex, Once};
 use x86_64::{PhysAddr, VirtAddr};
 
 static IO_APIC_PHYS_ADDR: Once<PhysAddr> = Once::new();
 
 static IO_APIC_VIRT_ADDR: Once<VirtAddr> = Once::new();
 
-pub fn init() {
-    // Get MADT
-    let acpi_tables_mutex_guard = crate::acpi::ACPI_TABLES.get().unwrap().lock();
-    let madt = acpi_tables_mutex_guard
-        .find_table::<Madt>()
-        .expect("Failed to find MADT");
-    madt.validate().expect("Failed to validate MADT");
+/// Number of redirection table entries (IO APIC pins)
+static NUMBER_OF_REDIRECTION_TABLE_ENTRIES: Once<u8> = Once::new();
+
+/// IOREGSEL and IOWIN pair is shared, entries are changed under this lock after init
+static REDIRECTION_TABLE_LOCK: Mutex<()> = Mutex::new(());
 
-    let platform_info = PLATFORM_INFO.get().unwrap();
+pub fn init() {
+    // Platform info is collected from MADT once in acpi::init, ACPI tables are not touched here
+    let platform_info = PLATFORM_INFO
+        .get()
+        .expect("PLATFORM_INFO must be populated by acpi::init before IO APIC init");
 
     // Check platform info and get IO APIC address
     let apic_info = match platform_info.interrupt_model {
@@ -64,6 +65,7 @@ pub fn init() {
         number_of_redirection_table_entries >= 24,
         "Number of redirection table entries is less than 24, bug"
     );
+    NUMBER_OF_REDIRECTION_TABLE_ENTRIES.call_once(|| number_of_redirection_table_entries as u8);
 
     // Fill redirection table
     // Fill with default value
@@ -158,6 +160,72 @@ pub fn init() {
     }
 }
 
+/// Returns GSI (IO APIC pin) of ISA IRQ
+///
+/// ISA IRQ is connected to the pin with the same number unless Interrupt Source Override says otherwise
+pub fn isa_irq_gsi(isa_irq: u8) -> u8 {
+    let platform_info = PLATFORM_INFO
+        .get()
+        .expect("PLATFORM_INFO must be populated by acpi::init before IO APIC use");
+    let InterruptModel::Apic(ref apic_info) = platform_info.interrupt_model else {
+        panic!("Interrupt model in platform info is not APIC");
+    };
+    apic_info
+        .interrupt_source_overrides
+        .iter()
+        .find(|interrupt_source_override| interrupt_source_override.isa_source == isa_irq)
+        .map_or(isa_irq, |interrupt_source_override| {
+            interrupt_source_override.global_system_interrupt as u8
+        })
+}
+
+/// Masks or unmasks IO APIC pin
+///
+/// Drivers unmask their IRQ after the device is configured
+pub fn set_mask(gsi: u8, masked: bool) {
+    modify_ioapic_redirection_table_entry(gsi, |entry| entry.set_interrupt_mask(masked));
+}
+
+/// Whether IO APIC pin is masked
+pub fn is_masked(gsi: u8) -> bool {
+    check_gsi(gsi);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _lock = REDIRECTION_TABLE_LOCK.lock();
+        read_ioapic_redirection_table_entry(gsi).interrupt_mask()
+    })
+}
+
+/// Changes vector of IO APIC pin
+pub fn redirect(gsi: u8, vector: u8) {
+    assert!(
+        (0x10..=0xFE).contains(&vector),
+        "Invalid vector {vector:#X} for IO APIC pin"
+    );
+    modify_ioapic_redirection_table_entry(gsi, |entry| entry.set_vector(vector as u64));
+}
+
+fn check_gsi(gsi: u8) {
+    let number_of_redirection_table_entries = *NUMBER_OF_REDIRECTION_TABLE_ENTRIES
+        .get()
+        .expect("IO APIC is not initialized");
+    assert!(
+        gsi < number_of_redirection_table_entries,
+        "GSI {gsi} is out of IO APIC redirection table ({number_of_redirection_table_entries} entries)"
+    );
+}
+
+/// Reads, modifies and writes back redirection table entry
+fn modify_ioapic_redirection_table_entry(gsi: u8, modify: impl FnOnce(&mut RedirectionTableEntry)) {
+    check_gsi(gsi);
+    // Interrupt handler on this core may change the table too
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _lock = REDIRECTION_TABLE_LOCK.lock();
+        let mut entry = read_ioapic_redirection_table_entry(gsi);
+        modify(&mut entry);
+        write_ioapic_redirection_table_entry(gsi, &entry);
+    });
+}
+
 fn write_ioapic_register(offset: u8, val: u32) {
     let io_apic_virt_addr = IO_APIC_VIRT_ADDR
         .get()
@@ -204,6 +272,14 @@ fn write_ioapic_redirection_table_entry(
     write_ioapic_register(offset_high, high);
 }
 
+fn read_ioapic_redirection_table_entry(index: u8) -> RedirectionTableEntry {
+    let offset_low = 0x10 + 2 * index;
+    let offset_high = offset_low + 1;
+    let low = read_ioapic_register(offset_low) as u64;
+    let high = read_ioapic_register(offset_high) as u64;
+    RedirectionTableEntry(high << 32 | low)
+}
+
 bitfield! {
     #[derive(Copy, Clone)]
     struct RedirectionTableEntry(u64);
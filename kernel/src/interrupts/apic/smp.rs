@@ -0,0 +1,429 @@
+//! Application processors bring-up
+//!
+//! APs start in real mode at a page below 1 MB (SIPI vector is the page number).
+//! The trampoline is copied to such page, it loads temporary GDT, enables long mode with the BSP's page tables
+//! and jumps to [ap_entry] on its own stack.
+//!
+//! APs are started one by one, because the trampoline (and its stack pointer) is shared.
+use super::{send_interrupt_command, InterruptCommandRegisterLow};
+use crate::acpi::PLATFORM_INFO;
+use crate::memory_management::physical_memory_manager::{
+    MemoryZoneEnum, MemoryZonesAndPrioritySpecifier,
+};
+use crate::memory_management::virtual_memory_manager::{self, VmError};
+use crate::memory_management::PAGE_SIZE;
+use crate::timers::{hpet, pit};
+use acpi_lib::platform::ProcessorState;
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Once;
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+core::arch::global_asm!(
+    r#"
+.section .text.ap_trampoline, "ax"
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_long_mode
+.global ap_trampoline_gdt
+.global ap_trampoline_gdt_pointer_base
+.global ap_trampoline_long_mode_pointer
+.global ap_trampoline_cr0
+.global ap_trampoline_cr3
+.global ap_trampoline_cr4
+.global ap_trampoline_efer
+.global ap_trampoline_stack_top
+.global ap_trampoline_entry
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+
+    lgdtl (ap_trampoline_gdt_pointer - ap_trampoline_start)
+
+    mov (ap_trampoline_cr4 - ap_trampoline_start), %eax
+    mov %eax, %cr4
+    mov (ap_trampoline_cr3 - ap_trampoline_start), %eax
+    mov %eax, %cr3
+    mov $0xC0000080, %ecx
+    mov (ap_trampoline_efer - ap_trampoline_start), %eax
+    xor %edx, %edx
+    wrmsr
+    // Protection and paging at once, long mode is activated
+    mov (ap_trampoline_cr0 - ap_trampoline_start), %eax
+    mov %eax, %cr0
+
+    ljmpl *(ap_trampoline_long_mode_pointer - ap_trampoline_start)
+
+.code64
+ap_trampoline_long_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    xor %ax, %ax
+    mov %ax, %fs
+    mov %ax, %gs
+    mov ap_trampoline_stack_top(%rip), %rsp
+    mov ap_trampoline_entry(%rip), %rax
+    call *%rax
+    ud2
+
+.balign 8
+ap_trampoline_gdt:
+    .quad 0
+    // 64-bit code
+    .quad 0x00AF9A000000FFFF
+    // Data
+    .quad 0x00CF92000000FFFF
+ap_trampoline_gdt_pointer:
+    .word 23
+ap_trampoline_gdt_pointer_base:
+    .long 0
+ap_trampoline_long_mode_pointer:
+    .long 0
+    .word 0x08
+
+.balign 8
+ap_trampoline_cr0:
+    .quad 0
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_cr4:
+    .quad 0
+ap_trampoline_efer:
+    .quad 0
+ap_trampoline_stack_top:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_end:
+
+.code64
+.text
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdt_pointer_base: u8;
+    static ap_trampoline_long_mode_pointer: u8;
+    static ap_trampoline_cr0: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_cr4: u8;
+    static ap_trampoline_efer: u8;
+    static ap_trampoline_stack_top: u8;
+    static ap_trampoline_entry: u8;
+}
+
+/// AP stack size
+const AP_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+/// Zones priority of AP stacks
+const AP_STACK_ZONES_PRIORITY: &MemoryZonesAndPrioritySpecifier =
+    &[MemoryZoneEnum::High, MemoryZoneEnum::Dma32];
+
+/// How long BSP waits for AP to reach [ap_entry]
+const AP_START_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Function called by every AP after its initialization
+static AP_MAIN: Once<fn()> = Once::new();
+
+/// Number of APs which reached [ap_entry]
+static STARTED_APS: AtomicUsize = AtomicUsize::new(0);
+
+/// Bit set of APIC IDs of APs idling with interrupts enabled, they handle IPIs
+static ONLINE_APS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+/// Returns APIC IDs of APs which finished ap_main and handle IPIs (e.g. TLB shootdown)
+pub fn online_aps() -> impl Iterator<Item = u8> {
+    (0..=u8::MAX).filter(|apic_id| {
+        ONLINE_APS[*apic_id as usize / 64].load(Ordering::Acquire) & (1 << (apic_id % 64)) != 0
+    })
+}
+
+/// Application processors bring-up error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SmpError {
+    /// No usable page below 1 MB for the trampoline
+    NoTrampolinePage,
+    /// PML4 is above 4 GB, it can't be loaded into CR3 before long mode
+    PageTablesAbove4Gb,
+    /// Failed to identity map the trampoline page
+    TrampolineMapping(VmError),
+    /// Failed to allocate AP stack
+    OutOfMemory,
+}
+
+/// Starts application processors listed in MADT
+///
+/// Every AP loads the kernel GDT and IDT, enables its Local APIC, calls ap_main (interrupts disabled)
+/// and idles with interrupts enabled, see [online_aps].<br>
+/// Timers must be inited (INIT-SIPI-SIPI delays use HPET or PIT).
+///
+/// Returns number of started APs
+pub fn start_application_processors(
+    memory_regions: &[MemoryRegion],
+    ap_main: fn(),
+) -> Result<usize, SmpError> {
+    let processor_info = PLATFORM_INFO
+        .get()
+        .expect("PLATFORM_INFO must be populated by acpi::init before APs start")
+        .processor_info
+        .as_ref()
+        .expect("No processor info in MADT");
+    AP_MAIN.call_once(|| ap_main);
+
+    let trampoline_phys_addr = prepare_trampoline(memory_regions)?;
+    let sipi_vector = (trampoline_phys_addr.as_u64() / PAGE_SIZE as u64) as u32;
+
+    for processor in processor_info.application_processors.iter() {
+        if processor.state == ProcessorState::Disabled {
+            continue;
+        }
+        let Ok(apic_id) = u8::try_from(processor.local_apic_id) else {
+            log::warn!(
+                "AP with APIC ID {} can't be started in xAPIC mode",
+                processor.local_apic_id
+            );
+            continue;
+        };
+
+        // Every AP gets its own stack, the trampoline is patched before each start
+        let stack_phys_addr = unsafe {
+            crate::memory_management::physical_memory_manager::alloc(
+                AP_STACK_ZONES_PRIORITY,
+                AP_STACK_SIZE,
+            )
+        };
+        if stack_phys_addr.is_null() {
+            return Err(SmpError::OutOfMemory);
+        }
+        let stack_top = virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(stack_phys_addr)
+            .as_u64()
+            + AP_STACK_SIZE as u64;
+        unsafe {
+            write_trampoline_field(
+                trampoline_phys_addr,
+                core::ptr::addr_of!(ap_trampoline_stack_top),
+                stack_top,
+            );
+        }
+
+        let started_aps = STARTED_APS.load(Ordering::Acquire);
+        start_application_processor(apic_id, sipi_vector);
+        if wait_for_ap(started_aps) {
+            log::info!("AP with APIC ID {apic_id} started");
+        } else {
+            // The stack is leaked, the AP may still wake up and use it
+            log::error!("AP with APIC ID {apic_id} didn't start");
+        }
+    }
+    Ok(STARTED_APS.load(Ordering::Acquire))
+}
+
+/// Copies the trampoline to a page below 1 MB, patches it and identity maps the page
+fn prepare_trampoline(memory_regions: &[MemoryRegion]) -> Result<PhysAddr, SmpError> {
+    let trampoline_size = trampoline_offset(unsafe { core::ptr::addr_of!(ap_trampoline_end) });
+    assert!(trampoline_size <= PAGE_SIZE, "AP trampoline is too big");
+
+    // Page 0 holds real mode IVT and BDA, SIPI vector 0 is avoided
+    let trampoline_phys_addr = memory_regions
+        .iter()
+        .filter(|region| region.kind == MemoryRegionKind::Usable)
+        .map(|region| {
+            (
+                x86_64::align_up(region.start.max(PAGE_SIZE as u64), PAGE_SIZE as u64),
+                region.end,
+            )
+        })
+        .find(|(start, end)| start + PAGE_SIZE as u64 <= (*end).min(0x10_0000))
+        .map(|(start, _)| PhysAddr::new(start))
+        .ok_or(SmpError::NoTrampolinePage)?;
+
+    let cr3_phys_addr = Cr3::read().0.start_address();
+    if cr3_phys_addr.as_u64() > u32::MAX as u64 {
+        return Err(SmpError::PageTablesAbove4Gb);
+    }
+    assert!(
+        !Cr4::read().contains(Cr4Flags::L5_PAGING),
+        "5-level paging is not supported by AP trampoline"
+    );
+
+    // The trampoline keeps running at its physical address after paging is enabled
+    match virtual_memory_manager::map_page(
+        VirtAddr::new(trampoline_phys_addr.as_u64()),
+        trampoline_phys_addr,
+        PageTableFlags::WRITABLE,
+    ) {
+        Ok(()) => {}
+        Err(VmError::AlreadyMapped)
+            if virtual_memory_manager::translate(VirtAddr::new(trampoline_phys_addr.as_u64()))
+                == Some(trampoline_phys_addr) => {}
+        Err(err) => return Err(SmpError::TrampolineMapping(err)),
+    }
+
+    unsafe {
+        let trampoline_virt_addr =
+            virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(trampoline_phys_addr);
+        core::ptr::copy_nonoverlapping(
+            core::ptr::addr_of!(ap_trampoline_start),
+            trampoline_virt_addr.as_mut_ptr::<u8>(),
+            trampoline_size,
+        );
+
+        // Linear addresses used before paging
+        write_trampoline_linear_address(
+            trampoline_phys_addr,
+            core::ptr::addr_of!(ap_trampoline_gdt_pointer_base),
+            core::ptr::addr_of!(ap_trampoline_gdt),
+        );
+        write_trampoline_linear_address(
+            trampoline_phys_addr,
+            core::ptr::addr_of!(ap_trampoline_long_mode_pointer),
+            core::ptr::addr_of!(ap_trampoline_long_mode),
+        );
+
+        // CR0, CR4 and EFER are the same as on the BSP, so the same code (SSE, NX pages) works on APs
+        // PCIDE can't be set before long mode, CET can't be set before CR0.WP, LMA is read-only
+        write_trampoline_field(
+            trampoline_phys_addr,
+            core::ptr::addr_of!(ap_trampoline_cr0),
+            Cr0::read_raw(),
+        );
+        write_trampoline_field(
+            trampoline_phys_addr,
+            core::ptr::addr_of!(ap_trampoline_cr3),
+            cr3_phys_addr.as_u64(),
+        );
+        write_trampoline_field(
+            trampoline_phys_addr,
+            core::ptr::addr_of!(ap_trampoline_cr4),
+            Cr4::read_raw() & !(Cr4Flags::PCID | Cr4Flags::CONTROL_FLOW_ENFORCEMENT).bits(),
+        );
+        write_trampoline_field(
+            trampoline_phys_addr,
+            core::ptr::addr_of!(ap_trampoline_efer),
+            Efer::read_raw() & !(1 << 10),
+        );
+        write_trampoline_field(
+            trampoline_phys_addr,
+            core::ptr::addr_of!(ap_trampoline_entry),
+            ap_entry as usize as u64,
+        );
+    }
+    Ok(trampoline_phys_addr)
+}
+
+/// Returns offset of trampoline symbol from trampoline start
+#[inline]
+fn trampoline_offset(symbol: *const u8) -> usize {
+    symbol as usize - unsafe { core::ptr::addr_of!(ap_trampoline_start) } as usize
+}
+
+/// Writes 8-byte trampoline data field in the copied trampoline
+unsafe fn write_trampoline_field(trampoline_phys_addr: PhysAddr, symbol: *const u8, value: u64) {
+    virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(trampoline_phys_addr)
+        .as_mut_ptr::<u8>()
+        .add(trampoline_offset(symbol))
+        .cast::<u64>()
+        .write_volatile(value);
+}
+
+/// Writes 4-byte physical address of trampoline symbol target into trampoline field symbol
+unsafe fn write_trampoline_linear_address(
+    trampoline_phys_addr: PhysAddr,
+    symbol: *const u8,
+    target: *const u8,
+) {
+    let linear_address = trampoline_phys_addr.as_u64() as u32 + trampoline_offset(target) as u32;
+    virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(trampoline_phys_addr)
+        .as_mut_ptr::<u8>()
+        .add(trampoline_offset(symbol))
+        .cast::<u32>()
+        .write_unaligned(linear_address);
+}
+
+/// Sends INIT-SIPI-SIPI sequence to AP
+///
+/// INIT IPI:<br>
+/// Delivery Mode                    8-10 = 101 - INIT <br>
+/// Level                            14 = 1 - Assert <br>
+/// Trigger Mode                     15 = 0 - Edge <br>
+/// Destination Shorthand            18-19 = 00 - No shorthand <br>
+///
+/// Startup IPI:<br>
+/// Vector                           0-7 = page number of the trampoline <br>
+/// Delivery Mode                    8-10 = 110 - Start Up <br>
+fn start_application_processor(apic_id: u8, sipi_vector: u32) {
+    let mut init_ipi = InterruptCommandRegisterLow(0);
+    init_ipi.set_delivery_mode(0b101);
+    init_ipi.set_level(true);
+    send_interrupt_command(apic_id, init_ipi);
+    delay(Duration::from_millis(10));
+
+    let mut startup_ipi = InterruptCommandRegisterLow(0);
+    startup_ipi.set_vector(sipi_vector);
+    startup_ipi.set_delivery_mode(0b110);
+    startup_ipi.set_level(true);
+    // The second SIPI is ignored if the AP started after the first one
+    for _ in 0..2 {
+        send_interrupt_command(apic_id, InterruptCommandRegisterLow(startup_ipi.0));
+        delay(Duration::from_micros(200));
+    }
+}
+
+/// Busy waits, interrupts are not needed
+fn delay(duration: Duration) {
+    if hpet::is_supported() {
+        hpet::busy_sleep(duration);
+    } else {
+        // PIT can't wait less than 1 ms
+        pit::busy_sleep((duration.as_millis() as u32).max(1));
+    }
+}
+
+/// Waits until STARTED_APS becomes greater than started_aps
+fn wait_for_ap(started_aps: usize) -> bool {
+    for _ in 0..AP_START_TIMEOUT.as_millis() {
+        if STARTED_APS.load(Ordering::Acquire) > started_aps {
+            return true;
+        }
+        delay(Duration::from_millis(1));
+    }
+    STARTED_APS.load(Ordering::Acquire) > started_aps
+}
+
+/// AP entry, called by the trampoline in long mode on the AP's stack
+extern "C" fn ap_entry() -> ! {
+    crate::gdt::init_ap();
+    crate::interrupts::idt::init_ap();
+    // PAT must match the BSP's before any mapping with non-default cache type is touched
+    crate::memory_management::virtual_memory_manager::init_pat();
+    // Software enable of this AP's Local APIC, LVT stays masked
+    super::fill_spurious_interrupt_vector_register();
+    crate::percpu::init();
+
+    STARTED_APS.fetch_add(1, Ordering::AcqRel);
+    if let Some(ap_main) = AP_MAIN.get() {
+        ap_main();
+    }
+
+    // Idle with interrupts enabled, APs only get IPIs, IO APIC and LVT interrupts go to the BSP or are masked
+    let apic_id = super::local_apic_id();
+    ONLINE_APS[apic_id as usize / 64].fetch_or(1 << (apic_id % 64), Ordering::AcqRel);
+    loop {
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+}
@@ -0,0 +1,328 @@
+//! Local APIC Timer
+//!
+//! The timer decrements Initial Count Register value at bus frequency divided by [DIVIDER].
+//!
+//! Initial Count Register is 32-bit, so for timer frequency F = bus frequency / [DIVIDER]
+//! representable interrupt frequencies are F / u32::MAX ..= F Hz.
+//! E.g. for 1 GHz bus frequency: ~4 Hz ..= 62.5 MHz.
+use super::{LvtRegister, CURRENT_COUNT_REGISTER, INITIAL_COUNT_REGISTER, LVT_TIMER_REGISTER};
+use crate::interrupts::idt::{self, LOCAL_APIC_TIMER_IDT_VECTOR};
+use crate::timers::{hpet, pit};
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use raw_cpuid::CpuId;
+use spin::Once;
+
+/// Calibration result with APIC bus frequency, the timer counts at this frequency divided by [DIVIDER]
+static CALIBRATION_RESULT: Once<CalibrationResult> = Once::new();
+
+/// Period of the tick started by [init]
+pub const DEFAULT_PERIOD: Duration = Duration::from_millis(10);
+
+/// Number of timer interrupts
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Sane APIC bus frequencies, tens of MHz to low GHz
+const BUS_FREQUENCY_SANE_RANGE: RangeInclusive<u64> = 10_000_000..=5_000_000_000;
+
+/// How long the timer counts during measurement
+const CALIBRATION_DURATION: Duration = Duration::from_millis(10);
+
+/// Bus frequency divider used for the timer
+pub const DIVIDER: u64 = 16;
+
+/// Divide Configuration Register value for [DIVIDER] (bits 0, 1, 3 = 011 - divide by 16)
+const DIVIDE_CONFIGURATION_VALUE: u32 = 0b0011;
+
+/// Error of timer initial count computation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InitialCountError {
+    /// Requested frequency is zero
+    ZeroFrequency,
+    /// Requested frequency is too high, initial count rounds to 0 and timer never fires
+    FrequencyTooHigh {
+        /// Maximum representable frequency
+        max_hz: u64,
+    },
+    /// Requested frequency is too low, initial count doesn't fit in 32-bit register
+    FrequencyTooLow {
+        /// Minimum representable frequency
+        min_hz: u64,
+    },
+}
+
+impl core::fmt::Display for InitialCountError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InitialCountError::ZeroFrequency => write!(f, "zero timer frequency"),
+            InitialCountError::FrequencyTooHigh { max_hz } => {
+                write!(f, "timer frequency is too high, max is {max_hz} Hz")
+            }
+            InitialCountError::FrequencyTooLow { min_hz } => {
+                write!(f, "timer frequency is too low, min is {min_hz} Hz")
+            }
+        }
+    }
+}
+
+/// Returns range of interrupt frequencies representable with given bus frequency
+pub fn representable_frequency_range(bus_frequency_hz: u64) -> core::ops::RangeInclusive<u64> {
+    let timer_frequency_hz = bus_frequency_hz / DIVIDER;
+    let min_hz = timer_frequency_hz.div_ceil(u32::MAX as u64).max(1);
+    min_hz..=timer_frequency_hz
+}
+
+/// Computes Initial Count Register value for interrupt frequency hz
+///
+/// initial count = bus frequency / (DIVIDER * hz), must be in 1..=u32::MAX
+pub fn initial_count(bus_frequency_hz: u64, hz: u64) -> Result<u32, InitialCountError> {
+    if hz == 0 {
+        return Err(InitialCountError::ZeroFrequency);
+    }
+    let initial_count = match DIVIDER.checked_mul(hz) {
+        Some(divisor) => bus_frequency_hz / divisor,
+        // Overflow means the divisor is much bigger than any frequency
+        None => 0,
+    };
+    checked_initial_count(bus_frequency_hz, initial_count)
+}
+
+/// Computes Initial Count Register value for interrupt period, rounded down to whole timer ticks
+///
+/// Like [initial_count], zero period is too high frequency
+pub fn initial_count_for_period(
+    bus_frequency_hz: u64,
+    period: Duration,
+) -> Result<u32, InitialCountError> {
+    let initial_count = crate::timers::fixed_point::duration_to_ticks_by_frequency(
+        period,
+        bus_frequency_hz / DIVIDER,
+    );
+    checked_initial_count(bus_frequency_hz, initial_count)
+}
+
+/// Checks that computed initial count is in 1..=u32::MAX
+fn checked_initial_count(
+    bus_frequency_hz: u64,
+    initial_count: u64,
+) -> Result<u32, InitialCountError> {
+    let range = representable_frequency_range(bus_frequency_hz);
+    if initial_count == 0 {
+        return Err(InitialCountError::FrequencyTooHigh {
+            max_hz: *range.end(),
+        });
+    }
+    u32::try_from(initial_count).map_err(|_| InitialCountError::FrequencyTooLow {
+        min_hz: *range.start(),
+    })
+}
+
+/// Computes Initial Count Register value, clamping it to 1..=u32::MAX
+///
+/// Logs a warning if clamping occurred
+pub fn initial_count_clamped(bus_frequency_hz: u64, hz: u64) -> u32 {
+    match initial_count(bus_frequency_hz, hz) {
+        Ok(initial_count) => initial_count,
+        Err(err @ InitialCountError::FrequencyTooLow { .. })
+        | Err(err @ InitialCountError::ZeroFrequency) => {
+            log::warn!("APIC Timer: {err}, initial count clamped to max");
+            u32::MAX
+        }
+        Err(err @ InitialCountError::FrequencyTooHigh { .. }) => {
+            log::warn!("APIC Timer: {err}, initial count clamped to 1");
+            1
+        }
+    }
+}
+
+/// Sets timer divider to [DIVIDER]
+pub(super) fn set_divider() {
+    unsafe {
+        super::DIVIDE_CONFIGURATION_REGISTER.write_volatile(DIVIDE_CONFIGURATION_VALUE);
+    }
+}
+
+/// LVT Timer Register timer mode (bits 17-18): interrupt once when count reaches 0
+const TIMER_MODE_ONE_SHOT: u32 = 0b00;
+
+/// LVT Timer Register timer mode (bits 17-18): reload initial count when count reaches 0
+const TIMER_MODE_PERIODIC: u32 = 0b01;
+
+/// Starts periodic timer interrupts with given period
+///
+/// The period is rounded down to whole timer ticks. Timer must be calibrated ([init])
+pub fn set_periodic(period: Duration) -> Result<(), InitialCountError> {
+    let initial_count = initial_count_for_period(bus_frequency_hz(), period)?;
+
+    set_divider();
+    let mut lvt_timer_register_value = LvtRegister(0);
+    lvt_timer_register_value.set_vector(LOCAL_APIC_TIMER_IDT_VECTOR as u32);
+    lvt_timer_register_value.set_timer_mode(TIMER_MODE_PERIODIC);
+    unsafe {
+        LVT_TIMER_REGISTER.write_volatile(lvt_timer_register_value.0);
+        // Writing initial count starts the timer
+        INITIAL_COUNT_REGISTER.write_volatile(initial_count);
+    }
+    Ok(())
+}
+
+/// Stops the timer, pending interrupt is not canceled
+pub fn stop() {
+    unsafe {
+        INITIAL_COUNT_REGISTER.write_volatile(0);
+    }
+}
+
+/// Method used to determine APIC bus frequency
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CalibrationMethod {
+    /// CPUID 0x15 core crystal clock frequency
+    Cpuid,
+    /// Hypervisor CPUID 0x40000010 APIC bus frequency
+    Hypervisor,
+    /// Measured against HPET
+    Hpet,
+    /// Measured against PIT
+    Pit,
+}
+
+/// Result of Local APIC Timer calibration
+#[derive(Debug, Copy, Clone)]
+pub struct CalibrationResult {
+    /// APIC bus frequency in Hz, mean of samples
+    pub bus_frequency_hz: u64,
+    /// Difference between max and min sample in Hz, 0 if frequency is reported, not measured
+    pub spread_hz: u64,
+    /// Number of samples
+    pub samples: usize,
+    pub method: CalibrationMethod,
+}
+
+/// Number of measurements
+const CALIBRATION_SAMPLES: usize = 5;
+
+/// Calibrates timer, stores the result and starts periodic tick with [DEFAULT_PERIOD]
+///
+/// HPET must be inited, it's used (or PIT if HPET is not supported) to measure the frequency if CPUID doesn't report it.
+/// Local APIC must be inited, CPU interrupts stay disabled
+pub fn init() -> &'static CalibrationResult {
+    let calibration_result = CALIBRATION_RESULT.call_once(calibrate);
+    let bus_frequency_hz = calibration_result.bus_frequency_hz;
+    assert!(
+        BUS_FREQUENCY_SANE_RANGE.contains(&bus_frequency_hz),
+        "APIC bus frequency {bus_frequency_hz} Hz is out of sane range, bug"
+    );
+    log::info!(
+        "APIC Timer: bus frequency {bus_frequency_hz} Hz (method: {:?}, spread: {} Hz over {} samples)",
+        calibration_result.method,
+        calibration_result.spread_hz,
+        calibration_result.samples
+    );
+
+    if let Err(err) = idt::register_handler(LOCAL_APIC_TIMER_IDT_VECTOR, |_| {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }) {
+        panic!("Failed to register APIC Timer interrupt handler: {err:?}");
+    }
+    if let Err(err) = set_periodic(DEFAULT_PERIOD) {
+        panic!("Failed to start APIC Timer with period {DEFAULT_PERIOD:?}: {err}");
+    }
+    log::info!("APIC Timer: periodic tick started, period {DEFAULT_PERIOD:?}");
+    calibration_result
+}
+
+/// Returns number of timer interrupts since [init]
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns calibration result, None if the timer is not inited
+pub fn calibration_result() -> Option<&'static CalibrationResult> {
+    CALIBRATION_RESULT.get()
+}
+
+/// Returns APIC bus frequency in Hz
+///
+/// Panics if the timer is not inited
+pub fn bus_frequency_hz() -> u64 {
+    calibration_result()
+        .expect("Local APIC Timer is not inited")
+        .bus_frequency_hz
+}
+
+/// Gets bus frequency from CPUID or measures it
+fn calibrate() -> CalibrationResult {
+    let cpuid = CpuId::new();
+    // Hypervisor reports APIC bus frequency in kHz
+    let hypervisor_frequency_hz = cpuid
+        .get_hypervisor_info()
+        .and_then(|hypervisor_info| hypervisor_info.apic_frequency())
+        .map_or(0, |frequency_khz| frequency_khz as u64 * 1000);
+    if hypervisor_frequency_hz != 0 {
+        return CalibrationResult {
+            bus_frequency_hz: hypervisor_frequency_hz,
+            spread_hz: 0,
+            samples: 1,
+            method: CalibrationMethod::Hypervisor,
+        };
+    }
+    // CPUID 0x15 ECX - core crystal clock frequency, the timer is clocked by it on Intel processors
+    // Usually 0 (not enumerated), especially in VMs
+    let crystal_frequency_hz = cpuid
+        .get_tsc_info()
+        .map_or(0, |tsc_info| tsc_info.nominal_frequency() as u64);
+    if crystal_frequency_hz != 0 {
+        return CalibrationResult {
+            bus_frequency_hz: crystal_frequency_hz,
+            spread_hz: 0,
+            samples: 1,
+            method: CalibrationMethod::Cpuid,
+        };
+    }
+
+    let method = if hpet::is_supported() {
+        CalibrationMethod::Hpet
+    } else {
+        CalibrationMethod::Pit
+    };
+    let mut min_frequency_hz = u64::MAX;
+    let mut max_frequency_hz = 0;
+    let mut frequencies_sum_hz: u128 = 0;
+    for _ in 0..CALIBRATION_SAMPLES {
+        let frequency_hz = measure_bus_frequency(method);
+        min_frequency_hz = min_frequency_hz.min(frequency_hz);
+        max_frequency_hz = max_frequency_hz.max(frequency_hz);
+        frequencies_sum_hz += frequency_hz as u128;
+    }
+    CalibrationResult {
+        bus_frequency_hz: (frequencies_sum_hz / CALIBRATION_SAMPLES as u128) as u64,
+        spread_hz: max_frequency_hz - min_frequency_hz,
+        samples: CALIBRATION_SAMPLES,
+        method,
+    }
+}
+
+/// Measures bus frequency by counting down masked one-shot timer during [CALIBRATION_DURATION]
+fn measure_bus_frequency(method: CalibrationMethod) -> u64 {
+    set_divider();
+    let mut lvt_timer_register_value = LvtRegister(0);
+    lvt_timer_register_value.set_vector(LOCAL_APIC_TIMER_IDT_VECTOR as u32);
+    lvt_timer_register_value.set_timer_mode(TIMER_MODE_ONE_SHOT);
+    lvt_timer_register_value.set_mask(true);
+    let remaining_count = unsafe {
+        LVT_TIMER_REGISTER.write_volatile(lvt_timer_register_value.0);
+        INITIAL_COUNT_REGISTER.write_volatile(u32::MAX);
+        match method {
+            CalibrationMethod::Hpet => hpet::busy_sleep(CALIBRATION_DURATION),
+            CalibrationMethod::Pit => pit::busy_sleep(CALIBRATION_DURATION.as_millis() as u32),
+            _ => unreachable!("Calibration method {method:?} doesn't measure"),
+        }
+        let remaining_count = CURRENT_COUNT_REGISTER.read_volatile();
+        // Stop
+        INITIAL_COUNT_REGISTER.write_volatile(0);
+        remaining_count
+    };
+    let elapsed_count = (u32::MAX - remaining_count) as u128;
+    (elapsed_count * DIVIDER as u128 * 1_000_000_000 / CALIBRATION_DURATION.as_nanos()) as u64
+}
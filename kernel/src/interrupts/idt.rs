@@ -1,7 +1,12 @@
-use super::apic;
-use crate::timers;
+use super::{apic, pic};
+use crate::memory_management::{demand_paging, virtual_memory_manager};
 use core::ops::RangeInclusive;
-use x86_64::structures::idt::{ExceptionVector, InterruptDescriptorTable, InterruptStackFrame};
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use x86_64::addr::VirtAddrNotValid;
+use x86_64::structures::idt::{
+    ExceptionVector, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+use x86_64::VirtAddr;
 
 static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
@@ -10,11 +15,23 @@ pub fn init() {
     #[allow(static_mut_refs)]
     unsafe {
         x86_64::set_general_handler!(&mut IDT, general_interrupt_handler);
+        // Double Fault runs on its own stack, so kernel stack overflow is reported instead of Triple Fault
+        IDT.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
         // Loads IDT using lidt
         IDT.load();
     }
 }
 
+/// Loads IDT filled by [init] on application processor
+pub fn init_ap() {
+    #[allow(static_mut_refs)]
+    unsafe {
+        IDT.load();
+    }
+}
+
 pub const CPU_EXCEPTIONS_IDT_VECTORS_RANGE: RangeInclusive<u8> = 0..=31;
 pub const IO_APIC_ISA_IRQ_VECTORS_RANGE: RangeInclusive<u8> = 32..=47;
 pub const IO_APIC_24_VECTORS_RANGE: RangeInclusive<u8> = 32..=55;
@@ -22,8 +39,191 @@ pub const LOCAL_APIC_TIMER_IDT_VECTOR: u8 = 56;
 pub const LOCAL_APIC_LINT0_IDT_VECTOR: u8 = 57;
 pub const LOCAL_APIC_LINT1_IDT_VECTOR: u8 = 58;
 pub const LOCAL_APIC_ERROR_IDT_VECTOR: u8 = 59;
+pub const HPET_ONESHOT_IDT_VECTOR: u8 = 60;
+pub const TLB_SHOOTDOWN_IDT_VECTOR: u8 = 61;
 pub const LOCAL_APIC_SPURIOUS_IDT_VECTOR: u8 = 255;
 
+/// Interrupt handler, receives vector
+///
+/// Called with interrupts disabled, EOI is sent by the dispatcher after it returns
+pub type InterruptHandler = fn(u8);
+
+/// Registered handlers indexed by vector, null if not registered
+///
+/// Atomic pointers, so the dispatcher looks handlers up without locks
+static INTERRUPT_HANDLERS: [AtomicPtr<()>; 256] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; 256];
+
+/// Interrupt handler registration error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegisterHandlerError {
+    /// CPU exception, TLB shootdown or spurious interrupt vector (Local APIC or PIC), they are handled by the kernel itself
+    ReservedVector,
+    /// Another handler is registered for the vector
+    AlreadyRegistered,
+}
+
+/// Registers handler of device interrupt vector
+///
+/// Handlers can be registered for IO APIC vectors and Local APIC Timer, LINT0, LINT1 and Error vectors
+pub fn register_handler(vector: u8, handler: InterruptHandler) -> Result<(), RegisterHandlerError> {
+    if CPU_EXCEPTIONS_IDT_VECTORS_RANGE.contains(&vector)
+        || vector == TLB_SHOOTDOWN_IDT_VECTOR
+        || vector == LOCAL_APIC_SPURIOUS_IDT_VECTOR
+        || vector == pic::MASTER_PIC_SPURIOUS_IDT_VECTOR
+        || vector == pic::SLAVE_PIC_SPURIOUS_IDT_VECTOR
+    {
+        return Err(RegisterHandlerError::ReservedVector);
+    }
+    INTERRUPT_HANDLERS[vector as usize]
+        .compare_exchange(
+            core::ptr::null_mut(),
+            handler as *mut (),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .map(|_| ())
+        .map_err(|_| RegisterHandlerError::AlreadyRegistered)
+}
+
+/// Removes handler of vector
+///
+/// The handler may still be running on another CPU when it returns
+pub fn unregister_handler(vector: u8) {
+    INTERRUPT_HANDLERS[vector as usize].store(core::ptr::null_mut(), Ordering::Release);
+}
+
+/// Returns registered handler of vector
+#[inline]
+fn registered_handler(vector: u8) -> Option<InterruptHandler> {
+    let handler = INTERRUPT_HANDLERS[vector as usize].load(Ordering::Acquire);
+    // Only fn(u8) pointers are stored
+    (!handler.is_null())
+        .then(|| unsafe { core::mem::transmute::<*mut (), InterruptHandler>(handler) })
+}
+
+/// Maximum allowed interrupt nesting depth
+///
+/// Interrupt gates clear IF, so device interrupts never nest, but exceptions do:
+/// a page fault inside an IRQ handler, then an NMI, then a fault in the NMI handler.
+/// Anything deeper means that someone re-enabled interrupts inside a handler.
+#[cfg(debug_assertions)]
+const MAX_INTERRUPT_NESTING_DEPTH: usize = 4;
+
+/// Interrupt nesting state of a core, kept in its [CpuLocal](crate::percpu::CpuLocal)
+///
+/// Nesting is tracked only in debug builds, release handlers don't touch per-CPU block
+#[cfg(debug_assertions)]
+#[derive(Debug)]
+pub struct InterruptNesting {
+    /// Current interrupt nesting depth
+    depth: AtomicUsize,
+    /// Vectors of the interrupts currently being handled, indexed by nesting depth
+    ///
+    /// Used only for diagnostics when the nesting depth is exceeded
+    vectors_stack: [AtomicU8; MAX_INTERRUPT_NESTING_DEPTH + 1],
+}
+
+#[cfg(debug_assertions)]
+impl InterruptNesting {
+    pub const fn new() -> Self {
+        Self {
+            depth: AtomicUsize::new(0),
+            vectors_stack: [const { AtomicU8::new(0) }; MAX_INTERRUPT_NESTING_DEPTH + 1],
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Default for InterruptNesting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Nesting state used before per-CPU block of the core exists
+///
+/// Only the BSP runs that early (and APs for a few instructions of their entry), exceptions there are fatal anyway
+#[cfg(debug_assertions)]
+static EARLY_INTERRUPT_NESTING: InterruptNesting = InterruptNesting::new();
+
+/// Maximum observed interrupt nesting depth of all cores
+#[cfg(debug_assertions)]
+static MAX_OBSERVED_INTERRUPT_NESTING_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns nesting state of this core
+#[cfg(debug_assertions)]
+#[inline]
+fn interrupt_nesting() -> &'static InterruptNesting {
+    crate::percpu::try_this_cpu().map_or(&EARLY_INTERRUPT_NESTING, |cpu_local| {
+        &cpu_local.interrupt_nesting
+    })
+}
+
+/// Number of interrupts with vectors not handled by the kernel and without registered handler
+static UNEXPECTED_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns number of interrupts with vectors not handled by the kernel and without registered handler
+pub fn unexpected_interrupt_count() -> u64 {
+    UNEXPECTED_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Number of handled breakpoint (int3) exceptions
+static BREAKPOINTS_HIT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns number of handled breakpoint (int3) exceptions
+pub fn breakpoints_hit() -> usize {
+    BREAKPOINTS_HIT.load(Ordering::Relaxed)
+}
+
+/// Returns maximum observed interrupt nesting depth
+///
+/// 1 means that interrupts have never been nested
+#[cfg(debug_assertions)]
+pub fn max_observed_nesting_depth() -> usize {
+    MAX_OBSERVED_INTERRUPT_NESTING_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Returns interrupt nesting depth of this core, 0 outside of handlers
+#[cfg(debug_assertions)]
+pub fn nesting_depth() -> usize {
+    interrupt_nesting().depth.load(Ordering::Relaxed)
+}
+
+/// Must be called at handler entry
+#[cfg(debug_assertions)]
+#[inline]
+fn enter_interrupt(index: u8) {
+    let interrupt_nesting = interrupt_nesting();
+    let depth = interrupt_nesting.depth.fetch_add(1, Ordering::Relaxed) + 1;
+    MAX_OBSERVED_INTERRUPT_NESTING_DEPTH.fetch_max(depth, Ordering::Relaxed);
+
+    if depth <= MAX_INTERRUPT_NESTING_DEPTH {
+        interrupt_nesting.vectors_stack[depth - 1].store(index, Ordering::Relaxed);
+    } else {
+        // Can't use locking printer, the interrupted handler may hold COM1 lock
+        crate::serial_println_lock_free!("Interrupt nesting depth exceeded: {depth}");
+        for (i, vector) in interrupt_nesting
+            .vectors_stack
+            .iter()
+            .take(MAX_INTERRUPT_NESTING_DEPTH)
+            .enumerate()
+        {
+            crate::serial_println_lock_free!("[{i}]: vector {}", vector.load(Ordering::Relaxed));
+        }
+        crate::serial_println_lock_free!("[{depth}]: vector {index}");
+        panic!("Unexpected re-entrant interrupt, interrupts were enabled inside a handler");
+    }
+}
+
+/// Must be called at handler exit
+#[cfg(debug_assertions)]
+#[inline]
+fn exit_interrupt() {
+    let previous_depth = interrupt_nesting().depth.fetch_sub(1, Ordering::Relaxed);
+    debug_assert_ne!(previous_depth, 0, "Interrupt nesting depth underflow");
+}
+
 /// A general handler function for an interrupt or an exception with the interrupt/exception index and an optional error code
 ///
 /// 0-31    CPU exceptions<br>
@@ -33,12 +233,74 @@ pub const LOCAL_APIC_SPURIOUS_IDT_VECTOR: u8 = 255;
 /// 57      Local APIC LINT0<br>
 /// 58      Local APIC LINT1<br>
 /// 59      Local APIC Error<br>
+/// 60      HPET one-shot comparator<br>
+/// 61      TLB shootdown IPI<br>
+/// 231     PIC spurious IRQ 7 (no Local APIC EOI)<br>
+/// 239     PIC spurious IRQ 15 (no Local APIC EOI)<br>
 /// 255     Local APIC Spurious-Interrupt (handler must do nothing (and even don't send an EOI))
 pub fn general_interrupt_handler(
     interrupt_stack_frame: InterruptStackFrame,
     index: u8,
     error_code: Option<u64>,
 ) {
+    #[cfg(debug_assertions)]
+    enter_interrupt(index);
+    #[cfg(feature = "interrupt-latency")]
+    let start_cycles = super::latency::read_cycles();
+
+    dispatch_interrupt(interrupt_stack_frame, index, error_code);
+
+    #[cfg(feature = "interrupt-latency")]
+    super::latency::record(index, start_cycles);
+    #[cfg(debug_assertions)]
+    exit_interrupt();
+}
+
+/// Sends EOI to Local APIC when dropped
+///
+/// The dispatcher creates it for fixed-delivery interrupts, so handlers never send an EOI themselves
+/// and the EOI is sent on every normal return path.
+/// Panics don't unwind in the kernel, so a panicking handler never sends it.
+struct EoiGuard;
+
+impl Drop for EoiGuard {
+    fn drop(&mut self) {
+        apic::send_eoi();
+    }
+}
+
+/// Whether interrupt requires EOI
+///
+/// CPU exceptions (including NMI) and spurious interrupt must not be acknowledged
+#[inline]
+fn requires_eoi(index: u8) -> bool {
+    IO_APIC_24_VECTORS_RANGE.contains(&index)
+        || matches!(
+            index,
+            LOCAL_APIC_TIMER_IDT_VECTOR
+                | LOCAL_APIC_LINT0_IDT_VECTOR
+                | LOCAL_APIC_LINT1_IDT_VECTOR
+                | LOCAL_APIC_ERROR_IDT_VECTOR
+                | HPET_ONESHOT_IDT_VECTOR
+                | TLB_SHOOTDOWN_IDT_VECTOR
+        )
+}
+
+/// Handles interrupt by index
+fn dispatch_interrupt(
+    interrupt_stack_frame: InterruptStackFrame,
+    index: u8,
+    error_code: Option<u64>,
+) {
+    // EOI sent when guard goes out of scope
+    let _eoi_guard = requires_eoi(index).then_some(EoiGuard);
+
+    // Device interrupts go to registered handlers, exceptions and spurious interrupt can't be registered
+    if let Some(handler) = registered_handler(index) {
+        handler(index);
+        return;
+    }
+
     match index {
         index if CPU_EXCEPTIONS_IDT_VECTORS_RANGE.contains(&index) => {
             // CPU Exception
@@ -46,14 +308,22 @@ pub fn general_interrupt_handler(
                 ExceptionVector::try_from(index).expect("Invalid exception vector number");
 
             match exception {
+                ExceptionVector::Breakpoint => {
+                    // Trap, saved instruction pointer is after int3, so execution continues. No EOI for exceptions
+                    BREAKPOINTS_HIT.fetch_add(1, Ordering::Relaxed);
+                    crate::serial_println_lock_free!(
+                        "Breakpoint at 0x{:X}\n{interrupt_stack_frame:#?}",
+                        interrupt_stack_frame.instruction_pointer.as_u64() - 1
+                    );
+                }
+                ExceptionVector::NonMaskableInterrupt if crate::shutdown::is_halting() => {
+                    // Halt IPI from the core which is shutting down
+                    crate::shutdown::halt_forever();
+                }
                 ExceptionVector::Page => {
-                    let cr2_virtual_address =
-                        x86_64::registers::control::Cr2::read().expect("Invalid address in CR2");
-                    panic!(
-                        "Exception: {exception:?}\n\
-                        Error code: {error_code:#?}\n\
-                        CR2: 0x{cr2_virtual_address:X}
-                        {interrupt_stack_frame:#?}"
+                    page_fault_handler(
+                        interrupt_stack_frame,
+                        error_code.expect("Page fault without error code"),
                     );
                 }
                 _ => {
@@ -65,41 +335,191 @@ pub fn general_interrupt_handler(
                 }
             }
         }
+        // Interrupts without handler are printed only at Trace level, lock-free printer is used
+        // because the interrupted code may hold COM1 lock
         index if IO_APIC_24_VECTORS_RANGE.contains(&index) => {
-            if IO_APIC_ISA_IRQ_VECTORS_RANGE.contains(&index) {
-                // PIT interrupt
-                if index == 32 {
-                    timers::pit::tick_interrupt_handler();
-                } else {
+            if log::log_enabled!(log::Level::Trace) {
+                if IO_APIC_ISA_IRQ_VECTORS_RANGE.contains(&index) {
                     crate::serial_println_lock_free!("IO APIC ISA IRQ interrupt: {index}");
+                } else {
+                    crate::serial_println_lock_free!("IO APIC *NOT* ISA IRQ interrupt: {index}");
                 }
-            } else {
-                crate::serial_println_lock_free!("IO APIC *NOT* ISA IRQ interrupt: {index}");
             }
-            apic::send_eoi();
         }
         LOCAL_APIC_TIMER_IDT_VECTOR => {
-            crate::serial_println_lock_free!("LOCAL APIC TIMER interrupt");
-            apic::send_eoi();
+            if log::log_enabled!(log::Level::Trace) {
+                crate::serial_println_lock_free!("LOCAL APIC TIMER interrupt");
+            }
         }
         LOCAL_APIC_LINT0_IDT_VECTOR => {
-            crate::serial_println_lock_free!("LOCAL APIC LINT0 interrupt");
-            apic::send_eoi();
+            if log::log_enabled!(log::Level::Trace) {
+                crate::serial_println_lock_free!("LOCAL APIC LINT0 interrupt");
+            }
         }
         LOCAL_APIC_LINT1_IDT_VECTOR => {
-            crate::serial_println_lock_free!("LOCAL APIC LINT1 interrupt");
-            apic::send_eoi();
+            if log::log_enabled!(log::Level::Trace) {
+                crate::serial_println_lock_free!("LOCAL APIC LINT1 interrupt");
+            }
         }
         LOCAL_APIC_ERROR_IDT_VECTOR => {
-            panic!("LOCAL APIC ERROR interrupt");
-            apic::send_eoi();
+            // Errors are usually caused by a bad IPI or vector, not fatal
+            let error_status = super::apic::read_error_status();
+            crate::serial_println_lock_free!("LOCAL APIC ERROR interrupt: {error_status}");
+        }
+        TLB_SHOOTDOWN_IDT_VECTOR => {
+            virtual_memory_manager::handle_tlb_shootdown();
         }
         LOCAL_APIC_SPURIOUS_IDT_VECTOR => {
-            crate::serial_println_lock_free!("LOCAL APIC SPURIOUS interrupt");
-            return;
+            // No EOI for spurious interrupt, not logged, they may come in bursts
+            apic::SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        }
+        pic::MASTER_PIC_SPURIOUS_IDT_VECTOR | pic::SLAVE_PIC_SPURIOUS_IDT_VECTOR => {
+            // PIC interrupt doesn't go through Local APIC, it's acknowledged (if at all) by PIC itself
+            pic::handle_spurious(index);
         }
         _ => {
-            unreachable!("Unexpected interrupt number: {index}!");
+            // Stray interrupt without handler (e.g. handler was unregistered), it's acknowledged and ignored.
+            // Vectors which require EOI (e.g. HPET one-shot) already have the guard, a second EOI would
+            // acknowledge an interrupt of lower priority which is still in service
+            UNEXPECTED_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+            if !requires_eoi(index) {
+                apic::send_eoi();
+            }
+        }
+    }
+}
+
+/// Handles Double Fault on IST stack
+///
+/// Kernel stack overflow ends up here: the page fault can't push its frame to the overflowed stack.
+/// It only panics, the panic handler prints without locks (the faulting code may hold COM1 lock)
+extern "x86-interrupt" fn double_fault_handler(
+    interrupt_stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    panic!(
+        "Exception: Double Fault\n\
+        Error code: {error_code:#X}\n\
+        Faulting stack pointer: 0x{:X} ({:?})\n\
+        {interrupt_stack_frame:#?}",
+        interrupt_stack_frame.stack_pointer.as_u64(),
+        virtual_memory_manager::virtual_memory_region(interrupt_stack_frame.stack_pointer.as_u64())
+    );
+}
+
+/// Handles page fault
+///
+/// Demand paging faults are resolved, then the faulting instruction is retried,
+/// other faults are decoded and cause panic
+fn page_fault_handler(interrupt_stack_frame: InterruptStackFrame, error_code: u64) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    // Non-canonical CR2 suggests a nested fault or hardware issue, report it with the rest of the frame
+    let cr2_virtual_address = match x86_64::registers::control::Cr2::read() {
+        Ok(cr2_virtual_address) => Ok(cr2_virtual_address),
+        Err(VirtAddrNotValid(cr2_value)) => {
+            crate::serial_println_lock_free!(
+                "Page Fault with invalid address in CR2: 0x{cr2_value:X}"
+            );
+            Err(cr2_value)
+        }
+    };
+
+    if let Ok(cr2_virtual_address) = cr2_virtual_address {
+        if demand_paging::handle_page_fault(cr2_virtual_address, error_code) {
+            return;
+        }
+    }
+
+    panic!(
+        "Exception: Page Fault\n\
+        Page fault {}\n\
+        Error code: {error_code:?}\n\
+        Faulting address (CR2): {}\n\
+        Faulting instruction: 0x{:X}\n\
+        {interrupt_stack_frame:#?}",
+        PageFaultErrorDescription::from(error_code),
+        FaultingAddress(cr2_virtual_address),
+        interrupt_stack_frame.instruction_pointer.as_u64(),
+    );
+}
+
+/// Decoded page fault error code
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PageFaultErrorDescription {
+    /// Protection violation on present page, otherwise page is not present
+    pub present: bool,
+    pub write: bool,
+    /// Access from user mode (CPL 3)
+    pub user: bool,
+    /// Reserved bit set in page table entry
+    pub reserved_write: bool,
+    pub instruction_fetch: bool,
+    pub protection_key: bool,
+    pub shadow_stack: bool,
+}
+
+impl From<PageFaultErrorCode> for PageFaultErrorDescription {
+    fn from(error_code: PageFaultErrorCode) -> Self {
+        Self {
+            present: error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+            write: error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+            user: error_code.contains(PageFaultErrorCode::USER_MODE),
+            reserved_write: error_code.contains(PageFaultErrorCode::MALFORMED_TABLE),
+            instruction_fetch: error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+            protection_key: error_code.contains(PageFaultErrorCode::PROTECTION_KEY),
+            shadow_stack: error_code.contains(PageFaultErrorCode::SHADOW_STACK),
+        }
+    }
+}
+
+/// Decodes page fault error code
+pub fn decode_page_fault_error_code(error_code: u64) -> PageFaultErrorDescription {
+    PageFaultErrorCode::from_bits_truncate(error_code).into()
+}
+
+/// "caused by a write to a non-present page in kernel mode", followed by set diagnostic bits
+impl core::fmt::Display for PageFaultErrorDescription {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let access = if self.instruction_fetch {
+            "an instruction fetch from"
+        } else if self.write {
+            "a write to"
+        } else {
+            "a read from"
+        };
+        let page = if self.present {
+            "present"
+        } else {
+            "non-present"
+        };
+        let mode = if self.user { "user" } else { "kernel" };
+        write!(f, "caused by {access} a {page} page in {mode} mode")?;
+        if self.reserved_write {
+            f.write_str(", reserved bit set in page table")?;
+        }
+        if self.protection_key {
+            f.write_str(", protection key violation")?;
+        }
+        if self.shadow_stack {
+            f.write_str(", shadow stack access")?;
+        }
+        Ok(())
+    }
+}
+
+/// CR2 value with its virtual memory region, or raw value if it's not a valid virtual address
+struct FaultingAddress(Result<VirtAddr, u64>);
+
+impl core::fmt::Display for FaultingAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Ok(virt_addr) => write!(
+                f,
+                "0x{:X} ({:?})",
+                virt_addr.as_u64(),
+                virtual_memory_manager::virtual_memory_region(virt_addr.as_u64())
+            ),
+            Err(cr2_value) => write!(f, "0x{cr2_value:X} (invalid, not canonical)"),
         }
     }
 }
@@ -0,0 +1,84 @@
+//! Per-vector interrupt handlers latency measurement
+//!
+//! Compiled only with `interrupt-latency` feature.
+//! TSC is read at handler entry and exit in the general interrupt handler.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Sum of cycles spent in handlers of each vector
+static TOTAL_CYCLES: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Number of handled interrupts of each vector
+static COUNT: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Minimum cycles spent in handler of each vector
+static MIN_CYCLES: [AtomicU64; 256] = [const { AtomicU64::new(u64::MAX) }; 256];
+
+/// Maximum cycles spent in handler of each vector
+static MAX_CYCLES: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Latency statistics of one vector (in TSC cycles)
+#[derive(Debug, Copy, Clone)]
+pub struct InterruptLatencyStats {
+    pub vector: u8,
+    pub count: u64,
+    pub min_cycles: u64,
+    pub max_cycles: u64,
+    pub total_cycles: u64,
+}
+
+impl InterruptLatencyStats {
+    /// Mean cycles per interrupt
+    pub fn mean_cycles(&self) -> u64 {
+        self.total_cycles.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+/// Reads TSC
+#[inline]
+pub fn read_cycles() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Records handler latency, must be called at handler exit
+#[inline]
+pub fn record(vector: u8, start_cycles: u64) {
+    let cycles = read_cycles().saturating_sub(start_cycles);
+    let vector = vector as usize;
+    TOTAL_CYCLES[vector].fetch_add(cycles, Ordering::Relaxed);
+    COUNT[vector].fetch_add(1, Ordering::Relaxed);
+    MIN_CYCLES[vector].fetch_min(cycles, Ordering::Relaxed);
+    MAX_CYCLES[vector].fetch_max(cycles, Ordering::Relaxed);
+}
+
+/// Returns statistics of every vector that was handled at least once
+pub fn interrupt_latency_stats() -> impl Iterator<Item = InterruptLatencyStats> {
+    (0..=u8::MAX).filter_map(|vector| {
+        let index = vector as usize;
+        let count = COUNT[index].load(Ordering::Relaxed);
+        if count == 0 {
+            return None;
+        }
+        Some(InterruptLatencyStats {
+            vector,
+            count,
+            min_cycles: MIN_CYCLES[index].load(Ordering::Relaxed),
+            max_cycles: MAX_CYCLES[index].load(Ordering::Relaxed),
+            total_cycles: TOTAL_CYCLES[index].load(Ordering::Relaxed),
+        })
+    })
+}
+
+/// Logs min/max/mean latency of every handled vector
+pub fn log_interrupt_latency_stats() {
+    for stats in interrupt_latency_stats() {
+        log::info!(
+            "Vector {}: count {}, min {}, max {}, mean {} cycles",
+            stats.vector,
+            stats.count,
+            stats.min_cycles,
+            stats.max_cycles,
+            stats.mean_cycles()
+        );
+    }
+}
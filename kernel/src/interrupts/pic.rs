@@ -1,5 +1,19 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// First vector of master PIC, IRQ 0-7
+///
+/// PIC is remapped above the vectors used by the kernel, so its spurious IRQs don't collide with them
+pub const MASTER_PIC_VECTORS_OFFSET: u8 = 0xE0;
+/// First vector of slave PIC, IRQ 8-15
+pub const SLAVE_PIC_VECTORS_OFFSET: u8 = MASTER_PIC_VECTORS_OFFSET + 8;
+/// Master PIC spurious IRQ 7
+pub const MASTER_PIC_SPURIOUS_IDT_VECTOR: u8 = MASTER_PIC_VECTORS_OFFSET + 7;
+/// Slave PIC spurious IRQ 15
+pub const SLAVE_PIC_SPURIOUS_IDT_VECTOR: u8 = SLAVE_PIC_VECTORS_OFFSET + 7;
+
 /// Master and slave Programmable Interrupt Controllers
-pub static mut PICS: pic8259::ChainedPics = unsafe { pic8259::ChainedPics::new(32, 32 + 8) };
+pub static mut PICS: pic8259::ChainedPics =
+    unsafe { pic8259::ChainedPics::new(MASTER_PIC_VECTORS_OFFSET, SLAVE_PIC_VECTORS_OFFSET) };
 
 /// Inits PIC and disable PIC interrupts
 ///
@@ -8,9 +22,90 @@ pub fn init_and_disable() {
     x86_64::instructions::interrupts::disable();
     #[allow(static_mut_refs)]
     unsafe {
-        // Mask all interrupts
+        // Remap away from CPU exceptions vectors first, so an IRQ latched while masking isn't taken as exception
+        PICS.initialize();
+        // Mask all interrupts, only spurious IRQ 7 and IRQ 15 can come after that
         PICS.disable();
-        // Init (all lines masked)
-        //PICS.initialize();
     };
 }
+
+/// Number of spurious PIC interrupts
+static SPURIOUS_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns number of spurious PIC interrupts
+pub fn spurious_count() -> u64 {
+    SPURIOUS_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Master PIC command port
+const MASTER_COMMAND_PORT: u16 = 0x20;
+/// Slave PIC command port
+const SLAVE_COMMAND_PORT: u16 = 0xA0;
+/// OCW3: next command port read returns In-Service Register
+const OCW3_READ_ISR: u8 = 0x0B;
+/// Non-specific End Of Interrupt
+const EOI: u8 = 0x20;
+
+/// Reads In-Service Registers of master and slave PIC
+pub fn read_in_service_registers() -> [u8; 2] {
+    let mut master_command_port = x86_64::instructions::port::Port::<u8>::new(MASTER_COMMAND_PORT);
+    let mut slave_command_port = x86_64::instructions::port::Port::<u8>::new(SLAVE_COMMAND_PORT);
+    unsafe {
+        master_command_port.write(OCW3_READ_ISR);
+        slave_command_port.write(OCW3_READ_ISR);
+        [master_command_port.read(), slave_command_port.read()]
+    }
+}
+
+/// Handles IRQ 7 or IRQ 15 vector of PIC
+///
+/// Spurious IRQ isn't set in In-Service Register and must not be acknowledged, otherwise EOI
+/// would complete another in-service IRQ. Spurious IRQ 15 still went through the cascade, so master PIC gets EOI.
+/// If the IRQ turns out to be in service, it's a real one and it's acknowledged.
+pub fn handle_spurious(vector: u8) {
+    debug_assert!(
+        vector == MASTER_PIC_SPURIOUS_IDT_VECTOR || vector == SLAVE_PIC_SPURIOUS_IDT_VECTOR,
+        "Not a PIC spurious vector"
+    );
+    let mut master_command_port = x86_64::instructions::port::Port::<u8>::new(MASTER_COMMAND_PORT);
+    let mut slave_command_port = x86_64::instructions::port::Port::<u8>::new(SLAVE_COMMAND_PORT);
+    let [master_isr, slave_isr] = read_in_service_registers();
+    let is_slave = vector == SLAVE_PIC_SPURIOUS_IDT_VECTOR;
+    let in_service = if is_slave {
+        slave_isr & (1 << 7) != 0
+    } else {
+        master_isr & (1 << 7) != 0
+    };
+    if !in_service {
+        SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    }
+    unsafe {
+        if is_slave && in_service {
+            slave_command_port.write(EOI);
+        }
+        if is_slave || in_service {
+            master_command_port.write(EOI);
+        }
+    }
+}
+
+/// Sends EOI for every in-service interrupt, so PIC doesn't hold anything in flight
+///
+/// PIC interrupts must be masked
+pub fn drain() {
+    let mut master_command_port = x86_64::instructions::port::Port::<u8>::new(MASTER_COMMAND_PORT);
+    let mut slave_command_port = x86_64::instructions::port::Port::<u8>::new(SLAVE_COMMAND_PORT);
+    // At most 8 nested interrupts on each PIC
+    for _ in 0..8 {
+        let [master_isr, slave_isr] = read_in_service_registers();
+        if master_isr == 0 && slave_isr == 0 {
+            return;
+        }
+        unsafe {
+            if slave_isr != 0 {
+                slave_command_port.write(EOI);
+            }
+            master_command_port.write(EOI);
+        }
+    }
+}
@@ -4,15 +4,35 @@
 #![no_main]
 #![allow(unused, dead_code)]
 
+extern crate alloc;
+
 use bootloader_api::config::Mapping;
+use memory_management::virtual_memory_manager;
 
 mod acpi;
+mod arch;
+mod backtrace;
+mod bitmap;
+mod cmdline;
 mod com_ports;
+mod cpu;
+mod drivers;
 mod gdt;
 mod interrupts;
 mod memory_management;
+mod percpu;
+mod ramdisk;
+#[cfg(feature = "selftest")]
+mod selftest;
 mod serial_debug;
+mod shutdown;
+mod sync;
 mod timers;
+mod util;
+
+/// Incremented by every started application processor
+static STARTED_APS_COUNTER: core::sync::atomic::AtomicUsize =
+    core::sync::atomic::AtomicUsize::new(0);
 
 static BOOTLOADER_CONFIG: bootloader_api::BootloaderConfig = {
     let mut config = bootloader_api::BootloaderConfig::new_default();
@@ -21,10 +41,13 @@ static BOOTLOADER_CONFIG: bootloader_api::BootloaderConfig = {
     // Configure mappings created by bootloader
     let mut mappings = bootloader_api::config::Mappings::new_default();
     // doc/virtual_memory_layout.txt
-    mappings.dynamic_range_start = Some(0xFFFF_9000_0000_0000);
-    mappings.dynamic_range_end = Some(0xFFFF_9FFF_FFFF_F000);
+    // Checked at runtime by virtual_memory_manager::check_boot_layout
+    mappings.dynamic_range_start = Some(virtual_memory_manager::DYNAMIC_RANGE_START);
+    mappings.dynamic_range_end = Some(virtual_memory_manager::DYNAMIC_RANGE_END & !0xFFF);
     // Complete physical memory mapping with offset
-    mappings.physical_memory = Some(Mapping::FixedAddress(0xFFFF_A000_0000_0000));
+    mappings.physical_memory = Some(Mapping::FixedAddress(
+        virtual_memory_manager::PHYSICAL_MEMORY_MAPPING_OFFSET,
+    ));
 
     config.mappings = mappings;
 
@@ -37,41 +60,163 @@ bootloader_api::entry_point!(kmain, config = &BOOTLOADER_CONFIG);
 fn kmain(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     // Init COM ports and logger
     com_ports::init();
-    serial_debug::serial_logger::init();
+    let log_level = serial_debug::serial_logger::level_from_cmdline();
+    serial_debug::serial_logger::init(
+        log_level.unwrap_or(serial_debug::serial_logger::DEFAULT_LEVEL),
+    );
 
     // Kernel start
     log::info!("--- KERNEL START ---");
+    log::info!("Command line: {:?}", cmdline::get());
+    if let Err(err) = log_level {
+        log::warn!("{err}, default log level is used");
+    }
+    #[cfg(feature = "selftest")]
+    selftest::logging();
+
+    // CPU features
+    cpu::init();
+    log::debug!("{:#?}", cpu::features());
+    assert!(cpu::features().apic, "CPU features don't report APIC");
 
     // Init GDT
     log::info!("GDT initialization");
     gdt::init();
 
-    // Fill IDT
+    // Enable FSGSBASE instructions
+    arch::init();
+
+    // Fill IDT, see interrupts.rs for interrupts init sequence
     interrupts::idt::init();
+    #[cfg(feature = "selftest")]
+    selftest::cpu();
 
     // Init memory manager
     log::info!("Memory Manager initialization");
     memory_management::init(boot_info);
+    log::info!(
+        "{} MB used for allocator metadata",
+        memory_management::physical_memory_manager::metadata_overhead_bytes() / (1024 * 1024)
+    );
+
+    // W^X for kernel mappings, before APs are started
+    let nx_enabled = virtual_memory_manager::enable_nxe();
+    virtual_memory_manager::harden_kernel_mappings(boot_info);
+
+    // Ramdisk, before boot info is released
+    ramdisk::init(boot_info);
+    #[cfg(feature = "selftest")]
+    selftest::memory(nx_enabled);
 
     // Get ACPI tables
     log::info!("Getting ACPI tables");
     acpi::init(boot_info);
 
-    // Init IO APIC, Bootstrap Processor Local APIC
+    // Disable PIC, init Bootstrap Processor Local APIC and IO APIC
     // But it doesn't enable interrupts
-    log::info!("APIC interrupts initialization and enabling");
-    interrupts::init();
+    log::info!("APIC interrupts initialization");
+    {
+        let _log_level_guard =
+            serial_debug::serial_logger::LogLevelGuard::new(log::LevelFilter::Trace);
+        interrupts::init();
+    }
+
+    // PS/2 keyboard
+    drivers::keyboard::init();
+
+    // Per-CPU block of the BSP
+    percpu::init();
+    assert_eq!(
+        percpu::this_cpu().apic_id,
+        interrupts::apic::local_apic_id() as u32,
+        "Per-CPU APIC ID differs from Local APIC ID register"
+    );
+    assert_eq!(
+        percpu::this_cpu().cpu_index,
+        0,
+        "BSP per-CPU index is not 0"
+    );
+    #[cfg(feature = "selftest")]
+    selftest::interrupts();
 
     // Init timers
     log::info!("Timers initialization");
     timers::init();
+    #[cfg(feature = "selftest")]
+    selftest::timers();
+
+    // RTC
+    drivers::rtc::init();
+
+    // PCI
+    drivers::pci::init();
+    #[cfg(feature = "selftest")]
+    selftest::devices();
+
+    // Start application processors, every AP increments the counter
+    log::info!("Application processors startup");
+    // Every AP must have its own TSS (Double Fault IST stack) and the BSP's PAT
+    static MISCONFIGURED_APS_COUNTER: core::sync::atomic::AtomicUsize =
+        core::sync::atomic::AtomicUsize::new(0);
+    match interrupts::apic::smp::start_application_processors(&boot_info.memory_regions, || {
+        if !gdt::tss_loaded() || !virtual_memory_manager::pat_programmed() {
+            MISCONFIGURED_APS_COUNTER.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        }
+        STARTED_APS_COUNTER.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }) {
+        Ok(started_aps) => log::info!(
+            "{started_aps} APs started, {} reached ap_main",
+            STARTED_APS_COUNTER.load(core::sync::atomic::Ordering::Relaxed)
+        ),
+        Err(err) => log::error!("Failed to start APs: {err:?}"),
+    }
+    assert_eq!(
+        MISCONFIGURED_APS_COUNTER.load(core::sync::atomic::Ordering::Relaxed),
+        0,
+        "AP has no TSS or its PAT differs from the BSP's"
+    );
+    #[cfg(feature = "selftest")]
+    selftest::smp();
+
+    // Boot info is not used after this point, release bootloader memory
+    let free_memory_before = memory_management::physical_memory_manager::total_free();
+    memory_management::physical_memory_manager::reclaim_bootloader_memory(boot_info);
+    log::info!(
+        "Free physical memory: {} KB before bootloader memory reclamation, {} KB after",
+        free_memory_before / 1024,
+        memory_management::physical_memory_manager::total_free() / 1024
+    );
 
     x86_64::instructions::interrupts::disable();
+    #[cfg(debug_assertions)]
+    log::info!(
+        "Max interrupt nesting depth: {}",
+        interrupts::idt::max_observed_nesting_depth()
+    );
+    log::info!(
+        "Emergency memory pool high-water mark: {}/{} pages",
+        memory_management::emergency_pool::emergency_pool_high_water_mark(),
+        memory_management::emergency_pool::emergency_pool_capacity()
+    );
+    memory_management::slab_allocator::log_slab_stats();
+    interrupts::apic::log_in_flight_interrupts();
+    // Spurious interrupts are legal on real hardware, they are only reported
+    let spurious_interrupts = interrupts::apic::spurious_count();
+    let unexpected_interrupts = interrupts::idt::unexpected_interrupt_count();
+    let level = if spurious_interrupts == 0 && unexpected_interrupts == 0 {
+        log::Level::Info
+    } else {
+        log::Level::Warn
+    };
+    log::log!(
+        level,
+        "Spurious interrupts: {spurious_interrupts}, unexpected interrupts: {unexpected_interrupts}"
+    );
+    #[cfg(feature = "interrupt-latency")]
+    interrupts::latency::log_interrupt_latency_stats();
     // Kernel finish
     log::info!("--- KERNEL FINISH ---");
-    loop {
-        x86_64::instructions::hlt();
-    }
+    shutdown::shutdown(shutdown::ShutdownReason::KernelFinished);
 }
 
 #[panic_handler]
@@ -79,7 +224,6 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     x86_64::instructions::interrupts::disable();
     serial_println_lock_free!("PANIC!!!");
     serial_println_lock_free!("{info}");
-    loop {
-        x86_64::instructions::hlt();
-    }
+    backtrace::print();
+    shutdown::shutdown(shutdown::ShutdownReason::Fatal);
 }
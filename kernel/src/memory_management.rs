@@ -1,3 +1,5 @@
+pub mod demand_paging;
+pub mod emergency_pool;
 pub mod general_purpose_allocator;
 pub mod physical_memory_manager;
 pub mod slab_allocator;
@@ -8,9 +10,15 @@ pub const PAGE_SIZE: usize = 4096;
 
 /// Inits Physical Memory Manager and Virtual Memory Manager
 pub fn init(boot_info: &bootloader_api::BootInfo) {
+    virtual_memory_manager::check_boot_layout(boot_info);
+    virtual_memory_manager::extend_cpmm_to_physical_memory_top(&boot_info.memory_regions);
+
     log::info!("Physical Memory Manager initialization");
     physical_memory_manager::init(boot_info);
 
+    log::info!("Emergency memory pool initialization");
+    emergency_pool::init();
+
     log::info!("Virtual Memory Manager initialization");
     virtual_memory_manager::init();
 
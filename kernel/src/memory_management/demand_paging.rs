@@ -0,0 +1,114 @@
+use super::virtual_memory_manager::{self, VmError};
+use super::PAGE_SIZE;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use tinyvec::ArrayVec;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// Virtual range which is left unmapped and populated on first access by the page fault handler
+#[derive(Debug, Copy, Clone)]
+struct DemandPagedRange {
+    /// First page
+    start: VirtAddr,
+    /// Size in bytes, page-aligned
+    size: usize,
+    /// Flags of mapped pages
+    page_table_flags: PageTableFlags,
+}
+
+impl Default for DemandPagedRange {
+    fn default() -> Self {
+        Self {
+            start: VirtAddr::zero(),
+            size: 0,
+            page_table_flags: PageTableFlags::empty(),
+        }
+    }
+}
+
+impl DemandPagedRange {
+    #[inline]
+    fn contains(&self, virt_addr: VirtAddr) -> bool {
+        virt_addr >= self.start && virt_addr - self.start < self.size as u64
+    }
+
+    #[inline]
+    fn overlaps(&self, other: &DemandPagedRange) -> bool {
+        self.start < other.start + other.size as u64 && other.start < self.start + self.size as u64
+    }
+}
+
+lazy_static! {
+    /// Registered demand-paged ranges
+    static ref DEMAND_PAGED_RANGES: Mutex<ArrayVec<[DemandPagedRange; 32]>> = {
+        Mutex::new(ArrayVec::new())
+    };
+}
+
+/// Registers virtual range as demand-paged
+///
+/// The range must be unmapped, its pages will be mapped with page_table_flags on first access.
+pub fn register_demand_paged_range(
+    start: VirtAddr,
+    size: usize,
+    page_table_flags: PageTableFlags,
+) -> Result<(), VmError> {
+    if !start.is_aligned(PAGE_SIZE as u64) || size == 0 || size % PAGE_SIZE != 0 {
+        return Err(VmError::Misaligned);
+    }
+    let new_range = DemandPagedRange {
+        start,
+        size,
+        page_table_flags,
+    };
+
+    let mut demand_paged_ranges_lock = DEMAND_PAGED_RANGES.lock();
+    if demand_paged_ranges_lock
+        .iter()
+        .any(|range| range.overlaps(&new_range))
+    {
+        return Err(VmError::AlreadyMapped);
+    }
+    demand_paged_ranges_lock
+        .try_push(new_range)
+        .map_or(Ok(()), |_| Err(VmError::OutOfVirtualSpace))
+}
+
+/// Tries to resolve page fault by mapping a frame in a demand-paged range
+///
+/// Returns true if a frame was mapped and the faulting instruction can be retried,
+/// false if the fault is not a demand paging fault (real bug).
+///
+/// Demand-paged memory must not be touched while a memory zone lock is held, frame allocation would deadlock.
+pub fn handle_page_fault(fault_addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // Only not-present faults
+    if error_code
+        .intersects(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::MALFORMED_TABLE)
+    {
+        return false;
+    }
+    // Lock free, fault may happen while the ranges are registered
+    let Some(demand_paged_ranges_lock) = DEMAND_PAGED_RANGES.try_lock() else {
+        return false;
+    };
+    let Some(range) = demand_paged_ranges_lock
+        .iter()
+        .find(|range| range.contains(fault_addr))
+        .copied()
+    else {
+        return false;
+    };
+    drop(demand_paged_ranges_lock);
+    if error_code.contains(PageFaultErrorCode::USER_MODE)
+        && !range
+            .page_table_flags
+            .contains(PageTableFlags::USER_ACCESSIBLE)
+    {
+        return false;
+    }
+
+    let page_virt_addr = fault_addr.align_down(PAGE_SIZE as u64);
+    virtual_memory_manager::map_new_page(page_virt_addr, range.page_table_flags).is_ok()
+}
@@ -0,0 +1,143 @@
+//! Small preallocated memory pool for contexts which can't block or fail on allocation
+//!
+//! Interrupt handlers, OOM path, panic path, etc. use it when the main allocator can't serve them
+//! (e.g. [try_alloc_nonblocking](super::physical_memory_manager::try_alloc_nonblocking) failed).
+//!
+//! The pool is filled once at init and is lock-free: each page is a bit in a free mask.
+use super::physical_memory_manager::{MemoryZoneEnum, MemoryZonesAndPrioritySpecifier};
+use super::PAGE_SIZE;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use spin::Once;
+use x86_64::VirtAddr;
+
+/// Number of pages in pool
+///
+/// Must be power of two (allocated as one block from buddy allocator) and fit in [EMERGENCY_POOL_FREE_MASK]
+const EMERGENCY_POOL_PAGES: usize = 16;
+
+/// Pool size, 64 KB
+const EMERGENCY_POOL_SIZE: usize = EMERGENCY_POOL_PAGES * PAGE_SIZE;
+
+/// Zones priority of emergency pool
+///
+/// Same as for general-purpose allocators, ISA DMA is reserved, see [alloc_isa_dma](super::physical_memory_manager::alloc_isa_dma)
+const EMERGENCY_POOL_ZONES_PRIORITY: &MemoryZonesAndPrioritySpecifier =
+    &[MemoryZoneEnum::High, MemoryZoneEnum::Dma32];
+
+/// Virtual address (in CPMM) of the first page of the pool
+static EMERGENCY_POOL_BASE: Once<VirtAddr> = Once::new();
+
+/// Bit N is set if page N of the pool is free
+static EMERGENCY_POOL_FREE_MASK: AtomicU32 = AtomicU32::new(0);
+
+/// Number of pages currently allocated from the pool
+static EMERGENCY_POOL_USED_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Maximum number of pages ever allocated from the pool at the same time
+static EMERGENCY_POOL_HIGH_WATER_MARK: AtomicUsize = AtomicUsize::new(0);
+
+/// Fills emergency pool
+///
+/// Must be called after Physical Memory Manager initialization
+pub fn init() {
+    const {
+        assert!(EMERGENCY_POOL_PAGES.is_power_of_two());
+        assert!(EMERGENCY_POOL_PAGES <= u32::BITS as usize);
+    }
+
+    EMERGENCY_POOL_BASE.call_once(|| {
+        let phys_addr = unsafe {
+            super::physical_memory_manager::alloc(
+                EMERGENCY_POOL_ZONES_PRIORITY,
+                EMERGENCY_POOL_SIZE,
+            )
+        };
+        assert!(
+            !phys_addr.is_null(),
+            "Failed to allocate memory for emergency pool"
+        );
+        super::virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(phys_addr)
+    });
+    let all_free_mask = if EMERGENCY_POOL_PAGES == u32::BITS as usize {
+        u32::MAX
+    } else {
+        (1 << EMERGENCY_POOL_PAGES) - 1
+    };
+    EMERGENCY_POOL_FREE_MASK.store(all_free_mask, Ordering::Release);
+}
+
+/// Allocs one page from emergency pool
+///
+/// size must be not more than one page
+///
+/// Never blocks, can be called from interrupt handler
+///
+/// Returns null ptr if pool is exhausted or size is too big
+pub fn emergency_alloc(size: usize) -> *mut u8 {
+    debug_assert_ne!(size, 0, "Trying to alloc zero size from emergency pool");
+    if size > PAGE_SIZE {
+        return null_mut();
+    }
+    let Some(base) = EMERGENCY_POOL_BASE.get() else {
+        return null_mut();
+    };
+
+    // Take lowest free page
+    let Ok(free_mask) =
+        EMERGENCY_POOL_FREE_MASK.fetch_update(Ordering::Acquire, Ordering::Relaxed, |mask| {
+            (mask != 0).then(|| mask & (mask - 1))
+        })
+    else {
+        return null_mut();
+    };
+    let page_index = free_mask.trailing_zeros() as usize;
+
+    let used_pages = EMERGENCY_POOL_USED_PAGES.fetch_add(1, Ordering::Relaxed) + 1;
+    EMERGENCY_POOL_HIGH_WATER_MARK.fetch_max(used_pages, Ordering::Relaxed);
+
+    (*base + (page_index * PAGE_SIZE) as u64).as_mut_ptr()
+}
+
+/// Frees page to emergency pool
+///
+/// Never blocks, can be called from interrupt handler
+///
+/// # Safety
+/// ptr must be previously allocated by [emergency_alloc]
+pub unsafe fn emergency_free(ptr: *mut u8) {
+    debug_assert!(!ptr.is_null(), "Trying to free null ptr to emergency pool");
+    let base = EMERGENCY_POOL_BASE.get().expect("Emergency pool not set");
+    let offset = VirtAddr::from_ptr(ptr) - *base;
+    debug_assert!(
+        (offset as usize) < EMERGENCY_POOL_SIZE && offset as usize % PAGE_SIZE == 0,
+        "Trying to free ptr not from emergency pool"
+    );
+    let page_bit = 1 << (offset as usize / PAGE_SIZE);
+
+    EMERGENCY_POOL_USED_PAGES.fetch_sub(1, Ordering::Relaxed);
+    let previous_mask = EMERGENCY_POOL_FREE_MASK.fetch_or(page_bit, Ordering::Release);
+    debug_assert_eq!(previous_mask & page_bit, 0, "Double free to emergency pool");
+}
+
+/// Returns maximum number of pages ever allocated from emergency pool at the same time
+///
+/// Used to size the pool
+pub fn emergency_pool_high_water_mark() -> usize {
+    EMERGENCY_POOL_HIGH_WATER_MARK.load(Ordering::Relaxed)
+}
+
+/// Lowers high-water mark to the number of pages allocated now
+///
+/// For checks which exhaust the pool on purpose, so they don't hide the real usage
+pub fn reset_emergency_pool_high_water_mark() {
+    EMERGENCY_POOL_HIGH_WATER_MARK.store(
+        EMERGENCY_POOL_USED_PAGES.load(Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+}
+
+/// Returns number of pages in emergency pool
+pub fn emergency_pool_capacity() -> usize {
+    EMERGENCY_POOL_PAGES
+}
@@ -1,4 +1,6 @@
-use crate::memory_management::physical_memory_manager::MemoryZoneEnum;
+use crate::memory_management::physical_memory_manager::{
+    MemoryZoneEnum, MemoryZonesAndPrioritySpecifier,
+};
 use crate::memory_management::PAGE_SIZE;
 use core::alloc::{AllocError, Layout};
 use core::ptr::{null_mut, NonNull};
@@ -7,6 +9,12 @@ use x86_64::{PhysAddr, VirtAddr};
 
 static DLMALLOC_ALLOCATOR: Once<Mutex<dlmalloc::Dlmalloc<DlmallocSystemAllocator>>> = Once::new();
 
+/// Zones priority of dlmalloc's "system" allocator
+///
+/// General-purpose memory prefers HIGH, then DMA32, ISA DMA is reserved, see [alloc_isa_dma](super::physical_memory_manager::alloc_isa_dma)
+const DLMALLOC_ZONES_PRIORITY: &MemoryZonesAndPrioritySpecifier =
+    &[MemoryZoneEnum::High, MemoryZoneEnum::Dma32];
+
 /// Inits general purpose allocator (dlmalloc)
 pub fn init() {
     DLMALLOC_ALLOCATOR.call_once(|| {
@@ -16,6 +24,14 @@ pub fn init() {
     });
 }
 
+/// Rounds size up to buddy allocator block size (power of two number of pages)
+///
+/// None on overflow
+#[inline]
+fn buddy_block_size(size: usize) -> Option<usize> {
+    size.max(PAGE_SIZE).checked_next_power_of_two()
+}
+
 /// "System" allocator required for dlmalloc allocator
 ///
 /// Wrapper over buddy allocator
@@ -23,22 +39,13 @@ struct DlmallocSystemAllocator;
 
 unsafe impl dlmalloc::Allocator for DlmallocSystemAllocator {
     fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
-        if !(size >= PAGE_SIZE && size.is_power_of_two()) {
-            unimplemented!(
-                "dlmalloc tries to allocate a memory size not suitable for buddy allocator: {size}"
-            );
-        }
-
-        let phys_addr = unsafe {
-            super::physical_memory_manager::alloc(
-                &[
-                    MemoryZoneEnum::High,
-                    MemoryZoneEnum::Dma32,
-                    MemoryZoneEnum::IsaDma,
-                ],
-                size,
-            )
+        // dlmalloc is told the rounded size, so it uses (and later frees) the whole block
+        let Some(size) = buddy_block_size(size) else {
+            return (null_mut(), 0, 0);
         };
+
+        let phys_addr =
+            unsafe { super::physical_memory_manager::alloc(DLMALLOC_ZONES_PRIORITY, size) };
         if phys_addr.is_null() {
             return (null_mut(), 0, 0);
         }
@@ -51,9 +58,9 @@ unsafe impl dlmalloc::Allocator for DlmallocSystemAllocator {
         if !(oldsize >= PAGE_SIZE && oldsize.is_power_of_two()) {
             unimplemented!("dlmalloc tries to remap a memory with oldsize not suitable for buddy allocator: {oldsize}");
         }
-        if !(newsize >= PAGE_SIZE && newsize.is_power_of_two()) {
-            unimplemented!("dlmalloc tries to remap a memory with newsize not suitable for buddy allocator: {newsize}");
-        }
+        let Some(newsize) = buddy_block_size(newsize) else {
+            return null_mut();
+        };
 
         if can_move {
             let virt_addr = VirtAddr::from_ptr(ptr);
@@ -81,7 +88,9 @@ unsafe impl dlmalloc::Allocator for DlmallocSystemAllocator {
 
     fn free(&self, ptr: *mut u8, size: usize) -> bool {
         debug_assert!(!ptr.is_null(), "dlmalloc tries to free null ptr");
-        if !(size >= PAGE_SIZE && size.is_power_of_two()) {
+        // Buddy allocator knows the block size, but dlmalloc must free whole blocks
+        // (size may be less than block size after remap, it is not rounded for dlmalloc)
+        if size < PAGE_SIZE || size % PAGE_SIZE != 0 {
             unimplemented!("dlmalloc tries to free a memory with size not suitable for buddy allocator: {size}");
         }
 
@@ -160,4 +169,141 @@ unsafe impl core::alloc::Allocator for GeneralPurposeAllocator {
                 .free(ptr.as_ptr(), layout.size(), layout.align());
         }
     }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+        unsafe { self.reallocate(ptr, old_layout, new_layout) }
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+        let new_ptr = unsafe { self.reallocate(ptr, old_layout, new_layout)? };
+        unsafe {
+            new_ptr
+                .cast::<u8>()
+                .add(old_layout.size())
+                .write_bytes(0, new_layout.size() - old_layout.size());
+        }
+        Ok(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() <= old_layout.size());
+        unsafe { self.reallocate(ptr, old_layout, new_layout) }
+    }
+}
+
+impl GeneralPurposeAllocator {
+    /// Resizes allocation with dlmalloc realloc, it resizes in place when it can
+    ///
+    /// Alignment change and zero sizes (not allocated by dlmalloc) go through allocate, copy and deallocate
+    unsafe fn reallocate(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        use core::alloc::Allocator;
+        if old_layout.align() != new_layout.align()
+            || old_layout.size() == 0
+            || new_layout.size() == 0
+        {
+            let new_ptr = self.allocate(new_layout)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    ptr.as_ptr(),
+                    new_ptr.cast::<u8>().as_ptr(),
+                    old_layout.size().min(new_layout.size()),
+                );
+                self.deallocate(ptr, old_layout);
+            }
+            return Ok(new_ptr);
+        }
+        let new_ptr = unsafe {
+            DLMALLOC_ALLOCATOR
+                .get()
+                .expect("dlmalloc allocator not set")
+                .lock()
+                .realloc(
+                    ptr.as_ptr(),
+                    old_layout.size(),
+                    old_layout.align(),
+                    new_layout.size(),
+                )
+        };
+        let new_ptr = NonNull::new(new_ptr).ok_or(AllocError)?;
+        debug_assert!(
+            new_ptr.as_ptr() as usize % new_layout.align() == 0,
+            "dlmalloc reallocs unaligned ptr"
+        );
+        Ok(NonNull::slice_from_raw_parts(new_ptr, new_layout.size()))
+    }
+}
+
+/// Global allocator, makes alloc crate (Box, Vec, String) work in kernel code and in libraries (e.g. AML interpreter)
+#[global_allocator]
+static KERNEL_GLOBAL_ALLOCATOR: KernelGlobalAllocator = KernelGlobalAllocator;
+
+/// GlobalAlloc over dlmalloc, same as [GeneralPurposeAllocator]
+pub struct KernelGlobalAllocator;
+
+unsafe impl core::alloc::GlobalAlloc for KernelGlobalAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() == 0 {
+            // Aligned dangling pointer, same as GeneralPurposeAllocator
+            return layout.align() as *mut u8;
+        }
+        let allocated_ptr = DLMALLOC_ALLOCATOR
+            .get()
+            .expect("dlmalloc allocator not set")
+            .lock()
+            .malloc(layout.size(), layout.align());
+        debug_assert!(allocated_ptr.is_aligned(), "dlmalloc allocs unaligned ptr");
+        allocated_ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        debug_assert!(!ptr.is_null(), "Trying to free null ptr");
+        if layout.size() == 0 {
+            return;
+        }
+        DLMALLOC_ALLOCATOR
+            .get()
+            .expect("dlmalloc allocator not set")
+            .lock()
+            .free(ptr, layout.size(), layout.align());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if layout.size() == 0 || new_size == 0 {
+            // Zero size is not allocated by dlmalloc, go through alloc and dealloc
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            let new_ptr = self.alloc(new_layout);
+            if !new_ptr.is_null() {
+                core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            return new_ptr;
+        }
+        DLMALLOC_ALLOCATOR
+            .get()
+            .expect("dlmalloc allocator not set")
+            .lock()
+            .realloc(ptr, layout.size(), layout.align(), new_size)
+    }
 }
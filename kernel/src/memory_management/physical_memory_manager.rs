@@ -1,11 +1,16 @@
-use super::{virtual_memory_manager, PAGE_SIZE};
+use super::virtual_memory_manager::{self, PageTablesWalkItem};
+use super::PAGE_SIZE;
+use alloc::vec;
+use alloc::vec::Vec;
 use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
 use buddy_alloc::BuddyAlloc;
-use core::mem::MaybeUninit;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use slab_allocator_lib::SlabInfo;
-use spin::{Mutex, Once};
+use spin::{Mutex, MutexGuard, Once};
 use tinyvec::ArrayVec;
 use x86_64::PhysAddr;
 
@@ -15,24 +20,191 @@ use x86_64::PhysAddr;
 ///
 /// DMA32 ZONE (16 MB - 4 GB)
 ///
-/// HIGH DMA ZONE (4 GB - 1 TB)
+/// HIGH DMA ZONE (4 GB - 16 TB)
 // TODO: Implement Debug trait
 struct MemoryZone {
     // Buddy allocator
     pub allocator: BuddyAlloc,
+    /// Recently freed single pages (LIFO), physical addresses
+    ///
+    /// Single pages are allocated and freed often (e.g. slabs), the cache saves buddy allocator splits and merges.
+    /// Buddy allocator gives whole minimum blocks, so every cached entry is a minimum block
+    /// and any allocation not bigger than [MemoryZone::min_block_size] is served from it.
+    pub free_pages_cache: ArrayVec<[u64; FREE_PAGES_CACHE_SIZE]>,
+    /// Minimum block size of buddy allocator, see [ISA_DMA_MIN_BLOCK_SIZE]
+    pub min_block_size: usize,
+    /// First page of memory managed by buddy allocator, only memory in the arena can be released to it
+    pub arena_first_page: PhysAddr,
+    /// Size of memory managed by buddy allocator
+    pub arena_size: usize,
     // Statistics
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Max number of pages in zone's free pages cache
+const FREE_PAGES_CACHE_SIZE: usize = 64;
+
+impl MemoryZone {
+    /// Allocs memory, single blocks are taken from free pages cache first
+    ///
+    /// # Safety
+    /// May return null ptr
+    unsafe fn malloc(&mut self, requested_size: usize) -> *mut u8 {
+        if requested_size <= self.min_block_size {
+            if let Some(page_addr) = self.free_pages_cache.pop() {
+                return page_addr as *mut u8;
+            }
+        }
+        self.allocator.malloc(requested_size)
+    }
+
+    /// Frees memory, single blocks are put in free pages cache
+    ///
+    /// block_pages is the number of allocated pages (from allocation bitmap), 0 if unknown.
+    /// If cache is full, half of it is flushed to buddy allocator
+    ///
+    /// # Safety
+    /// Freed memory must be previously allocated memory
+    unsafe fn free(&mut self, freed_ptr: *mut u8, block_pages: usize) {
+        if block_pages == 0 || block_pages * PAGE_SIZE > self.min_block_size {
+            self.allocator.free(freed_ptr);
+            return;
+        }
+        if self.free_pages_cache.len() == FREE_PAGES_CACHE_SIZE {
+            // Flush oldest pages
+            for page_addr in self.free_pages_cache.drain(..FREE_PAGES_CACHE_SIZE / 2) {
+                self.allocator.free(page_addr as *mut u8);
+            }
+        }
+        self.free_pages_cache.push(freed_ptr as u64);
+    }
+
+    /// Returns size of memory in free pages cache
+    fn cached_size(&self) -> usize {
+        self.free_pages_cache.len() * self.min_block_size
+    }
+
+    /// Returns free memory in bytes, including free pages cache
+    fn free_size(&mut self) -> usize {
+        unsafe { self.allocator.arena_free_size() + self.cached_size() }
+    }
+
+    /// Returns size of the largest block that can be allocated, see [largest_free_block]
+    fn largest_free_block(&mut self) -> usize {
+        let arena_free_size = unsafe { self.allocator.arena_free_size() };
+        let mut block_size = if arena_free_size == 0 {
+            0
+        } else {
+            1 << arena_free_size.ilog2()
+        };
+        while block_size >= PAGE_SIZE {
+            let block_ptr = unsafe { self.allocator.malloc(block_size) };
+            if !block_ptr.is_null() {
+                unsafe { self.allocator.free(block_ptr) };
+                return block_size;
+            }
+            block_size /= 2;
+        }
+        // Minimum blocks of free pages cache are still allocatable
+        if self.free_pages_cache.is_empty() {
+            0
+        } else {
+            self.min_block_size
+        }
+    }
+}
+
+/// Zone over caller's memory, it's not one of the physical memory zones
+///
+/// Has the same allocator and out of memory diagnostics as the real zones,
+/// so they can be checked by exhausting a small zone without touching real memory.
+pub struct StandaloneZone(MemoryZone);
+
+impl StandaloneZone {
+    /// Returns allocator metadata size, None if arena size or minimum block size is invalid
+    pub fn metadata_size(arena_size: usize, min_block_size: usize) -> Option<usize> {
+        BuddyAlloc::sizeof_alignment(arena_size, min_block_size)
+    }
+
+    /// Creates zone with all arena memory free
+    ///
+    /// # Safety
+    /// Metadata ([StandaloneZone::metadata_size] bytes) and arena must be writable and used only by the zone while it lives,
+    /// arena must be aligned to min_block_size
+    pub unsafe fn new(
+        metadata: *mut u8,
+        arena: *mut u8,
+        arena_size: usize,
+        min_block_size: usize,
+    ) -> Option<Self> {
+        Some(Self(MemoryZone {
+            allocator: BuddyAlloc::init_alignment(metadata, arena, arena_size, min_block_size)?,
+            free_pages_cache: ArrayVec::new(),
+            min_block_size,
+            // Arena is not physical memory, bootloader memory is never released to it
+            arena_first_page: PhysAddr::zero(),
+            arena_size: 0,
+        }))
+    }
+
+    /// Allocs memory like [alloc], failure is diagnosed the same way
+    ///
+    /// Returns null ptr if the zone is exhausted
+    pub fn alloc(&mut self, requested_size: usize) -> *mut u8 {
+        let allocated_ptr = unsafe { self.0.malloc(requested_size) };
+        #[cfg(feature = "oom-diagnostics")]
+        if allocated_ptr.is_null() {
+            log_zones_state(
+                "standalone zone",
+                requested_size,
+                &[(
+                    "Standalone",
+                    Some((self.0.free_size(), self.0.largest_free_block())),
+                )],
+            );
+        }
+        allocated_ptr
+    }
+
+    /// Frees memory allocated by [StandaloneZone::alloc]
+    ///
+    /// # Safety
+    /// Memory must be allocated by this zone, block_pages is the number of allocated pages
+    pub unsafe fn free(&mut self, freed_ptr: *mut u8, block_pages: usize) {
+        self.0.free(freed_ptr, block_pages);
+    }
+
+    /// Returns free memory in bytes, including free pages cache
+    pub fn free_size(&mut self) -> usize {
+        self.0.free_size()
+    }
+
+    /// Returns size of the largest block that can be allocated
+    pub fn largest_free_block(&mut self) -> usize {
+        self.0.largest_free_block()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MemoryZoneEnum {
     /// (1-16 MB)
     IsaDma,
     /// (16 MB - 4 GB)
     Dma32,
-    /// (4 GB - 1 TB)
+    /// (4 GB - 16 TB)
     High,
 }
 
+impl MemoryZoneEnum {
+    /// Zone name for logs
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::IsaDma => "ISA DMA",
+            Self::Dma32 => "DMA32",
+            Self::High => "HIGH",
+        }
+    }
+}
+
 /// Specifies from which zones memory can be allocated and the priority in which it should be allocated
 ///
 /// Example:<br>
@@ -41,7 +213,7 @@ pub enum MemoryZoneEnum {
 /// or<br>
 /// [Dma32, High]:
 /// Attempts to allocate memory first from Dma32, then from HIGH, but not trying to allocate memory from ISA DMA<br>
-type MemoryZonesAndPrioritySpecifier = [MemoryZoneEnum];
+pub type MemoryZonesAndPrioritySpecifier = [MemoryZoneEnum];
 
 // ISA DMA
 
@@ -98,24 +270,178 @@ const DMA32_MAX_SIZE: usize = (DMA32_MAX_LAST_PAGE_ADDR.as_u64() as usize + PAGE
 
 // HIGH
 
-/// HIGH zone: 4 GB - 1 TB
+/// HIGH zone: 4 GB - 16 TB
 ///
 /// First usable page: 0x1_0000_0000
 ///
-/// Last usable page: 0xFF_FFFF_F000
+/// Last usable page: 0xFFF_FFFF_F000
+///
+/// Upper bound is the size of Complete Physical Memory Mapping window, memory above it can't be accessed
+/// and is dropped with a warning (architectural 52-bit maximum would need a bigger window).
+///
+/// Allocator metadata is taken from DMA32 like for other zones. Metadata of terabytes of HIGH memory may not fit
+/// in DMA32 (~2.5 GB for 16 TB), then it's taken from the last HIGH usable region which is big enough.
 static HIGH_ZONE: Once<Mutex<MemoryZone>> = Once::new();
 
 /// Address of the first page of the HIGH memory (first page of 5th GB)
 const HIGH_ZONE_MIN_FIRST_PAGE_ADDR: PhysAddr = PhysAddr::new(0x1_0000_0000);
 
-/// Address of the last page of the HIGH memory (last page of 1st TB)
-const HIGH_ZONE_MAX_LAST_PAGE_ADDR: PhysAddr = PhysAddr::new(0xFF_FFFF_F000);
+/// Address of the last page of the HIGH memory (last page of 16th TB, last page of CPMM window)
+const HIGH_ZONE_MAX_LAST_PAGE_ADDR: PhysAddr = PhysAddr::new(
+    virtual_memory_manager::PHYSICAL_MEMORY_MAPPING_END
+        - virtual_memory_manager::PHYSICAL_MEMORY_MAPPING_OFFSET
+        + 1
+        - PAGE_SIZE as u64,
+);
 
 /// HIGH memory size
 #[allow(unused)]
 const HIGH_ZONE_MAX_SIZE: usize = (HIGH_ZONE_MAX_LAST_PAGE_ADDR.as_u64() as usize + PAGE_SIZE
     - HIGH_ZONE_MIN_FIRST_PAGE_ADDR.as_u64() as usize);
 
+// Minimum block sizes
+//
+// Buddy allocator metadata size is proportional to the number of minimum blocks in the zone,
+// so doubling minimum block size halves metadata (DMA32 with 4 KB blocks needs ~513 KB, with 64 KB ~33 KB).
+// The cost of larger blocks:
+// - every allocation is rounded up to the minimum block size (internal fragmentation)
+// - usable regions are trimmed to block boundaries, memory at unaligned region edges is lost
+// Blocks smaller than PAGE_SIZE need more metadata, physical memory manager API is still page granular.
+//
+// Must be a power of two, metadata must fit in the zone's metadata buffer (checked at init)
+
+/// Minimum buddy allocator block size of ISA DMA zone
+const ISA_DMA_MIN_BLOCK_SIZE: usize = PAGE_SIZE;
+
+/// Minimum buddy allocator block size of DMA32 zone
+const DMA32_MIN_BLOCK_SIZE: usize = PAGE_SIZE;
+
+/// Minimum buddy allocator block size of HIGH zone
+const HIGH_MIN_BLOCK_SIZE: usize = PAGE_SIZE;
+
+const _: () = assert!(
+    ISA_DMA_MIN_BLOCK_SIZE.is_power_of_two()
+        && DMA32_MIN_BLOCK_SIZE.is_power_of_two()
+        && HIGH_MIN_BLOCK_SIZE.is_power_of_two(),
+    "Minimum block size must be a power of two"
+);
+
+/// Returns part of usable region aligned to minimum block size, None if no whole block fits
+///
+/// Buddy allocator manages whole blocks, so only aligned part of region can be released to it
+fn block_aligned_region(
+    usable_region: &UsableRegion,
+    min_block_size: usize,
+) -> Option<(PhysAddr, usize)> {
+    let start = usable_region.first_page.align_up(min_block_size as u64);
+    let end = (usable_region.last_page + PAGE_SIZE as u64).align_down(min_block_size as u64);
+    (start < end).then(|| (start, (end - start) as usize))
+}
+
+// Metadata overhead
+
+/// Size of the SlabInfo pointers array, reserved in usable memory
+static SLAB_INFO_PTRS_ARRAY_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Size of the HIGH allocator metadata, reserved in DMA32 memory
+static HIGH_ALLOCATOR_METADATA_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Size of the allocation bitmap, reserved in usable memory
+static ALLOCATION_BITMAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+// Allocation tracking
+
+/// Tracks which pages are allocated by buddy allocators
+///
+/// Buddy allocator can't tell whether a page is allocated, so allocations are tracked separately, one bit per page.
+static ALLOCATION_BITMAP: Once<AllocationBitmap> = Once::new();
+
+/// Two bitmaps for every page from first usable page to last usable page
+///
+/// Atomics are used, so zones can update the bitmap without common lock
+struct AllocationBitmap {
+    /// First page tracked by bitmap
+    first_page: PhysAddr,
+    /// Number of tracked pages
+    pages: usize,
+    /// Bit is set if page is allocated
+    allocated: &'static [AtomicU64],
+    /// Bit is set if page is the first page of an allocated block
+    ///
+    /// Free doesn't know the block size, the block ends at the next head or at the first not allocated page
+    block_heads: &'static [AtomicU64],
+}
+
+impl AllocationBitmap {
+    /// Returns page index if address is tracked
+    #[inline]
+    fn page_index(&self, phys_addr: PhysAddr) -> Option<usize> {
+        if phys_addr < self.first_page {
+            return None;
+        }
+        let page_index = (phys_addr - self.first_page) as usize / PAGE_SIZE;
+        (page_index < self.pages).then_some(page_index)
+    }
+
+    #[inline]
+    fn test(bitmap: &[AtomicU64], index: usize) -> bool {
+        bitmap[index / 64].load(Ordering::Relaxed) & (1 << (index % 64)) != 0
+    }
+
+    #[inline]
+    fn set(bitmap: &[AtomicU64], index: usize) {
+        bitmap[index / 64].fetch_or(1 << (index % 64), Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn clear(bitmap: &[AtomicU64], index: usize) {
+        bitmap[index / 64].fetch_and(!(1 << (index % 64)), Ordering::Relaxed);
+    }
+
+    /// Marks block as allocated
+    fn mark_allocated(&self, phys_addr: PhysAddr, size: usize) {
+        let Some(first_page_index) = self.page_index(phys_addr) else {
+            return;
+        };
+        let last_page_index = (first_page_index + size.div_ceil(PAGE_SIZE)).min(self.pages);
+        Self::set(self.block_heads, first_page_index);
+        for page_index in first_page_index..last_page_index {
+            Self::set(self.allocated, page_index);
+        }
+    }
+
+    /// Marks block as free
+    ///
+    /// Returns number of pages in block
+    fn mark_free(&self, phys_addr: PhysAddr) -> usize {
+        let Some(first_page_index) = self.page_index(phys_addr) else {
+            return 0;
+        };
+        debug_assert!(
+            Self::test(self.block_heads, first_page_index),
+            "Freed address is not the start of an allocated block, double free?"
+        );
+        Self::clear(self.block_heads, first_page_index);
+        Self::clear(self.allocated, first_page_index);
+        let mut block_pages = 1;
+        for page_index in first_page_index + 1..self.pages {
+            if !Self::test(self.allocated, page_index) || Self::test(self.block_heads, page_index) {
+                break;
+            }
+            Self::clear(self.allocated, page_index);
+            block_pages += 1;
+        }
+        block_pages
+    }
+
+    /// Whether page is allocated
+    #[inline]
+    fn is_allocated(&self, phys_addr: PhysAddr) -> bool {
+        self.page_index(phys_addr)
+            .is_some_and(|page_index| Self::test(self.allocated, page_index))
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 /// Can be used by memory allocators
 struct UsableRegion {
@@ -145,6 +471,9 @@ impl Default for UsableRegion {
     }
 }
 
+/// List of usable regions
+type UsableRegionsList = Mutex<ArrayVec<[UsableRegion; 128]>>;
+
 lazy_static! {
     /// Memory regions that's may be used by allocators, sorted
     static ref USABLE_REGIONS: Mutex<ArrayVec<[UsableRegion; 128]>> = {
@@ -177,60 +506,14 @@ lazy_static! {
 pub fn init(boot_info: &bootloader_api::BootInfo) {
     collect_usable_regions(&boot_info.memory_regions);
     init_slab_info_ptrs_array();
+    init_allocation_bitmap();
     init_allocators();
 
     // Check lists
-    assert!(USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.first_page));
-    assert!(USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.last_page));
-    assert!(USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.size() >= PAGE_SIZE));
-
-    assert!(ISA_DMA_USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.first_page));
-    assert!(ISA_DMA_USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.last_page));
-    assert!(ISA_DMA_USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.size() >= PAGE_SIZE));
-
-    assert!(DMA32_USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.first_page));
-    assert!(DMA32_USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.last_page));
-    assert!(DMA32_USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.size() >= PAGE_SIZE));
-
-    assert!(HIGH_USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.first_page));
-    assert!(HIGH_USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.last_page));
-    assert!(HIGH_USABLE_REGIONS
-        .lock()
-        .iter()
-        .is_sorted_by_key(|v| v.size() >= PAGE_SIZE));
+    assert_regions_sorted_or_dump("USABLE_REGIONS", &USABLE_REGIONS);
+    assert_regions_sorted_or_dump("ISA_DMA_USABLE_REGIONS", &ISA_DMA_USABLE_REGIONS);
+    assert_regions_sorted_or_dump("DMA32_USABLE_REGIONS", &DMA32_USABLE_REGIONS);
+    assert_regions_sorted_or_dump("HIGH_USABLE_REGIONS", &HIGH_USABLE_REGIONS);
 
     assert_eq!(
         USABLE_REGIONS.lock().len(),
@@ -293,35 +576,10 @@ pub fn init(boot_info: &bootloader_api::BootInfo) {
 fn collect_usable_regions(memory_regions: &[MemoryRegion]) {
     // Collect all usable regions
     let mut usable_regions_lock = USABLE_REGIONS.lock();
-    for usable_region in memory_regions
-        .iter()
-        .filter(|usable_region| usable_region.kind == MemoryRegionKind::Usable)
-    {
-        if usable_region.start < ISA_DMA_ZONE_MIN_FIRST_PAGE_ADDR.as_u64()
-            || usable_region.end > HIGH_ZONE_MAX_LAST_PAGE_ADDR.as_u64()
-        {
-            continue;
+    for memory_region in memory_regions {
+        if let Some(usable_region) = usable_region_from_memory_region(memory_region) {
+            usable_regions_lock.push(usable_region);
         }
-
-        let mut first_page = PhysAddr::new(usable_region.start);
-        first_page = first_page.align_up(PAGE_SIZE as u64);
-        let mut last_page = PhysAddr::new(usable_region.end);
-        last_page -= PAGE_SIZE as u64;
-        last_page = last_page.align_down(PAGE_SIZE as u64);
-
-        if last_page <= first_page {
-            continue;
-        }
-        if last_page - first_page < PAGE_SIZE as u64 {
-            continue;
-        }
-        assert!(first_page.is_aligned(PAGE_SIZE as u64));
-        assert!(last_page.is_aligned(PAGE_SIZE as u64));
-
-        usable_regions_lock.push(UsableRegion {
-            first_page,
-            last_page,
-        })
     }
     // Sort
     usable_regions_lock
@@ -407,6 +665,151 @@ fn collect_usable_regions(memory_regions: &[MemoryRegion]) {
     }
 }
 
+/// Checks that regions list is sorted, otherwise dumps all regions lists and zones state and panics
+///
+/// Init-time invariant check, regions_list must not be locked by caller
+#[track_caller]
+fn assert_regions_sorted_or_dump(
+    regions_list_name: &str,
+    regions_list: &Mutex<ArrayVec<[UsableRegion; 128]>>,
+) {
+    let is_sorted = {
+        let regions_list_lock = regions_list.lock();
+        regions_list_lock.is_sorted_by_key(|v| v.first_page)
+            && regions_list_lock.is_sorted_by_key(|v| v.last_page)
+            && regions_list_lock.is_sorted_by_key(|v| v.size() >= PAGE_SIZE)
+    };
+    if !is_sorted {
+        log::error!(
+            "{regions_list_name} sort broken, looks like bug (probably the memory map is not quite right)"
+        );
+        dump_regions_and_zones();
+        panic!("{regions_list_name} sort broken");
+    }
+}
+
+/// Logs all usable regions lists and zones state
+///
+/// Lists and zones locked by someone else are skipped
+fn dump_regions_and_zones() {
+    let regions_lists: [(&str, &Mutex<ArrayVec<[UsableRegion; 128]>>); 4] = [
+        ("USABLE_REGIONS", &USABLE_REGIONS),
+        ("ISA_DMA_USABLE_REGIONS", &ISA_DMA_USABLE_REGIONS),
+        ("DMA32_USABLE_REGIONS", &DMA32_USABLE_REGIONS),
+        ("HIGH_USABLE_REGIONS", &HIGH_USABLE_REGIONS),
+    ];
+    for (regions_list_name, regions_list) in regions_lists {
+        let Some(regions_list_lock) = regions_list.try_lock() else {
+            log::error!("{regions_list_name}: locked");
+            continue;
+        };
+        log::error!("{regions_list_name}: {} regions", regions_list_lock.len());
+        for (i, region) in regions_list_lock.iter().enumerate() {
+            log::error!(
+                "  [{i}] {:#X} - {:#X} ({} KB)",
+                region.first_page.as_u64(),
+                region.last_page.as_u64() + PAGE_SIZE as u64 - 1,
+                region.size() / 1024
+            );
+        }
+    }
+
+    let zones: [(&str, &Once<Mutex<MemoryZone>>); 3] = [
+        ("ISA DMA", &ISA_DMA_ZONE),
+        ("DMA32", &DMA32_ZONE),
+        ("HIGH", &HIGH_ZONE),
+    ];
+    for (zone_name, zone) in zones {
+        match zone.get().map(Mutex::try_lock) {
+            None => log::error!("{zone_name} zone: not inited"),
+            Some(None) => log::error!("{zone_name} zone: locked"),
+            Some(Some(mut zone_lock)) => log::error!(
+                "{zone_name} zone: {} KB free, {} cached blocks, {} bytes minimum block",
+                unsafe { zone_lock.allocator.arena_free_size() } / 1024,
+                zone_lock.free_pages_cache.len(),
+                zone_lock.min_block_size
+            ),
+        }
+    }
+}
+
+/// Returns page-aligned part of usable memory map region which can be used by zones
+///
+/// Memory below ISA DMA zone and above HIGH zone is skipped.
+fn usable_region_from_memory_region(memory_region: &MemoryRegion) -> Option<UsableRegion> {
+    if memory_region.kind != MemoryRegionKind::Usable {
+        return None;
+    }
+    if memory_region.start < ISA_DMA_ZONE_MIN_FIRST_PAGE_ADDR.as_u64()
+        || memory_region.start > HIGH_ZONE_MAX_LAST_PAGE_ADDR.as_u64()
+    {
+        return None;
+    }
+    // Part above the HIGH zone is lost
+    let zones_end = HIGH_ZONE_MAX_LAST_PAGE_ADDR.as_u64() + PAGE_SIZE as u64;
+    if memory_region.end > zones_end {
+        log::warn!(
+            "{} MB of usable memory above 0x{zones_end:X} can't be used",
+            (memory_region.end - zones_end) / (1024 * 1024)
+        );
+    }
+
+    let mut first_page = PhysAddr::new(memory_region.start);
+    first_page = first_page.align_up(PAGE_SIZE as u64);
+    let mut last_page = PhysAddr::new(memory_region.end.min(zones_end));
+    last_page -= PAGE_SIZE as u64;
+    last_page = last_page.align_down(PAGE_SIZE as u64);
+
+    if last_page <= first_page {
+        return None;
+    }
+    if last_page - first_page < PAGE_SIZE as u64 {
+        return None;
+    }
+    assert!(first_page.is_aligned(PAGE_SIZE as u64));
+    assert!(last_page.is_aligned(PAGE_SIZE as u64));
+    Some(UsableRegion {
+        first_page,
+        last_page,
+    })
+}
+
+/// Splits memory map region into zones like [init] does
+///
+/// Returns zones with first and last usable pages of the region in them, used to check zones bounds on fake memory maps
+pub fn split_memory_region_by_zones(
+    memory_region: &MemoryRegion,
+) -> Vec<(MemoryZoneEnum, PhysAddr, PhysAddr)> {
+    let mut zone_regions = Vec::new();
+    let Some(usable_region) = usable_region_from_memory_region(memory_region) else {
+        return zone_regions;
+    };
+    for (zone, limit_first_page, limit_last_page) in [
+        (
+            MemoryZoneEnum::IsaDma,
+            ISA_DMA_ZONE_MIN_FIRST_PAGE_ADDR,
+            ISA_DMA_ZONE_MAX_LAST_PAGE_ADDR,
+        ),
+        (
+            MemoryZoneEnum::Dma32,
+            DMA32_MIN_FIRST_PAGE_ADDR,
+            DMA32_MAX_LAST_PAGE_ADDR,
+        ),
+        (
+            MemoryZoneEnum::High,
+            HIGH_ZONE_MIN_FIRST_PAGE_ADDR,
+            HIGH_ZONE_MAX_LAST_PAGE_ADDR,
+        ),
+    ] {
+        if let Some(zone_region) =
+            adjust_usable_region(&usable_region, limit_first_page, limit_last_page)
+        {
+            zone_regions.push((zone, zone_region.first_page, zone_region.last_page));
+        }
+    }
+    zone_regions
+}
+
 /// If usable_region can be entered in min and max, then the entered, reduced, region will be returned, otherwise None.
 fn adjust_usable_region(
     usable_region: &UsableRegion,
@@ -431,23 +834,15 @@ fn adjust_usable_region(
     Some(new_usable_region)
 }
 
-/// Inits array of SlabInfo pointers
-fn init_slab_info_ptrs_array() {
-    // Calculate required memory size for store SlabInfo's
-    // SlabInfo per page from first usable to last usable
-    let first_usable_page_addr = USABLE_REGIONS.lock().first().unwrap().first_page.as_u64();
-    let last_usable_page_addr = USABLE_REGIONS.lock().last().unwrap().last_page.as_u64();
-    let number_of_slab_infos =
-        (last_usable_page_addr + PAGE_SIZE as u64 - first_usable_page_addr) as usize / PAGE_SIZE;
-
-    let mut required_memory_size = number_of_slab_infos * size_of::<*mut SlabInfo>();
-    required_memory_size = x86_64::align_up(required_memory_size as u64, PAGE_SIZE as u64) as usize;
+/// Reserves memory in the last usable region which is big enough, before allocators initialization
+///
+/// Reserved memory is removed from usable regions, so allocators never see it
+///
+/// Returns zero address if there is no suitable region
+fn reserve_memory_in_usable_regions(required_memory_size: usize) -> PhysAddr {
     assert_eq!(required_memory_size % PAGE_SIZE, 0);
-
-    // Reserve required memory in usable region
-    // Physical address of the array
     let mut required_memory_phys_addr: PhysAddr = PhysAddr::zero();
-    assert!(USABLE_REGIONS.lock().is_sorted_by_key(|v| { v.first_page }));
+    assert_regions_sorted_or_dump("USABLE_REGIONS", &USABLE_REGIONS);
     for usable_region in USABLE_REGIONS.lock().iter_mut().rev() {
         if usable_region.size() >= required_memory_size + PAGE_SIZE {
             // Use this region
@@ -482,15 +877,32 @@ fn init_slab_info_ptrs_array() {
             break;
         }
     }
+    required_memory_phys_addr
+}
+
+/// Inits array of SlabInfo pointers
+fn init_slab_info_ptrs_array() {
+    // Calculate required memory size for store SlabInfo's
+    // SlabInfo per page from first usable to last usable
+    let first_usable_page_addr = USABLE_REGIONS.lock().first().unwrap().first_page.as_u64();
+    let last_usable_page_addr = USABLE_REGIONS.lock().last().unwrap().last_page.as_u64();
+    let number_of_slab_infos =
+        (last_usable_page_addr + PAGE_SIZE as u64 - first_usable_page_addr) as usize / PAGE_SIZE;
+
+    let mut required_memory_size = number_of_slab_infos * size_of::<*mut SlabInfo>();
+    required_memory_size = x86_64::align_up(required_memory_size as u64, PAGE_SIZE as u64) as usize;
+    assert_eq!(required_memory_size % PAGE_SIZE, 0);
+
+    // Reserve required memory in usable region
+    // Physical address of the array
+    let required_memory_phys_addr = reserve_memory_in_usable_regions(required_memory_size);
     assert!(
         !required_memory_phys_addr.is_null(),
         "Failed to find memory for SlabInfo pointers array"
     );
+    SLAB_INFO_PTRS_ARRAY_SIZE.store(required_memory_size, Ordering::Relaxed);
     assert!(required_memory_phys_addr.is_aligned(align_of::<SlabInfo>() as u64));
-    assert!(
-        USABLE_REGIONS.lock().is_sorted_by_key(|v| { v.first_page }),
-        "Usable regions sort broken, looks like bug (probably the memory map is not quite right)"
-    );
+    assert_regions_sorted_or_dump("USABLE_REGIONS", &USABLE_REGIONS);
 
     // Memory reserved, make slice
     // Convert to virtual address
@@ -512,6 +924,75 @@ fn init_slab_info_ptrs_array() {
     }
 }
 
+/// Inits allocation bitmap
+fn init_allocation_bitmap() {
+    // Bit per page from first usable to last usable
+    // Must be calculated before the reservation, which moves first usable page
+    let first_page = USABLE_REGIONS.lock().first().unwrap().first_page;
+    let last_page = USABLE_REGIONS.lock().last().unwrap().last_page;
+    let pages = (last_page + PAGE_SIZE as u64 - first_page) as usize / PAGE_SIZE;
+    let words = pages.div_ceil(u64::BITS as usize);
+
+    // allocated + block_heads
+    let mut required_memory_size = 2 * words * size_of::<AtomicU64>();
+    required_memory_size = x86_64::align_up(required_memory_size as u64, PAGE_SIZE as u64) as usize;
+
+    let required_memory_phys_addr = reserve_memory_in_usable_regions(required_memory_size);
+    assert!(
+        !required_memory_phys_addr.is_null(),
+        "Failed to find memory for allocation bitmap"
+    );
+    ALLOCATION_BITMAP_SIZE.store(required_memory_size, Ordering::Relaxed);
+
+    let required_memory_virt_addr =
+        virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(required_memory_phys_addr);
+    // Unlike SlabInfo pointers, bitmap must be zeroed (2 bits per page, 2 MB for 32 GB)
+    let bitmaps: &'static [AtomicU64] = unsafe {
+        core::ptr::write_bytes(
+            required_memory_virt_addr.as_mut_ptr::<AtomicU64>(),
+            0,
+            2 * words,
+        );
+        core::slice::from_raw_parts(required_memory_virt_addr.as_ptr(), 2 * words)
+    };
+    let (allocated, block_heads) = bitmaps.split_at(words);
+
+    ALLOCATION_BITMAP.call_once(|| AllocationBitmap {
+        first_page,
+        pages,
+        allocated,
+        block_heads,
+    });
+}
+
+/// Marks block as allocated in allocation bitmap
+#[inline]
+fn track_allocation(phys_addr: PhysAddr, size: usize) {
+    if let Some(allocation_bitmap) = ALLOCATION_BITMAP.get() {
+        allocation_bitmap.mark_allocated(phys_addr, size);
+    }
+}
+
+/// Marks block as free in allocation bitmap
+///
+/// Returns number of pages in block, 0 if unknown
+#[inline]
+fn track_free(phys_addr: PhysAddr) -> usize {
+    ALLOCATION_BITMAP.get().map_or(0, |allocation_bitmap| {
+        allocation_bitmap.mark_free(phys_addr)
+    })
+}
+
+/// Whether page is currently allocated by buddy allocators
+///
+/// O(1), returns false for addresses outside any zone
+pub fn is_allocated(phys_addr: PhysAddr) -> bool {
+    let phys_addr = phys_addr.align_down(PAGE_SIZE as u64);
+    ALLOCATION_BITMAP
+        .get()
+        .is_some_and(|allocation_bitmap| allocation_bitmap.is_allocated(phys_addr))
+}
+
 /// Inits zone allocators
 fn init_allocators() {
     // Init allocators
@@ -531,23 +1012,36 @@ fn init_allocators() {
             // 1
             let first_page = isa_dma_usable_regions_lock.first().unwrap().first_page;
             let last_page = isa_dma_usable_regions_lock.last().unwrap().last_page;
-            let range_size = (last_page + PAGE_SIZE as u64 - first_page) as usize;
+            let first_page = first_page.align_down(ISA_DMA_MIN_BLOCK_SIZE as u64);
+            let range_size = x86_64::align_up(
+                last_page + PAGE_SIZE as u64 - first_page,
+                ISA_DMA_MIN_BLOCK_SIZE as u64,
+            ) as usize;
 
             // 2
-            let metadata_size = BuddyAlloc::sizeof_alignment(range_size, PAGE_SIZE)
+            let metadata_size = BuddyAlloc::sizeof_alignment(range_size, ISA_DMA_MIN_BLOCK_SIZE)
                 .expect("Failed to calculate metadata size for ISA DMA allocator!");
-            assert!(metadata_size <= ISA_DMA_ALLOCATOR_METADATA.len());
+            assert!(
+                metadata_size <= ISA_DMA_ALLOCATOR_METADATA.len(),
+                "ISA DMA allocator metadata ({metadata_size} bytes) doesn't fit in {} bytes, minimum block size {} is too small",
+                ISA_DMA_ALLOCATOR_METADATA.len(),
+                ISA_DMA_MIN_BLOCK_SIZE
+            );
 
             // 4
             ISA_DMA_ZONE.call_once(|| {
                 Mutex::new(MemoryZone {
+                    free_pages_cache: ArrayVec::new(),
                     allocator: BuddyAlloc::init_alignment(
                         ISA_DMA_ALLOCATOR_METADATA.as_mut_ptr(),
                         first_page.as_u64() as *mut u8,
                         range_size,
-                        PAGE_SIZE,
+                        ISA_DMA_MIN_BLOCK_SIZE,
                     )
                     .expect("Failed to init ISA DMA buddy allocator!"),
+                    min_block_size: ISA_DMA_MIN_BLOCK_SIZE,
+                    arena_first_page: first_page,
+                    arena_size: range_size,
                 })
             });
 
@@ -561,19 +1055,21 @@ fn init_allocators() {
 
             // 6
             for usable_region in isa_dma_usable_regions_lock.iter() {
+                let Some((start, size)) =
+                    block_aligned_region(usable_region, ISA_DMA_MIN_BLOCK_SIZE)
+                else {
+                    continue;
+                };
                 ISA_DMA_ZONE
                     .get()
                     .unwrap()
                     .lock()
                     .allocator
-                    .unsafe_release_range(
-                        usable_region.first_page.as_u64() as *mut u8,
-                        usable_region.size(),
-                    );
+                    .unsafe_release_range(start.as_u64() as *mut u8, size);
             }
             log::info!("ISA DMA allocator inited");
         } else {
-            log::info!("ISA DMA allocator not inited. No memory.")
+            log::warn!("No ISA DMA memory available; legacy ISA DMA devices unsupported")
         }
     }
 
@@ -585,23 +1081,36 @@ fn init_allocators() {
             // 1
             let first_page = dma32_usable_regions_lock.first().unwrap().first_page;
             let last_page = dma32_usable_regions_lock.last().unwrap().last_page;
-            let range_size = (last_page + PAGE_SIZE as u64 - first_page) as usize;
+            let first_page = first_page.align_down(DMA32_MIN_BLOCK_SIZE as u64);
+            let range_size = x86_64::align_up(
+                last_page + PAGE_SIZE as u64 - first_page,
+                DMA32_MIN_BLOCK_SIZE as u64,
+            ) as usize;
 
             // 2
-            let metadata_size = BuddyAlloc::sizeof_alignment(range_size, PAGE_SIZE)
+            let metadata_size = BuddyAlloc::sizeof_alignment(range_size, DMA32_MIN_BLOCK_SIZE)
                 .expect("Failed to calculate metadata size for DMA32 allocator!");
-            assert!(metadata_size <= DMA32_ALLOCATOR_METADATA.len());
+            assert!(
+                metadata_size <= DMA32_ALLOCATOR_METADATA.len(),
+                "DMA32 allocator metadata ({metadata_size} bytes) doesn't fit in {} bytes, minimum block size {} is too small",
+                DMA32_ALLOCATOR_METADATA.len(),
+                DMA32_MIN_BLOCK_SIZE
+            );
 
             // 4
             DMA32_ZONE.call_once(|| {
                 Mutex::new(MemoryZone {
+                    free_pages_cache: ArrayVec::new(),
                     allocator: BuddyAlloc::init_alignment(
                         DMA32_ALLOCATOR_METADATA.as_mut_ptr(),
                         first_page.as_u64() as *mut u8,
                         range_size,
-                        PAGE_SIZE,
+                        DMA32_MIN_BLOCK_SIZE,
                     )
                     .expect("Failed to init DMA32 buddy allocator!"),
+                    min_block_size: DMA32_MIN_BLOCK_SIZE,
+                    arena_first_page: first_page,
+                    arena_size: range_size,
                 })
             });
 
@@ -615,15 +1124,16 @@ fn init_allocators() {
 
             // 6
             for usable_region in dma32_usable_regions_lock.iter() {
+                let Some((start, size)) = block_aligned_region(usable_region, DMA32_MIN_BLOCK_SIZE)
+                else {
+                    continue;
+                };
                 DMA32_ZONE
                     .get()
                     .unwrap()
                     .lock()
                     .allocator
-                    .unsafe_release_range(
-                        usable_region.first_page.as_u64() as *mut u8,
-                        usable_region.size(),
-                    );
+                    .unsafe_release_range(start.as_u64() as *mut u8, size);
             }
             log::info!("DMA32 allocator inited");
         } else {
@@ -633,21 +1143,25 @@ fn init_allocators() {
 
     // Init HIGH allocator
     unsafe {
-        let high_usable_regions_lock = HIGH_USABLE_REGIONS.lock();
+        let mut high_usable_regions_lock = HIGH_USABLE_REGIONS.lock();
         if high_usable_regions_lock.len() != 0 {
             // 1
             let first_page = high_usable_regions_lock.first().unwrap().first_page;
             let last_page = high_usable_regions_lock.last().unwrap().last_page;
-            let range_size = (last_page + PAGE_SIZE as u64 - first_page) as usize;
+            let first_page = first_page.align_down(HIGH_MIN_BLOCK_SIZE as u64);
+            let range_size = x86_64::align_up(
+                last_page + PAGE_SIZE as u64 - first_page,
+                HIGH_MIN_BLOCK_SIZE as u64,
+            ) as usize;
 
             // 2
-            let mut metadata_size = BuddyAlloc::sizeof_alignment(range_size, PAGE_SIZE)
+            let mut metadata_size = BuddyAlloc::sizeof_alignment(range_size, HIGH_MIN_BLOCK_SIZE)
                 .expect("Failed to calculate metadata size for HIGH allocator!");
             metadata_size = x86_64::align_up(metadata_size as u64, PAGE_SIZE as u64) as usize;
             assert_eq!(metadata_size % PAGE_SIZE, 0);
 
             // 3
-            // For 32 GB with 4KB pages ~ 5 MB
+            // For 32 GB with 4KB pages ~ 5 MB, for 16 TB ~ 2.5 GB
             // Allocate memory from DMA32
             let mut high_allocator_metadata: *mut u8 = null_mut();
             for usable_region in DMA32_USABLE_REGIONS.lock().iter_mut() {
@@ -673,12 +1187,41 @@ fn init_allocators() {
                         .lock()
                         .allocator
                         .reserve_range(high_allocator_metadata, metadata_size);
+                    track_allocation(PhysAddr::new(high_allocator_metadata as u64), metadata_size);
                     break;
                 }
             }
+            // Metadata of terabytes doesn't fit in DMA32, take it from HIGH memory itself
+            // HIGH memory is not released yet, so the region is just shrunk
+            if high_allocator_metadata.is_null() {
+                for usable_region in high_usable_regions_lock.iter_mut().rev() {
+                    if usable_region.size() >= metadata_size + PAGE_SIZE {
+                        high_allocator_metadata = usable_region.first_page.as_u64() as *mut u8;
+                        usable_region.first_page += metadata_size as u64;
+                        assert!(usable_region.size() >= PAGE_SIZE);
+
+                        // Don't forget to change data in other list
+                        for v in USABLE_REGIONS.lock().iter_mut() {
+                            if v.first_page == usable_region.first_page - metadata_size as u64 {
+                                v.first_page = usable_region.first_page;
+                                assert!(v.size() >= PAGE_SIZE);
+                                break;
+                            }
+                        }
+                        log::info!(
+                            "HIGH allocator metadata ({} MB) doesn't fit in DMA32, placed in HIGH memory",
+                            metadata_size / (1024 * 1024)
+                        );
+                        break;
+                    }
+                }
+            }
 
-            // If HIGH allocator initialization has started, it means that memory is more than 4 GB, and therefore we should definitely find memory in DMA32
-            assert!(!high_allocator_metadata.is_null(), "Failed to allocate memory for HIGH allocator's metadata! It's impossible, looks like bug!");
+            assert!(
+                !high_allocator_metadata.is_null(),
+                "Failed to allocate {metadata_size} bytes for HIGH allocator's metadata, no usable region is big enough"
+            );
+            HIGH_ALLOCATOR_METADATA_SIZE.store(metadata_size, Ordering::Relaxed);
 
             // Convert physical address to virtual
             let high_allocator_metadata = high_allocator_metadata
@@ -687,13 +1230,17 @@ fn init_allocators() {
             // 4
             HIGH_ZONE.call_once(|| {
                 Mutex::new(MemoryZone {
+                    free_pages_cache: ArrayVec::new(),
                     allocator: BuddyAlloc::init_alignment(
                         high_allocator_metadata,
                         first_page.as_u64() as *mut u8,
                         range_size,
-                        PAGE_SIZE,
+                        HIGH_MIN_BLOCK_SIZE,
                     )
                     .expect("Failed to init HIGH buddy allocator!"),
+                    min_block_size: HIGH_MIN_BLOCK_SIZE,
+                    arena_first_page: first_page,
+                    arena_size: range_size,
                 })
             });
 
@@ -707,15 +1254,16 @@ fn init_allocators() {
 
             // 6
             for usable_region in high_usable_regions_lock.iter() {
+                let Some((start, size)) = block_aligned_region(usable_region, HIGH_MIN_BLOCK_SIZE)
+                else {
+                    continue;
+                };
                 HIGH_ZONE
                     .get()
                     .unwrap()
                     .lock()
                     .allocator
-                    .unsafe_release_range(
-                        usable_region.first_page.as_u64() as *mut u8,
-                        usable_region.size(),
-                    );
+                    .unsafe_release_range(start.as_u64() as *mut u8, size);
             }
             log::info!("HIGH allocator inited");
         } else {
@@ -728,6 +1276,234 @@ fn init_allocators() {
     }
 }
 
+/// Returns how much physical memory is consumed by allocators metadata
+///
+/// SlabInfo pointers array + allocation bitmap + HIGH allocator metadata + static ISA DMA and DMA32 allocators metadata
+pub fn metadata_overhead_bytes() -> usize {
+    #[allow(static_mut_refs)]
+    let static_metadata_size =
+        unsafe { ISA_DMA_ALLOCATOR_METADATA.len() + DMA32_ALLOCATOR_METADATA.len() };
+    SLAB_INFO_PTRS_ARRAY_SIZE.load(Ordering::Relaxed)
+        + ALLOCATION_BITMAP_SIZE.load(Ordering::Relaxed)
+        + HIGH_ALLOCATOR_METADATA_SIZE.load(Ordering::Relaxed)
+        + static_metadata_size
+}
+
+/// Returns free memory of all zones in bytes, including free pages caches
+pub fn total_free() -> usize {
+    [&ISA_DMA_ZONE, &DMA32_ZONE, &HIGH_ZONE]
+        .into_iter()
+        .filter_map(Once::get)
+        .map(|zone| {
+            let mut zone_lock = lock_zone(zone);
+            let arena_free_size = unsafe { zone_lock.allocator.arena_free_size() };
+            arena_free_size + zone_lock.cached_size()
+        })
+        .sum()
+}
+
+/// Releases bootloader memory (boot info, unused parts of kernel ELF file, etc.) to zones
+///
+/// Takes boot info, so it can't be used after the call, call it when nothing references boot structures.
+/// Pages still mapped outside of Complete Physical Memory Mapping (kernel code and data, stack) and page tables are kept.
+/// Boot info pages are released, but stay mapped.
+/// Only memory inside zone arena and allocation bitmap range can be released,
+/// bootloader regions outside of them (before the first or after the last usable page of zone) stay lost.
+///
+/// Returns number of released bytes
+pub fn reclaim_bootloader_memory(boot_info: &'static mut bootloader_api::BootInfo) -> usize {
+    // Pages of bootloader regions in zones and index of the first page in in_use
+    let bootloader_regions: Vec<(UsableRegion, usize)> = boot_info
+        .memory_regions
+        .iter()
+        .filter(|memory_region| memory_region.kind == MemoryRegionKind::Bootloader)
+        .filter_map(|memory_region| {
+            let first_page = PhysAddr::new(memory_region.start)
+                .align_up(PAGE_SIZE as u64)
+                .max(ISA_DMA_ZONE_MIN_FIRST_PAGE_ADDR);
+            let end = PhysAddr::new(memory_region.end)
+                .align_down(PAGE_SIZE as u64)
+                .min(HIGH_ZONE_MAX_LAST_PAGE_ADDR + PAGE_SIZE as u64);
+            (first_page < end).then(|| UsableRegion {
+                first_page,
+                last_page: end - PAGE_SIZE as u64,
+            })
+        })
+        .scan(0, |pages, region| {
+            let offset = *pages;
+            *pages += region.size() / PAGE_SIZE;
+            Some((region, offset))
+        })
+        .collect();
+    let pages = bootloader_regions
+        .last()
+        .map_or(0, |(region, offset)| offset + region.size() / PAGE_SIZE);
+    let mut in_use = vec![false; pages];
+
+    // Boot info and memory regions array, released although mapped
+    let boot_info_virt_ranges = [
+        (
+            boot_info as *const _ as u64,
+            size_of::<bootloader_api::BootInfo>(),
+        ),
+        (
+            boot_info.memory_regions.as_ptr() as u64,
+            size_of_val(&*boot_info.memory_regions),
+        ),
+    ]
+    .map(|(start, size)| {
+        x86_64::align_down(start, PAGE_SIZE as u64)
+            ..x86_64::align_up(start + size as u64, PAGE_SIZE as u64)
+    });
+
+    let mut mark_in_use = |phys_addr: PhysAddr, size: u64| {
+        for (region, offset) in bootloader_regions.iter() {
+            let start = phys_addr.max(region.first_page);
+            let end = (phys_addr + size).min(region.last_page + PAGE_SIZE as u64);
+            if start >= end {
+                continue;
+            }
+            let first_index = offset + (start - region.first_page) as usize / PAGE_SIZE;
+            let last_index = offset + (end - region.first_page).div_ceil(PAGE_SIZE as u64) as usize;
+            in_use[first_index..last_index].fill(true);
+        }
+    };
+    virtual_memory_manager::walk_page_tables(&mut |item| match item {
+        PageTablesWalkItem::PageTable(phys_addr) => mark_in_use(phys_addr, PAGE_SIZE as u64),
+        PageTablesWalkItem::Page {
+            virt_addr,
+            phys_addr,
+            size,
+        } => {
+            if !boot_info_virt_ranges
+                .iter()
+                .any(|range| range.contains(&virt_addr.as_u64()))
+            {
+                mark_in_use(phys_addr, size);
+            }
+        }
+    });
+
+    // Release runs of unused pages
+    let mut released_size = 0;
+    for (region, offset) in bootloader_regions.iter() {
+        let region_pages = region.size() / PAGE_SIZE;
+        let mut page = 0;
+        while page < region_pages {
+            if in_use[offset + page] {
+                page += 1;
+                continue;
+            }
+            let run_first_page = page;
+            while page < region_pages && !in_use[offset + page] {
+                page += 1;
+            }
+            let run = UsableRegion {
+                first_page: region.first_page + (run_first_page * PAGE_SIZE) as u64,
+                last_page: region.first_page + ((page - 1) * PAGE_SIZE) as u64,
+            };
+            released_size += release_bootloader_run(&run);
+        }
+    }
+
+    let regions_lists: [(&str, &UsableRegionsList); 4] = [
+        ("USABLE_REGIONS", &USABLE_REGIONS),
+        ("ISA_DMA_USABLE_REGIONS", &ISA_DMA_USABLE_REGIONS),
+        ("DMA32_USABLE_REGIONS", &DMA32_USABLE_REGIONS),
+        ("HIGH_USABLE_REGIONS", &HIGH_USABLE_REGIONS),
+    ];
+    for (regions_list_name, regions_list) in regions_lists {
+        regions_list
+            .lock()
+            .as_mut_slice()
+            .sort_unstable_by_key(|v| v.first_page);
+        assert_regions_sorted_or_dump(regions_list_name, regions_list);
+    }
+    log::info!("Reclaimed {} KB of bootloader memory", released_size / 1024);
+    released_size
+}
+
+/// Releases free pages of bootloader region to zones, splitting them as [collect_usable_regions] does
+///
+/// Returns number of released bytes
+fn release_bootloader_run(run: &UsableRegion) -> usize {
+    let Some(allocation_bitmap) = ALLOCATION_BITMAP.get() else {
+        return 0;
+    };
+    let zones: [(
+        &Once<Mutex<MemoryZone>>,
+        PhysAddr,
+        PhysAddr,
+        &UsableRegionsList,
+    ); 3] = [
+        (
+            &ISA_DMA_ZONE,
+            ISA_DMA_ZONE_MIN_FIRST_PAGE_ADDR,
+            ISA_DMA_ZONE_MAX_LAST_PAGE_ADDR,
+            &ISA_DMA_USABLE_REGIONS,
+        ),
+        (
+            &DMA32_ZONE,
+            DMA32_MIN_FIRST_PAGE_ADDR,
+            DMA32_MAX_LAST_PAGE_ADDR,
+            &DMA32_USABLE_REGIONS,
+        ),
+        (
+            &HIGH_ZONE,
+            HIGH_ZONE_MIN_FIRST_PAGE_ADDR,
+            HIGH_ZONE_MAX_LAST_PAGE_ADDR,
+            &HIGH_USABLE_REGIONS,
+        ),
+    ];
+    let mut released_size = 0;
+    for (zone, zone_first_page, zone_last_page, zone_regions_list) in zones {
+        let (Some(zone), Some(zone_run)) = (
+            zone.get(),
+            adjust_usable_region(run, zone_first_page, zone_last_page),
+        ) else {
+            continue;
+        };
+        let mut zone_lock = lock_zone(zone);
+        // Arena and allocation bitmap limits
+        let Some(zone_run) = adjust_usable_region(
+            &zone_run,
+            zone_lock.arena_first_page.max(allocation_bitmap.first_page),
+            (zone_lock.arena_first_page + zone_lock.arena_size as u64)
+                .min(allocation_bitmap.first_page + (allocation_bitmap.pages * PAGE_SIZE) as u64)
+                - PAGE_SIZE as u64,
+        ) else {
+            continue;
+        };
+        let Some((start, size)) = block_aligned_region(&zone_run, zone_lock.min_block_size) else {
+            continue;
+        };
+        let released_region = UsableRegion {
+            first_page: start,
+            last_page: start + (size - PAGE_SIZE) as u64,
+        };
+        let mut usable_regions_lock = USABLE_REGIONS.lock();
+        let mut zone_regions_lock = zone_regions_list.lock();
+        if usable_regions_lock.len() == usable_regions_lock.capacity()
+            || zone_regions_lock.len() == zone_regions_lock.capacity()
+        {
+            log::warn!(
+                "Usable regions lists are full, {} KB of bootloader memory are not reclaimed",
+                size / 1024
+            );
+            continue;
+        }
+        usable_regions_lock.push(released_region);
+        zone_regions_lock.push(released_region);
+        unsafe {
+            zone_lock
+                .allocator
+                .unsafe_release_range(start.as_u64() as *mut u8, size);
+        }
+        released_size += size;
+    }
+    released_size
+}
+
 /// Allocs memory from zone using buddy allocators
 ///
 /// request_size must be one or more pages
@@ -757,25 +1533,282 @@ pub unsafe fn alloc(
         // Zone exist?
         if let Some(requested_memory_zone) = requested_memory_zone.get() {
             // Try to alloc memory from zone
-            let allocated_ptr = unsafe {
-                requested_memory_zone
-                    .lock()
-                    .allocator
-                    .malloc(requested_size)
-            };
+            let allocated_ptr = unsafe { lock_zone(requested_memory_zone).malloc(requested_size) };
             if !allocated_ptr.is_null() {
                 debug_assert_eq!(
                     allocated_ptr as usize % PAGE_SIZE,
                     0,
                     "Buddy allocator allocates non aligned address"
                 );
-                return PhysAddr::new(allocated_ptr as u64);
+                let allocated_addr = PhysAddr::new(allocated_ptr as u64);
+                track_allocation(allocated_addr, requested_size);
+                return allocated_addr;
             }
         }
     }
+    #[cfg(feature = "oom-diagnostics")]
+    log_alloc_failure(memory_zones_and_priority_specifier, requested_size);
     PhysAddr::zero()
 }
 
+/// Log target of [alloc] failure diagnostics
+pub const OOM_LOG_TARGET: &str = "physical_memory_manager::oom";
+
+/// Zone name with its free memory and the largest free block, None if zone doesn't exist
+#[cfg(feature = "oom-diagnostics")]
+type ZoneState = (&'static str, Option<(usize, usize)>);
+
+/// Logs free memory and the largest free block of every requested zone
+///
+/// Zones are inspected before logging, so no zone lock is held while COM1 is locked.
+#[cfg(feature = "oom-diagnostics")]
+fn log_alloc_failure(
+    memory_zones_and_priority_specifier: &MemoryZonesAndPrioritySpecifier,
+    requested_size: usize,
+) {
+    let mut zone_states: [ZoneState; 3] = [("", None); 3];
+    for (zone_state, zone) in zone_states
+        .iter_mut()
+        .zip(memory_zones_and_priority_specifier)
+    {
+        let free_memory = zone_by_enum(*zone).get().map(|memory_zone| {
+            let mut zone_lock = lock_zone(memory_zone);
+            (zone_lock.free_size(), zone_lock.largest_free_block())
+        });
+        *zone_state = (zone.name(), free_memory);
+    }
+    let zones_number = memory_zones_and_priority_specifier
+        .len()
+        .min(zone_states.len());
+    log_zones_state("zones", requested_size, &zone_states[..zones_number]);
+}
+
+/// Logs allocation failure with zones state
+///
+/// Free memory much bigger than the largest free block means fragmentation, otherwise exhaustion.
+#[cfg(feature = "oom-diagnostics")]
+fn log_zones_state(source: &str, requested_size: usize, zone_states: &[ZoneState]) {
+    struct ZoneStates<'a>(&'a [ZoneState]);
+
+    impl core::fmt::Display for ZoneStates<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            for (zone_name, free_memory) in self.0 {
+                match free_memory {
+                    Some((free_size, largest_free_block)) => write!(
+                        f,
+                        " {zone_name}: {} KB free, largest free block {} KB;",
+                        free_size / 1024,
+                        largest_free_block / 1024
+                    )?,
+                    None => write!(f, " {zone_name}: doesn't exist;")?,
+                }
+            }
+            Ok(())
+        }
+    }
+
+    log::warn!(
+        target: OOM_LOG_TARGET,
+        "Failed to allocate {} KB from {source}:{}",
+        requested_size / 1024,
+        ZoneStates(zone_states)
+    );
+}
+
+/// Returns zone by its enum
+fn zone_by_enum(zone: MemoryZoneEnum) -> &'static Once<Mutex<MemoryZone>> {
+    match zone {
+        MemoryZoneEnum::IsaDma => &ISA_DMA_ZONE,
+        MemoryZoneEnum::Dma32 => &DMA32_ZONE,
+        MemoryZoneEnum::High => &HIGH_ZONE,
+    }
+}
+
+/// Returns free memory of zone in bytes, including free pages cache, None if zone doesn't exist
+pub fn zone_free_size(zone: MemoryZoneEnum) -> Option<usize> {
+    Some(lock_zone(zone_by_enum(zone).get()?).free_size())
+}
+
+/// Returns size of the largest block that can be allocated from zone, None if zone doesn't exist
+///
+/// Buddy allocator doesn't report it, so blocks are probed from the biggest power of two not exceeding free memory
+/// down to [PAGE_SIZE]. Largest free block much smaller than [zone_free_size] means fragmentation.
+pub fn largest_free_block(zone: MemoryZoneEnum) -> Option<usize> {
+    Some(lock_zone(zone_by_enum(zone).get()?).largest_free_block())
+}
+
+/// Maximum number of live over-allocated [alloc_aligned] blocks
+const ALIGNED_ALLOCATIONS_MAX: usize = 64;
+
+/// Aligned address and the original block of over-allocated [alloc_aligned] allocations
+///
+/// [free] looks up the original block here, only if [ALIGNED_ALLOCATIONS_COUNT] is not zero
+static ALIGNED_ALLOCATIONS: Mutex<ArrayVec<[(u64, u64); ALIGNED_ALLOCATIONS_MAX]>> = Mutex::new(
+    ArrayVec::from_array_empty([(0, 0); ALIGNED_ALLOCATIONS_MAX]),
+);
+
+/// Number of entries in [ALIGNED_ALLOCATIONS]
+static ALIGNED_ALLOCATIONS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocs memory aligned to align, which may be bigger than size (e.g. 4 KB aligned to 64 KB for a device ring)
+///
+/// size is rounded up to pages, align must be a power of two.
+/// Buddy blocks are aligned relative to the zone start only, so if a block of the size is not aligned,
+/// a block of (size + align - [PAGE_SIZE]) rounded up to a power of two is allocated and the aligned sub-range is returned.
+/// The whole block stays allocated until [free], e.g. 4 KB aligned to 2 MB takes 4 MB.
+/// At most [ALIGNED_ALLOCATIONS_MAX] over-allocated blocks may be live at the same time.
+///
+/// Returned address is freed by [free]
+///
+/// # Safety
+/// May return null address<br>
+/// Allocated memory is uninitialized
+pub unsafe fn alloc_aligned(
+    memory_zones_and_priority_specifier: &MemoryZonesAndPrioritySpecifier,
+    size: usize,
+    align: usize,
+) -> PhysAddr {
+    assert!(align.is_power_of_two(), "Alignment must be a power of two");
+    let size = size.max(PAGE_SIZE).next_multiple_of(PAGE_SIZE);
+
+    // Block of the size may be aligned already
+    let phys_addr = alloc(
+        memory_zones_and_priority_specifier,
+        size.next_power_of_two(),
+    );
+    if phys_addr.is_null() || phys_addr.is_aligned(align as u64) {
+        return phys_addr;
+    }
+    free(phys_addr);
+
+    let mut aligned_allocations = ALIGNED_ALLOCATIONS.lock();
+    if aligned_allocations.len() == ALIGNED_ALLOCATIONS_MAX {
+        log::warn!("Too many live over-allocated aligned allocations");
+        return PhysAddr::zero();
+    }
+    let block_size = (size + align - PAGE_SIZE).next_power_of_two();
+    let block_addr = alloc(memory_zones_and_priority_specifier, block_size);
+    if block_addr.is_null() {
+        return block_addr;
+    }
+    let aligned_addr = block_addr.align_up(align as u64);
+    debug_assert!(aligned_addr + size as u64 <= block_addr + block_size as u64);
+    aligned_allocations.push((aligned_addr.as_u64(), block_addr.as_u64()));
+    ALIGNED_ALLOCATIONS_COUNT.fetch_add(1, Ordering::Relaxed);
+    aligned_addr
+}
+
+/// Returns the original block of over-allocated [alloc_aligned] allocation and forgets it
+///
+/// Returns the address itself if it's not such allocation, None if [ALIGNED_ALLOCATIONS] is locked and lock is not waited
+fn take_aligned_allocation_block(phys_addr: PhysAddr, wait_lock: bool) -> Option<PhysAddr> {
+    if ALIGNED_ALLOCATIONS_COUNT.load(Ordering::Relaxed) == 0 {
+        return Some(phys_addr);
+    }
+    let mut aligned_allocations = if wait_lock {
+        ALIGNED_ALLOCATIONS.lock()
+    } else {
+        ALIGNED_ALLOCATIONS.try_lock()?
+    };
+    let Some(index) = aligned_allocations
+        .iter()
+        .position(|(aligned_addr, _)| *aligned_addr == phys_addr.as_u64())
+    else {
+        return Some(phys_addr);
+    };
+    let (_, block_addr) = aligned_allocations.swap_remove(index);
+    ALIGNED_ALLOCATIONS_COUNT.fetch_sub(1, Ordering::Relaxed);
+    Some(PhysAddr::new(block_addr))
+}
+
+/// Allocs memory from ISA DMA zone
+///
+/// ISA DMA memory is scarce (15 MB at most) and reserved for legacy devices (floppy, sound cards, etc.)
+/// which can only address first 16 MB. General-purpose allocators (slab, dlmalloc, emergency pool) never use it,
+/// their zones priority is [High, Dma32].
+///
+/// request_size must be one or more pages
+///
+/// # Safety
+/// Allocated memory is uninitialized
+pub unsafe fn alloc_isa_dma(requested_size: usize) -> Result<PhysAddr, IsaDmaAllocError> {
+    if !isa_dma_available() {
+        return Err(IsaDmaAllocError::Unavailable);
+    }
+    let phys_addr = alloc(&[MemoryZoneEnum::IsaDma], requested_size);
+    if phys_addr.is_null() {
+        return Err(IsaDmaAllocError::OutOfMemory);
+    }
+    Ok(phys_addr)
+}
+
+/// ISA DMA allocation error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IsaDmaAllocError {
+    /// No usable memory below 16 MB, ISA DMA zone doesn't exist
+    Unavailable,
+    /// ISA DMA zone exists, but has no free block of requested size
+    OutOfMemory,
+}
+
+impl core::fmt::Display for IsaDmaAllocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let description = match self {
+            IsaDmaAllocError::Unavailable => "ISA DMA unavailable",
+            IsaDmaAllocError::OutOfMemory => "out of ISA DMA memory",
+        };
+        f.write_str(description)
+    }
+}
+
+/// Returns true if there is usable ISA DMA memory (ISA DMA zone is inited)
+pub fn isa_dma_available() -> bool {
+    ISA_DMA_ZONE.get().is_some()
+}
+
+/// Allocs memory from zone using buddy allocators, never waits lock
+///
+/// Same as [alloc], but skips zones whose lock is held by someone else,
+/// so it can be called from an interrupt handler that may have preempted a lock holder.
+///
+/// Can spuriously fail under contention even if there is free memory,
+/// callers must be ready to fall back (e.g. to a preallocated pool).
+///
+/// # Safety
+/// Allocated memory is uninitialized
+pub unsafe fn try_alloc_nonblocking(
+    memory_zones_and_priority_specifier: &MemoryZonesAndPrioritySpecifier,
+    requested_size: usize,
+) -> Option<PhysAddr> {
+    debug_assert!(
+        requested_size >= PAGE_SIZE && requested_size.is_power_of_two(),
+        "Requested size must be one or more pages"
+    );
+
+    for requested_memory_zone_specifier in memory_zones_and_priority_specifier.iter() {
+        let requested_memory_zone = match requested_memory_zone_specifier {
+            MemoryZoneEnum::IsaDma => &ISA_DMA_ZONE,
+            MemoryZoneEnum::Dma32 => &DMA32_ZONE,
+            MemoryZoneEnum::High => &HIGH_ZONE,
+        };
+        // Zone exist and not locked?
+        if let Some(mut zone_lock) = requested_memory_zone.get().and_then(try_lock_zone) {
+            let allocated_ptr = unsafe { zone_lock.malloc(requested_size) };
+            if !allocated_ptr.is_null() {
+                debug_assert_eq!(
+                    allocated_ptr as usize % PAGE_SIZE,
+                    0,
+                    "Buddy allocator allocates non aligned address"
+                );
+                let allocated_addr = PhysAddr::new(allocated_ptr as u64);
+                track_allocation(allocated_addr, requested_size);
+                return Some(allocated_addr);
+            }
+        }
+    }
+    None
+}
+
 /// Frees memory to buddy allocator
 ///
 /// May be slow because may wait lock
@@ -789,15 +1822,51 @@ pub unsafe fn free(freed_addr: PhysAddr) {
         "Trying to free non aligned address"
     );
 
+    let freed_addr = take_aligned_allocation_block(freed_addr, true).unwrap();
     let memory_zone = get_zone_allocator_by_addr(freed_addr);
 
+    // Before freeing, the block may be reused right after free
+    let block_pages = track_free(freed_addr);
     unsafe {
-        memory_zone
-            .get()
-            .expect("Trying to free memory from non-existing zone")
-            .lock()
-            .allocator
-            .free(freed_addr.as_u64() as *mut u8);
+        lock_zone(
+            memory_zone
+                .get()
+                .expect("Trying to free memory from non-existing zone"),
+        )
+        .free(freed_addr.as_u64() as *mut u8, block_pages);
+    }
+}
+
+/// Frees memory to buddy allocator, never waits lock
+///
+/// Returns false if the zone lock is held by someone else, in this case memory is not freed and the caller must retry later
+///
+/// # Safety
+/// Freed memory must be previously allocated memory
+pub unsafe fn try_free(freed_addr: PhysAddr) -> bool {
+    debug_assert!(!freed_addr.is_null(), "Trying to free null address");
+    debug_assert!(
+        freed_addr.is_aligned(PAGE_SIZE as u64),
+        "Trying to free non aligned address"
+    );
+
+    // Aligned address and its original block are in the same zone
+    let memory_zone = get_zone_allocator_by_addr(freed_addr)
+        .get()
+        .expect("Trying to free memory from non-existing zone");
+
+    if let Some(mut zone_lock) = try_lock_zone(memory_zone) {
+        // Under zone lock, so the original block is not forgotten if memory is not freed
+        let Some(freed_addr) = take_aligned_allocation_block(freed_addr, false) else {
+            return false;
+        };
+        let block_pages = track_free(freed_addr);
+        unsafe {
+            zone_lock.free(freed_addr.as_u64() as *mut u8, block_pages);
+        }
+        true
+    } else {
+        false
     }
 }
 
@@ -808,25 +1877,128 @@ pub unsafe fn realloc(phys_addr: PhysAddr, requested_size: usize, ignore_data: b
     }
     let memory_zone = get_zone_allocator_by_addr(phys_addr);
 
-    memory_zone
-        .get()
-        .expect("Trying to free memory from non-existing zone")
-        .lock()
-        .allocator
-        .realloc(phys_addr.as_u64() as *mut u8, requested_size, ignore_data)
+    let mut zone_lock = lock_zone(
+        memory_zone
+            .get()
+            .expect("Trying to free memory from non-existing zone"),
+    );
+    let new_ptr =
+        zone_lock
+            .allocator
+            .realloc(phys_addr.as_u64() as *mut u8, requested_size, ignore_data);
+    // Under lock, the old block may be reused right after unlock
+    if !new_ptr.is_null() {
+        track_free(phys_addr);
+        track_allocation(
+            PhysAddr::new(new_ptr as u64),
+            requested_size.max(PAGE_SIZE).next_power_of_two(),
+        );
+    }
+    new_ptr
+}
+
+/// Zone lock hold time after which a warning is printed, in TSC cycles
+///
+/// ~10 ms at 1 GHz, the buddy allocator operations should take microseconds
+#[cfg(debug_assertions)]
+const ZONE_LOCK_HOLD_WARNING_THRESHOLD_CYCLES: u64 = 10_000_000;
+
+/// Zone lock guard which warns if the lock was held longer than [ZONE_LOCK_HOLD_WARNING_THRESHOLD_CYCLES]
+///
+/// Detects lock contention and buddy allocator pathologies, used only in debug builds
+#[cfg(debug_assertions)]
+struct ZoneLockGuard<'a> {
+    guard: ManuallyDrop<MutexGuard<'a, MemoryZone>>,
+    /// TSC value when the lock was acquired
+    locked_at_cycles: u64,
+}
+
+#[cfg(debug_assertions)]
+impl Deref for ZoneLockGuard<'_> {
+    type Target = MemoryZone;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+#[cfg(debug_assertions)]
+impl DerefMut for ZoneLockGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for ZoneLockGuard<'_> {
+    fn drop(&mut self) {
+        let held_cycles =
+            unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(self.locked_at_cycles);
+        // Unlock first, printing over serial must not prolong the hold it reports
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        if held_cycles > ZONE_LOCK_HOLD_WARNING_THRESHOLD_CYCLES {
+            // APIC ID from CPUID, works even before Local APIC initialization
+            let apic_id = raw_cpuid::CpuId::new()
+                .get_feature_info()
+                .map(|feature_info| feature_info.initial_local_apic_id());
+            // Lock free, the warning may be printed from an interrupt handler
+            crate::serial_println_lock_free!(
+                "WARNING: Memory zone lock was held for {held_cycles} cycles by CPU with APIC ID {apic_id:?}"
+            );
+        }
+    }
+}
+
+/// Locks zone, in debug builds measures hold time
+#[cfg(debug_assertions)]
+fn lock_zone(zone: &Mutex<MemoryZone>) -> ZoneLockGuard<'_> {
+    let guard = zone.lock();
+    ZoneLockGuard {
+        guard: ManuallyDrop::new(guard),
+        locked_at_cycles: unsafe { core::arch::x86_64::_rdtsc() },
+    }
+}
+
+/// Locks zone
+#[cfg(not(debug_assertions))]
+fn lock_zone(zone: &Mutex<MemoryZone>) -> MutexGuard<'_, MemoryZone> {
+    zone.lock()
+}
+
+/// Tries to lock zone without waiting, in debug builds measures hold time
+#[cfg(debug_assertions)]
+fn try_lock_zone(zone: &Mutex<MemoryZone>) -> Option<ZoneLockGuard<'_>> {
+    let guard = zone.try_lock()?;
+    Some(ZoneLockGuard {
+        guard: ManuallyDrop::new(guard),
+        locked_at_cycles: unsafe { core::arch::x86_64::_rdtsc() },
+    })
+}
+
+/// Tries to lock zone without waiting
+#[cfg(not(debug_assertions))]
+fn try_lock_zone(zone: &Mutex<MemoryZone>) -> Option<MutexGuard<'_, MemoryZone>> {
+    zone.try_lock()
 }
 
 fn get_zone_allocator_by_addr(phys_addr: PhysAddr) -> &'static Once<Mutex<MemoryZone>> {
+    zone_by_enum(zone_of(phys_addr).expect("Trying to free invalid address"))
+}
+
+/// Returns zone of physical address, None if the address is out of all zones
+pub fn zone_of(phys_addr: PhysAddr) -> Option<MemoryZoneEnum> {
     if phys_addr >= ISA_DMA_ZONE_MIN_FIRST_PAGE_ADDR && phys_addr <= ISA_DMA_ZONE_MAX_LAST_PAGE_ADDR
     {
-        &ISA_DMA_ZONE
+        Some(MemoryZoneEnum::IsaDma)
     } else if phys_addr >= DMA32_MIN_FIRST_PAGE_ADDR && phys_addr <= DMA32_MAX_LAST_PAGE_ADDR {
-        &DMA32_ZONE
+        Some(MemoryZoneEnum::Dma32)
     } else if phys_addr >= HIGH_ZONE_MIN_FIRST_PAGE_ADDR
         && phys_addr <= HIGH_ZONE_MAX_LAST_PAGE_ADDR
     {
-        &HIGH_ZONE
+        Some(MemoryZoneEnum::High)
     } else {
-        unreachable!("Trying to free invalid address");
+        None
     }
 }
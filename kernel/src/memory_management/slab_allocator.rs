@@ -1,8 +1,12 @@
-use crate::memory_management::physical_memory_manager::MemoryZoneEnum;
+use crate::memory_management::physical_memory_manager::{
+    MemoryZoneEnum, MemoryZonesAndPrioritySpecifier,
+};
 use crate::memory_management::PAGE_SIZE;
 use core::mem::MaybeUninit;
-use core::ptr::null_mut;
-use slab_allocator_lib::{Cache, MemoryBackend, ObjectSizeType, SlabInfo};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+pub use slab_allocator_lib::{Cache, ObjectSizeType};
+use slab_allocator_lib::{MemoryBackend, SlabInfo};
 use spin::{Mutex, Once};
 use x86_64::VirtAddr;
 
@@ -16,16 +20,140 @@ use x86_64::VirtAddr;
 // MaybeUninit is used because initializing the entire array memory before creating a slice is a heavy operation
 pub static mut SLAB_INFO_PTRS: Once<&'static mut [MaybeUninit<*mut SlabInfo>]> = Once::new();
 
+/// Zones priority of [DefaultMemoryBackend]
+///
+/// General-purpose slabs prefer HIGH, then DMA32, ISA DMA is reserved, see [alloc_isa_dma](super::physical_memory_manager::alloc_isa_dma)
+const DEFAULT_MEMORY_BACKEND_ZONES_PRIORITY: &MemoryZonesAndPrioritySpecifier =
+    &[MemoryZoneEnum::High, MemoryZoneEnum::Dma32];
+
+/// Zones priority of [SlabInfoCacheMemoryBackend]
+///
+/// Same as [DEFAULT_MEMORY_BACKEND_ZONES_PRIORITY], SlabInfo's don't need DMA memory
+const SLAB_INFO_CACHE_MEMORY_BACKEND_ZONES_PRIORITY: &MemoryZonesAndPrioritySpecifier =
+    &[MemoryZoneEnum::High, MemoryZoneEnum::Dma32];
+
+/// Slab size of SlabInfo cache
+///
+/// Every slab of every other cache needs a SlabInfo, on large-memory systems with many slabs in flight
+/// bigger slabs reduce the number of SlabInfo cache refills (and its lock holds). Must be power of two and page-aligned.
+const SLAB_INFO_CACHE_SLAB_SIZE: usize = 4096;
+
 /// Cache with SlabInfo's
+///
+/// ObjectSizeType::Small - SlabInfo is stored inside slab, so the cache never needs SlabInfo for itself
+/// (Large would require external SlabInfo's, from this very cache)
 static SLAB_INFO_CACHE: Once<Mutex<Cache<SlabInfo, SlabInfoCacheMemoryBackend>>> = Once::new();
 
+/// Number of SlabInfo's allocated from [SLAB_INFO_CACHE] (one per slab of other caches)
+static SLAB_INFO_CACHE_LIVE_OBJECTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Maximum of [SLAB_INFO_CACHE_LIVE_OBJECTS]
+static SLAB_INFO_CACHE_PEAK_LIVE_OBJECTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of slabs of [SLAB_INFO_CACHE]
+static SLAB_INFO_CACHE_SLABS: AtomicUsize = AtomicUsize::new(0);
+
+/// Object sizes of generic caches, see [kmalloc]
+pub const GENERIC_CACHE_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Biggest object size of generic caches
+pub const KMALLOC_MAX_SIZE: usize = GENERIC_CACHE_SIZES[GENERIC_CACHE_SIZES.len() - 1];
+
+/// Objects of generic caches up to this size keep SlabInfo inside slab
+///
+/// Bigger objects would waste too much of a small slab, so they use external SlabInfo's
+const GENERIC_CACHE_SMALL_OBJECT_MAX_SIZE: usize = PAGE_SIZE / 8;
+
+/// Slab size of generic caches with small objects
+const GENERIC_CACHE_SMALL_SLAB_SIZE: usize = PAGE_SIZE;
+
+/// Slab size of generic caches with large objects, fits 8 objects of [KMALLOC_MAX_SIZE]
+const GENERIC_CACHE_LARGE_SLAB_SIZE: usize = 4 * PAGE_SIZE;
+
+// Objects of generic caches, alignment is equal to size, so every object is naturally aligned
+#[repr(C, align(16))]
+struct GenericObject16([u8; 16]);
+#[repr(C, align(32))]
+struct GenericObject32([u8; 32]);
+#[repr(C, align(64))]
+struct GenericObject64([u8; 64]);
+#[repr(C, align(128))]
+struct GenericObject128([u8; 128]);
+#[repr(C, align(256))]
+struct GenericObject256([u8; 256]);
+#[repr(C, align(512))]
+struct GenericObject512([u8; 512]);
+#[repr(C, align(1024))]
+struct GenericObject1024([u8; 1024]);
+#[repr(C, align(2048))]
+struct GenericObject2048([u8; 2048]);
+
+type GenericCache<T> = Once<Mutex<Cache<T, DefaultMemoryBackend>>>;
+
+static GENERIC_CACHE16: GenericCache<GenericObject16> = Once::new();
+static GENERIC_CACHE32: GenericCache<GenericObject32> = Once::new();
+static GENERIC_CACHE64: GenericCache<GenericObject64> = Once::new();
+static GENERIC_CACHE128: GenericCache<GenericObject128> = Once::new();
+static GENERIC_CACHE256: GenericCache<GenericObject256> = Once::new();
+static GENERIC_CACHE512: GenericCache<GenericObject512> = Once::new();
+static GENERIC_CACHE1024: GenericCache<GenericObject1024> = Once::new();
+static GENERIC_CACHE2048: GenericCache<GenericObject2048> = Once::new();
+
+/// Statistics of slab cache
+#[derive(Debug, Copy, Clone)]
+pub struct SlabCacheStats {
+    /// Cache name
+    pub name: &'static str,
+    /// Object size in bytes
+    pub object_size: usize,
+    /// Slab size in bytes
+    pub slab_size: usize,
+    /// Number of slabs
+    pub slabs: usize,
+    /// Number of allocated objects
+    pub live_objects: usize,
+    /// Maximum number of allocated objects at the same time
+    pub peak_live_objects: usize,
+}
+
+/// Returns statistics of slab caches
+pub fn slab_stats() -> [SlabCacheStats; 1] {
+    [SlabCacheStats {
+        name: "SlabInfo",
+        object_size: size_of::<SlabInfo>(),
+        slab_size: SLAB_INFO_CACHE_SLAB_SIZE,
+        slabs: SLAB_INFO_CACHE_SLABS.load(Ordering::Relaxed),
+        live_objects: SLAB_INFO_CACHE_LIVE_OBJECTS.load(Ordering::Relaxed),
+        peak_live_objects: SLAB_INFO_CACHE_PEAK_LIVE_OBJECTS.load(Ordering::Relaxed),
+    }]
+}
+
+/// Logs statistics of slab caches
+pub fn log_slab_stats() {
+    for cache_stats in slab_stats() {
+        log::info!(
+            "Slab cache {}: {} live objects ({} peak) of {} bytes, {} slabs of {} bytes",
+            cache_stats.name,
+            cache_stats.live_objects,
+            cache_stats.peak_live_objects,
+            cache_stats.object_size,
+            cache_stats.slabs,
+            cache_stats.slab_size
+        );
+    }
+}
+
 /// Inits slab caches
 pub fn init() {
+    const {
+        assert!(SLAB_INFO_CACHE_SLAB_SIZE.is_power_of_two());
+        assert!(SLAB_INFO_CACHE_SLAB_SIZE % PAGE_SIZE == 0);
+    }
     // Init SlabInfo cache
     SLAB_INFO_CACHE.call_once(|| {
         Mutex::new(
             Cache::new(
-                4096,
+                SLAB_INFO_CACHE_SLAB_SIZE,
                 PAGE_SIZE,
                 ObjectSizeType::Small,
                 SlabInfoCacheMemoryBackend,
@@ -33,10 +161,250 @@ pub fn init() {
             .unwrap_or_else(|error| panic!("Failed to create SlabInfo cache: {error}")),
         )
     });
+    // Init generic caches
+    init_generic_cache(&GENERIC_CACHE16);
+    init_generic_cache(&GENERIC_CACHE32);
+    init_generic_cache(&GENERIC_CACHE64);
+    init_generic_cache(&GENERIC_CACHE128);
+    init_generic_cache(&GENERIC_CACHE256);
+    init_generic_cache(&GENERIC_CACHE512);
+    init_generic_cache(&GENERIC_CACHE1024);
+    init_generic_cache(&GENERIC_CACHE2048);
+}
+
+fn init_generic_cache<T>(generic_cache: &GenericCache<T>) {
+    let (slab_size, object_size_type) = if size_of::<T>() <= GENERIC_CACHE_SMALL_OBJECT_MAX_SIZE {
+        (GENERIC_CACHE_SMALL_SLAB_SIZE, ObjectSizeType::Small)
+    } else {
+        (GENERIC_CACHE_LARGE_SLAB_SIZE, ObjectSizeType::Large)
+    };
+    generic_cache.call_once(|| {
+        Mutex::new(
+            Cache::new(slab_size, PAGE_SIZE, object_size_type, DefaultMemoryBackend)
+                .unwrap_or_else(|error| {
+                    panic!(
+                        "Failed to create generic cache of {} bytes: {error}",
+                        size_of::<T>()
+                    )
+                }),
+        )
+    });
+}
+
+/// Returns object size of the smallest generic cache fitting the size
+///
+/// None if size is 0 or bigger than [KMALLOC_MAX_SIZE]
+#[inline]
+fn generic_cache_size(size: usize) -> Option<usize> {
+    if size == 0 || size > KMALLOC_MAX_SIZE {
+        return None;
+    }
+    Some(size.max(GENERIC_CACHE_SIZES[0]).next_power_of_two())
+}
+
+/// Allocates memory from the smallest fitting generic cache
+///
+/// Returns null if size is 0, bigger than [KMALLOC_MAX_SIZE] (use [GeneralPurposeAllocator](super::general_purpose_allocator::GeneralPurposeAllocator))
+/// or memory is exhausted.
+///
+/// Object is aligned to its generic cache size, e.g. 100 bytes are allocated from 128 bytes cache and 128 bytes aligned.
+/// So alignment is at least 16 bytes and at least the size rounded up to a power of two.
+pub fn kmalloc(size: usize) -> *mut u8 {
+    let Some(generic_cache_size) = generic_cache_size(size) else {
+        return null_mut();
+    };
+    let object_ptr = match generic_cache_size {
+        16 => generic_cache_alloc(&GENERIC_CACHE16),
+        32 => generic_cache_alloc(&GENERIC_CACHE32),
+        64 => generic_cache_alloc(&GENERIC_CACHE64),
+        128 => generic_cache_alloc(&GENERIC_CACHE128),
+        256 => generic_cache_alloc(&GENERIC_CACHE256),
+        512 => generic_cache_alloc(&GENERIC_CACHE512),
+        1024 => generic_cache_alloc(&GENERIC_CACHE1024),
+        2048 => generic_cache_alloc(&GENERIC_CACHE2048),
+        _ => unreachable!(),
+    };
+    debug_assert!(
+        object_ptr as usize % generic_cache_size == 0,
+        "Generic cache object is not naturally aligned"
+    );
+    object_ptr
+}
+
+/// Frees memory allocated by [kmalloc]
+///
+/// Size selects generic cache, so it must be the size passed to [kmalloc] (or any size of the same cache).
+/// The cache finds slab of the object by saved SlabInfo pointer, so only the size class must be remembered.
+///
+/// # Safety
+/// Pointer must be returned by [kmalloc] and not freed yet
+pub unsafe fn kfree(ptr: *mut u8, size: usize) {
+    debug_assert!(!ptr.is_null(), "kfree of null ptr");
+    let generic_cache_size =
+        generic_cache_size(size).unwrap_or_else(|| panic!("kfree of invalid size {size}"));
+    match generic_cache_size {
+        16 => generic_cache_free(&GENERIC_CACHE16, ptr),
+        32 => generic_cache_free(&GENERIC_CACHE32, ptr),
+        64 => generic_cache_free(&GENERIC_CACHE64, ptr),
+        128 => generic_cache_free(&GENERIC_CACHE128, ptr),
+        256 => generic_cache_free(&GENERIC_CACHE256, ptr),
+        512 => generic_cache_free(&GENERIC_CACHE512, ptr),
+        1024 => generic_cache_free(&GENERIC_CACHE1024, ptr),
+        2048 => generic_cache_free(&GENERIC_CACHE2048, ptr),
+        _ => unreachable!(),
+    }
+}
+
+#[inline]
+fn generic_cache_alloc<T>(generic_cache: &GenericCache<T>) -> *mut u8 {
+    unsafe {
+        generic_cache
+            .get()
+            .expect("Generic cache not set")
+            .lock()
+            .alloc()
+            .cast()
+    }
+}
+
+#[inline]
+unsafe fn generic_cache_free<T>(generic_cache: &GenericCache<T>, ptr: *mut u8) {
+    generic_cache
+        .get()
+        .expect("Generic cache not set")
+        .lock()
+        .free(ptr.cast());
+}
+
+/// Creates cache of typed kernel objects (e.g. processes), caller wraps it into its own lock
+///
+/// Slab size must be a power of two multiple of [PAGE_SIZE].
+/// Small objects keep SlabInfo inside slab, Large objects use external SlabInfo's from the SlabInfo cache.
+pub fn new_cache<T>(
+    slab_size: usize,
+    object_size_type: ObjectSizeType,
+) -> Cache<T, DefaultMemoryBackend> {
+    assert!(
+        slab_size.is_power_of_two() && slab_size % PAGE_SIZE == 0,
+        "Slab size {slab_size} is not a power of two multiple of page size"
+    );
+    Cache::new(slab_size, PAGE_SIZE, object_size_type, DefaultMemoryBackend).unwrap_or_else(
+        |error| {
+            panic!(
+                "Failed to create cache of {} bytes objects: {error}",
+                size_of::<T>()
+            )
+        },
+    )
+}
+
+/// Cache of typed kernel objects with constructor and destructor, caller wraps it into its own lock
+///
+/// `slab_allocator_lib::Cache` has no constructors, so freed objects are kept constructed in the cache's own free list
+/// and reused without running constructor again (classic SLAB). Constructor runs once per object taken from the slab cache,
+/// destructor runs when the object is given back to it by [TypedCache::shrink] or when the cache is dropped.
+/// So freed object must be in its constructed state (e.g. unlocked lock, empty list links).
+/// Objects not freed before drop are leaked together with their slabs.
+pub struct TypedCache<T> {
+    cache: Cache<TypedCacheSlot<T>, DefaultMemoryBackend>,
+    ctor: fn(&mut MaybeUninit<T>),
+    dtor: fn(&mut T),
+    /// Freed constructed objects, linked through [TypedCacheSlot::next_free]
+    free_list: *mut TypedCacheSlot<T>,
+    /// Number of objects in free list
+    free_objects: usize,
+}
+
+// Free list points only to objects owned by the cache
+unsafe impl<T: Send> Send for TypedCache<T> {}
+
+/// Object of [TypedCache]
+///
+/// Object is first, so object pointer is slot pointer. Free list link is separate from the object,
+/// so the slab cache's own bookkeeping in freed memory can't clobber constructed objects.
+#[repr(C)]
+struct TypedCacheSlot<T> {
+    object: T,
+    next_free: *mut TypedCacheSlot<T>,
+}
+
+impl<T> TypedCache<T> {
+    /// Creates cache, see [new_cache] for slab size and object size type
+    pub fn new(
+        slab_size: usize,
+        object_size_type: ObjectSizeType,
+        ctor: fn(&mut MaybeUninit<T>),
+        dtor: fn(&mut T),
+    ) -> Self {
+        Self {
+            cache: new_cache(slab_size, object_size_type),
+            ctor,
+            dtor,
+            free_list: null_mut(),
+            free_objects: 0,
+        }
+    }
+
+    /// Allocates constructed object, None if memory is exhausted
+    ///
+    /// Object is valid until [TypedCache::free] or until the cache is dropped
+    pub fn alloc(&mut self) -> Option<NonNull<T>> {
+        if let Some(slot_ptr) = NonNull::new(self.free_list) {
+            self.free_list = unsafe { slot_ptr.as_ref().next_free };
+            self.free_objects -= 1;
+            // object is at offset 0 of repr(C) slot
+            return Some(slot_ptr.cast());
+        }
+        let slot_ptr = NonNull::new(unsafe { self.cache.alloc() })?;
+        (self.ctor)(unsafe { slot_ptr.cast::<MaybeUninit<T>>().as_mut() });
+        Some(slot_ptr.cast())
+    }
+
+    /// Frees object to the cache, it stays constructed
+    ///
+    /// # Safety
+    /// Object must be allocated from this cache and not freed yet, it must be in constructed state
+    pub unsafe fn free(&mut self, object: NonNull<T>) {
+        let slot_ptr = object.as_ptr().cast::<TypedCacheSlot<T>>();
+        (*slot_ptr).next_free = self.free_list;
+        self.free_list = slot_ptr;
+        self.free_objects += 1;
+    }
+
+    /// Destructs freed objects and gives them back to the slab cache
+    ///
+    /// Returns number of destructed objects
+    pub fn shrink(&mut self) -> usize {
+        let destructed_objects = self.free_objects;
+        while !self.free_list.is_null() {
+            let slot_ptr = self.free_list;
+            unsafe {
+                self.free_list = (*slot_ptr).next_free;
+                (self.dtor)(&mut (*slot_ptr).object);
+                self.cache.free(slot_ptr);
+            }
+        }
+        self.free_objects = 0;
+        destructed_objects
+    }
+
+    /// Returns number of freed constructed objects
+    pub fn free_objects(&self) -> usize {
+        self.free_objects
+    }
+}
+
+impl<T> Drop for TypedCache<T> {
+    /// Destructs freed objects, they would be lost with the free list otherwise
+    fn drop(&mut self) {
+        self.shrink();
+    }
 }
 
 /// MemoryBackend suitable for any cache
-struct DefaultMemoryBackend;
+///
+/// Empty slabs can't be reclaimed on demand, `slab_allocator_lib::Cache` exposes only alloc and free.
+pub struct DefaultMemoryBackend;
 
 impl MemoryBackend for DefaultMemoryBackend {
     unsafe fn alloc_slab(&mut self, slab_size: usize, page_size: usize) -> *mut u8 {
@@ -45,14 +413,8 @@ impl MemoryBackend for DefaultMemoryBackend {
             "Slab allocator tries to allocate invalid slab size"
         );
         // Alloc physical frame with slab size
-        let phys_addr = super::physical_memory_manager::alloc(
-            &[
-                MemoryZoneEnum::High,
-                MemoryZoneEnum::Dma32,
-                MemoryZoneEnum::IsaDma,
-            ],
-            slab_size,
-        );
+        let phys_addr =
+            super::physical_memory_manager::alloc(DEFAULT_MEMORY_BACKEND_ZONES_PRIORITY, slab_size);
         if phys_addr.is_null() {
             return null_mut();
         }
@@ -77,6 +439,10 @@ impl MemoryBackend for DefaultMemoryBackend {
             .expect("SlabInfo cache not set")
             .lock()
             .alloc();
+        if !slab_info_ptr.is_null() {
+            let live_objects = SLAB_INFO_CACHE_LIVE_OBJECTS.fetch_add(1, Ordering::Relaxed) + 1;
+            SLAB_INFO_CACHE_PEAK_LIVE_OBJECTS.fetch_max(live_objects, Ordering::Relaxed);
+        }
         slab_info_ptr
     }
 
@@ -90,6 +456,7 @@ impl MemoryBackend for DefaultMemoryBackend {
             .expect("SlabInfo cache not set")
             .lock()
             .free(slab_info_ptr);
+        SLAB_INFO_CACHE_LIVE_OBJECTS.fetch_sub(1, Ordering::Relaxed);
     }
 
     unsafe fn save_slab_info_ptr(&mut self, object_page_addr: usize, slab_info_ptr: *mut SlabInfo) {
@@ -148,16 +515,13 @@ impl MemoryBackend for SlabInfoCacheMemoryBackend {
         );
         // Alloc physical frame with slab size
         let phys_addr = super::physical_memory_manager::alloc(
-            &[
-                MemoryZoneEnum::High,
-                MemoryZoneEnum::Dma32,
-                MemoryZoneEnum::IsaDma,
-            ],
+            SLAB_INFO_CACHE_MEMORY_BACKEND_ZONES_PRIORITY,
             slab_size,
         );
         if phys_addr.is_null() {
             return null_mut();
         }
+        SLAB_INFO_CACHE_SLABS.fetch_add(1, Ordering::Relaxed);
         super::virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(phys_addr).as_mut_ptr()
     }
 
@@ -171,6 +535,7 @@ impl MemoryBackend for SlabInfoCacheMemoryBackend {
         let phys_addr =
             super::virtual_memory_manager::phys_addr_from_virt_addr_from_cpmm(virt_addr);
         super::physical_memory_manager::free(phys_addr);
+        SLAB_INFO_CACHE_SLABS.fetch_sub(1, Ordering::Relaxed);
     }
 
     unsafe fn alloc_slab_info(&mut self) -> *mut SlabInfo {
@@ -1,4 +1,17 @@
+use super::physical_memory_manager::{MemoryZoneEnum, MemoryZonesAndPrioritySpecifier};
+use super::PAGE_SIZE;
+use crate::cpu;
+use crate::interrupts::apic::{self, smp};
+use crate::interrupts::idt;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use tinyvec::ArrayVec;
 use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+use x86_64::structures::paging::page_table::PageTableEntry;
 use x86_64::structures::paging::page_table::PageTableLevel;
 use x86_64::structures::paging::{PageTable, PageTableFlags};
 use x86_64::{PhysAddr, VirtAddr};
@@ -13,6 +26,231 @@ use x86_64::{PhysAddr, VirtAddr};
 /// doc/virtual_memory_layout.txt
 pub const PHYSICAL_MEMORY_MAPPING_OFFSET: u64 = 0xFFFF_A000_0000_0000;
 
+/// Bootloader dynamic range (kernel code, stack, etc.) start
+///
+/// doc/virtual_memory_layout.txt
+pub const DYNAMIC_RANGE_START: u64 = 0xFFFF_9000_0000_0000;
+
+/// Bootloader dynamic range end
+pub const DYNAMIC_RANGE_END: u64 = 0xFFFF_9FFF_FFFF_FFFF;
+
+/// Complete Physical Memory Mapping end
+pub const PHYSICAL_MEMORY_MAPPING_END: u64 = 0xFFFF_AFFF_FFFF_FFFF;
+
+/// Virtual Memory Allocations area start
+pub const VIRTUAL_MEMORY_ALLOCATIONS_START: u64 = 0xFFFF_B000_0000_0000;
+
+/// Virtual Memory Allocations area end
+pub const VIRTUAL_MEMORY_ALLOCATIONS_END: u64 = 0xFFFF_BFFF_FFFF_FFFF;
+
+/// Virtual memory manager error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// Virtual address already mapped
+    AlreadyMapped,
+    /// Virtual address not mapped
+    NotMapped,
+    /// Failed to allocate physical frame (for page or page table)
+    OutOfFrames,
+    /// Address or size not page-aligned
+    Misaligned,
+    /// No free virtual address range
+    OutOfVirtualSpace,
+    /// Zero size allocation
+    ZeroSize,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            VmError::AlreadyMapped => "virtual address already mapped",
+            VmError::NotMapped => "virtual address not mapped",
+            VmError::OutOfFrames => "out of physical frames",
+            VmError::Misaligned => "address or size not page-aligned",
+            VmError::OutOfVirtualSpace => "out of virtual address space",
+            VmError::ZeroSize => "zero size",
+        };
+        f.write_str(description)
+    }
+}
+
+/// Zones priority of page tables
+///
+/// Page tables don't need DMA memory
+const PAGE_TABLES_ZONES_PRIORITY: &MemoryZonesAndPrioritySpecifier =
+    &[MemoryZoneEnum::High, MemoryZoneEnum::Dma32];
+
+/// Region of virtual memory layout
+///
+/// doc/virtual_memory_layout.txt
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VirtualMemoryRegion {
+    /// 0x0000_0000_0000_0000 - 0x0000_7FFF_FFFF_FFFF
+    Userspace,
+    /// 0x0000_8000_0000_0000 - 0xFFFF_7FFF_FFFF_FFFF
+    NonCanonical,
+    /// Bootloader dynamic range (kernel code, stack, etc.)
+    Dynamic,
+    /// Complete Physical Memory Mapping
+    CompletePhysicalMemoryMapping,
+    /// Virtual Memory Allocations
+    VirtualMemoryAllocations,
+    /// Kernel space not used by layout
+    Unused,
+}
+
+/// Classifies virtual address by virtual memory layout
+pub fn virtual_memory_region(virt_addr: u64) -> VirtualMemoryRegion {
+    match virt_addr {
+        0..=0x0000_7FFF_FFFF_FFFF => VirtualMemoryRegion::Userspace,
+        0x0000_8000_0000_0000..=0xFFFF_7FFF_FFFF_FFFF => VirtualMemoryRegion::NonCanonical,
+        DYNAMIC_RANGE_START..=DYNAMIC_RANGE_END => VirtualMemoryRegion::Dynamic,
+        PHYSICAL_MEMORY_MAPPING_OFFSET..=PHYSICAL_MEMORY_MAPPING_END => {
+            VirtualMemoryRegion::CompletePhysicalMemoryMapping
+        }
+        VIRTUAL_MEMORY_ALLOCATIONS_START..=VIRTUAL_MEMORY_ALLOCATIONS_END => {
+            VirtualMemoryRegion::VirtualMemoryAllocations
+        }
+        _ => VirtualMemoryRegion::Unused,
+    }
+}
+
+/// Checks that bootloader placed Complete Physical Memory Mapping and dynamic range as configured
+///
+/// Must be called before the first CPMM conversion, otherwise every conversion is wrong and faults are very confusing
+pub fn check_boot_layout(boot_info: &bootloader_api::BootInfo) {
+    let physical_memory_offset = boot_info.physical_memory_offset.as_ref().copied();
+    assert_eq!(
+        physical_memory_offset,
+        Some(PHYSICAL_MEMORY_MAPPING_OFFSET),
+        "Bootloader placed Complete Physical Memory Mapping not at the configured offset"
+    );
+
+    // Kernel stack and boot info are mapped in dynamic range
+    let stack_variable = 0u8;
+    let stack_pointer = core::ptr::addr_of!(stack_variable) as u64;
+    assert!(
+        (DYNAMIC_RANGE_START..=DYNAMIC_RANGE_END).contains(&stack_pointer),
+        "Bootloader placed kernel stack (0x{stack_pointer:X}) outside of the configured dynamic range"
+    );
+    let boot_info_virt_addr = boot_info as *const _ as u64;
+    assert!(
+        (DYNAMIC_RANGE_START..=DYNAMIC_RANGE_END).contains(&boot_info_virt_addr),
+        "Bootloader placed boot info (0x{boot_info_virt_addr:X}) outside of the configured dynamic range"
+    );
+
+    // CPMM really maps physical memory: PML4 address in CPMM translates back to PML4 physical address
+    let pml4_phys_addr = x86_64::registers::control::Cr3::read().0.start_address();
+    let pml4_virt_addr = virt_addr_in_cpmm_from_phys_addr(pml4_phys_addr);
+    let (entry, level) = leaf_page_table_entry(pml4_virt_addr)
+        .expect("Complete Physical Memory Mapping is not mapped");
+    let offset = pml4_virt_addr.as_u64() & (level.entry_address_space_alignment() - 1);
+    assert_eq!(
+        unsafe { (*entry).addr() } + offset,
+        pml4_phys_addr,
+        "Complete Physical Memory Mapping doesn't map physical memory at the configured offset"
+    );
+}
+
+/// Page tables for Complete Physical Memory Mapping extension, used before Physical Memory Manager is inited
+///
+/// With 1 GB pages one table maps 512 GB, with 2 MB pages - 1 GB
+static mut CPMM_EXTENSION_PAGE_TABLES: [PageTable; CPMM_EXTENSION_PAGE_TABLES_NUMBER] =
+    [const { PageTable::new() }; CPMM_EXTENSION_PAGE_TABLES_NUMBER];
+
+const CPMM_EXTENSION_PAGE_TABLES_NUMBER: usize = 16;
+
+/// Number of used [CPMM_EXTENSION_PAGE_TABLES]
+static CPMM_EXTENSION_PAGE_TABLES_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes sure Complete Physical Memory Mapping covers all physical memory from the memory map
+///
+/// Bootloader may map less than the top of physical RAM, then high memory CPMM conversions fault.
+/// The uncovered range is mapped with huge pages (1 GB if supported, otherwise 2 MB).
+///
+/// Must be called before Physical Memory Manager initialization (it uses CPMM for metadata),
+/// so page tables are taken from a static pool.
+pub fn extend_cpmm_to_physical_memory_top(memory_regions: &[bootloader_api::info::MemoryRegion]) {
+    let physical_memory_top = memory_regions
+        .iter()
+        .map(|memory_region| memory_region.end)
+        .max()
+        .expect("Empty memory map");
+    assert!(
+        physical_memory_top <= PHYSICAL_MEMORY_MAPPING_END - PHYSICAL_MEMORY_MAPPING_OFFSET + 1,
+        "Physical memory top 0x{physical_memory_top:X} doesn't fit in Complete Physical Memory Mapping window"
+    );
+    let has_1gb_pages = cpu::features().one_gb_pages;
+
+    let mut mapped_bytes: u64 = 0;
+    let mut phys_addr: u64 = 0;
+    while phys_addr < physical_memory_top {
+        let virt_addr = virt_addr_in_cpmm_from_phys_addr(PhysAddr::new(phys_addr));
+        let page_size = match leaf_page_table_entry(virt_addr) {
+            Ok((_, level)) => level.entry_address_space_alignment(),
+            Err(VmError::NotMapped) => {
+                let page_size = map_cpmm_huge_page(PhysAddr::new(phys_addr), has_1gb_pages);
+                mapped_bytes += page_size;
+                page_size
+            }
+            Err(err) => panic!("Failed to check Complete Physical Memory Mapping: {err}"),
+        };
+        // Next page of the leaf (huge page may start before phys_addr)
+        phys_addr = x86_64::align_down(phys_addr, page_size) + page_size;
+    }
+    if mapped_bytes != 0 {
+        tlb::flush_all();
+        log::info!(
+            "Complete Physical Memory Mapping extended by {} MB up to 0x{physical_memory_top:X}",
+            mapped_bytes / (1024 * 1024)
+        );
+    }
+}
+
+/// Maps the largest page at phys_addr in CPMM which fits into the existing page tables and alignment
+///
+/// Returns page size
+fn map_cpmm_huge_page(phys_addr: PhysAddr, has_1gb_pages: bool) -> u64 {
+    let virt_addr = virt_addr_in_cpmm_from_phys_addr(phys_addr);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    loop {
+        // Walk stops at the first not present entry, every new table is entered by the next walk
+        let Err((entry, level)) = walk_address(virt_addr, |_, _| {}) else {
+            panic!("Page is mapped, but the walk says it's not mapped, bug");
+        };
+        let entry = unsafe { &mut *entry };
+
+        let page_size = level.entry_address_space_alignment();
+        let huge_page_allowed = match level {
+            PageTableLevel::Three => has_1gb_pages,
+            PageTableLevel::Two => true,
+            _ => false,
+        };
+        if huge_page_allowed && phys_addr.is_aligned(page_size) {
+            entry.set_addr(phys_addr, flags | PageTableFlags::HUGE_PAGE);
+            return page_size;
+        }
+        if level == PageTableLevel::One {
+            entry.set_addr(phys_addr, flags);
+            return page_size;
+        }
+
+        // Create next level page table from the static pool
+        let page_table_index = CPMM_EXTENSION_PAGE_TABLES_USED.fetch_add(1, Ordering::Relaxed);
+        assert!(
+            page_table_index < CPMM_EXTENSION_PAGE_TABLES_NUMBER,
+            "Out of page tables for Complete Physical Memory Mapping extension"
+        );
+        #[allow(static_mut_refs)]
+        let new_page_table_virt_addr =
+            VirtAddr::from_ptr(unsafe { &CPMM_EXTENSION_PAGE_TABLES[page_table_index] });
+        // Kernel image is not in CPMM, translate by walk
+        let new_page_table_phys_addr =
+            translate(new_page_table_virt_addr).expect("Kernel page table pool is not mapped");
+        entry.set_addr(new_page_table_phys_addr, flags);
+    }
+}
+
 /// Setting up some virtual memory things
 pub fn init() {
     // Unmap all pages in userspace (lower half)
@@ -31,6 +269,215 @@ pub fn init() {
         }
     }
     tlb::flush_all();
+
+    init_pat();
+}
+
+/// IA32_PAT MSR
+const IA32_PAT_MSR: u32 = 0x277;
+
+/// PAT memory type encodings
+const PAT_MEMORY_TYPE_UC: u64 = 0x00;
+const PAT_MEMORY_TYPE_WC: u64 = 0x01;
+const PAT_MEMORY_TYPE_WT: u64 = 0x04;
+const PAT_MEMORY_TYPE_WB: u64 = 0x06;
+const PAT_MEMORY_TYPE_UC_MINUS: u64 = 0x07;
+
+/// PAT entries
+///
+/// PA0-PA3 are the same as the power-on default, so existing PCD/PWT mappings keep their meaning.<br>
+/// PA4 is changed to WC, the rest are default.
+///
+/// | Entry | PAT PCD PWT | Type |
+/// |-------|-------------|------|
+/// | PA0   | 0   0   0   | WB   |
+/// | PA1   | 0   0   1   | WT   |
+/// | PA2   | 0   1   0   | UC-  |
+/// | PA3   | 0   1   1   | UC   |
+/// | PA4   | 1   0   0   | WC   |
+/// | PA5   | 1   0   1   | WT   |
+/// | PA6   | 1   1   0   | UC-  |
+/// | PA7   | 1   1   1   | UC   |
+const PAT_VALUE: u64 = PAT_MEMORY_TYPE_WB
+    | PAT_MEMORY_TYPE_WT << 8
+    | PAT_MEMORY_TYPE_UC_MINUS << 16
+    | PAT_MEMORY_TYPE_UC << 24
+    | PAT_MEMORY_TYPE_WC << 32
+    | PAT_MEMORY_TYPE_WT << 40
+    | PAT_MEMORY_TYPE_UC_MINUS << 48
+    | PAT_MEMORY_TYPE_UC << 56;
+
+/// Programs PAT, makes Write-Combining available
+///
+/// Every core must call it before it touches [set_cache_type] mappings, PAT must be the same on all cores
+pub fn init_pat() {
+    if !cpu::features().pat {
+        log::warn!("PAT not supported, Write-Combining is not available");
+        return;
+    }
+    unsafe {
+        Msr::new(IA32_PAT_MSR).write(PAT_VALUE);
+    }
+    tlb::flush_all();
+}
+
+/// Returns true if PAT of this core is programmed by [init_pat] (or PAT is not supported)
+pub fn pat_programmed() -> bool {
+    !cpu::features().pat || unsafe { Msr::new(IA32_PAT_MSR).read() } == PAT_VALUE
+}
+
+/// Memory cache type of mapping
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheType {
+    /// Default for RAM
+    WriteBack,
+    WriteThrough,
+    /// Uncacheable, can be overridden by MTRR WC
+    UncachedMinus,
+    /// Strong uncacheable, for MMIO (e.g. APIC registers)
+    Uncacheable,
+    /// For framebuffers, requires PAT
+    WriteCombining,
+}
+
+impl CacheType {
+    /// Returns (PAT, PCD, PWT) bits selecting this type in [PAT_VALUE]
+    fn pat_pcd_pwt(self) -> (bool, bool, bool) {
+        match self {
+            CacheType::WriteBack => (false, false, false),
+            CacheType::WriteThrough => (false, false, true),
+            CacheType::UncachedMinus => (false, true, false),
+            CacheType::Uncacheable => (false, true, true),
+            CacheType::WriteCombining => (true, false, false),
+        }
+    }
+
+    /// Returns type selected by (PAT, PCD, PWT) bits in [PAT_VALUE]
+    fn from_pat_pcd_pwt(pat_pcd_pwt: (bool, bool, bool)) -> Self {
+        match pat_pcd_pwt {
+            (true, false, false) => CacheType::WriteCombining,
+            // PA5-PA7 are the same as PA1-PA3
+            (_, false, false) => CacheType::WriteBack,
+            (_, false, true) => CacheType::WriteThrough,
+            (_, true, false) => CacheType::UncachedMinus,
+            (_, true, true) => CacheType::Uncacheable,
+        }
+    }
+}
+
+/// PAT bit in 4 KB page entry
+const PTE_PAT_BIT: u64 = 1 << 7;
+
+/// PAT bit in 2 MB and 1 GB page entries
+const HUGE_PAGE_PAT_BIT: u64 = 1 << 12;
+
+/// Changes cache type of pages mapping
+///
+/// Updates PAT/PCD/PWT bits of every page in range and flushes TLB for every page.<br>
+/// Pages before the first not mapped page are changed even if an error is returned.
+///
+/// If a page is mapped by a huge (2MB or 1GB) page, the cache type of the whole huge page will be changed.
+pub fn set_cache_type(
+    virt_addr: VirtAddr,
+    pages: usize,
+    cache_type: CacheType,
+) -> Result<(), VmError> {
+    if !virt_addr.is_aligned(PAGE_SIZE as u64) {
+        return Err(VmError::Misaligned);
+    }
+    let (pat, pcd, pwt) = cache_type.pat_pcd_pwt();
+
+    for page_index in 0..pages {
+        let page_virt_addr = virt_addr + (page_index * PAGE_SIZE) as u64;
+        let (entry, level) = leaf_page_table_entry(page_virt_addr)?;
+        unsafe {
+            let mut flags = (*entry).flags();
+            flags.set(PageTableFlags::NO_CACHE, pcd);
+            flags.set(PageTableFlags::WRITE_THROUGH, pwt);
+            (*entry).set_flags(flags);
+
+            // PAT bit is not in PageTableFlags, so set it in raw entry
+            let pat_bit = if level == PageTableLevel::One {
+                PTE_PAT_BIT
+            } else {
+                HUGE_PAGE_PAT_BIT
+            };
+            let raw_entry = entry as *mut u64;
+            if pat {
+                *raw_entry |= pat_bit;
+            } else {
+                *raw_entry &= !pat_bit;
+            }
+        }
+        tlb::flush(page_virt_addr);
+    }
+    Ok(())
+}
+
+/// Returns cache type of the page (4 KB, 2 MB or 1 GB) mapping virtual address, see [set_cache_type]
+pub fn cache_type(virt_addr: VirtAddr) -> Result<CacheType, VmError> {
+    let (entry, level) = leaf_page_table_entry(virt_addr)?;
+    let raw_entry = unsafe { *(entry as *const u64) };
+    let pat_bit = if level == PageTableLevel::One {
+        PTE_PAT_BIT
+    } else {
+        HUGE_PAGE_PAT_BIT
+    };
+    let flags = PageTableFlags::from_bits_truncate(raw_entry);
+    Ok(CacheType::from_pat_pcd_pwt((
+        raw_entry & pat_bit != 0,
+        flags.contains(PageTableFlags::NO_CACHE),
+        flags.contains(PageTableFlags::WRITE_THROUGH),
+    )))
+}
+
+/// Returns page table in Complete Physical Memory Mapping by its frame
+#[inline]
+fn page_table_in_cpmm(page_table_phys_addr: PhysAddr) -> *mut PageTable {
+    let page_table =
+        virt_addr_in_cpmm_from_phys_addr(page_table_phys_addr).as_mut_ptr::<PageTable>();
+    debug_assert!(!page_table.is_null(), "Page table null ptr");
+    debug_assert!(page_table.is_aligned(), "Not aligned page table address");
+    page_table
+}
+
+/// Whether present entry maps a page (4 KB, 2 MB or 1 GB) instead of pointing to the next level table
+#[inline]
+fn is_leaf_entry(level: PageTableLevel, flags: PageTableFlags) -> bool {
+    level == PageTableLevel::One || flags.contains(PageTableFlags::HUGE_PAGE)
+}
+
+/// Walks page tables of current address space for virtual address
+///
+/// Calls visitor for the entry of every visited level. Returns the leaf entry and its level if the address is mapped,
+/// otherwise the first not present entry and its level.
+fn walk_address(
+    virt_addr: VirtAddr,
+    mut visitor: impl FnMut(PageTableLevel, &PageTableEntry),
+) -> Result<(*mut PageTableEntry, PageTableLevel), (*mut PageTableEntry, PageTableLevel)> {
+    let mut current_level = PageTableLevel::Four;
+    let mut page_table_phys_addr = x86_64::registers::control::Cr3::read().0.start_address();
+    loop {
+        let page_table = page_table_in_cpmm(page_table_phys_addr);
+        let entry = unsafe { &mut (*page_table)[virt_addr.page_table_index(current_level)] };
+        visitor(current_level, entry);
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err((entry, current_level));
+        }
+        if is_leaf_entry(current_level, flags) {
+            return Ok((entry, current_level));
+        }
+        current_level = current_level.next_lower_level().unwrap();
+        page_table_phys_addr = entry.addr();
+    }
+}
+
+/// Returns last level page table entry mapping virtual address, and its level
+fn leaf_page_table_entry(
+    virt_addr: VirtAddr,
+) -> Result<(*mut PageTableEntry, PageTableLevel), VmError> {
+    walk_address(virt_addr, |_, _| {}).map_err(|_| VmError::NotMapped)
 }
 
 /// Converts physical address to virtual address in Complete Physical Memory Mapping area
@@ -85,3 +532,518 @@ pub fn set_flags_in_page_table(
         }
     }
 }
+
+/// Maps 4 KB page to physical frame
+///
+/// Missing page tables are allocated from Physical Memory Manager.<br>
+/// PRESENT flag is always set.
+///
+/// Flushes TLB for the page
+pub fn map_page(
+    virt_addr: VirtAddr,
+    phys_addr: PhysAddr,
+    page_table_flags: PageTableFlags,
+) -> Result<(), VmError> {
+    if !virt_addr.is_aligned(PAGE_SIZE as u64) || !phys_addr.is_aligned(PAGE_SIZE as u64) {
+        return Err(VmError::Misaligned);
+    }
+
+    // Upper level entries must allow everything allowed by the page
+    let mut parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if page_table_flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        parent_flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+
+    let mut current_level = PageTableLevel::Four;
+    let mut page_table_phys_addr = x86_64::registers::control::Cr3::read().0.start_address();
+    loop {
+        let page_table_virt_addr = virt_addr_in_cpmm_from_phys_addr(page_table_phys_addr);
+        let page_table = page_table_virt_addr.as_mut_ptr::<PageTable>();
+        debug_assert!(!page_table.is_null(), "Page table null ptr");
+        debug_assert!(page_table.is_aligned(), "Not aligned page table address");
+
+        let entry = unsafe { &mut (*page_table)[virt_addr.page_table_index(current_level)] };
+        if current_level == PageTableLevel::One {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                return Err(VmError::AlreadyMapped);
+            }
+            entry.set_addr(phys_addr, page_table_flags | PageTableFlags::PRESENT);
+            tlb::flush(virt_addr);
+            return Ok(());
+        }
+
+        if entry.flags().contains(PageTableFlags::PRESENT) {
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(VmError::AlreadyMapped);
+            }
+            entry.set_flags(entry.flags() | parent_flags);
+        } else {
+            // Create next level page table
+            let new_page_table_phys_addr = unsafe {
+                super::physical_memory_manager::alloc(PAGE_TABLES_ZONES_PRIORITY, PAGE_SIZE)
+            };
+            if new_page_table_phys_addr.is_null() {
+                return Err(VmError::OutOfFrames);
+            }
+            unsafe {
+                virt_addr_in_cpmm_from_phys_addr(new_page_table_phys_addr)
+                    .as_mut_ptr::<PageTable>()
+                    .write(PageTable::new());
+            }
+            entry.set_addr(new_page_table_phys_addr, parent_flags);
+        }
+        current_level = current_level.next_lower_level().unwrap();
+        page_table_phys_addr = entry.addr();
+    }
+}
+
+/// Zones priority of frames mapped by [map_new_page]
+///
+/// Kernel pages don't need DMA memory
+const NEW_PAGES_ZONES_PRIORITY: &MemoryZonesAndPrioritySpecifier =
+    &[MemoryZoneEnum::High, MemoryZoneEnum::Dma32];
+
+/// Allocates zeroed physical frame and maps 4 KB page to it
+///
+/// Returns physical address of the frame, the frame is freed if mapping fails
+pub fn map_new_page(
+    virt_addr: VirtAddr,
+    page_table_flags: PageTableFlags,
+) -> Result<PhysAddr, VmError> {
+    let frame_phys_addr =
+        unsafe { super::physical_memory_manager::alloc(NEW_PAGES_ZONES_PRIORITY, PAGE_SIZE) };
+    if frame_phys_addr.is_null() {
+        return Err(VmError::OutOfFrames);
+    }
+    // Fresh memory must not leak old data
+    unsafe {
+        virt_addr_in_cpmm_from_phys_addr(frame_phys_addr)
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, PAGE_SIZE);
+    }
+    if let Err(err) = map_page(virt_addr, frame_phys_addr, page_table_flags) {
+        unsafe {
+            super::physical_memory_manager::free(frame_phys_addr);
+        }
+        return Err(err);
+    }
+    Ok(frame_phys_addr)
+}
+
+lazy_static! {
+    /// Free ranges of Virtual Memory Allocations region, (first page, number of pages), sorted by address
+    ///
+    /// Merged on free, so the list stays short unless the region is fragmented
+    static ref VMALLOC_FREE_RANGES: Mutex<ArrayVec<[(u64, u64); 64]>> = {
+        let mut free_ranges = ArrayVec::new();
+        free_ranges.push((
+            VIRTUAL_MEMORY_ALLOCATIONS_START,
+            (VIRTUAL_MEMORY_ALLOCATIONS_END + 1 - VIRTUAL_MEMORY_ALLOCATIONS_START) / PAGE_SIZE as u64,
+        ));
+        Mutex::new(free_ranges)
+    };
+}
+
+/// Unmapped pages after every [vmalloc] allocation, overrun faults instead of corrupting the next allocation
+const VMALLOC_GUARD_PAGES: usize = 1;
+
+/// Allocates virtually contiguous memory in Virtual Memory Allocations region
+///
+/// Bootloader dynamic range is not used, bootloader placed kernel code, stack and boot info there.
+/// Every page is backed by a zeroed physical frame, frames are not physically contiguous.
+/// Allocation is followed by [VMALLOC_GUARD_PAGES] unmapped guard pages.
+///
+/// Freed by [vfree]
+pub fn vmalloc(pages: usize, page_table_flags: PageTableFlags) -> Result<VirtAddr, VmError> {
+    if pages == 0 {
+        return Err(VmError::ZeroSize);
+    }
+    let base = reserve_vmalloc_range(pages + VMALLOC_GUARD_PAGES)?;
+    for page in 0..pages {
+        let page_virt_addr = base + (page * PAGE_SIZE) as u64;
+        if let Err(err) = map_new_page(page_virt_addr, page_table_flags) {
+            // Roll back mapped pages, the whole reserved range is released
+            unmap_and_free_pages(base, page);
+            release_vmalloc_range(base, pages + VMALLOC_GUARD_PAGES);
+            return Err(err);
+        }
+    }
+    Ok(base)
+}
+
+/// Unmaps pages allocated by [vmalloc], frees their frames and the virtual range
+///
+/// pages must be the same as passed to [vmalloc]
+pub fn vfree(base: VirtAddr, pages: usize) {
+    assert!(
+        virtual_memory_region(base.as_u64()) == VirtualMemoryRegion::VirtualMemoryAllocations,
+        "vfree of address {base:?} not from vmalloc"
+    );
+    unmap_and_free_pages(base, pages);
+    release_vmalloc_range(base, pages + VMALLOC_GUARD_PAGES);
+}
+
+/// Unmaps first pages of [vmalloc] range and frees their frames, the virtual range stays reserved
+fn unmap_and_free_pages(base: VirtAddr, pages: usize) {
+    for page in 0..pages {
+        let page_virt_addr = base + (page * PAGE_SIZE) as u64;
+        if let Some(frame_phys_addr) = unmap_page(page_virt_addr) {
+            unsafe {
+                super::physical_memory_manager::free(frame_phys_addr);
+            }
+        }
+    }
+}
+
+/// Takes first fitting range from [VMALLOC_FREE_RANGES]
+fn reserve_vmalloc_range(pages: usize) -> Result<VirtAddr, VmError> {
+    let pages = pages as u64;
+    let mut free_ranges_lock = VMALLOC_FREE_RANGES.lock();
+    let index = free_ranges_lock
+        .iter()
+        .position(|(_, free_pages)| *free_pages >= pages)
+        .ok_or(VmError::OutOfVirtualSpace)?;
+    let (start, free_pages) = free_ranges_lock[index];
+    if free_pages == pages {
+        free_ranges_lock.remove(index);
+    } else {
+        free_ranges_lock[index] = (start + pages * PAGE_SIZE as u64, free_pages - pages);
+    }
+    Ok(VirtAddr::new(start))
+}
+
+/// Returns range to [VMALLOC_FREE_RANGES], merging it with neighbours
+fn release_vmalloc_range(base: VirtAddr, pages: usize) {
+    let (start, pages) = (base.as_u64(), pages as u64);
+    let end = start + pages * PAGE_SIZE as u64;
+    let mut free_ranges_lock = VMALLOC_FREE_RANGES.lock();
+    let index = free_ranges_lock.partition_point(|(free_start, _)| *free_start < start);
+    let merges_previous = index > 0 && {
+        let (previous_start, previous_pages) = free_ranges_lock[index - 1];
+        previous_start + previous_pages * PAGE_SIZE as u64 == start
+    };
+    let merges_next = free_ranges_lock
+        .get(index)
+        .is_some_and(|(next_start, _)| *next_start == end);
+    match (merges_previous, merges_next) {
+        (true, true) => {
+            let (_, next_pages) = free_ranges_lock.remove(index);
+            free_ranges_lock[index - 1].1 += pages + next_pages;
+        }
+        (true, false) => free_ranges_lock[index - 1].1 += pages,
+        (false, true) => {
+            let next_pages = free_ranges_lock[index].1;
+            free_ranges_lock[index] = (start, pages + next_pages);
+        }
+        (false, false) => {
+            if free_ranges_lock.try_insert(index, (start, pages)).is_some() {
+                // Range is leaked, virtual space is huge
+                log::warn!("vmalloc free ranges list is full, {pages} pages at {base:?} are lost");
+            }
+        }
+    }
+}
+
+/// Unmaps 4 KB page and flushes TLB for it
+///
+/// Returns physical address of the frame, None if the page was not mapped.
+/// Huge pages are not split, None is returned and the page stays mapped.
+/// Page tables are not freed. Frame is not freed, it's the caller's job.
+pub fn unmap_page(virt_addr: VirtAddr) -> Option<PhysAddr> {
+    let (entry, level) = leaf_page_table_entry(virt_addr).ok()?;
+    if level != PageTableLevel::One {
+        log::warn!("Can't unmap {virt_addr:?}, it's in a {level:?} level huge page");
+        return None;
+    }
+    let entry = unsafe { &mut *entry };
+    let phys_addr = entry.addr();
+    entry.set_unused();
+    tlb_shootdown(virt_addr);
+    Some(phys_addr)
+}
+
+/// Address invalidated by current [tlb_shootdown]
+static TLB_SHOOTDOWN_ADDR: AtomicU64 = AtomicU64::new(0);
+
+/// Number of cores which invalidated [TLB_SHOOTDOWN_ADDR]
+static TLB_SHOOTDOWN_ACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// One shootdown at a time, the address is global
+static TLB_SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Number of TLB shootdown IPIs handled by all cores
+static TLB_SHOOTDOWNS_HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+/// Invalidates page in TLBs of this core and other cores
+///
+/// Other cores are APs from [smp::online_aps], they get TLB shootdown IPI and the caller waits for all acknowledgments.
+/// The BSP doesn't handle the IPI, so an AP must not call it for now.
+pub fn tlb_shootdown(virt_addr: VirtAddr) {
+    tlb::flush(virt_addr);
+    let this_apic_id = apic::local_apic_id();
+    let targets = || smp::online_aps().filter(move |apic_id| *apic_id != this_apic_id);
+    if targets().next().is_none() {
+        return;
+    }
+
+    let _lock = TLB_SHOOTDOWN_LOCK.lock();
+    TLB_SHOOTDOWN_ADDR.store(virt_addr.as_u64(), Ordering::Release);
+    TLB_SHOOTDOWN_ACKS.store(0, Ordering::Release);
+    let mut expected_acks = 0;
+    for apic_id in targets() {
+        apic::send_ipi(apic_id, idt::TLB_SHOOTDOWN_IDT_VECTOR);
+        expected_acks += 1;
+    }
+    while TLB_SHOOTDOWN_ACKS.load(Ordering::Acquire) < expected_acks {
+        core::hint::spin_loop();
+    }
+}
+
+/// TLB shootdown IPI handler
+pub fn handle_tlb_shootdown() {
+    tlb::flush(VirtAddr::new(TLB_SHOOTDOWN_ADDR.load(Ordering::Acquire)));
+    TLB_SHOOTDOWNS_HANDLED.fetch_add(1, Ordering::Relaxed);
+    TLB_SHOOTDOWN_ACKS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Returns number of TLB shootdown IPIs handled by all cores
+pub fn tlb_shootdowns_handled() -> usize {
+    TLB_SHOOTDOWNS_HANDLED.load(Ordering::Relaxed)
+}
+
+/// Translates virtual address to physical address by walking page tables
+///
+/// Works for any mapped address, not only CPMM (e.g. for drivers giving buffers to hardware).
+/// Offset in 1 GB and 2 MB huge pages is taken into account.
+///
+/// Returns None if the address is not mapped
+pub fn translate(virt_addr: VirtAddr) -> Option<PhysAddr> {
+    let (entry, level) = leaf_page_table_entry(virt_addr).ok()?;
+    let offset = virt_addr.as_u64() & (level.entry_address_space_alignment() - 1);
+    Some(unsafe { (*entry).addr() } + offset)
+}
+
+/// Returns flags of the page (4 KB, 2 MB or 1 GB) mapping virtual address
+pub fn page_flags(virt_addr: VirtAddr) -> Result<PageTableFlags, VmError> {
+    let (entry, _) = leaf_page_table_entry(virt_addr)?;
+    Ok(unsafe { (*entry).flags() })
+}
+
+/// Enables no-execute pages (EFER.NXE), returns false if CPU doesn't support NX
+///
+/// Bootloader usually enables it already. APs copy EFER of the BSP.
+pub fn enable_nxe() -> bool {
+    if !cpu::features().nx {
+        log::warn!("NX not supported, all pages are executable");
+        return false;
+    }
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
+    true
+}
+
+/// ELF program header flags
+const ELF_SEGMENT_EXECUTABLE: u32 = 1;
+const ELF_SEGMENT_WRITABLE: u32 = 2;
+
+/// ELF PT_LOAD program header type
+const ELF_PT_LOAD: u32 = 1;
+
+/// Kernel image segment, virtual range and ELF flags
+#[derive(Debug, Copy, Clone, Default)]
+struct KernelSegment {
+    start: u64,
+    end: u64,
+    flags: u32,
+}
+
+/// Enforces W^X on kernel mappings
+///
+/// Kernel sections boundaries are taken from PT_LOAD program headers of the kernel ELF file
+/// (bootloader leaves it in memory, [boot_info.kernel_addr](bootloader_api::BootInfo::kernel_addr)),
+/// so no linker symbols are needed. Segments are page-aligned by the linker.
+/// - Non-executable segments (rodata, data, bss) get NO_EXECUTE, read-only segments (text, rodata) lose WRITABLE
+/// - Pages of dynamic range outside the kernel image (stack, boot info, ramdisk) get NO_EXECUTE
+///
+/// Sets CR0.WP, so read-only pages are protected from the kernel too.
+/// NX must be enabled by [enable_nxe], otherwise NO_EXECUTE is not set.
+/// Must be called before APs are started, TLB is flushed only on this CPU.
+///
+/// Must be called before boot info is released
+pub fn harden_kernel_mappings(boot_info: &bootloader_api::BootInfo) {
+    let nx_enabled = Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE);
+    let mut segments: ArrayVec<[KernelSegment; 16]> = ArrayVec::new();
+    let elf = virt_addr_in_cpmm_from_phys_addr(PhysAddr::new(boot_info.kernel_addr)).as_ptr::<u8>();
+    unsafe {
+        let program_headers_offset = elf.byte_add(0x20).cast::<u64>().read_unaligned();
+        let program_header_size = elf.byte_add(0x36).cast::<u16>().read_unaligned() as u64;
+        let program_headers_number = elf.byte_add(0x38).cast::<u16>().read_unaligned() as u64;
+        for index in 0..program_headers_number {
+            let program_header =
+                elf.byte_add((program_headers_offset + index * program_header_size) as usize);
+            if program_header.cast::<u32>().read_unaligned() != ELF_PT_LOAD {
+                continue;
+            }
+            let flags = program_header.byte_add(0x04).cast::<u32>().read_unaligned();
+            let virt_addr = program_header.byte_add(0x10).cast::<u64>().read_unaligned();
+            let memory_size = program_header.byte_add(0x28).cast::<u64>().read_unaligned();
+            let start = boot_info.kernel_image_offset + virt_addr;
+            segments.push(KernelSegment {
+                start: x86_64::align_down(start, PAGE_SIZE as u64),
+                end: x86_64::align_up(start + memory_size, PAGE_SIZE as u64),
+                flags,
+            });
+        }
+    }
+
+    // Page shared by segments gets the most permissive flags
+    let page_segments_flags = |page: u64| {
+        segments
+            .as_slice()
+            .iter()
+            .filter(|segment| (segment.start..segment.end).contains(&page))
+            .fold(0, |flags, segment| flags | segment.flags)
+    };
+    let mut changed_pages = 0;
+    let mut update_page = |page: VirtAddr, no_execute: bool, read_only: bool| {
+        let Ok((entry, _)) = leaf_page_table_entry(page) else {
+            return;
+        };
+        unsafe {
+            let mut flags = (*entry).flags();
+            let previous_flags = flags;
+            if no_execute && nx_enabled {
+                flags.insert(PageTableFlags::NO_EXECUTE);
+            }
+            if read_only {
+                flags.remove(PageTableFlags::WRITABLE);
+            }
+            if flags != previous_flags {
+                (*entry).set_flags(flags);
+                changed_pages += 1;
+            }
+        }
+    };
+    for segment in segments.as_slice() {
+        for page in (segment.start..segment.end).step_by(PAGE_SIZE) {
+            let flags = page_segments_flags(page);
+            update_page(
+                VirtAddr::new(page),
+                flags & ELF_SEGMENT_EXECUTABLE == 0,
+                flags & ELF_SEGMENT_WRITABLE == 0,
+            );
+        }
+    }
+
+    // Kernel pages of dynamic range are skipped (PIE kernel is placed there)
+    // Only leaf flags are changed, so it's safe to do while walking
+    walk_page_tables(&mut |item| {
+        if let PageTablesWalkItem::Page { virt_addr, .. } = item {
+            if virtual_memory_region(virt_addr.as_u64()) == VirtualMemoryRegion::Dynamic
+                && page_segments_flags(virt_addr.as_u64()) == 0
+            {
+                update_page(virt_addr, true, false);
+            }
+        }
+    });
+
+    unsafe {
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    tlb::flush_all();
+    log::info!(
+        "Kernel mappings hardened: {} segments, {changed_pages} pages changed{}",
+        segments.len(),
+        if nx_enabled { "" } else { ", NX is disabled" }
+    );
+}
+
+/// Item visited by [walk_page_tables]
+#[derive(Debug, Copy, Clone)]
+pub enum PageTablesWalkItem {
+    /// Frame of page table
+    PageTable(PhysAddr),
+    /// Mapped page (4 KB, 2 MB or 1 GB)
+    Page {
+        virt_addr: VirtAddr,
+        phys_addr: PhysAddr,
+        size: u64,
+    },
+}
+
+/// Calls visitor for every page table and every mapped page of current address space
+///
+/// Complete Physical Memory Mapping is skipped (except its page tables), it maps all physical memory
+pub fn walk_page_tables(visitor: &mut impl FnMut(PageTablesWalkItem)) {
+    let pml4_phys_addr = x86_64::registers::control::Cr3::read().0.start_address();
+    walk_page_table(pml4_phys_addr, PageTableLevel::Four, 0, visitor);
+}
+
+fn walk_page_table(
+    page_table_phys_addr: PhysAddr,
+    level: PageTableLevel,
+    virt_addr_base: u64,
+    visitor: &mut impl FnMut(PageTablesWalkItem),
+) {
+    visitor(PageTablesWalkItem::PageTable(page_table_phys_addr));
+    let page_table = page_table_in_cpmm(page_table_phys_addr);
+    let entry_size = level.entry_address_space_alignment();
+    for (index, entry) in unsafe { (*page_table).iter().enumerate() } {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        // Sign extension of the address
+        let virt_addr = VirtAddr::new_truncate(virt_addr_base + index as u64 * entry_size);
+        if is_leaf_entry(level, flags) {
+            if virtual_memory_region(virt_addr.as_u64())
+                != VirtualMemoryRegion::CompletePhysicalMemoryMapping
+            {
+                visitor(PageTablesWalkItem::Page {
+                    virt_addr,
+                    phys_addr: entry.addr(),
+                    size: entry_size,
+                });
+            }
+            continue;
+        }
+        walk_page_table(
+            entry.addr(),
+            level.next_lower_level().unwrap(),
+            virt_addr.as_u64() & 0x0000_FFFF_FFFF_FFFF,
+            visitor,
+        );
+    }
+}
+
+/// Walks page tables for virtual address and prints every level over serial
+///
+/// Prints index, entry physical address and flags for every level, and final physical address
+/// or the level at which translation failed.
+pub fn explain(virt_addr: VirtAddr) {
+    crate::serial_println!("Translation of {virt_addr:?}:");
+    let walk_result = walk_address(virt_addr, |level, entry| {
+        let flags = entry.flags();
+        crate::serial_println!(
+            "{level:?}: index {}, entry address 0x{:X}, flags {flags:?}{}",
+            u16::from(virt_addr.page_table_index(level)),
+            entry.addr().as_u64(),
+            if level != PageTableLevel::One && flags.contains(PageTableFlags::HUGE_PAGE) {
+                " (huge page)"
+            } else {
+                ""
+            }
+        );
+    });
+    match walk_result {
+        Ok((entry, level)) => {
+            let offset = virt_addr.as_u64() & (level.entry_address_space_alignment() - 1);
+            crate::serial_println!(
+                "Physical address: 0x{:X}",
+                unsafe { (*entry).addr() }.as_u64() + offset
+            );
+        }
+        Err((_, level)) => crate::serial_println!("Not mapped at level {level:?}"),
+    }
+}
@@ -0,0 +1,95 @@
+//! Per-CPU data
+//!
+//! Every core gets its own [CpuLocal] block, IA32_GS_BASE points to it, so [this_cpu] is a single gs-relative read.
+use crate::arch;
+use crate::interrupts::apic;
+#[cfg(debug_assertions)]
+use crate::interrupts::idt::InterruptNesting;
+use crate::memory_management::physical_memory_manager::{
+    self, MemoryZoneEnum, MemoryZonesAndPrioritySpecifier,
+};
+use crate::memory_management::virtual_memory_manager;
+use crate::memory_management::PAGE_SIZE;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Per-CPU block size
+const CPU_LOCAL_BLOCK_SIZE: usize = PAGE_SIZE;
+
+/// Zones priority of per-CPU blocks
+const CPU_LOCAL_ZONES_PRIORITY: &MemoryZonesAndPrioritySpecifier =
+    &[MemoryZoneEnum::High, MemoryZoneEnum::Dma32];
+
+/// Index of the next core calling [init]
+static NEXT_CPU_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// Per-CPU data block
+#[repr(C)]
+#[derive(Debug)]
+pub struct CpuLocal {
+    /// Address of this block, [this_cpu] reads it from gs:0
+    self_ptr: *const CpuLocal,
+    /// Local APIC ID
+    pub apic_id: u32,
+    /// 0 for the BSP, APs are numbered in start order
+    pub cpu_index: usize,
+    /// Interrupt nesting depth of this core
+    #[cfg(debug_assertions)]
+    pub interrupt_nesting: InterruptNesting,
+}
+
+const _: () = assert!(core::mem::size_of::<CpuLocal>() <= CPU_LOCAL_BLOCK_SIZE);
+
+/// Allocates per-CPU block of this core and loads it into GS base
+///
+/// Called once by every core, by the BSP first. Memory manager must be inited.
+pub fn init() {
+    let phys_addr =
+        unsafe { physical_memory_manager::alloc(CPU_LOCAL_ZONES_PRIORITY, CPU_LOCAL_BLOCK_SIZE) };
+    assert!(!phys_addr.is_null(), "Failed to allocate per-CPU block");
+    let virt_addr = virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(phys_addr);
+    let cpu_local = virt_addr.as_mut_ptr::<CpuLocal>();
+    unsafe {
+        cpu_local.write(CpuLocal {
+            self_ptr: cpu_local,
+            apic_id: apic::local_apic_id() as u32,
+            cpu_index: NEXT_CPU_INDEX.fetch_add(1, Ordering::Relaxed),
+            #[cfg(debug_assertions)]
+            interrupt_nesting: InterruptNesting::new(),
+        });
+    }
+    arch::set_gs_base(virt_addr);
+}
+
+/// Returns per-CPU block of this core
+///
+/// [init] must be called on this core
+#[inline]
+pub fn this_cpu() -> &'static CpuLocal {
+    let cpu_local: *const CpuLocal;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, gs:[0]",
+            out(reg) cpu_local,
+            options(nostack, preserves_flags, readonly)
+        );
+        &*cpu_local
+    }
+}
+
+/// Returns per-CPU block of this core, None if [init] hasn't been called on it yet
+///
+/// Used by code that may run before [init], e.g. interrupt handlers.
+/// GS base is trusted only if it's mapped and the block there points to itself.
+#[inline]
+pub fn try_this_cpu() -> Option<&'static CpuLocal> {
+    let gs_base = arch::get_gs_base();
+    if gs_base.is_null()
+        || !gs_base.is_aligned(core::mem::align_of::<CpuLocal>() as u64)
+        || virtual_memory_manager::translate(gs_base).is_none()
+    {
+        return None;
+    }
+    let cpu_local = gs_base.as_ptr::<CpuLocal>();
+    // self_ptr is the first field, its aligned read stays in the translated page
+    (unsafe { (*cpu_local).self_ptr } == cpu_local).then(|| unsafe { &*cpu_local })
+}
@@ -0,0 +1,138 @@
+//! Ramdisk loaded by the bootloader (image builder `--initrd`)
+//!
+//! The ramdisk is a CPIO archive in "newc" format (`find . | cpio -o -H newc`), files are read-only.
+//! Its pages stay mapped, so bootloader memory reclaim keeps them.
+use spin::Once;
+
+/// Ramdisk contents, None if there is no ramdisk
+static RAMDISK: Once<Option<&'static [u8]>> = Once::new();
+
+/// "newc" header magic
+const CPIO_NEWC_MAGIC: &[u8] = b"070701";
+
+/// Header size: magic and 13 8-digit hex fields
+const CPIO_NEWC_HEADER_SIZE: usize = 110;
+
+/// Name of the last entry
+const CPIO_TRAILER_NAME: &str = "TRAILER!!!";
+
+/// Header fields indexes (after magic)
+const CPIO_FILE_SIZE_FIELD: usize = 6;
+const CPIO_NAME_SIZE_FIELD: usize = 11;
+
+/// Locates the ramdisk
+///
+/// Must be called before boot info is released
+pub fn init(boot_info: &bootloader_api::BootInfo) {
+    let ramdisk = RAMDISK.call_once(|| {
+        let ramdisk_addr = boot_info.ramdisk_addr.into_option()?;
+        // SAFETY: bootloader mapped the ramdisk, mapping is never removed
+        Some(unsafe {
+            core::slice::from_raw_parts(ramdisk_addr as *const u8, boot_info.ramdisk_len as usize)
+        })
+    });
+    let Some(ramdisk) = ramdisk else {
+        log::info!("No ramdisk");
+        return;
+    };
+    log::info!("Ramdisk: {} bytes", ramdisk.len());
+    for entry in cpio_entries(ramdisk) {
+        match entry {
+            Ok((name, data)) => log::info!("Ramdisk file {name:?}: {} bytes", data.len()),
+            Err(err) => log::warn!("Ramdisk is not a valid CPIO newc archive: {err}"),
+        }
+    }
+}
+
+/// Returns ramdisk contents
+pub fn data() -> Option<&'static [u8]> {
+    *RAMDISK.get()?
+}
+
+/// Returns contents of ramdisk file
+///
+/// Leading "/" and "./" are ignored, "etc/config", "/etc/config" and "./etc/config" are the same file
+pub fn open(path: &str) -> Option<&'static [u8]> {
+    cpio_find(data()?, path)
+}
+
+/// Returns contents of archive file, see [open]
+pub fn cpio_find<'a>(archive: &'a [u8], path: &str) -> Option<&'a [u8]> {
+    let path = normalize_path(path);
+    cpio_entries(archive)
+        .map_while(Result::ok)
+        .find(|(name, _)| normalize_path(name) == path)
+        .map(|(_, data)| data)
+}
+
+fn normalize_path(path: &str) -> &str {
+    path.trim_start_matches("./").trim_start_matches('/')
+}
+
+/// Iterates over (name, data) of archive entries until the trailer, stops after the first error
+pub fn cpio_entries(archive: &[u8]) -> impl Iterator<Item = Result<(&str, &[u8]), &'static str>> {
+    let mut offset = 0;
+    let mut failed = false;
+    core::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let entry = cpio_entry(archive, offset);
+        match entry {
+            Ok(None) => None,
+            Ok(Some(entry)) => {
+                offset = entry.next_offset;
+                Some(Ok((entry.name, entry.data)))
+            }
+            Err(err) => {
+                failed = true;
+                Some(Err(err))
+            }
+        }
+    })
+}
+
+/// Archive entry and offset of the next one
+struct CpioEntry<'a> {
+    name: &'a str,
+    data: &'a [u8],
+    next_offset: usize,
+}
+
+/// Parses entry at offset, None for trailer
+fn cpio_entry(archive: &[u8], offset: usize) -> Result<Option<CpioEntry>, &'static str> {
+    let header = archive
+        .get(offset..offset + CPIO_NEWC_HEADER_SIZE)
+        .ok_or("truncated header")?;
+    if !header.starts_with(CPIO_NEWC_MAGIC) {
+        return Err("bad magic");
+    }
+    let field = |index: usize| -> Result<usize, &'static str> {
+        let start = CPIO_NEWC_MAGIC.len() + index * 8;
+        let digits = core::str::from_utf8(&header[start..start + 8]).map_err(|_| "bad field")?;
+        usize::from_str_radix(digits, 16).map_err(|_| "bad field")
+    };
+    let file_size = field(CPIO_FILE_SIZE_FIELD)?;
+    let name_size = field(CPIO_NAME_SIZE_FIELD)?;
+
+    // Name is NUL-terminated, header and name are padded to 4 bytes
+    let name_start = offset + CPIO_NEWC_HEADER_SIZE;
+    let name = archive
+        .get(name_start..name_start + name_size)
+        .and_then(|name| name.strip_suffix(b"\0"))
+        .ok_or("truncated name")?;
+    let name = core::str::from_utf8(name).map_err(|_| "name is not UTF-8")?;
+    if name == CPIO_TRAILER_NAME {
+        return Ok(None);
+    }
+    let data_start = (name_start + name_size).next_multiple_of(4);
+    let data = archive
+        .get(data_start..data_start + file_size)
+        .ok_or("truncated data")?;
+    let next_offset = (data_start + file_size).next_multiple_of(4);
+    Ok(Some(CpioEntry {
+        name,
+        data,
+        next_offset,
+    }))
+}
@@ -0,0 +1,2015 @@
+//! Boot self-tests, run by kmain after initialization phases with `selftest` feature
+//!
+//! Checks panic on failure. Some of them stop the kernel on purpose, they have their own features.
+use crate::memory_management::virtual_memory_manager;
+use crate::memory_management::PAGE_SIZE;
+use crate::{
+    acpi, arch, backtrace, bitmap, com_ports, cpu, drivers, gdt, interrupts, memory_management,
+    percpu, ramdisk, serial_debug, sync, timers, util,
+};
+
+/// Logger and serial output checks, COM ports and logger must be inited
+pub fn logging() {
+    // Check log sinks, counting sink gets records only while it counts
+    {
+        use serial_debug::serial_logger;
+        let is_check_record =
+            |record: &log::Record| record.args().as_str() == Some("Log sink check");
+        let records = serial_logger::count_records(is_check_record, || {
+            log::info!("Log sink check");
+        });
+        assert_eq!(records, Ok(1), "Log sink didn't get the record");
+        // The sink is disabled between counts, the record is not counted by the next one
+        log::info!("Log sink check");
+        let records = serial_logger::count_records(is_check_record, || {});
+        assert_eq!(records, Ok(0), "Log sink got the record while disabled");
+    }
+    // Check log level filter, Warn suppresses info records
+    {
+        use serial_debug::serial_logger;
+        let previous_level = log::max_level();
+        log::set_max_level(log::LevelFilter::Warn);
+        let info_records = serial_logger::count_records(
+            |record| record.args().as_str() == Some("Log level check"),
+            || log::info!("Log level check"),
+        );
+        let warn_records = serial_logger::count_records(
+            |record| record.args().as_str() == Some("Log level check, the warning is expected"),
+            || log::warn!("Log level check, the warning is expected"),
+        );
+        log::set_max_level(previous_level);
+        assert_eq!(info_records, Ok(0), "Info record passed Warn filter");
+        assert_eq!(warn_records, Ok(1), "Warn record didn't pass Warn filter");
+    }
+
+    // Check printing while COM1 is locked by this CPU (as an interrupt handler does if it interrupts printing),
+    // it must not deadlock
+    {
+        use com_ports::SerialChannel;
+        use serial_debug::serial_printer;
+        let fallbacks_before = serial_printer::lock_free_fallbacks();
+        let com1_lock = SerialChannel::Com1.lock();
+        assert!(
+            SerialChannel::Com1.is_locked_by_this_cpu(),
+            "COM1 owner is not recorded"
+        );
+        crate::serial_println!("Printed while COM1 is locked");
+        log::info!("Logged while COM1 is locked");
+        drop(com1_lock);
+        assert!(
+            !SerialChannel::Com1.is_locked_by_this_cpu(),
+            "COM1 owner is not cleared"
+        );
+        assert!(
+            serial_printer::lock_free_fallbacks() > fallbacks_before,
+            "Re-entry on COM1 lock owner was not detected"
+        );
+        // Unlocked port is locked normally
+        let fallbacks_before = serial_printer::lock_free_fallbacks();
+        crate::serial_println!("Printed with COM1 lock");
+        assert_eq!(
+            serial_printer::lock_free_fallbacks(),
+            fallbacks_before,
+            "COM1 lock was bypassed without re-entry"
+        );
+    }
+}
+
+/// GDT, FS/GS base and TSS checks, GDT, FSGSBASE and IDT must be inited
+pub fn cpu() {
+    // Check FS/GS base, written values are read back and swapgs exchanges GS bases, then bases are restored
+    {
+        use x86_64::VirtAddr;
+        let fs_base = arch::get_fs_base();
+        let gs_base = arch::get_gs_base();
+        let kernel_gs_base = arch::get_kernel_gs_base();
+        let test_fs_base = VirtAddr::new(0xFFFF_8000_1234_5000);
+        let test_gs_base = VirtAddr::new(0x0000_7FFF_ABCD_E000);
+        let test_kernel_gs_base = VirtAddr::new(0xFFFF_FFFF_8765_4000);
+        arch::set_fs_base(test_fs_base);
+        arch::set_gs_base(test_gs_base);
+        arch::set_kernel_gs_base(test_kernel_gs_base);
+        assert_eq!(arch::get_fs_base(), test_fs_base, "Wrong FS base");
+        assert_eq!(arch::get_gs_base(), test_gs_base, "Wrong GS base");
+        assert_eq!(
+            arch::get_kernel_gs_base(),
+            test_kernel_gs_base,
+            "Wrong kernel GS base"
+        );
+        unsafe { arch::swapgs() };
+        assert_eq!(
+            arch::get_gs_base(),
+            test_kernel_gs_base,
+            "swapgs didn't swap GS base"
+        );
+        assert_eq!(
+            arch::get_kernel_gs_base(),
+            test_gs_base,
+            "swapgs didn't swap kernel GS base"
+        );
+        unsafe { arch::swapgs() };
+        arch::set_fs_base(fs_base);
+        arch::set_gs_base(gs_base);
+        arch::set_kernel_gs_base(kernel_gs_base);
+        assert_eq!(arch::get_gs_base(), gs_base, "GS base is not restored");
+    }
+
+    // Check TSS, Double Fault stack is set and it isn't the current stack
+    {
+        use x86_64::VirtAddr;
+        let double_fault_stack_top = gdt::double_fault_stack_top().expect("TSS is not loaded");
+        assert!(
+            !double_fault_stack_top.is_null() && double_fault_stack_top.is_aligned(16u64),
+            "Wrong Double Fault stack top {double_fault_stack_top:?}"
+        );
+        let local = 0u8;
+        assert!(
+            !(double_fault_stack_top - gdt::DOUBLE_FAULT_STACK_SIZE as u64..double_fault_stack_top)
+                .contains(&VirtAddr::from_ptr(&raw const local)),
+            "Kernel runs on Double Fault stack"
+        );
+        #[cfg(feature = "stack-overflow-check")]
+        {
+            #[allow(unconditional_recursion)]
+            fn recurse(depth: u64) -> u64 {
+                // Frame is not optimized out, the call is not a tail call
+                let frame = core::hint::black_box([depth; 64]);
+                recurse(depth + 1) + frame[0]
+            }
+            recurse(0);
+        }
+    }
+}
+
+/// Memory management checks, memory manager, W^X of kernel mappings and ramdisk must be inited
+pub fn memory(nx_enabled: bool) {
+    // Check bitmap, clear bit and clear run search across word boundaries and in partial last word
+    {
+        use bitmap::{ArrayBitmap, Bitmap};
+        let mut bitmap = ArrayBitmap::<2>::new_array();
+        assert_eq!(bitmap.len(), 128);
+        assert_eq!(bitmap.find_first_clear(), Some(0));
+        bitmap.set_range(0, 63);
+        assert_eq!(bitmap.find_first_clear(), Some(63));
+        bitmap.set(63);
+        assert!(bitmap.test(63), "Bitmap bit is not set");
+        assert_eq!(bitmap.find_first_clear(), Some(64));
+        bitmap.set_range(64, 64);
+        assert_eq!(bitmap.find_first_clear(), None);
+        assert_eq!(bitmap.find_first_clear_run(1), None);
+        // Run crosses the word boundary
+        bitmap.clear_range(60, 8);
+        assert!(
+            !bitmap.test(63) && !bitmap.test(64),
+            "Bitmap bits are not cleared"
+        );
+        assert_eq!(bitmap.find_first_clear(), Some(60));
+        assert_eq!(bitmap.find_first_clear_run(8), Some(60));
+        assert_eq!(bitmap.find_first_clear_run(9), None);
+        // Shorter run before a longer one is skipped
+        bitmap.clear(10);
+        assert_eq!(bitmap.find_first_clear_run(1), Some(10));
+        assert_eq!(bitmap.find_first_clear_run(2), Some(60));
+        // Whole clear word continues the run
+        bitmap.clear_range(64, 64);
+        assert_eq!(bitmap.find_first_clear_run(68), Some(60));
+        assert_eq!(bitmap.find_first_clear_run(69), None);
+
+        // Bits past the length in the last word are ignored
+        let mut words = [u64::MAX; 2];
+        let mut bitmap = Bitmap::new_in(&mut words[..], 100);
+        assert_eq!(bitmap.find_first_clear_run(100), Some(0));
+        assert_eq!(bitmap.find_first_clear_run(101), None);
+        bitmap.set_range(0, 100);
+        assert_eq!(bitmap.find_first_clear(), None);
+        bitmap.clear(99);
+        assert_eq!(bitmap.find_first_clear(), Some(99));
+        assert_eq!(bitmap.find_first_clear_run(1), Some(99));
+        assert_eq!(bitmap.find_first_clear_run(2), None);
+    }
+
+    // Check kernel pages flags: code is read-only, rodata is read-only and not executable, data and stack are not executable
+    {
+        use x86_64::structures::paging::PageTableFlags;
+        use x86_64::VirtAddr;
+        static DATA: core::sync::atomic::AtomicU8 = core::sync::atomic::AtomicU8::new(0);
+        static RODATA: [u8; 4] = *b"rodt";
+        let stack_variable = 0u8;
+        let flags = |address: u64| {
+            virtual_memory_manager::page_flags(VirtAddr::new(address))
+                .expect("Kernel page is not mapped")
+        };
+        let code_flags = flags(crate::kmain as usize as u64);
+        let rodata_flags = flags(core::ptr::addr_of!(RODATA) as u64);
+        let data_flags = flags(core::ptr::addr_of!(DATA) as u64);
+        let stack_flags = flags(core::ptr::addr_of!(stack_variable) as u64);
+        assert!(
+            !code_flags.intersects(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE),
+            "Kernel code page flags {code_flags:?}"
+        );
+        assert!(
+            !rodata_flags.contains(PageTableFlags::WRITABLE),
+            "Kernel rodata page flags {rodata_flags:?}"
+        );
+        if nx_enabled {
+            for flags in [rodata_flags, data_flags, stack_flags] {
+                assert!(
+                    flags.contains(PageTableFlags::NO_EXECUTE),
+                    "Kernel data page is executable: {flags:?}"
+                );
+            }
+        }
+        #[cfg(feature = "nx-fault-check")]
+        unsafe {
+            // ret
+            static mut DATA_CODE: [u8; 1] = [0xC3];
+            let function: extern "C" fn() =
+                core::mem::transmute(core::ptr::addr_of!(DATA_CODE) as *const u8);
+            function();
+        }
+    }
+
+    // Check CPIO parser on an archive of two files
+    {
+        const ARCHIVE: &[u8] = concat!(
+            "07070100000001000081A40000000000000000000000010000000000000006",
+            "000000000000000000000000000000000000000A00000000hello.txt\0Hello\n\0\0",
+            "07070100000002000081A40000000000000000000000010000000000000008",
+            "000000000000000000000000000000000000000B00000000etc/config\0\0\0\0log=info",
+            "07070100000000000000000000000000000000000000010000000000000000",
+            "000000000000000000000000000000000000000B00000000TRAILER!!!\0\0\0\0",
+        )
+        .as_bytes();
+        assert_eq!(
+            ramdisk::cpio_find(ARCHIVE, "hello.txt"),
+            Some(&b"Hello\n"[..]),
+            "CPIO parser is broken"
+        );
+        assert_eq!(
+            ramdisk::cpio_find(ARCHIVE, "/etc/config"),
+            Some(&b"log=info"[..]),
+            "CPIO parser is broken"
+        );
+        assert_eq!(ramdisk::cpio_find(ARCHIVE, "TRAILER!!!"), None);
+        assert_eq!(ramdisk::cpio_entries(ARCHIVE).count(), 2);
+    }
+
+    // Check global allocator
+    let boxed_value = alloc::boxed::Box::new(0xDEAD_BEEF_u64);
+    assert_eq!(*boxed_value, 0xDEAD_BEEF, "Global allocator is broken");
+    let mut vector = alloc::vec::Vec::new();
+    vector.extend(0..1024_u32);
+    assert_eq!(
+        vector.iter().sum::<u32>(),
+        1023 * 1024 / 2,
+        "Global allocator is broken"
+    );
+    drop((boxed_value, vector));
+    // Sizes which are not page-aligned powers of two
+    for size in [3000, 4097, 12_345, 100_000, 300_000, 1_000_001] {
+        let mut buffer = alloc::vec![0xA5_u8; size];
+        buffer[size - 1] = 0x5A;
+        assert!(
+            buffer[0] == 0xA5 && buffer[size - 1] == 0x5A,
+            "Global allocator is broken"
+        );
+    }
+    // Check GeneralPurposeAllocator grow and shrink, contents survive every resize
+    {
+        use memory_management::general_purpose_allocator::GeneralPurposeAllocator;
+        let mut vector = alloc::vec::Vec::new_in(GeneralPurposeAllocator);
+        for value in 0..100_000_u32 {
+            vector.push(value);
+        }
+        vector.truncate(1000);
+        vector.shrink_to_fit();
+        vector.extend(1000..2000_u32);
+        assert!(
+            vector.iter().copied().eq(0..2000_u32),
+            "GeneralPurposeAllocator realloc lost contents"
+        );
+
+        // Alignment above dlmalloc's own survives resizes
+        use core::alloc::{Allocator, Layout};
+        const ALIGN: usize = 256;
+        let mut layout = Layout::from_size_align(64, ALIGN).unwrap();
+        let mut ptr = GeneralPurposeAllocator
+            .allocate(layout)
+            .expect("Allocation failed")
+            .cast::<u8>();
+        unsafe { ptr.write_bytes(0x3C, layout.size()) };
+        for new_size in [8192, 100_000, 128, 4096, 64] {
+            let new_layout = Layout::from_size_align(new_size, ALIGN).unwrap();
+            let new_ptr = unsafe {
+                if new_size > layout.size() {
+                    GeneralPurposeAllocator.grow(ptr, layout, new_layout)
+                } else {
+                    GeneralPurposeAllocator.shrink(ptr, layout, new_layout)
+                }
+            }
+            .expect("Resize failed")
+            .cast::<u8>();
+            assert!(
+                new_ptr.as_ptr() as usize % new_layout.align() == 0,
+                "GeneralPurposeAllocator realloc broke alignment"
+            );
+            let kept_size = layout.size().min(new_size);
+            let bytes = unsafe { core::slice::from_raw_parts(new_ptr.as_ptr(), kept_size) };
+            assert!(
+                bytes.iter().all(|&byte| byte == 0x3C),
+                "GeneralPurposeAllocator realloc lost contents"
+            );
+            unsafe { new_ptr.write_bytes(0x3C, new_size) };
+            (ptr, layout) = (new_ptr, new_layout);
+        }
+        unsafe { GeneralPurposeAllocator.deallocate(ptr, layout) };
+    }
+
+    // Check aligned physical allocation
+    unsafe {
+        use memory_management::physical_memory_manager::{self, MemoryZoneEnum};
+        const ALIGN: usize = 2 * 1024 * 1024;
+        let phys_addr = physical_memory_manager::alloc_aligned(
+            &[MemoryZoneEnum::High, MemoryZoneEnum::Dma32],
+            PAGE_SIZE,
+            ALIGN,
+        );
+        assert!(
+            !phys_addr.is_null() && phys_addr.is_aligned(ALIGN as u64),
+            "Aligned physical allocation is broken"
+        );
+        physical_memory_manager::free(phys_addr);
+    }
+
+    // Check allocation tracking, every page of a block is allocated until the block is freed
+    unsafe {
+        use memory_management::physical_memory_manager::{self, MemoryZoneEnum};
+        use x86_64::PhysAddr;
+        const BLOCK_SIZE: usize = 4 * PAGE_SIZE;
+        let phys_addr = physical_memory_manager::alloc(
+            &[MemoryZoneEnum::High, MemoryZoneEnum::Dma32],
+            BLOCK_SIZE,
+        );
+        assert!(!phys_addr.is_null(), "Physical allocation failed");
+        for offset in (0..BLOCK_SIZE as u64).step_by(PAGE_SIZE / 2) {
+            assert!(
+                physical_memory_manager::is_allocated(phys_addr + offset),
+                "Page of allocated block at +0x{offset:X} is free"
+            );
+        }
+        physical_memory_manager::free(phys_addr);
+        for offset in (0..BLOCK_SIZE as u64).step_by(PAGE_SIZE) {
+            assert!(
+                !physical_memory_manager::is_allocated(phys_addr + offset),
+                "Page of freed block at +0x{offset:X} is allocated"
+            );
+        }
+        assert!(
+            !physical_memory_manager::is_allocated(PhysAddr::new(1 << 51)),
+            "Page out of zones is allocated"
+        );
+    }
+
+    // Check free pages cache, freed page is reused first, refill flushes the cache and no memory is lost
+    unsafe {
+        use memory_management::physical_memory_manager::{self, MemoryZoneEnum};
+        const ZONE: &[MemoryZoneEnum] = &[MemoryZoneEnum::Dma32];
+        // More than the cache holds
+        const PAGES: usize = 200;
+        let free_size_before = physical_memory_manager::zone_free_size(MemoryZoneEnum::Dma32);
+        let page = physical_memory_manager::alloc(ZONE, PAGE_SIZE);
+        assert!(!page.is_null(), "Page allocation failed");
+        physical_memory_manager::free(page);
+        assert_eq!(
+            physical_memory_manager::alloc(ZONE, PAGE_SIZE),
+            page,
+            "Freed page is not reused from free pages cache"
+        );
+        physical_memory_manager::free(page);
+        let mut pages = [x86_64::PhysAddr::zero(); PAGES];
+        for page in pages.iter_mut() {
+            *page = physical_memory_manager::alloc(ZONE, PAGE_SIZE);
+            assert!(!page.is_null(), "Page allocation failed");
+        }
+        for page in pages {
+            physical_memory_manager::free(page);
+        }
+        assert_eq!(
+            physical_memory_manager::zone_free_size(MemoryZoneEnum::Dma32),
+            free_size_before,
+            "Free pages cache lost memory"
+        );
+        // Cache is refilled from buddy allocator after it's drained
+        for page in pages.iter_mut() {
+            *page = physical_memory_manager::alloc(ZONE, PAGE_SIZE);
+            assert!(!page.is_null(), "Page allocation failed");
+        }
+        for page in pages {
+            physical_memory_manager::free(page);
+        }
+        assert_eq!(
+            physical_memory_manager::zone_free_size(MemoryZoneEnum::Dma32),
+            free_size_before,
+            "Free pages cache lost memory after refill"
+        );
+    }
+
+    // Check out of memory diagnostics, small standalone zone is exhausted, then everything is freed
+    {
+        use core::alloc::Layout;
+        use memory_management::physical_memory_manager::{self, StandaloneZone};
+        use serial_debug::serial_logger;
+        const ARENA_SIZE: usize = 16 * PAGE_SIZE;
+        let metadata_size = StandaloneZone::metadata_size(ARENA_SIZE, PAGE_SIZE)
+            .expect("Failed to calculate standalone zone metadata size");
+        let metadata_layout = Layout::from_size_align(metadata_size, 8).unwrap();
+        let arena_layout = Layout::from_size_align(ARENA_SIZE, PAGE_SIZE).unwrap();
+        let metadata = unsafe { alloc::alloc::alloc(metadata_layout) };
+        let arena = unsafe { alloc::alloc::alloc(arena_layout) };
+        assert!(!metadata.is_null() && !arena.is_null(), "Allocation failed");
+        let mut zone = unsafe { StandaloneZone::new(metadata, arena, ARENA_SIZE, PAGE_SIZE) }
+            .expect("Failed to create standalone zone");
+        assert_eq!(zone.free_size(), ARENA_SIZE, "Standalone zone is not free");
+        assert_eq!(zone.largest_free_block(), ARENA_SIZE);
+
+        // Fragmented: every second page is allocated
+        let mut pages = alloc::vec::Vec::new();
+        while let Some(page) = core::ptr::NonNull::new(zone.alloc(PAGE_SIZE)) {
+            pages.push(page);
+        }
+        for page in pages.iter().skip(1).step_by(2) {
+            unsafe { zone.free(page.as_ptr(), 1) };
+        }
+        let is_oom_record =
+            |record: &log::Record| record.target() == physical_memory_manager::OOM_LOG_TARGET;
+        let oom_records = serial_logger::count_records(is_oom_record, || {
+            assert!(
+                zone.alloc(2 * PAGE_SIZE).is_null(),
+                "Fragmented zone allocated 2 pages"
+            );
+        });
+        assert_eq!(
+            oom_records,
+            Ok(cfg!(feature = "oom-diagnostics") as usize),
+            "Out of memory diagnostics are wrong"
+        );
+        assert_eq!(zone.free_size(), ARENA_SIZE / 2);
+        assert_eq!(
+            zone.largest_free_block(),
+            PAGE_SIZE,
+            "Fragmentation is not visible"
+        );
+
+        for page in pages.iter().step_by(2) {
+            unsafe { zone.free(page.as_ptr(), 1) };
+        }
+        assert_eq!(
+            zone.free_size(),
+            ARENA_SIZE,
+            "Standalone zone memory is lost after exhaustion"
+        );
+        unsafe {
+            alloc::alloc::dealloc(arena, arena_layout);
+            alloc::alloc::dealloc(metadata, metadata_layout);
+        }
+    }
+
+    // Check minimum block size of 4 pages, metadata is smaller and every allocation takes a whole block
+    {
+        use core::alloc::Layout;
+        use memory_management::physical_memory_manager::StandaloneZone;
+        const ARENA_SIZE: usize = 16 * PAGE_SIZE;
+        const MIN_BLOCK_SIZE: usize = 4 * PAGE_SIZE;
+        let metadata_size = StandaloneZone::metadata_size(ARENA_SIZE, MIN_BLOCK_SIZE)
+            .expect("Failed to calculate standalone zone metadata size");
+        assert!(
+            metadata_size < StandaloneZone::metadata_size(ARENA_SIZE, PAGE_SIZE).unwrap(),
+            "Bigger minimum block doesn't reduce metadata"
+        );
+        let metadata_layout = Layout::from_size_align(metadata_size, 8).unwrap();
+        let arena_layout = Layout::from_size_align(ARENA_SIZE, MIN_BLOCK_SIZE).unwrap();
+        let metadata = unsafe { alloc::alloc::alloc(metadata_layout) };
+        let arena = unsafe { alloc::alloc::alloc(arena_layout) };
+        assert!(!metadata.is_null() && !arena.is_null(), "Allocation failed");
+        let mut zone = unsafe { StandaloneZone::new(metadata, arena, ARENA_SIZE, MIN_BLOCK_SIZE) }
+            .expect("Failed to create standalone zone");
+
+        // Page and two pages both take a block
+        let page = zone.alloc(PAGE_SIZE);
+        let two_pages = zone.alloc(2 * PAGE_SIZE);
+        for ptr in [page, two_pages] {
+            assert!(!ptr.is_null(), "Standalone zone allocation failed");
+            assert_eq!(
+                (ptr as usize - arena as usize) % MIN_BLOCK_SIZE,
+                0,
+                "Allocation is not aligned to minimum block"
+            );
+        }
+        assert_eq!(zone.free_size(), ARENA_SIZE - 2 * MIN_BLOCK_SIZE);
+        let mut blocks = alloc::vec![page, two_pages];
+        while let Some(block) = core::ptr::NonNull::new(zone.alloc(PAGE_SIZE)) {
+            blocks.push(block.as_ptr());
+        }
+        assert_eq!(
+            blocks.len(),
+            ARENA_SIZE / MIN_BLOCK_SIZE,
+            "Wrong allocation granularity"
+        );
+        unsafe {
+            zone.free(two_pages, 2);
+            for &block in blocks.iter().filter(|&&block| block != two_pages) {
+                zone.free(block, 1);
+            }
+        }
+        assert_eq!(
+            zone.free_size(),
+            ARENA_SIZE,
+            "Standalone zone memory is lost"
+        );
+        unsafe {
+            alloc::alloc::dealloc(arena, arena_layout);
+            alloc::alloc::dealloc(metadata, metadata_layout);
+        }
+    }
+
+    // Check zones split of fake memory map, HIGH zone ends with CPMM window (16 TB)
+    {
+        use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+        use memory_management::physical_memory_manager::{
+            split_memory_region_by_zones, zone_of, MemoryZoneEnum,
+        };
+        use x86_64::PhysAddr;
+        const TB: u64 = 1 << 40;
+        let region = |start, end, kind| MemoryRegion { start, end, kind };
+        let page = |addr| PhysAddr::new(addr);
+        let fake_memory_map = [
+            (
+                region(0x10_0000, 0x800_0000, MemoryRegionKind::Usable),
+                alloc::vec![
+                    (MemoryZoneEnum::IsaDma, page(0x10_0000), page(0xFF_F000)),
+                    (MemoryZoneEnum::Dma32, page(0x100_0000), page(0x7FF_F000)),
+                ],
+            ),
+            (
+                region(0xC000_0000, 0x2_0000_0000, MemoryRegionKind::Usable),
+                alloc::vec![
+                    (MemoryZoneEnum::Dma32, page(0xC000_0000), page(0xFFFF_F000)),
+                    (
+                        MemoryZoneEnum::High,
+                        page(0x1_0000_0000),
+                        page(0x1_FFFF_F000)
+                    ),
+                ],
+            ),
+            // Above 1 TB
+            (
+                region(TB, TB + TB / 2, MemoryRegionKind::Usable),
+                alloc::vec![(MemoryZoneEnum::High, page(TB), page(TB + TB / 2 - 0x1000))],
+            ),
+            (
+                region(2 * TB, 3 * TB, MemoryRegionKind::UnknownUefi(0)),
+                alloc::vec![],
+            ),
+            // Crosses the end of HIGH zone, the rest is dropped
+            (
+                region(
+                    16 * TB - 0x10_0000,
+                    16 * TB + 0x10_0000,
+                    MemoryRegionKind::Usable,
+                ),
+                alloc::vec![(
+                    MemoryZoneEnum::High,
+                    page(16 * TB - 0x10_0000),
+                    page(16 * TB - 0x1000)
+                )],
+            ),
+            (
+                region(32 * TB, 33 * TB, MemoryRegionKind::Usable),
+                alloc::vec![],
+            ),
+        ];
+        for (memory_region, zone_regions) in fake_memory_map {
+            assert_eq!(
+                split_memory_region_by_zones(&memory_region),
+                zone_regions,
+                "Memory region {memory_region:?} is split incorrectly"
+            );
+            for (zone, first_page, last_page) in zone_regions {
+                assert_eq!(
+                    zone_of(first_page),
+                    Some(zone),
+                    "Wrong zone of {first_page:?}"
+                );
+                assert_eq!(
+                    zone_of(last_page),
+                    Some(zone),
+                    "Wrong zone of {last_page:?}"
+                );
+            }
+        }
+        assert_eq!(
+            zone_of(page(16 * TB)),
+            None,
+            "Memory above HIGH zone has a zone"
+        );
+    }
+
+    // Check memory map without usable memory in 1-16 MB, ISA DMA zone gets nothing and its allocation error is distinct
+    {
+        use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+        use memory_management::physical_memory_manager::{
+            self, split_memory_region_by_zones, IsaDmaAllocError, MemoryZoneEnum,
+        };
+        let fake_memory_map = [
+            MemoryRegion {
+                start: 0,
+                end: 0x9_F000,
+                kind: MemoryRegionKind::Usable,
+            },
+            // Firmware reserves all of 1-16 MB
+            MemoryRegion {
+                start: 0x9_F000,
+                end: 0x100_0000,
+                kind: MemoryRegionKind::UnknownBios(2),
+            },
+            MemoryRegion {
+                start: 0x100_0000,
+                end: 0x8000_0000,
+                kind: MemoryRegionKind::Usable,
+            },
+        ];
+        assert!(
+            !fake_memory_map
+                .iter()
+                .flat_map(split_memory_region_by_zones)
+                .any(|(zone, _, _)| zone == MemoryZoneEnum::IsaDma),
+            "Reserved or conventional memory went to ISA DMA zone"
+        );
+
+        let result = unsafe { physical_memory_manager::alloc_isa_dma(PAGE_SIZE) };
+        if physical_memory_manager::isa_dma_available() {
+            let phys_addr = result.expect("ISA DMA allocation failed");
+            unsafe { physical_memory_manager::free(phys_addr) };
+        } else {
+            assert_eq!(result, Err(IsaDmaAllocError::Unavailable));
+        }
+        assert_ne!(
+            alloc::format!("{}", IsaDmaAllocError::Unavailable),
+            alloc::format!("{}", IsaDmaAllocError::OutOfMemory),
+            "ISA DMA errors are reported the same way"
+        );
+    }
+
+    // Check vmalloc, 3 pages with a pattern
+    {
+        use x86_64::structures::paging::PageTableFlags;
+        const PAGES: usize = 3;
+        let base = virtual_memory_manager::vmalloc(PAGES, PageTableFlags::WRITABLE)
+            .expect("vmalloc failed");
+        let words = unsafe {
+            core::slice::from_raw_parts_mut(base.as_mut_ptr::<u64>(), PAGES * PAGE_SIZE / 8)
+        };
+        words
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, word)| *word = i as u64);
+        assert!(
+            words.iter().enumerate().all(|(i, word)| *word == i as u64),
+            "vmalloc memory is broken"
+        );
+        // Single address walk and full walk see the same mapping
+        let second_page = base + PAGE_SIZE as u64;
+        let frame = virtual_memory_manager::translate(second_page + 8)
+            .expect("vmalloc page is not translated");
+        assert_eq!(
+            frame.as_u64() % PAGE_SIZE as u64,
+            8,
+            "Wrong translation offset"
+        );
+        let mut walk_frame = None;
+        virtual_memory_manager::walk_page_tables(&mut |item| {
+            if let virtual_memory_manager::PageTablesWalkItem::Page {
+                virt_addr,
+                phys_addr,
+                size,
+            } = item
+            {
+                if virt_addr == second_page {
+                    walk_frame = Some((phys_addr, size));
+                }
+            }
+        });
+        assert_eq!(
+            walk_frame,
+            Some((frame.align_down(PAGE_SIZE as u64), PAGE_SIZE as u64)),
+            "Page tables walk and translate disagree"
+        );
+        virtual_memory_manager::explain(second_page);
+        virtual_memory_manager::vfree(base, PAGES);
+        virtual_memory_manager::explain(second_page);
+        assert_eq!(
+            virtual_memory_manager::vmalloc(0, PageTableFlags::WRITABLE),
+            Err(virtual_memory_manager::VmError::ZeroSize),
+            "Zero size vmalloc is not rejected"
+        );
+    }
+    // Check cache type change, pages are toggled between WB and UC, PAT/PCD/PWT reach the page table entries
+    {
+        use virtual_memory_manager::CacheType;
+        use x86_64::structures::paging::PageTableFlags;
+        const PAGES: usize = 2;
+        let base = virtual_memory_manager::vmalloc(PAGES, PageTableFlags::WRITABLE)
+            .expect("vmalloc failed");
+        unsafe { base.as_mut_ptr::<u64>().write_volatile(0xCAC4E) };
+        let check_pages = |cache_type: CacheType, pcd_pwt: PageTableFlags| {
+            for page_index in 0..PAGES {
+                let page = base + (page_index * PAGE_SIZE) as u64;
+                let flags = virtual_memory_manager::page_flags(page).expect("Page is not mapped");
+                assert_eq!(
+                    flags & (PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH),
+                    pcd_pwt,
+                    "Wrong PCD/PWT bits of {cache_type:?} page"
+                );
+                assert_eq!(
+                    virtual_memory_manager::cache_type(page),
+                    Ok(cache_type),
+                    "Wrong cache type of page"
+                );
+            }
+        };
+        check_pages(CacheType::WriteBack, PageTableFlags::empty());
+        for (cache_type, pcd_pwt) in [
+            (
+                CacheType::Uncacheable,
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            ),
+            (CacheType::WriteBack, PageTableFlags::empty()),
+            // Selected by PAT bit only
+            (CacheType::WriteCombining, PageTableFlags::empty()),
+            (CacheType::WriteBack, PageTableFlags::empty()),
+        ] {
+            if cache_type == CacheType::WriteCombining && !cpu::features().pat {
+                continue;
+            }
+            virtual_memory_manager::set_cache_type(base, PAGES, cache_type)
+                .expect("Failed to set cache type");
+            check_pages(cache_type, pcd_pwt);
+            assert_eq!(
+                unsafe { base.as_ptr::<u64>().read_volatile() },
+                0xCAC4E,
+                "Page data changed with cache type"
+            );
+        }
+        virtual_memory_manager::vfree(base, PAGES);
+        assert_eq!(
+            virtual_memory_manager::set_cache_type(base, 1, CacheType::Uncacheable),
+            Err(virtual_memory_manager::VmError::NotMapped),
+            "Cache type of unmapped page is changed"
+        );
+    }
+    // Check demand paging, first touch of unmapped page is resolved by the page fault handler
+    {
+        use memory_management::demand_paging;
+        use x86_64::structures::paging::PageTableFlags;
+        use x86_64::VirtAddr;
+        // Start of kernel space is not used by the layout
+        let start = VirtAddr::new(0xFFFF_8000_0000_0000);
+        assert_eq!(
+            virtual_memory_manager::virtual_memory_region(start.as_u64()),
+            virtual_memory_manager::VirtualMemoryRegion::Unused
+        );
+        demand_paging::register_demand_paged_range(start, 2 * PAGE_SIZE, PageTableFlags::WRITABLE)
+            .expect("Failed to register demand-paged range");
+        assert_eq!(
+            virtual_memory_manager::translate(start + PAGE_SIZE as u64),
+            None,
+            "Demand-paged page is mapped before access"
+        );
+        let word = (start + PAGE_SIZE as u64 + 8).as_mut_ptr::<u64>();
+        unsafe {
+            assert_eq!(word.read_volatile(), 0, "Demand-paged page is not zeroed");
+            word.write_volatile(0xD1D_FA17);
+            assert_eq!(
+                word.read_volatile(),
+                0xD1D_FA17,
+                "Demand-paged page is broken"
+            );
+        }
+        assert!(
+            virtual_memory_manager::translate(start + PAGE_SIZE as u64).is_some(),
+            "Demand-paged page is not mapped after access"
+        );
+        assert_eq!(
+            virtual_memory_manager::translate(start),
+            None,
+            "Untouched demand-paged page is mapped"
+        );
+        assert_eq!(
+            demand_paging::register_demand_paged_range(start, PAGE_SIZE, PageTableFlags::WRITABLE),
+            Err(virtual_memory_manager::VmError::AlreadyMapped),
+            "Overlapping demand-paged range is registered"
+        );
+    }
+    // Check translation of 4 KB page, CPMM huge page and unmapped hole
+    {
+        use x86_64::structures::paging::PageTableFlags;
+        let page =
+            virtual_memory_manager::vmalloc(1, PageTableFlags::WRITABLE).expect("vmalloc failed");
+        let frame = virtual_memory_manager::translate(page + 8).expect("Page is not translated");
+        unsafe {
+            page.as_mut_ptr::<u64>().add(1).write_volatile(0xC0FFEE);
+            assert_eq!(
+                virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(frame)
+                    .as_ptr::<u64>()
+                    .read_volatile(),
+                0xC0FFEE,
+                "4 KB page translation is broken"
+            );
+        }
+        virtual_memory_manager::vfree(page, 1);
+
+        // CPMM is mapped with huge pages
+        let phys_addr = x86_64::registers::control::Cr3::read().0.start_address() + 0x123_u64;
+        assert_eq!(
+            virtual_memory_manager::translate(
+                virtual_memory_manager::virt_addr_in_cpmm_from_phys_addr(phys_addr)
+            ),
+            Some(phys_addr),
+            "CPMM translation is broken"
+        );
+        // Lower half is unmapped
+        assert_eq!(
+            virtual_memory_manager::translate(x86_64::VirtAddr::new(0x1000)),
+            None,
+            "Unmapped address is translated"
+        );
+    }
+    // Check hexdump of a known pattern: partial first and last lines, unmapped page and overflowing range
+    {
+        use alloc::format;
+        use alloc::string::String;
+        use x86_64::VirtAddr;
+        #[repr(C, align(16))]
+        struct Pattern([u8; 32]);
+        let mut pattern = Pattern([0; 32]);
+        pattern.0[..16].copy_from_slice(b"0123456789ABCDEF");
+        pattern.0[16..20].copy_from_slice(&[0x00, 0x7F, b'x', b' ']);
+        let base = VirtAddr::from_ptr(&raw const pattern);
+
+        let mut output = String::new();
+        util::hexdump_to(&mut output, base + 4u64, 16).unwrap();
+        let expected = format!(
+            "0x{:016X}:             34 35 36 37  38 39 41 42 43 44 45 46  |    456789ABCDEF|\n\
+             0x{:016X}: 00 7F 78 20                                       |..x             |\n",
+            base.as_u64(),
+            base.as_u64() + 16
+        );
+        assert_eq!(output, expected, "Wrong hexdump of pattern");
+
+        output.clear();
+        util::hexdump_to(&mut output, VirtAddr::new(0x1000), 0x10).unwrap();
+        assert_eq!(
+            output, "0x0000000000001000: <unmapped up to 0x1010>\n",
+            "Wrong hexdump of unmapped page"
+        );
+
+        output.clear();
+        util::hexdump_to(&mut output, VirtAddr::new(0xFFFF_FFFF_FFFF_FFF0), 0x20).unwrap();
+        assert_eq!(
+            output, "hexdump: range 0xFFFFFFFFFFFFFFF0 + 0x20 overflows\n",
+            "Wrong hexdump of overflowing range"
+        );
+    }
+    // Check unmap, the page must be not translatable after it
+    {
+        use x86_64::structures::paging::PageTableFlags;
+        let page =
+            virtual_memory_manager::vmalloc(1, PageTableFlags::WRITABLE).expect("vmalloc failed");
+        let frame = virtual_memory_manager::unmap_page(page).expect("Page is not mapped");
+        assert_eq!(
+            virtual_memory_manager::translate(page),
+            None,
+            "Unmapped page is translated"
+        );
+        unsafe {
+            memory_management::physical_memory_manager::free(frame);
+        }
+        #[cfg(feature = "unmap-fault-check")]
+        unsafe {
+            page.as_ptr::<u8>().read_volatile();
+        }
+        virtual_memory_manager::vfree(page, 1);
+    }
+
+    // Check generic slab caches, every object is written and read back completely
+    for size in memory_management::slab_allocator::GENERIC_CACHE_SIZES {
+        let ptr = memory_management::slab_allocator::kmalloc(size);
+        assert!(!ptr.is_null(), "kmalloc of {size} bytes failed");
+        let object = unsafe { core::slice::from_raw_parts_mut(ptr, size) };
+        object
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, byte)| *byte = i as u8);
+        assert!(
+            object.iter().enumerate().all(|(i, byte)| *byte == i as u8),
+            "kmalloc object of {size} bytes is broken"
+        );
+        unsafe {
+            memory_management::slab_allocator::kfree(ptr, size);
+        }
+    }
+
+    // Check emergency pool exhaustion, it fails gracefully and high-water mark reaches capacity, then it's reset
+    {
+        use memory_management::emergency_pool::*;
+        let capacity = emergency_pool_capacity();
+        assert!(
+            emergency_alloc(PAGE_SIZE + 1).is_null(),
+            "Emergency pool allocated more than a page"
+        );
+        let mut pages: alloc::vec::Vec<*mut u8> =
+            (0..capacity).map(|_| emergency_alloc(PAGE_SIZE)).collect();
+        assert!(
+            pages.iter().all(|page| !page.is_null()),
+            "Emergency pool is exhausted before its capacity"
+        );
+        pages.sort_unstable();
+        pages.dedup();
+        assert_eq!(pages.len(), capacity, "Emergency pool page allocated twice");
+        assert!(
+            emergency_alloc(1).is_null(),
+            "Exhausted emergency pool allocated a page"
+        );
+        assert_eq!(emergency_pool_high_water_mark(), capacity);
+        for &page in &pages {
+            unsafe { emergency_free(page) };
+        }
+        let page = emergency_alloc(1);
+        assert!(!page.is_null(), "Emergency pool memory is lost");
+        unsafe { emergency_free(page) };
+        assert_eq!(
+            emergency_pool_high_water_mark(),
+            capacity,
+            "High-water mark is lowered"
+        );
+        reset_emergency_pool_high_water_mark();
+    }
+
+    // Check ISA DMA reservation, slab and heap memory never comes from ISA DMA zone, explicit allocation does
+    {
+        use memory_management::physical_memory_manager::{self, MemoryZoneEnum};
+        use memory_management::slab_allocator::{kfree, kmalloc, KMALLOC_MAX_SIZE};
+        use x86_64::VirtAddr;
+        const OBJECTS: usize = 256;
+        const OBJECT_SIZE: usize = KMALLOC_MAX_SIZE;
+        let is_in_isa_dma = |ptr: *const u8| {
+            let phys_addr = virtual_memory_manager::translate(VirtAddr::from_ptr(ptr))
+                .expect("Allocated memory is not mapped");
+            physical_memory_manager::zone_of(phys_addr) == Some(MemoryZoneEnum::IsaDma)
+        };
+        let isa_dma_free_size = physical_memory_manager::zone_free_size(MemoryZoneEnum::IsaDma);
+        let objects: alloc::vec::Vec<*mut u8> =
+            (0..OBJECTS).map(|_| kmalloc(OBJECT_SIZE)).collect();
+        let heap_block = alloc::vec![0u8; 1024 * 1024];
+        assert!(
+            objects.iter().all(|object| !object.is_null()),
+            "kmalloc failed"
+        );
+        assert!(
+            !objects.iter().any(|&object| is_in_isa_dma(object)),
+            "Slab object is in ISA DMA zone"
+        );
+        assert!(
+            !heap_block
+                .iter()
+                .step_by(PAGE_SIZE)
+                .any(|byte| is_in_isa_dma(byte)),
+            "Heap memory is in ISA DMA zone"
+        );
+        assert_eq!(
+            physical_memory_manager::zone_free_size(MemoryZoneEnum::IsaDma),
+            isa_dma_free_size,
+            "General allocations took ISA DMA memory"
+        );
+        drop(heap_block);
+        for object in objects {
+            unsafe { kfree(object, OBJECT_SIZE) };
+        }
+
+        if physical_memory_manager::isa_dma_available() {
+            let phys_addr = unsafe { physical_memory_manager::alloc_isa_dma(PAGE_SIZE) }
+                .expect("ISA DMA allocation failed");
+            assert_eq!(
+                physical_memory_manager::zone_of(phys_addr),
+                Some(MemoryZoneEnum::IsaDma),
+                "ISA DMA allocation is out of ISA DMA zone"
+            );
+            unsafe { physical_memory_manager::free(phys_addr) };
+            assert_eq!(
+                physical_memory_manager::zone_free_size(MemoryZoneEnum::IsaDma),
+                isa_dma_free_size,
+                "ISA DMA memory is lost"
+            );
+        }
+    }
+
+    // Check typed cache, 200 objects take several slabs
+    {
+        struct Object48([u64; 6]);
+        let mut cache = memory_management::slab_allocator::new_cache::<Object48>(
+            PAGE_SIZE,
+            memory_management::slab_allocator::ObjectSizeType::Small,
+        );
+        let objects: alloc::vec::Vec<*mut Object48> = (0..200_u64)
+            .map(|i| {
+                let object = unsafe { cache.alloc() };
+                assert!(!object.is_null(), "Typed cache allocation failed");
+                unsafe { object.write(Object48([i; 6])) };
+                object
+            })
+            .collect();
+        for (i, object) in objects.into_iter().enumerate() {
+            assert!(
+                unsafe { (*object).0 == [i as u64; 6] },
+                "Typed cache object is broken"
+            );
+            unsafe { cache.free(object) };
+        }
+    }
+
+    // Check typed cache constructor, reused object keeps constructed state without constructor call
+    {
+        use core::mem::MaybeUninit;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use memory_management::slab_allocator::{ObjectSizeType, TypedCache};
+        const MAGIC: u64 = 0x5AB_C7A5;
+        struct Constructed {
+            magic: u64,
+            value: u64,
+        }
+        static CTOR_CALLS: AtomicUsize = AtomicUsize::new(0);
+        static DTOR_CALLS: AtomicUsize = AtomicUsize::new(0);
+        let mut cache = TypedCache::<Constructed>::new(
+            PAGE_SIZE,
+            ObjectSizeType::Small,
+            |object| {
+                CTOR_CALLS.fetch_add(1, Ordering::Relaxed);
+                object.write(Constructed {
+                    magic: MAGIC,
+                    value: 0,
+                });
+            },
+            |object| {
+                DTOR_CALLS.fetch_add(1, Ordering::Relaxed);
+                object.magic = 0;
+            },
+        );
+        let mut first = cache.alloc().expect("Typed cache allocation failed");
+        let second = cache.alloc().expect("Typed cache allocation failed");
+        assert!(
+            unsafe { first.as_ref().magic == MAGIC && second.as_ref().magic == MAGIC },
+            "Typed cache object is not constructed"
+        );
+        unsafe { first.as_mut().value = 1 };
+        unsafe { cache.free(first) };
+        let reused = cache.alloc().expect("Typed cache allocation failed");
+        assert_eq!(reused, first, "Typed cache didn't reuse freed object");
+        assert!(
+            unsafe { reused.as_ref().magic == MAGIC && reused.as_ref().value == 1 },
+            "Reused typed cache object lost its state"
+        );
+        assert_eq!(
+            CTOR_CALLS.load(Ordering::Relaxed),
+            2,
+            "Typed cache constructor ran for reused object"
+        );
+        unsafe {
+            cache.free(reused);
+            cache.free(second);
+        }
+        assert_eq!(
+            cache.shrink(),
+            2,
+            "Typed cache didn't destruct freed objects"
+        );
+        assert_eq!(
+            DTOR_CALLS.load(Ordering::Relaxed),
+            2,
+            "Typed cache destructor didn't run"
+        );
+        // Dropped cache destructs objects left in its free list
+        let object = cache.alloc().expect("Typed cache allocation failed");
+        unsafe { cache.free(object) };
+        drop(cache);
+        assert_eq!(
+            DTOR_CALLS.load(Ordering::Relaxed),
+            3,
+            "Typed cache drop didn't destruct freed objects"
+        );
+    }
+
+    // Check backtrace, frames of nested calls must be walked
+    {
+        #[inline(never)]
+        fn nested_frames(depth: usize) -> usize {
+            if depth == 0 {
+                #[cfg(feature = "backtrace-panic-check")]
+                panic!("Backtrace check");
+                let mut frames = 0;
+                backtrace::walk(|_| frames += 1);
+                return frames;
+            }
+            // Not a tail call, the frame stays
+            core::hint::black_box(nested_frames(depth - 1))
+        }
+        const DEPTH: usize = 3;
+        let frames = nested_frames(DEPTH);
+        assert!(
+            frames > DEPTH,
+            "Backtrace walked {frames} frames, expected more than {DEPTH}"
+        );
+    }
+}
+
+/// Interrupts, keyboard and serial checks, interrupts and per-CPU block of the BSP must be inited
+pub fn interrupts() {
+    // Check Scancode Set 1 decoding: A press and release, extended Up press and release, Pause, left Shift press
+    {
+        use drivers::keyboard::{KeyCode, KeyEvent, ScancodeDecoder};
+        let mut decoder = ScancodeDecoder::new();
+        let mut events = [
+            0x1E, 0x9E, 0xE0, 0x48, 0xE0, 0xC8, 0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5, 0x2A,
+        ]
+        .into_iter()
+        .filter_map(|scancode| decoder.decode(scancode));
+        for (key, pressed) in [
+            (KeyCode::Char('a'), true),
+            (KeyCode::Char('a'), false),
+            (KeyCode::Up, true),
+            (KeyCode::Up, false),
+            (KeyCode::LeftShift, true),
+        ] {
+            assert_eq!(
+                events.next(),
+                Some(KeyEvent { key, pressed }),
+                "Scancode decoding is broken"
+            );
+        }
+        assert_eq!(events.next(), None, "Scancode decoding is broken");
+    }
+
+    // Check breakpoint, execution must continue after int3
+    {
+        let breakpoints_hit = interrupts::idt::breakpoints_hit();
+        x86_64::instructions::interrupts::int3();
+        assert_eq!(
+            interrupts::idt::breakpoints_hit(),
+            breakpoints_hit + 1,
+            "Breakpoint is not handled"
+        );
+        // Depth is counted in the per-CPU block now, it must be back to 0
+        #[cfg(debug_assertions)]
+        assert_eq!(
+            interrupts::idt::nesting_depth(),
+            0,
+            "Per-CPU interrupt nesting depth is not restored"
+        );
+    }
+
+    // Check PIC, it's remapped and masked, its spurious IRQ 7 is counted without EOI
+    {
+        use interrupts::pic;
+        #[allow(static_mut_refs)]
+        let masks = unsafe { pic::PICS.read_masks() };
+        assert_eq!(masks, [0xFF, 0xFF], "PIC lines are not masked");
+        let spurious_count = pic::spurious_count();
+        unsafe { core::arch::asm!("int 0xE7") };
+        assert_eq!(
+            pic::spurious_count(),
+            spurious_count + 1,
+            "PIC spurious IRQ 7 is not handled"
+        );
+    }
+
+    // Check page fault error code decoding
+    for (error_code, description) in [
+        (
+            0b0,
+            "caused by a read from a non-present page in kernel mode",
+        ),
+        (
+            0b10,
+            "caused by a write to a non-present page in kernel mode",
+        ),
+        (0b111, "caused by a write to a present page in user mode"),
+        (
+            0b1_0001,
+            "caused by an instruction fetch from a present page in kernel mode",
+        ),
+        (
+            0b1001,
+            "caused by a read from a present page in kernel mode, reserved bit set in page table",
+        ),
+    ] {
+        assert_eq!(
+            alloc::format!(
+                "{}",
+                interrupts::idt::decode_page_fault_error_code(error_code)
+            ),
+            description,
+            "Page fault error code {error_code:#b} is decoded incorrectly"
+        );
+    }
+
+    // Check Local APIC Error Status Register decoding
+    {
+        use interrupts::apic::ApicErrorStatus;
+        let status = ApicErrorStatus::from(0xA4);
+        assert!(
+            status.send_accept_error() && status.send_illegal_vector(),
+            "APIC error status is decoded incorrectly"
+        );
+        assert!(
+            status.illegal_register_address() && !status.received_illegal_vector(),
+            "APIC error status is decoded incorrectly"
+        );
+        for (register_value, description) in [
+            (0x00, "no errors (0x00)"),
+            (0x01, "send checksum error (0x01)"),
+            (0x40, "received illegal vector (0x40)"),
+            (0x18, "receive accept error, redirectable IPI (0x18)"),
+            (
+                0xA4,
+                "send accept error, send illegal vector, illegal register address (0xA4)",
+            ),
+            // Reserved bits are not decoded
+            (0x102, "receive checksum error (0x102)"),
+        ] {
+            assert_eq!(
+                alloc::format!("{}", ApicErrorStatus::from(register_value)),
+                description,
+                "APIC error status {register_value:#X} is decoded incorrectly"
+            );
+        }
+    }
+
+    // Check IO APIC masking, GSI 1 (keyboard) is masked and restored
+    {
+        use interrupts::apic::ioapic;
+        let was_masked = ioapic::is_masked(1);
+        ioapic::set_mask(1, true);
+        assert!(ioapic::is_masked(1), "IO APIC mask is not set");
+        ioapic::set_mask(1, was_masked);
+        assert_eq!(
+            ioapic::is_masked(1),
+            was_masked,
+            "IO APIC mask is not restored"
+        );
+    }
+
+    // Check serial channels, distinct strings go to COM1 and COM2 (looped back if COM2 is present)
+    {
+        use com_ports::SerialChannel;
+        crate::serial_println!(Com1; "COM1 channel check");
+        if SerialChannel::Com2.is_present() {
+            const COM2_CHECK: &str = "COM2 check";
+            while SerialChannel::Com2.try_receive_raw().is_some() {}
+            SerialChannel::Com2.set_loopback(true);
+            crate::serial_print!(Com2; "{COM2_CHECK}");
+            SerialChannel::Com2.flush();
+            let mut received = [0; COM2_CHECK.len()];
+            for byte in received.iter_mut() {
+                *byte = SerialChannel::Com2.try_receive_raw().unwrap_or(0);
+            }
+            SerialChannel::Com2.set_loopback(false);
+            assert_eq!(&received, COM2_CHECK.as_bytes(), "COM2 channel is broken");
+            crate::serial_println!(Com2; "COM2 channel check");
+        } else {
+            log::info!("COM2 is not present");
+        }
+    }
+    // Check COM port settings math, the running COM1 keeps 38400 8N1 set by uart_16550
+    {
+        use com_ports::{line_settings, ConfigureError, Parity};
+        assert_eq!(
+            line_settings(38400, 8, Parity::None, 1),
+            Ok((3, 0b0000_0011))
+        );
+        assert_eq!(
+            line_settings(57600, 8, Parity::None, 1),
+            Ok((2, 0b0000_0011))
+        );
+        assert_eq!(
+            line_settings(115200, 5, Parity::Odd, 1),
+            Ok((1, 0b0000_1000))
+        );
+        assert_eq!(
+            line_settings(9600, 7, Parity::Even, 2),
+            Ok((12, 0b0001_1110))
+        );
+        assert_eq!(
+            line_settings(921600, 8, Parity::None, 1),
+            Err(ConfigureError::BaudRateNotDivisor(921600))
+        );
+        assert_eq!(
+            line_settings(0, 8, Parity::None, 1),
+            Err(ConfigureError::BaudRateNotDivisor(0))
+        );
+        assert_eq!(
+            line_settings(9600, 9, Parity::None, 1),
+            Err(ConfigureError::DataBitsOutOfRange(9))
+        );
+        assert_eq!(
+            line_settings(9600, 8, Parity::None, 3),
+            Err(ConfigureError::StopBitsOutOfRange(3))
+        );
+        assert_eq!(
+            com_ports::com1_divisor(),
+            3,
+            "COM1 divisor is not 38400 baud"
+        );
+    }
+    // Check COM1 receive, byte is looped back and received with interrupts disabled
+    {
+        while com_ports::try_read_byte().is_some() {}
+        com_ports::SerialChannel::Com1.set_loopback(true);
+        #[allow(static_mut_refs)]
+        unsafe {
+            com_ports::COM1_PORT_LOCK_FREE.send_raw(0xA5);
+        }
+        com_ports::flush_com1();
+        com_ports::receive_com1();
+        com_ports::SerialChannel::Com1.set_loopback(false);
+        assert_eq!(
+            com_ports::try_read_byte(),
+            Some(0xA5),
+            "COM1 loopback byte is not received"
+        );
+    }
+}
+
+/// Timers checks, timers must be inited
+pub fn timers() {
+    // Check timer fixed-point conversions at the extremes: 10 MHz HPET and 3 GHz TSC periods, saturation
+    {
+        use core::time::Duration;
+        use timers::fixed_point::*;
+        // 10 MHz, 100 ns period is exact
+        let period_ns = period_ns_from_period_fs(100_000_000);
+        assert_eq!(
+            frequency_from_period_fs(100_000_000),
+            Fixed::from_num(10_000_000)
+        );
+        assert_eq!(period_ns, Fixed::from_num(100));
+        assert_eq!(
+            ticks_to_duration(10_000_000, period_ns),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            duration_to_ticks(Duration::from_secs(1), period_ns),
+            10_000_000
+        );
+        // 3 GHz, 0.333 ns period loses up to 0.075% in fixed point, frequency variants are exact
+        let period_ns = period_ns_from_period_fs(333_333);
+        let duration = ticks_to_duration(3_000_000_000, period_ns);
+        assert!(
+            Duration::from_secs(1) - duration < Duration::from_micros(750),
+            "3 GHz ticks are converted to {duration:?} instead of 1s"
+        );
+        assert_eq!(
+            ticks_to_duration_by_frequency(3_000_000_000, 3_000_000_000),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            ticks_to_duration_by_frequency(3, 3_000_000_000),
+            Duration::from_nanos(1)
+        );
+        assert_eq!(
+            ticks_to_duration_by_frequency(2, 3_000_000_000),
+            Duration::ZERO
+        );
+        assert_eq!(
+            duration_to_ticks_by_frequency(Duration::from_secs(1), 3_000_000_000),
+            3_000_000_000
+        );
+        // Saturation instead of overflow
+        let max_duration = Duration::from_nanos(u64::MAX);
+        assert_eq!(
+            ticks_to_duration(u64::MAX, Fixed::from_num(100)),
+            max_duration
+        );
+        assert_eq!(ticks_to_duration_by_frequency(u64::MAX, 1), max_duration);
+        assert_eq!(duration_to_ticks(Duration::MAX, period_ns), u64::MAX);
+        assert_eq!(
+            duration_to_ticks_by_frequency(Duration::MAX, 5_000_000_000),
+            u64::MAX
+        );
+    }
+
+    // Check APIC Timer initial count at boundary frequencies of 1 GHz bus, too low frequency of 100 GHz bus
+    {
+        use core::time::Duration;
+        use interrupts::apic::timer::{self, InitialCountError};
+        const BUS_FREQUENCY_HZ: u64 = 1_000_000_000;
+        // Timer counts at 1 GHz / 16 = 62.5 MHz
+        const MAX_HZ: u64 = BUS_FREQUENCY_HZ / timer::DIVIDER;
+        assert_eq!(
+            timer::representable_frequency_range(BUS_FREQUENCY_HZ),
+            1..=MAX_HZ
+        );
+        assert_eq!(timer::initial_count(BUS_FREQUENCY_HZ, 1), Ok(MAX_HZ as u32));
+        assert_eq!(timer::initial_count(BUS_FREQUENCY_HZ, 1000), Ok(62_500));
+        assert_eq!(timer::initial_count(BUS_FREQUENCY_HZ, MAX_HZ), Ok(1));
+        assert_eq!(
+            timer::initial_count(BUS_FREQUENCY_HZ, MAX_HZ + 1),
+            Err(InitialCountError::FrequencyTooHigh { max_hz: MAX_HZ })
+        );
+        assert_eq!(
+            timer::initial_count(BUS_FREQUENCY_HZ, u64::MAX),
+            Err(InitialCountError::FrequencyTooHigh { max_hz: MAX_HZ })
+        );
+        assert_eq!(
+            timer::initial_count(BUS_FREQUENCY_HZ, 0),
+            Err(InitialCountError::ZeroFrequency)
+        );
+        assert_eq!(
+            timer::initial_count_for_period(BUS_FREQUENCY_HZ, Duration::from_millis(10)),
+            Ok(625_000)
+        );
+        // 100 GHz / 16 = 6.25 GHz, 1 Hz count doesn't fit in 32 bits
+        const FAST_BUS_FREQUENCY_HZ: u64 = 100_000_000_000;
+        assert_eq!(
+            timer::initial_count(FAST_BUS_FREQUENCY_HZ, 1),
+            Err(InitialCountError::FrequencyTooLow { min_hz: 2 })
+        );
+        assert_eq!(
+            timer::initial_count(FAST_BUS_FREQUENCY_HZ, 2),
+            Ok(3_125_000_000)
+        );
+        assert_eq!(
+            timer::initial_count_clamped(FAST_BUS_FREQUENCY_HZ, 1),
+            u32::MAX
+        );
+        assert_eq!(
+            timer::initial_count_clamped(BUS_FREQUENCY_HZ, MAX_HZ + 1),
+            1
+        );
+    }
+    // Check Local APIC Timer calibration, measured samples must agree within 5%
+    {
+        use interrupts::apic::timer;
+        let calibration_result = timer::calibration_result().expect("APIC Timer is not calibrated");
+        assert_ne!(
+            calibration_result.bus_frequency_hz, 0,
+            "APIC bus frequency is zero"
+        );
+        assert!(
+            calibration_result.spread_hz <= calibration_result.bus_frequency_hz / 20,
+            "APIC bus frequency is unstable: {calibration_result:?}"
+        );
+    }
+    // Check APIC Timer tick, it must fire within a few periods after interrupts are enabled
+    {
+        use interrupts::apic::timer;
+        let ticks = timer::ticks();
+        let timeout = timers::monotonic_now() + timer::DEFAULT_PERIOD * 10;
+        x86_64::instructions::interrupts::enable();
+        while timer::ticks() == ticks && timers::monotonic_now() < timeout {
+            core::hint::spin_loop();
+        }
+        x86_64::instructions::interrupts::disable();
+        assert!(timer::ticks() > ticks, "APIC Timer interrupt didn't fire");
+    }
+    // Check APIC Timer rate, ticks over HPET interval must agree with calibrated bus frequency within 10%
+    #[cfg(feature = "timer-accuracy-check")]
+    if timers::hpet::is_supported() {
+        use core::time::Duration;
+        use interrupts::apic::timer;
+        use timers::hpet;
+        const INTERVAL: Duration = Duration::from_millis(200);
+        let bus_frequency_hz = timer::bus_frequency_hz();
+        let initial_count =
+            timer::initial_count_for_period(bus_frequency_hz, timer::DEFAULT_PERIOD)
+                .expect("Default APIC Timer period is not representable");
+        let start_ticks = timer::ticks();
+        let start_hpet_ticks = hpet::get_current_ticks();
+        let end_hpet_ticks = start_hpet_ticks + hpet::duration_to_ticks(INTERVAL);
+        x86_64::instructions::interrupts::enable();
+        while hpet::get_current_ticks() < end_hpet_ticks {
+            core::hint::spin_loop();
+        }
+        x86_64::instructions::interrupts::disable();
+        let elapsed_ticks = timer::ticks() - start_ticks;
+        let elapsed = hpet::ticks_to_duration(hpet::get_current_ticks() - start_hpet_ticks);
+        // Timer counts at bus frequency / DIVIDER, one interrupt per initial count
+        let expected_ticks = (elapsed.as_nanos() * (bus_frequency_hz / timer::DIVIDER) as u128
+            / (initial_count as u128 * 1_000_000_000)) as u64;
+        let tolerance = expected_ticks / 10 + 1;
+        assert!(
+            elapsed_ticks.abs_diff(expected_ticks) <= tolerance,
+            "APIC Timer ticked {elapsed_ticks} times over {elapsed:?} of HPET, {expected_ticks} expected at {bus_frequency_hz} Hz bus frequency"
+        );
+    }
+    // Check APIC Timer periodic mode, unrepresentable periods are rejected
+    {
+        use core::time::Duration;
+        use interrupts::apic::timer::{self, InitialCountError};
+        assert!(
+            matches!(
+                timer::set_periodic(Duration::ZERO),
+                Err(InitialCountError::FrequencyTooHigh { .. })
+            ),
+            "Zero APIC Timer period is accepted"
+        );
+        assert!(
+            matches!(
+                timer::set_periodic(Duration::from_secs(u32::MAX as u64)),
+                Err(InitialCountError::FrequencyTooLow { .. })
+            ),
+            "Too long APIC Timer period is accepted"
+        );
+    }
+    // Check APIC Timer periodic mode, 10 ms period must give about 10 ticks over 100 ms
+    #[cfg(feature = "timer-accuracy-check")]
+    {
+        use core::time::Duration;
+        use interrupts::apic::timer;
+        const PERIOD: Duration = Duration::from_millis(10);
+        const INTERVAL: Duration = Duration::from_millis(100);
+        timer::set_periodic(PERIOD).expect("Failed to set APIC Timer period");
+        let ticks = timer::ticks();
+        let end = timers::monotonic_now() + INTERVAL;
+        x86_64::instructions::interrupts::enable();
+        while timers::monotonic_now() < end {
+            core::hint::spin_loop();
+        }
+        x86_64::instructions::interrupts::disable();
+        let elapsed_ticks = timer::ticks() - ticks;
+        // First tick comes a full period after start, emulators may be late by a tick
+        assert!(
+            (8..=11).contains(&elapsed_ticks),
+            "APIC Timer ticked {elapsed_ticks} times over {INTERVAL:?} with period {PERIOD:?}"
+        );
+        timer::set_periodic(timer::DEFAULT_PERIOD).expect("Failed to restore APIC Timer period");
+    }
+    // Check IrqMutex, interrupts are disabled while it's held and restored after, nested locks restore in order
+    {
+        use x86_64::instructions::interrupts;
+        let outer = sync::IrqMutex::new(0u32);
+        let inner = sync::IrqMutex::new(0u32);
+        {
+            let _guard = outer.lock();
+            assert!(
+                !interrupts::are_enabled(),
+                "Interrupts are enabled under IrqMutex"
+            );
+        }
+        assert!(!interrupts::are_enabled(), "IrqMutex enabled interrupts");
+
+        interrupts::enable();
+        {
+            let mut outer_guard = outer.lock();
+            *outer_guard += 1;
+            assert!(
+                !interrupts::are_enabled(),
+                "Interrupts are enabled under IrqMutex"
+            );
+            assert!(outer.try_lock().is_none(), "Held IrqMutex is locked again");
+            {
+                let mut inner_guard = inner.lock();
+                *inner_guard += 1;
+            }
+            assert!(
+                !interrupts::are_enabled(),
+                "Inner IrqMutex enabled interrupts while outer is held"
+            );
+        }
+        assert!(
+            interrupts::are_enabled(),
+            "IrqMutex didn't restore interrupts"
+        );
+        {
+            let guard = outer.try_lock().expect("Free IrqMutex is not locked");
+            assert_eq!(*guard, 1);
+            assert!(
+                !interrupts::are_enabled(),
+                "Interrupts are enabled under IrqMutex"
+            );
+        }
+        assert!(
+            interrupts::are_enabled(),
+            "IrqMutex didn't restore interrupts"
+        );
+        interrupts::disable();
+        assert_eq!(*inner.lock(), 1);
+    }
+    // Check clock source
+    let mut previous_time = timers::monotonic_now();
+    for _ in 0..1000 {
+        let time = timers::monotonic_now();
+        assert!(time >= previous_time, "Monotonic clock went backwards");
+        previous_time = time;
+    }
+    // Check TSC drift against HPET over one second, calibrated TSC must agree within 1%
+    #[cfg(feature = "timer-accuracy-check")]
+    if timers::tsc::is_supported() && timers::hpet::is_supported() {
+        use core::time::Duration;
+        use timers::{hpet, tsc};
+        const INTERVAL: Duration = Duration::from_secs(1);
+        let start_tsc = tsc::now();
+        let start_hpet = hpet::get_current_ticks_as_duration();
+        while hpet::get_current_ticks_as_duration() - start_hpet < INTERVAL {
+            core::hint::spin_loop();
+        }
+        let elapsed_tsc = tsc::now() - start_tsc;
+        let elapsed_hpet = hpet::get_current_ticks_as_duration() - start_hpet;
+        let drift = elapsed_tsc.abs_diff(elapsed_hpet);
+        assert!(
+            drift <= elapsed_hpet / 100,
+            "TSC measured {elapsed_tsc:?} over {elapsed_hpet:?} of HPET, drift {drift:?}"
+        );
+    }
+    // Check HPET legacy replacement enabling on fake registers, the real route is not enabled (it takes PIT IRQ)
+    {
+        use x86_64::VirtAddr;
+        // General Capabilities and ID, reserved, General Configuration
+        #[repr(C, align(8))]
+        struct FakeHpetRegisters([u64; 3]);
+        const LEGACY_REPLACEMENT_CAP: u64 = 1 << 15;
+        const ENABLE_CNF: u64 = 1 << 0;
+        const LEGACY_REPLACEMENT_CNF: u64 = 1 << 1;
+
+        let mut registers = FakeHpetRegisters([0x8086_A201, 0, ENABLE_CNF]);
+        let result = unsafe {
+            timers::hpet::enable_legacy_replacement_at(VirtAddr::from_ptr(&raw mut registers))
+        };
+        assert!(
+            result.is_err(),
+            "HPET legacy replacement enabled without capability"
+        );
+        assert_eq!(registers.0[2], ENABLE_CNF, "HPET configuration changed");
+
+        registers.0[0] |= LEGACY_REPLACEMENT_CAP;
+        let result = unsafe {
+            timers::hpet::enable_legacy_replacement_at(VirtAddr::from_ptr(&raw mut registers))
+        };
+        assert!(
+            result.is_ok(),
+            "HPET legacy replacement is not enabled with capability"
+        );
+        assert_eq!(
+            registers.0[2],
+            ENABLE_CNF | LEGACY_REPLACEMENT_CNF,
+            "Wrong HPET configuration"
+        );
+    }
+    // Check HPET comparator routing, unsupported GSI and missing comparator are rejected
+    if timers::hpet::is_supported() {
+        use timers::hpet;
+        let routing_capability = hpet::comparator_interrupt_routing_capability(0)
+            .expect("HPET comparator 0 doesn't exist");
+        let lowest_gsi = hpet::select_comparator_gsi(0, None);
+        assert_eq!(
+            lowest_gsi
+                .ok()
+                .map(|gsi| routing_capability & (1 << gsi) != 0),
+            (routing_capability != 0).then_some(true),
+            "Selected GSI is not supported by HPET comparator"
+        );
+        // GSI 32 and above can't be in 32-bit capability
+        let unsupported_gsi = (!routing_capability).trailing_zeros();
+        for gsi in [unsupported_gsi, u32::BITS, u32::MAX] {
+            assert!(
+                hpet::select_comparator_gsi(0, Some(gsi)).is_err(),
+                "HPET comparator 0 accepted unsupported GSI {gsi}"
+            );
+            assert!(
+                hpet::set_comparator_gsi(0, gsi).is_err(),
+                "HPET comparator 0 is routed to unsupported GSI {gsi}"
+            );
+        }
+        let missing_comparator = hpet::comparators_number();
+        assert!(
+            hpet::select_comparator_gsi(missing_comparator, None).is_err(),
+            "Missing HPET comparator {missing_comparator} is routed"
+        );
+    }
+    // Check HPET one-shot, 50 ms callback must fire and not earlier than requested
+    if timers::hpet::is_supported() {
+        use core::sync::atomic::{AtomicU64, Ordering};
+        use core::time::Duration;
+        use timers::hpet;
+        static FIRED_TICKS: AtomicU64 = AtomicU64::new(0);
+        const DELAY: Duration = Duration::from_millis(50);
+        let start_ticks = hpet::get_current_ticks();
+        hpet::set_oneshot(DELAY, || {
+            FIRED_TICKS.store(hpet::get_current_ticks(), Ordering::Relaxed)
+        })
+        .expect("Failed to set HPET one-shot");
+        let timeout_ticks = start_ticks + hpet::duration_to_ticks(DELAY * 4);
+        x86_64::instructions::interrupts::enable();
+        while FIRED_TICKS.load(Ordering::Relaxed) == 0 && hpet::get_current_ticks() < timeout_ticks
+        {
+            core::hint::spin_loop();
+        }
+        x86_64::instructions::interrupts::disable();
+        let fired_ticks = FIRED_TICKS.load(Ordering::Relaxed);
+        assert_ne!(fired_ticks, 0, "HPET one-shot didn't fire");
+        let elapsed = hpet::ticks_to_duration(fired_ticks - start_ticks);
+        assert!(
+            elapsed >= DELAY,
+            "HPET one-shot fired after {elapsed:?} instead of {DELAY:?}"
+        );
+        log::debug!("HPET one-shot of {DELAY:?} fired after {elapsed:?}");
+    }
+    // Check halting sleep, 100 ms must be woken by the one-shot interrupt on time
+    #[cfg(feature = "timer-accuracy-check")]
+    {
+        use core::time::Duration;
+        const DURATION: Duration = Duration::from_millis(100);
+        let timer_interrupts = timers::sleep_hlt_timer_interrupts();
+        let start = timers::monotonic_now();
+        timers::sleep_hlt(DURATION);
+        let elapsed = timers::monotonic_now() - start;
+        assert!(
+            elapsed >= DURATION && elapsed < DURATION * 2,
+            "sleep_hlt slept {elapsed:?} instead of {DURATION:?}"
+        );
+        assert!(
+            !timers::hpet::is_supported()
+                || timers::sleep_hlt_timer_interrupts() > timer_interrupts,
+            "sleep_hlt wasn't woken by timer interrupt"
+        );
+        assert!(
+            !x86_64::instructions::interrupts::are_enabled(),
+            "sleep_hlt didn't restore interrupt state"
+        );
+    }
+    // Check timer queue, callbacks at 20/40/60 ms must fire in order, cancelled one must not fire
+    if timers::hpet::is_supported() {
+        use core::sync::atomic::{AtomicU64, Ordering};
+        use core::time::Duration;
+        use timers::{hpet, timer_queue};
+        static FIRED_TICKS: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+        static CANCELLED_FIRED: AtomicU64 = AtomicU64::new(0);
+        const STEP: Duration = Duration::from_millis(20);
+        let start_ticks = hpet::get_current_ticks();
+        // Scheduled out of order, the queue sorts them
+        timer_queue::schedule(STEP * 3, || {
+            FIRED_TICKS[2].store(hpet::get_current_ticks(), Ordering::Relaxed)
+        })
+        .expect("Failed to schedule timer callback");
+        timer_queue::schedule(STEP, || {
+            FIRED_TICKS[0].store(hpet::get_current_ticks(), Ordering::Relaxed)
+        })
+        .expect("Failed to schedule timer callback");
+        let cancelled =
+            timer_queue::schedule(STEP * 3 / 2, || CANCELLED_FIRED.store(1, Ordering::Relaxed))
+                .expect("Failed to schedule timer callback");
+        timer_queue::schedule(STEP * 2, || {
+            FIRED_TICKS[1].store(hpet::get_current_ticks(), Ordering::Relaxed)
+        })
+        .expect("Failed to schedule timer callback");
+        assert!(cancelled.cancel(), "Failed to cancel timer callback");
+        assert!(!cancelled.cancel(), "Timer callback cancelled twice");
+
+        let timeout_ticks = start_ticks + hpet::duration_to_ticks(STEP * 12);
+        x86_64::instructions::interrupts::enable();
+        while timer_queue::pending() != 0 && hpet::get_current_ticks() < timeout_ticks {
+            core::hint::spin_loop();
+        }
+        x86_64::instructions::interrupts::disable();
+        assert_eq!(timer_queue::pending(), 0, "Timer callbacks didn't fire");
+        assert_eq!(
+            CANCELLED_FIRED.load(Ordering::Relaxed),
+            0,
+            "Cancelled timer callback fired"
+        );
+        let mut previous_fired_ticks = start_ticks;
+        for (i, fired_ticks) in FIRED_TICKS.iter().enumerate() {
+            let fired_ticks = fired_ticks.load(Ordering::Relaxed);
+            assert!(
+                fired_ticks >= previous_fired_ticks,
+                "Timer callback {i} fired out of order"
+            );
+            previous_fired_ticks = fired_ticks;
+        }
+    }
+}
+
+/// RTC, PCI and IPI checks, RTC and PCI must be inited
+pub fn devices() {
+    // Check BCD conversion and date arithmetic
+    {
+        use drivers::rtc::{self, DateTime};
+        assert_eq!(rtc::bcd_to_binary(0x59), 59, "BCD conversion is broken");
+        assert_eq!(rtc::bcd_to_binary(0x09), 9, "BCD conversion is broken");
+        assert!(rtc::is_leap_year(2000) && rtc::is_leap_year(2024));
+        assert!(!rtc::is_leap_year(1900) && !rtc::is_leap_year(2023));
+        assert_eq!(rtc::days_since_unix_epoch(1970, 1, 1), Some(0));
+        // 2000-02-29 and 2000-03-01 (day after leap day), 2100-03-01 (non-leap century)
+        assert_eq!(rtc::days_since_unix_epoch(2000, 2, 29), Some(11016));
+        assert_eq!(rtc::days_since_unix_epoch(2000, 3, 1), Some(11017));
+        assert_eq!(rtc::days_since_unix_epoch(2100, 3, 1), Some(47541));
+        // Garbage CMOS values are rejected instead of panicking
+        assert_eq!(rtc::days_since_unix_epoch(2023, 2, 29), None);
+        assert_eq!(rtc::days_since_unix_epoch(2024, 4, 31), None);
+        assert_eq!(rtc::days_since_unix_epoch(2024, 13, 1), None);
+        assert_eq!(rtc::days_since_unix_epoch(2024, 0xA5, 0), None);
+        assert_eq!(rtc::days_since_unix_epoch(1969, 12, 31), None);
+        let date_time = DateTime {
+            year: 2024,
+            month: 12,
+            day: 31,
+            hour: 23,
+            minute: 59,
+            second: 59,
+        };
+        assert_eq!(date_time.to_unix_seconds(), Some(1735689599));
+        assert_eq!(
+            DateTime {
+                hour: 24,
+                ..date_time
+            }
+            .to_unix_seconds(),
+            None
+        );
+        // NMI must be enabled back after RTC reads
+        assert_eq!(
+            unsafe { x86_64::instructions::port::Port::<u8>::new(0x70).read() } & 0x80,
+            0,
+            "NMI stays disabled after RTC read"
+        );
+    }
+
+    // Check QEMU default devices: host bridge and IDE (i440FX) or SATA (Q35) controller of the boot drive
+    #[cfg(feature = "qemu-devices-check")]
+    {
+        use drivers::pci;
+        assert!(
+            pci::find_by_class(0x06, 0x00).next().is_some(),
+            "PCI host bridge is not found"
+        );
+        assert!(
+            pci::find_by_class(0x01, 0x01)
+                .chain(pci::find_by_class(0x01, 0x06))
+                .next()
+                .is_some(),
+            "PCI IDE/SATA controller is not found"
+        );
+    }
+    // Check PCI extended configuration space: it's unreachable without MCFG, Q35 e1000e has AER capability
+    #[cfg(feature = "qemu-devices-check")]
+    {
+        use drivers::pci;
+        // Extended configuration space: without MCFG (i440FX) it's not reachable,
+        // with MCFG (Q35) e1000e NIC has AER extended capability at 0x100
+        const EXTENDED_CAPABILITIES_OFFSET: u16 = 0x100;
+        const AER_CAPABILITY_ID: u16 = 0x0001;
+        let host_bridge = pci::find_by_class(0x06, 0x00).next().unwrap();
+        if acpi::mcfg::regions().is_empty() {
+            assert_eq!(
+                pci::read_config_u32(host_bridge.address, EXTENDED_CAPABILITIES_OFFSET),
+                None,
+                "PCI extended configuration space is reachable without MCFG"
+            );
+        } else if let Some(e1000e) = pci::devices()
+            .iter()
+            .find(|device| device.vendor_id == 0x8086 && device.device_id == 0x10D3)
+        {
+            let extended_capability_header =
+                pci::read_config_u32(e1000e.address, EXTENDED_CAPABILITIES_OFFSET)
+                    .expect("PCI extended configuration space is not reachable with MCFG");
+            assert_eq!(
+                extended_capability_header as u16, AER_CAPABILITY_ID,
+                "Unexpected e1000e extended capability"
+            );
+        }
+    }
+
+    // Check IPI, this CPU sends it to itself by APIC ID and by self shorthand, vector of masked GSI 23 is borrowed
+    {
+        use core::sync::atomic::{AtomicU8, Ordering};
+        static RECEIVED_VECTOR: AtomicU8 = AtomicU8::new(0);
+        let vector = *interrupts::idt::IO_APIC_24_VECTORS_RANGE.end();
+        interrupts::idt::register_handler(vector, |vector| {
+            RECEIVED_VECTOR.store(vector, Ordering::Relaxed)
+        })
+        .expect("Failed to register IPI check handler");
+        let send_ipi_by_apic_id =
+            |vector| interrupts::apic::send_ipi(interrupts::apic::local_apic_id(), vector);
+        for (send, description) in [
+            (&send_ipi_by_apic_id as &dyn Fn(u8), "IPI by APIC ID"),
+            (&interrupts::apic::send_self_ipi, "Self-IPI"),
+        ] {
+            RECEIVED_VECTOR.store(0, Ordering::Relaxed);
+            send(vector);
+            x86_64::instructions::interrupts::enable();
+            for _ in 0..1_000_000 {
+                if RECEIVED_VECTOR.load(Ordering::Relaxed) != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            x86_64::instructions::interrupts::disable();
+            assert_eq!(
+                RECEIVED_VECTOR.load(Ordering::Relaxed),
+                vector,
+                "{description} is not received by the registered handler"
+            );
+        }
+        interrupts::idt::unregister_handler(vector);
+    }
+}
+
+/// Checks which need started APs
+pub fn smp() {
+    // Check TLB shootdown, every online AP must invalidate unmapped page (run QEMU with -smp 2 or more)
+    {
+        use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+        use x86_64::structures::paging::PageTableFlags;
+        // APs go online right after ap_main
+        let started_aps = crate::STARTED_APS_COUNTER.load(core::sync::atomic::Ordering::Relaxed);
+        for _ in 0..1_000_000 {
+            if interrupts::apic::smp::online_aps().count() >= started_aps {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        let online_aps = interrupts::apic::smp::online_aps().count();
+        let handled_before = virtual_memory_manager::tlb_shootdowns_handled();
+        let page =
+            virtual_memory_manager::vmalloc(1, PageTableFlags::WRITABLE).expect("vmalloc failed");
+        virtual_memory_manager::vfree(page, 1);
+        assert_eq!(
+            virtual_memory_manager::tlb_shootdowns_handled() - handled_before,
+            online_aps,
+            "TLB shootdown is not handled by every online AP"
+        );
+        log::info!("TLB shootdown handled by {online_aps} APs");
+
+        // Every AP caches the page, then it's remapped to another frame, APs must read the new frame
+        static READ_ADDR: AtomicU64 = AtomicU64::new(0);
+        static READ_VALUE: AtomicU64 = AtomicU64::new(0);
+        static READS: AtomicUsize = AtomicUsize::new(0);
+        const OLD_PATTERN: u64 = 0x01D_F4A3E;
+        const NEW_PATTERN: u64 = 0x4E3_F4A3E;
+        let read_on_ap = |apic_id: u8, vector: u8| {
+            let reads_before = READS.load(Ordering::Acquire);
+            interrupts::apic::send_ipi(apic_id, vector);
+            for _ in 0..10_000_000 {
+                if READS.load(Ordering::Acquire) != reads_before {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            assert_ne!(
+                READS.load(Ordering::Acquire),
+                reads_before,
+                "AP {apic_id} didn't read the page"
+            );
+            READ_VALUE.load(Ordering::Relaxed)
+        };
+        // Vector of masked GSI 23 is borrowed like in IPI check
+        let vector = *interrupts::idt::IO_APIC_24_VECTORS_RANGE.end();
+        interrupts::idt::register_handler(vector, |_| {
+            let value =
+                unsafe { (READ_ADDR.load(Ordering::Relaxed) as *const u64).read_volatile() };
+            READ_VALUE.store(value, Ordering::Relaxed);
+            READS.fetch_add(1, Ordering::Release);
+        })
+        .expect("Failed to register TLB check handler");
+        let base =
+            virtual_memory_manager::vmalloc(2, PageTableFlags::WRITABLE).expect("vmalloc failed");
+        let new_page = base + PAGE_SIZE as u64;
+        unsafe {
+            base.as_mut_ptr::<u64>().write_volatile(OLD_PATTERN);
+            new_page.as_mut_ptr::<u64>().write_volatile(NEW_PATTERN);
+        }
+        let new_frame = virtual_memory_manager::translate(new_page).expect("Page is not mapped");
+        READ_ADDR.store(base.as_u64(), Ordering::Relaxed);
+        for apic_id in interrupts::apic::smp::online_aps() {
+            assert_eq!(
+                read_on_ap(apic_id, vector),
+                OLD_PATTERN,
+                "AP read wrong data"
+            );
+        }
+        let old_frame = virtual_memory_manager::unmap_page(base).expect("Page is not mapped");
+        virtual_memory_manager::map_page(base, new_frame, PageTableFlags::WRITABLE)
+            .expect("Failed to remap page");
+        for apic_id in interrupts::apic::smp::online_aps() {
+            assert_eq!(
+                read_on_ap(apic_id, vector),
+                NEW_PATTERN,
+                "AP {apic_id} reads the old mapping after TLB shootdown"
+            );
+        }
+        interrupts::idt::unregister_handler(vector);
+        // Restore the mapping, so vfree frees both frames once
+        virtual_memory_manager::unmap_page(base);
+        virtual_memory_manager::map_page(base, old_frame, PageTableFlags::WRITABLE)
+            .expect("Failed to restore page mapping");
+        virtual_memory_manager::vfree(base, 2);
+    }
+}
@@ -1,8 +1,11 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use log::{LevelFilter, Metadata, Record};
+use spin::{Once, RwLock};
 
 #[allow(dead_code)]
 static SERIAL_LOGGER: SerialLogger = SerialLogger;
 
+/// Writes records to COM1 and fans them out to registered [LogSink]s
 struct SerialLogger;
 
 impl log::Log for SerialLogger {
@@ -13,15 +16,154 @@ impl log::Log for SerialLogger {
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
             crate::serial_println!("{}: {}", record.level(), record.args());
+            for registered_sink in SINKS.read().iter().flatten() {
+                if registered_sink.enabled.load(Ordering::Relaxed) {
+                    registered_sink.sink.log(record);
+                }
+            }
         }
     }
 
     fn flush(&self) {}
 }
 
+/// Log output besides serial (e.g. framebuffer console)
+///
+/// Gets records that passed the level filter
+pub trait LogSink: Sync {
+    fn log(&self, record: &Record);
+}
+
+/// Maximum number of registered sinks
+const MAX_SINKS: usize = 8;
+
+struct RegisteredSink {
+    sink: &'static dyn LogSink,
+    enabled: AtomicBool,
+}
+
+/// Registered sinks, serial is not among them, it's always written
+static SINKS: RwLock<[Option<RegisteredSink>; MAX_SINKS]> =
+    RwLock::new([const { None }; MAX_SINKS]);
+
+/// Registered sink handle
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SinkId(usize);
+
+/// All [MAX_SINKS] sinks are registered
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TooManySinks;
+
+/// Registers enabled sink
+///
+/// Must not be called while logging on this CPU (e.g. from interrupt handler), the sinks list is locked
+pub fn register_sink(sink: &'static dyn LogSink) -> Result<SinkId, TooManySinks> {
+    let mut sinks = SINKS.write();
+    let (index, slot) = sinks
+        .iter_mut()
+        .enumerate()
+        .find(|(_, slot)| slot.is_none())
+        .ok_or(TooManySinks)?;
+    *slot = Some(RegisteredSink {
+        sink,
+        enabled: AtomicBool::new(true),
+    });
+    Ok(SinkId(index))
+}
+
+/// Enables or disables sink, disabled sink doesn't get records
+pub fn set_sink_enabled(sink_id: SinkId, enabled: bool) {
+    if let Some(registered_sink) = &SINKS.read()[sink_id.0] {
+        registered_sink.enabled.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Sink of [count_records]
+struct CountingSink {
+    filter: RwLock<fn(&Record) -> bool>,
+    count: AtomicUsize,
+}
+
+impl LogSink for CountingSink {
+    fn log(&self, record: &Record) {
+        if (self.filter.read())(record) {
+            self.count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+static COUNTING_SINK: CountingSink = CountingSink {
+    filter: RwLock::new(|_| false),
+    count: AtomicUsize::new(0),
+};
+
+/// Registered [COUNTING_SINK], it's registered once and disabled between uses
+static COUNTING_SINK_ID: Once<Result<SinkId, TooManySinks>> = Once::new();
+
+/// Returns number of records accepted by filter that are logged while f runs
+///
+/// Meant for checks that something is (or isn't) logged, must not be nested
+pub fn count_records(filter: fn(&Record) -> bool, f: impl FnOnce()) -> Result<usize, TooManySinks> {
+    let sink_id = (*COUNTING_SINK_ID.call_once(|| register_sink(&COUNTING_SINK)))?;
+    set_sink_enabled(sink_id, false);
+    // Interrupt handler may log, the filter must not be locked on this CPU then
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *COUNTING_SINK.filter.write() = filter;
+    });
+    COUNTING_SINK.count.store(0, Ordering::Relaxed);
+    set_sink_enabled(sink_id, true);
+    f();
+    set_sink_enabled(sink_id, false);
+    Ok(COUNTING_SINK.count.load(Ordering::Relaxed))
+}
+
+/// Default log level, use [LogLevelGuard] to trace a specific code region
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
 /// Inits logger
-pub fn init() {
+pub fn init(level: LevelFilter) {
     log::set_logger(&SERIAL_LOGGER)
-        .map(|()| log::set_max_level(LevelFilter::Trace))
+        .map(|()| log::set_max_level(level))
         .expect("Failed to init logger");
 }
+
+/// Returns level from `log=<level>` command line argument (e.g. `log=debug`), [DEFAULT_LEVEL] if it's absent
+pub fn level_from_cmdline() -> Result<LevelFilter, &'static str> {
+    crate::cmdline::arg("log").map_or(Ok(DEFAULT_LEVEL), |level| {
+        level
+            .parse()
+            .map_err(|_| "invalid log level in command line")
+    })
+}
+
+/// Raises log level for a code region, restores previous level on drop
+///
+/// ```ignore
+/// {
+///     let _log_level_guard = LogLevelGuard::new(LevelFilter::Trace);
+///     interrupts::init();
+/// }
+/// ```
+#[must_use = "log level is restored when the guard is dropped"]
+pub struct LogLevelGuard {
+    previous_level: LevelFilter,
+}
+
+impl LogLevelGuard {
+    /// Raises log level to level, if it is lower
+    #[inline]
+    pub fn new(level: LevelFilter) -> Self {
+        let previous_level = log::max_level();
+        if level > previous_level {
+            log::set_max_level(level);
+        }
+        Self { previous_level }
+    }
+}
+
+impl Drop for LogLevelGuard {
+    #[inline]
+    fn drop(&mut self) {
+        log::set_max_level(self.previous_level);
+    }
+}
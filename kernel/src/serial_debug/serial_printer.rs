@@ -1,51 +1,61 @@
-use crate::com_ports;
-
-/// Serial port printer for QEMU logs writing
-///
-/// Locks COM1 PORT
-///
-/// **Don't use in interrupts**
-#[allow(dead_code)]
-pub static mut SERIAL_PRINTER: SerialPrinter = SerialPrinter;
+use crate::com_ports::{self, SerialChannel};
+use core::sync::atomic::{AtomicU64, Ordering};
 
 /// Serial port printer for QEMU logs writing but without locking COM1
 ///
 /// Can be used in interrupts
 #[allow(dead_code)]
-pub static mut SERIAL_PRINTER_LOCK_FREE: SerialPrinterLockFree = SerialPrinterLockFree;
+pub static mut SERIAL_PRINTER_LOCK_FREE: SerialPrinterLockFree =
+    SerialPrinterLockFree(SerialChannel::Com1);
+
+/// Number of writes sent without the lock because this CPU already held it
+static LOCK_FREE_FALLBACKS: AtomicU64 = AtomicU64::new(0);
 
 /// Serial port printer for QEMU logs writing
 ///
-/// Locks COM1 PORT
+/// Locks the port of the channel. If the port is locked by this CPU (e.g. an interrupt handler prints
+/// while the interrupted code holds the lock) it sends without the lock instead of deadlocking,
+/// output of such write may interleave with the interrupted one. Lock held by another CPU is waited for.
 ///
-/// **Don't use in interrupts**<br>
-/// If an interrupt occurs during a locked COM1 port and the interrupt handler tries to use this function, it will freeze.
-/// Use [SerialPrinterLockFree] in interrupts instead
-pub struct SerialPrinter;
+/// Can be used in interrupts
+pub struct SmartSerialPrinter(pub SerialChannel);
 
-/// Serial port printer but not locks COM1 PORT
-/// Useful for in interrupts printing
-pub struct SerialPrinterLockFree;
+/// Serial port printer but not locks the port of the channel
+///
+/// Used by panic handler, [SmartSerialPrinter] may wait for the lock
+pub struct SerialPrinterLockFree(pub SerialChannel);
 
-impl core::fmt::Write for SerialPrinter {
+impl core::fmt::Write for SmartSerialPrinter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let mut com1_port_lock = com_ports::COM1_PORT.lock();
+        if self.0.is_locked_by_this_cpu() {
+            LOCK_FREE_FALLBACKS.fetch_add(1, Ordering::Relaxed);
+            return SerialPrinterLockFree(self.0).write_str(s);
+        }
+        let mut port_lock = self.0.lock();
         for ch in s.bytes() {
             if !ch.is_ascii_control() || ch == b'\n' {
-                com1_port_lock.send(ch);
+                port_lock.send(ch);
             }
         }
         Ok(())
     }
 }
 
+/// Returns number of writes of [SmartSerialPrinter] sent without the lock on re-entry
+pub fn lock_free_fallbacks() -> u64 {
+    LOCK_FREE_FALLBACKS.load(Ordering::Relaxed)
+}
+
 impl core::fmt::Write for SerialPrinterLockFree {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         for ch in s.bytes() {
             if !ch.is_ascii_control() || ch == b'\n' {
-                #[allow(static_mut_refs)]
-                unsafe {
-                    com_ports::COM1_PORT_LOCK_FREE.send(ch);
+                match self.0 {
+                    #[allow(static_mut_refs)]
+                    SerialChannel::Com1 => unsafe {
+                        com_ports::COM1_PORT_LOCK_FREE.send(ch);
+                    },
+                    channel => channel.send_lock_free(ch),
                 }
             }
         }
@@ -55,48 +65,52 @@ impl core::fmt::Write for SerialPrinterLockFree {
 
 /// Prints ASCII string to COM1
 ///
-/// Locks COM1 PORT
-///
-/// **Don't use in interrupts**
+/// Uses [SmartSerialPrinter], can be used in interrupts<br>
+/// Other port is selected by [SerialChannel](crate::com_ports::SerialChannel) variant before `;`:
+/// `serial_print!(Com2; "trace")`
 /// # May panic
 #[macro_export]
 macro_rules! serial_print {
-    ($($arg:tt)*) => ({
+    ($channel:ident; $($arg:tt)*) => ({
         use core::fmt::Write;
-        #[allow(static_mut_refs)]
-        #[allow(clippy::macro_metavars_in_unsafe)]
-        let result = unsafe { $crate::serial_debug::serial_printer::SERIAL_PRINTER.write_fmt(format_args!($($arg)*)) };
-        result.expect("Failed to write to SERIAL_PRINTER")
+        $crate::serial_debug::serial_printer::SmartSerialPrinter($crate::com_ports::SerialChannel::$channel)
+            .write_fmt(format_args!($($arg)*))
+            .expect("Failed to write to SmartSerialPrinter")
     });
+    ($($arg:tt)*) => ($crate::serial_print!(Com1; $($arg)*));
 }
 
 /// Prints ASCII string with newline to COM1
 ///
-/// Locks COM1 PORT
-///
-/// **Don't use in interrupts**<br>
-/// Use serial_println_lock_free instead
+/// Uses [SmartSerialPrinter], can be used in interrupts
 /// ```ignore
-/// let mut com1_lock = com_ports::COM1_PORT.lock();
+/// let mut com1_lock = com_ports::SerialChannel::Com1.lock();
 /// com1_lock.write_str("COM1 locked\n"); // Printed
-/// //serial_println!("DEADLOCK!!!"); // Not printed, deadlock, infinite loop
-/// serial_println_lock_free!("But serial_println_lock_free may print"); // Printed
+/// serial_println!("No deadlock"); // Printed without the lock, this CPU holds it
 /// ```
 /// # May panic
 #[macro_export]
 macro_rules! serial_println {
     () => ($crate::serial_print!("\n"));
+    ($channel:ident;) => ($crate::serial_print!($channel; "\n"));
+    ($channel:ident; $($arg:tt)*) => ($crate::serial_print!($channel; "{}\n", format_args!($($arg)*)));
     ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
 }
 
 // Lock free variants
-// For interrupts printng
+// For panic handler, they never wait
 
 /// Prints ASCII string to COM1 without lock
 ///
-/// Can be used in interrupts
+/// Can be used in panic handler<br>
+/// Other port is selected like in [serial_print]: `serial_print_lock_free!(Com2; "trace")`
 #[macro_export]
 macro_rules! serial_print_lock_free {
+    ($channel:ident; $($arg:tt)*) => ({
+        use core::fmt::Write;
+        let _ = $crate::serial_debug::serial_printer::SerialPrinterLockFree($crate::com_ports::SerialChannel::$channel)
+            .write_fmt(format_args!($($arg)*));
+    });
     ($($arg:tt)*) => ({
         use core::fmt::Write;
         // Can't panic because used in panic handler
@@ -106,17 +120,13 @@ macro_rules! serial_print_lock_free {
     });
 }
 
-/// Prints ASCII string to COM1 without lock
+/// Prints ASCII string with newline to COM1 without lock
 ///
-/// Can be used in interrupts
-/// ```ignore
-/// let mut com1_lock = com_ports::COM1_PORT.lock();
-/// com1_lock.write_str("COM1 locked\n"); // Printed
-/// //serial_println!("DEADLOCK!!!"); // Not printed, deadlock, infinite loop
-/// serial_println_lock_free!("But serial_println_lock_free may print"); // Printed
-/// ```
+/// Can be used in panic handler
 #[macro_export]
 macro_rules! serial_println_lock_free {
     () => ($crate::serial_print_lock_free!("\n"));
+    ($channel:ident;) => ($crate::serial_print_lock_free!($channel; "\n"));
+    ($channel:ident; $($arg:tt)*) => ($crate::serial_print_lock_free!($channel; "{}\n", format_args!($($arg)*)));
     ($($arg:tt)*) => ($crate::serial_print_lock_free!("{}\n", format_args!($($arg)*)));
 }
@@ -0,0 +1,76 @@
+//! Orderly kernel stop
+//!
+//! 1. Disable interrupts on this core
+//! 2. Halt other cores: NMI IPI to every online AP, its NMI handler halts the core
+//! 3. Flush serial output
+//! 4. Power off through ACPI (halt on fatal errors, so the output stays on screen)
+use crate::com_ports;
+use crate::interrupts::apic::{self, smp};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by the first core which calls [shutdown]
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Whether shutdown is in progress, NMI handler halts the core then
+pub fn is_halting() -> bool {
+    HALTING.load(Ordering::Acquire)
+}
+
+/// Why the kernel stops
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// kmain finished its work
+    KernelFinished,
+    /// Unrecoverable error (panic, fatal exception)
+    Fatal,
+    /// Shutdown requested (power button, command)
+    Requested,
+}
+
+impl core::fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ShutdownReason::KernelFinished => write!(f, "kernel finished"),
+            ShutdownReason::Fatal => write!(f, "fatal error"),
+            ShutdownReason::Requested => write!(f, "requested"),
+        }
+    }
+}
+
+/// Stops the kernel, never returns
+///
+/// Can be called at any stage of boot, from interrupt handlers and from any core, it doesn't lock anything.
+/// If another core is already shutting down, this core just halts.
+pub fn shutdown(reason: ShutdownReason) -> ! {
+    x86_64::instructions::interrupts::disable();
+    if HALTING.swap(true, Ordering::AcqRel) {
+        halt_forever()
+    }
+    // Caller may hold COM1 lock
+    crate::serial_println_lock_free!("Shutdown: {reason}");
+
+    // NMI gets through even if the AP runs with interrupts disabled (e.g. spins in a handler).
+    // No APs are online before SMP bring-up, so Local APIC isn't touched that early
+    let mut this_apic_id = None;
+    for apic_id in smp::online_aps() {
+        if *this_apic_id.get_or_insert_with(apic::local_apic_id) != apic_id {
+            apic::send_nmi_ipi(apic_id);
+        }
+    }
+
+    com_ports::flush_com1();
+
+    if reason == ShutdownReason::Fatal {
+        halt_forever()
+    }
+    crate::acpi::power::shutdown()
+}
+
+/// Halts this core forever
+pub fn halt_forever() -> ! {
+    loop {
+        // NMI wakes the core up
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,149 @@
+//! Synchronization primitives
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+
+/// Spin mutex which disables interrupts while it's held
+///
+/// A lock taken by both normal code and interrupt handlers must be IRQ-safe:
+/// if an interrupt arrives while normal code holds [spin::Mutex], the handler spins forever.
+pub struct IrqMutex<T> {
+    mutex: Mutex<T>,
+}
+
+/// Guard of [IrqMutex], restores interrupt flag after the lock is released
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    /// Whether interrupts were enabled before lock
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            mutex: Mutex::new(value),
+        }
+    }
+
+    /// Disables interrupts and locks mutex
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let interrupts_were_enabled = x86_64::instructions::interrupts::are_enabled();
+        x86_64::instructions::interrupts::disable();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.mutex.lock()),
+            interrupts_were_enabled,
+        }
+    }
+
+    /// Tries to lock mutex, interrupts are left untouched on failure
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let interrupts_were_enabled = x86_64::instructions::interrupts::are_enabled();
+        x86_64::instructions::interrupts::disable();
+        match self.mutex.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_were_enabled,
+            }),
+            None => {
+                if interrupts_were_enabled {
+                    x86_64::instructions::interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock first, an interrupt after enable may take the lock
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        if self.interrupts_were_enabled {
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+}
+
+/// Lock-free byte ring buffer with single producer (e.g. interrupt handler) and multiple consumers
+///
+/// Bytes pushed into full buffer are dropped
+pub struct ByteRingBuffer<const N: usize> {
+    buffer: [AtomicU8; N],
+    /// Number of pushed bytes, only producer changes it
+    head: AtomicUsize,
+    /// Number of popped bytes
+    tail: AtomicUsize,
+}
+
+impl<const N: usize> ByteRingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [const { AtomicU8::new(0) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pushes byte, returns false if the buffer is full
+    ///
+    /// Must not be called on two cores at once
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == N {
+            return false;
+        }
+        self.buffer[head % N].store(byte, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Pops the oldest byte
+    pub fn pop(&self) -> Option<u8> {
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            let head = self.head.load(Ordering::Acquire);
+            if tail == head {
+                return None;
+            }
+            // The slot is not overwritten until tail is moved past it
+            let byte = self.buffer[tail % N].load(Ordering::Relaxed);
+            if self
+                .tail
+                .compare_exchange(
+                    tail,
+                    tail.wrapping_add(1),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Some(byte);
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for ByteRingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
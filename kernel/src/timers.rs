@@ -1,10 +1,16 @@
 use crate::acpi::ACPI_TABLES;
+use crate::interrupts::apic;
 use acpi_lib::hpet::HpetTable;
 use acpi_lib::{AcpiError, AcpiResult};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
 use spin::Once;
 
+pub mod fixed_point;
 pub mod hpet;
 pub mod pit;
+pub mod timer_queue;
+pub mod tsc;
 
 enum TimerName {
     PIT,
@@ -35,19 +41,115 @@ pub fn init() {
     // Detect and init HPET
     hpet::init();
 
-    // Check Invariant TSC support using cpuid (works on Intel and AMD)
-    // TODO: add ITSC
-    let cpuid = raw_cpuid::CpuId::new();
-    let has_invariant_tsc = cpuid
-        .get_advanced_power_mgmt_info()
-        .expect("Failed to get cpuid advanced power management info")
-        .has_invariant_tsc();
-    match has_invariant_tsc {
-        true => {
-            log::info!("Invariant TSC supported");
+    // Detect and calibrate Invariant TSC
+    tsc::init();
+
+    // Calibrate bootstrap processor's Local APIC Timer and start its tick
+    apic::timer::init();
+
+    let clock_source = CLOCK_SOURCE.call_once(|| {
+        if tsc::is_supported() {
+            ClockSource::Tsc
+        } else if hpet::is_supported() {
+            ClockSource::Hpet
+        } else {
+            ClockSource::Pit
+        }
+    });
+    log::info!("Clock source: {clock_source:?}");
+}
+
+/// Timer used by [monotonic_now] and [sleep]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockSource {
+    /// Invariant TSC, nanosecond resolution
+    Tsc,
+    /// HPET, usually 10-100 ns resolution
+    Hpet,
+    /// PIT tick counter, millisecond resolution, needs interrupts
+    Pit,
+}
+
+/// Clock source, the finest-grained available timer, selected at [init]
+static CLOCK_SOURCE: Once<ClockSource> = Once::new();
+
+/// Returns clock source
+///
+/// Panics if timers are not inited
+#[inline]
+pub fn clock_source() -> ClockSource {
+    *CLOCK_SOURCE.get().expect("Timers are not inited")
+}
+
+/// Returns monotonic time since an arbitrary point (e.g. power on)
+///
+/// Panics if timers are not inited
+pub fn monotonic_now() -> Duration {
+    match clock_source() {
+        ClockSource::Tsc => tsc::now(),
+        ClockSource::Hpet => hpet::get_current_ticks_as_duration(),
+        ClockSource::Pit => pit::get_ticks_counter_as_duration(),
+    }
+}
+
+/// Sleeps using clock source
+///
+/// PIT sleep is rounded down to its tick interval and needs interrupts.
+/// Panics if timers are not inited
+pub fn sleep(sleep_duration: Duration) {
+    match clock_source() {
+        ClockSource::Tsc => tsc::busy_sleep(sleep_duration),
+        ClockSource::Hpet => hpet::sleep(sleep_duration),
+        ClockSource::Pit => {
+            pit::sleep(u32::try_from(sleep_duration.as_millis()).unwrap_or(u32::MAX));
         }
-        false => {
-            log::info!("Invariant TSC not supported");
+    }
+}
+
+/// Set by [sleep_hlt] timer queue callback
+static SLEEP_HLT_WOKEN: AtomicBool = AtomicBool::new(false);
+
+/// Number of [sleep_hlt] timer interrupts
+static SLEEP_HLT_TIMER_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// Sleeps halting CPU until [timer_queue] callback
+///
+/// Other interrupts wake the CPU too, it halts again until the callback is called.
+/// Falls back to [sleep] if timer queue is not available or this CPU is not BSP (IO APIC delivers to BSP only).
+/// Interrupt state is restored.
+/// Panics if timers are not inited
+pub fn sleep_hlt(sleep_duration: Duration) {
+    let deadline = monotonic_now() + sleep_duration;
+    let interrupts_enabled = x86_64::instructions::interrupts::are_enabled();
+    x86_64::instructions::interrupts::disable();
+    SLEEP_HLT_WOKEN.store(false, Ordering::Relaxed);
+    if !apic::is_bsp()
+        || timer_queue::schedule(sleep_duration, || {
+            SLEEP_HLT_TIMER_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+            SLEEP_HLT_WOKEN.store(true, Ordering::Release);
+        })
+        .is_err()
+    {
+        if interrupts_enabled {
+            x86_64::instructions::interrupts::enable();
         }
+        sleep(deadline.saturating_sub(monotonic_now()));
+        return;
+    }
+
+    // Flag is checked with interrupts disabled, sti; hlt doesn't lose the interrupt between check and halt
+    while !SLEEP_HLT_WOKEN.load(Ordering::Acquire) {
+        x86_64::instructions::interrupts::enable_and_hlt();
+        x86_64::instructions::interrupts::disable();
     }
+    if interrupts_enabled {
+        x86_64::instructions::interrupts::enable();
+    }
+    // HPET and clock source may disagree slightly
+    sleep(deadline.saturating_sub(monotonic_now()));
+}
+
+/// Returns number of timer interrupts that woke [sleep_hlt]
+pub fn sleep_hlt_timer_interrupts() -> u64 {
+    SLEEP_HLT_TIMER_INTERRUPTS.load(Ordering::Relaxed)
 }
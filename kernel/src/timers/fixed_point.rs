@@ -0,0 +1,82 @@
+//! Fixed-point timer arithmetic shared by HPET, TSC and Local APIC Timer
+//!
+//! Floating point is not used in the kernel, periods are stored as [Fixed] (FixedU64<U12>).
+//!
+//! Precision: [Fixed] has delta 1/2^12 = 0.00024414062, so a period in nanoseconds has absolute error
+//! less than 0.00025 ns. For 10 MHz HPET (100 ns period) relative error is ~0.0000025%,
+//! for 3 GHz TSC (0.333 ns period) it is ~0.07%, high-frequency counters should use
+//! [ticks_to_duration_by_frequency] and [duration_to_ticks_by_frequency] which are exact.
+//!
+//! Overflow: ticks/duration conversions are done in u128 and saturate at u64::MAX, they never panic.
+use core::time::Duration;
+use fixed::types::extra::U12;
+use fixed::FixedU64;
+
+/// Fixed-point type of timer periods and frequencies
+pub type Fixed = FixedU64<U12>;
+
+const FEMTOSECONDS_IN_SECOND: u64 = 1_000_000_000_000_000;
+
+const FEMTOSECONDS_IN_NANOSECOND: u64 = 1_000_000;
+
+const NANOSECONDS_IN_SECOND: u128 = 1_000_000_000;
+
+/// Calculates frequency in Hz from period in femtoseconds
+///
+/// f = 10^15 / period, period must be not zero
+#[inline]
+pub fn frequency_from_period_fs(period_fs: u64) -> Fixed {
+    assert_ne!(period_fs, 0, "Zero timer period");
+    Fixed::from_num(FEMTOSECONDS_IN_SECOND) / period_fs
+}
+
+/// Calculates period in nanoseconds from period in femtoseconds
+#[inline]
+pub fn period_ns_from_period_fs(period_fs: u64) -> Fixed {
+    Fixed::from_num(period_fs) / FEMTOSECONDS_IN_NANOSECOND
+}
+
+/// Converts ticks to duration using period in nanoseconds
+///
+/// Saturates at u64::MAX nanoseconds
+#[inline]
+pub fn ticks_to_duration(ticks: u64, period_ns: Fixed) -> Duration {
+    // ticks * period, in u128 to not overflow, fractional bits are dropped
+    let nanoseconds = (ticks as u128 * period_ns.to_bits() as u128) >> Fixed::FRAC_NBITS;
+    Duration::from_nanos(saturate_to_u64(nanoseconds))
+}
+
+/// Converts duration to ticks using period in nanoseconds, rounds down
+///
+/// Period must be not zero, saturates at u64::MAX ticks
+#[inline]
+pub fn duration_to_ticks(duration: Duration, period_ns: Fixed) -> u64 {
+    assert_ne!(period_ns, Fixed::ZERO, "Zero timer period");
+    // nanoseconds / period, both with FRAC_NBITS fractional bits
+    let nanoseconds_bits = duration.as_nanos().saturating_mul(1 << Fixed::FRAC_NBITS);
+    saturate_to_u64(nanoseconds_bits / period_ns.to_bits() as u128)
+}
+
+/// Converts ticks to duration using frequency in Hz, exact (rounds down to nanosecond)
+///
+/// Frequency must be not zero, saturates at u64::MAX nanoseconds
+#[inline]
+pub fn ticks_to_duration_by_frequency(ticks: u64, frequency_hz: u64) -> Duration {
+    assert_ne!(frequency_hz, 0, "Zero timer frequency");
+    let nanoseconds = ticks as u128 * NANOSECONDS_IN_SECOND / frequency_hz as u128;
+    Duration::from_nanos(saturate_to_u64(nanoseconds))
+}
+
+/// Converts duration to ticks using frequency in Hz, exact (rounds down to tick)
+///
+/// Saturates at u64::MAX ticks
+#[inline]
+pub fn duration_to_ticks_by_frequency(duration: Duration, frequency_hz: u64) -> u64 {
+    let ticks = duration.as_nanos().saturating_mul(frequency_hz as u128) / NANOSECONDS_IN_SECOND;
+    saturate_to_u64(ticks)
+}
+
+#[inline]
+fn saturate_to_u64(value: u128) -> u64 {
+    u64::try_from(value).unwrap_or(u64::MAX)
+}
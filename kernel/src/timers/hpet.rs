@@ -1,15 +1,67 @@
+use super::fixed_point::{self, Fixed};
 use crate::acpi::ACPI_TABLES;
+use crate::interrupts::apic::ioapic;
+use crate::interrupts::idt;
 use crate::memory_management::virtual_memory_manager;
+use acpi_lib::address::AddressSpace;
+use acpi_lib::sdt::SdtHeader;
 use acpi_lib::{AcpiError, AcpiTable, HpetInfo};
 use bitfield::bitfield;
 use core::time::Duration;
-use fixed::types::extra::U12;
-use fixed::FixedU64;
-use spin::Once;
+use spin::{Mutex, Once};
 use x86_64::{PhysAddr, VirtAddr};
 
 static HPET_TIMER: Once<Result<HPETTimer, &'static str>> = Once::new();
 
+/// ACPI HPET table layout
+///
+/// Fields of [acpi_lib::hpet::HpetTable] are private, so base address space is read through this struct.
+#[repr(C, packed)]
+struct HpetTableRaw {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: RawGenericAddress,
+    hpet_number: u8,
+    clock_tick_unit: u16,
+    page_protection_and_oem: u8,
+}
+
+const _: () = assert!(core::mem::size_of::<HpetTableRaw>() == 56);
+const _: () = assert!(core::mem::offset_of!(HpetTableRaw, base_address) == 40);
+
+/// ACPI Generic Address Structure as it is in the table
+#[repr(C, packed)]
+struct RawGenericAddress {
+    address_space_id: u8,
+    register_bit_width: u8,
+    register_bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+impl HpetTableRaw {
+    /// Returns address space of HPET registers
+    fn base_address_space(&self) -> Result<AddressSpace, AcpiError> {
+        let address_space = match self.base_address.address_space_id {
+            0x00 => AddressSpace::SystemMemory,
+            0x01 => AddressSpace::SystemIo,
+            0x02 => AddressSpace::PciConfigSpace,
+            0x03 => AddressSpace::EmbeddedController,
+            0x04 => AddressSpace::SmBus,
+            0x05 => AddressSpace::SystemCmos,
+            0x06 => AddressSpace::PciBarTarget,
+            0x07 => AddressSpace::Ipmi,
+            0x08 => AddressSpace::GeneralIo,
+            0x09 => AddressSpace::GenericSerialBus,
+            0x0A => AddressSpace::PlatformCommunicationsChannel,
+            0x7F => AddressSpace::FunctionalFixedHardware,
+            address_space_id @ 0x80..=0xFF => AddressSpace::OemDefined(address_space_id),
+            _ => return Err(AcpiError::InvalidGenericAddress),
+        };
+        Ok(address_space)
+    }
+}
+
 /// Detects and creates HPET (but not starts, only detects)
 pub fn init() {
     // Have HPET?
@@ -29,26 +81,20 @@ pub fn init() {
     // HPET in System Memory?
     // It is unlikely to encounter System I/O, I assume System Memory used
     // In this version, the library panics when creating HpetInfo::new() if HPET uses System I/O, but I'll check it out anyway.
-    unsafe {
-        let hpet_table_ptr = ACPI_TABLES
-            .get()
-            .unwrap()
-            .lock()
-            .find_table::<acpi_lib::hpet::HpetTable>()
-            .unwrap()
-            .virtual_start()
-            .as_ptr();
-        (*hpet_table_ptr)
-            .validate()
-            .expect("Invalid HPET table detected");
-        // Since the library is written by strange people, the fields of the HpetTable structure are private, let's check it manually using a pointer.
-        // TODO: Contribute with public fields in HpetTable
-        // BASE_ADDRESS is 12 byte at 40 byte offset
-        // If first byte is 0 - System Memory
-        // If first byte is 1 - System IO
-        let base_address_first_byte = *(hpet_table_ptr.byte_add(40) as *mut u8);
-        assert_eq!(base_address_first_byte, 0, "HPET uses System IO");
-    }
+    let hpet_table = ACPI_TABLES
+        .get()
+        .unwrap()
+        .lock()
+        .find_table::<acpi_lib::hpet::HpetTable>()
+        .unwrap();
+    hpet_table.validate().expect("Invalid HPET table detected");
+    // SAFETY: HpetTableRaw has HpetTable layout, the table is in static ACPI tables memory
+    let hpet_table_raw = unsafe { &*(hpet_table.virtual_start().as_ptr() as *const HpetTableRaw) };
+    let base_address_space = hpet_table_raw.base_address_space();
+    assert!(
+        matches!(base_address_space, Ok(AddressSpace::SystemMemory)),
+        "HPET doesn't use System Memory: {base_address_space:?}"
+    );
 
     // HPET detected
     log::info!("HPET supported");
@@ -60,6 +106,27 @@ pub fn init() {
         log::info!("HPET cannot be used: {err}");
     }
 
+    if HPET_TIMER.get().unwrap().is_err() {
+        return;
+    }
+    for comparator in 0..comparators_number() {
+        log::info!(
+            "HPET comparator {comparator} interrupt routing capability: {:#010X}",
+            comparator_interrupt_routing_capability(comparator).unwrap()
+        );
+    }
+
+    log::info!(
+        "HPET legacy replacement route supported: {}",
+        has_legacy_replacement()
+    );
+
+    log::info!(
+        "HPET frequency: {} Hz, period: {} ns",
+        frequency_hz().unwrap(),
+        period_ns().unwrap()
+    );
+
     // Run main counter and interrupts (if comparators has enabled interrupts)
     log::info!("Run HPET");
     run();
@@ -70,15 +137,42 @@ pub fn is_supported() -> bool {
     HPET_TIMER.get().unwrap().is_ok()
 }
 
+/// Returns HPET if it's supported and detected
+#[inline]
+fn hpet_timer() -> Option<&'static HPETTimer> {
+    HPET_TIMER.get()?.as_ref().ok()
+}
+
+/// Returns HPET main counter frequency in Hz (rounded down)
+///
+/// None if HPET is not supported
+pub fn frequency_hz() -> Option<u64> {
+    hpet_timer().map(|hpet_timer| hpet_timer.frequency.to_num())
+}
+
+/// Returns HPET main counter period (tick length) in femtoseconds, exact
+///
+/// None if HPET is not supported
+pub fn period_fs() -> Option<u64> {
+    hpet_timer().map(|hpet_timer| hpet_timer.period_in_femtoseconds.to_num())
+}
+
+/// Returns HPET main counter period (tick length) in nanoseconds
+///
+/// None if HPET is not supported
+pub fn period_ns() -> Option<Fixed> {
+    hpet_timer().map(|hpet_timer| hpet_timer.period_in_nanoseconds)
+}
+
 // HPET control structure
 struct HPETTimer {
     hpet_acpi_info: HpetInfo,
     base_address: VirtAddr,
     /// Period in femtoseconds (femtoseconds per tick)
-    period_in_femtoseconds: FixedU64<U12>,
+    period_in_femtoseconds: Fixed,
     /// Period in nanoseconds (nanoseconds per tick)
-    period_in_nanoseconds: FixedU64<U12>,
-    frequency: FixedU64<U12>,
+    period_in_nanoseconds: Fixed,
+    frequency: Fixed,
 }
 
 impl HPETTimer {
@@ -113,17 +207,13 @@ impl HPETTimer {
         );
 
         // Calculate frequency (Min: 10 MHz) using counter clock period (in femtoseconds)
-        // f = 10^15 / period
-        // FixedU64<U12> has delta 1/2^12 = 0.00024414062
-        let femtoseconds_in_second: FixedU64<U12> =
-            FixedU64::<U12>::from_num(1_000_000_000_000_000u64);
-        let period_in_femtoseconds: FixedU64<U12> = FixedU64::<U12>::from_num(counter_clock_period);
-        let frequency: FixedU64<U12> = femtoseconds_in_second / counter_clock_period;
+        let period_in_femtoseconds = Fixed::from_num(counter_clock_period);
+        let frequency = fixed_point::frequency_from_period_fs(counter_clock_period);
 
         // For Duration calculation
-        let period_in_nanoseconds: FixedU64<U12> = period_in_femtoseconds / 1_000_000;
+        let period_in_nanoseconds = fixed_point::period_ns_from_period_fs(counter_clock_period);
         assert!(
-            period_in_nanoseconds > FixedU64::<U12>::DELTA,
+            period_in_nanoseconds > Fixed::DELTA,
             "Calculated period in nanoseconds small than delta"
         );
 
@@ -147,28 +237,94 @@ impl HPETTimer {
 
     /// General Configuration Register
     #[inline]
-    fn read_general_configuration_register_value(&self) -> GeneralConfigurationRegisterValue {
+    fn read_general_configuration_register_value(
+        base_address: VirtAddr,
+    ) -> GeneralConfigurationRegisterValue {
         // Offset: 0x010 - 0x017 (8 bytes)
-        let register_value: u64 = unsafe { *(self.base_address.as_ptr::<u64>().byte_add(0x010)) };
+        let register_value: u64 = unsafe { *(base_address.as_ptr::<u64>().byte_add(0x010)) };
         GeneralConfigurationRegisterValue(register_value)
     }
 
     /// General Configuration Register
     #[inline]
     fn write_general_configuration_register_value(
-        &self,
+        base_address: VirtAddr,
         register_value: GeneralConfigurationRegisterValue,
     ) {
         // Offset: 0x010 - 0x017 (8 bytes)
         unsafe {
-            let register_ptr = self
-                .base_address
+            let register_ptr = base_address
                 .as_mut_ptr::<GeneralConfigurationRegisterValue>()
                 .byte_add(0x010);
             register_ptr.write_volatile(register_value);
         }
     }
 
+    /// Number of comparators (timers)
+    #[inline]
+    fn comparators_number(&self) -> u8 {
+        Self::read_general_capabilities_and_id_register_value(self.base_address).number_timers_cap()
+            as u8
+            + 1
+    }
+
+    /// Timer N Configuration and Capability Register
+    #[inline]
+    fn read_timer_configuration_and_capability_register_value(
+        &self,
+        comparator: u8,
+    ) -> TimerConfigurationAndCapabilityRegisterValue {
+        debug_assert!(
+            comparator < self.comparators_number(),
+            "Invalid HPET comparator number"
+        );
+        // Offset: (0x100 + 0x20 * N) - (0x107 + 0x20 * N) (8 bytes)
+        let register_value: u64 = unsafe {
+            self.base_address
+                .as_ptr::<u64>()
+                .byte_add(0x100 + 0x20 * comparator as usize)
+                .read_volatile()
+        };
+        TimerConfigurationAndCapabilityRegisterValue(register_value)
+    }
+
+    /// Timer N Configuration and Capability Register
+    #[inline]
+    fn write_timer_configuration_and_capability_register_value(
+        &self,
+        comparator: u8,
+        register_value: TimerConfigurationAndCapabilityRegisterValue,
+    ) {
+        debug_assert!(
+            comparator < self.comparators_number(),
+            "Invalid HPET comparator number"
+        );
+        // Offset: (0x100 + 0x20 * N) - (0x107 + 0x20 * N) (8 bytes)
+        unsafe {
+            let register_ptr = self
+                .base_address
+                .as_mut_ptr::<TimerConfigurationAndCapabilityRegisterValue>()
+                .byte_add(0x100 + 0x20 * comparator as usize);
+            register_ptr.write_volatile(register_value);
+        }
+    }
+
+    /// Timer N Comparator Value Register
+    #[inline]
+    fn write_timer_comparator_value_register(&self, comparator: u8, register_value: u64) {
+        debug_assert!(
+            comparator < self.comparators_number(),
+            "Invalid HPET comparator number"
+        );
+        // Offset: (0x108 + 0x20 * N) - (0x10F + 0x20 * N) (8 bytes)
+        unsafe {
+            self.base_address
+                .as_mut_ptr::<u64>()
+                .byte_add(0x108 + 0x20 * comparator as usize)
+                .write_volatile(register_value);
+        }
+    }
+
     /// Main Counter Value Register
     #[inline]
     fn read_main_counter_value_register(&self) -> u64 {
@@ -186,9 +342,10 @@ impl HPETTimer {
 /// See General Configuration Register::ENABLE_CNF = 1
 pub fn run() {
     let hpet_timer = HPET_TIMER.get().unwrap().as_ref().unwrap();
-    let mut register_value = hpet_timer.read_general_configuration_register_value();
+    let mut register_value =
+        HPETTimer::read_general_configuration_register_value(hpet_timer.base_address);
     register_value.set_enable_cnf(true);
-    hpet_timer.write_general_configuration_register_value(register_value);
+    HPETTimer::write_general_configuration_register_value(hpet_timer.base_address, register_value);
 }
 
 /// Halts main counter and disables interrupts
@@ -196,9 +353,236 @@ pub fn run() {
 /// See General Configuration Register::ENABLE_CNF = 0
 pub fn halt() {
     let hpet_timer = HPET_TIMER.get().unwrap().as_ref().unwrap();
-    let mut register_value = hpet_timer.read_general_configuration_register_value();
+    let mut register_value =
+        HPETTimer::read_general_configuration_register_value(hpet_timer.base_address);
     register_value.set_enable_cnf(false);
-    hpet_timer.write_general_configuration_register_value(register_value);
+    HPETTimer::write_general_configuration_register_value(hpet_timer.base_address, register_value);
+}
+
+/// Whether HPET can route comparators 0 and 1 like legacy PIT and RTC
+///
+/// See General Capabilities and ID Register::LEG_RT_CAP
+pub fn has_legacy_replacement() -> bool {
+    hpet_timer().is_some_and(|hpet_timer| {
+        HPETTimer::read_general_capabilities_and_id_register_value(hpet_timer.base_address)
+            .legacy_replacement_cap()
+    })
+}
+
+/// Enables legacy replacement route
+///
+/// Comparator 0 then interrupts through IRQ 0 (IO APIC GSI from Interrupt Source Override, usually 2)
+/// and comparator 1 through IRQ 8 (RTC), their routing configuration is ignored, PIT and RTC don't interrupt.
+/// Some firmware routes HPET interrupts only this way (routing capability is empty).
+///
+/// See General Configuration Register::LEG_RT_CNF = 1
+pub fn enable_legacy_replacement() -> Result<(), &'static str> {
+    let hpet_timer = hpet_timer().ok_or("HPET is not supported")?;
+    unsafe { enable_legacy_replacement_at(hpet_timer.base_address) }
+}
+
+/// Enables legacy replacement route of HPET registers at base address, see [enable_legacy_replacement]
+///
+/// # Safety
+/// Base address must point to HPET registers (a fake registers block is fine), at least to General Configuration Register
+pub unsafe fn enable_legacy_replacement_at(base_address: VirtAddr) -> Result<(), &'static str> {
+    if !HPETTimer::read_general_capabilities_and_id_register_value(base_address)
+        .legacy_replacement_cap()
+    {
+        return Err("HPET doesn't support legacy replacement route");
+    }
+    let mut register_value = HPETTimer::read_general_configuration_register_value(base_address);
+    register_value.set_legacy_replacement_cnf(true);
+    HPETTimer::write_general_configuration_register_value(base_address, register_value);
+    Ok(())
+}
+
+/// Whether legacy replacement route is enabled
+pub fn is_legacy_replacement_enabled() -> bool {
+    hpet_timer().is_some_and(|hpet_timer| {
+        HPETTimer::read_general_configuration_register_value(hpet_timer.base_address)
+            .legacy_replacement_cnf()
+    })
+}
+
+/// Returns number of HPET comparators
+pub fn comparators_number() -> u8 {
+    let hpet_timer = HPET_TIMER.get().unwrap().as_ref().unwrap();
+    hpet_timer.comparators_number()
+}
+
+/// Returns Timer N Interrupt Routing Capability
+///
+/// Bit N is set if the comparator can be routed to IO APIC GSI N
+pub fn comparator_interrupt_routing_capability(comparator: u8) -> Result<u32, &'static str> {
+    let hpet_timer = HPET_TIMER.get().unwrap().as_ref().unwrap();
+    if comparator >= hpet_timer.comparators_number() {
+        return Err("Comparator does not exist");
+    }
+    Ok(hpet_timer
+        .read_timer_configuration_and_capability_register_value(comparator)
+        .int_route_cap() as u32)
+}
+
+/// Selects IO APIC GSI for comparator's interrupts
+///
+/// If requested_gsi is Some, checks that the comparator can be routed to it,
+/// otherwise selects the lowest GSI supported by the comparator.
+///
+/// A comparator can't be routed to an arbitrary GSI, programming an unsupported route silently delivers no interrupt.
+pub fn select_comparator_gsi(
+    comparator: u8,
+    requested_gsi: Option<u32>,
+) -> Result<u32, &'static str> {
+    let routing_capability = comparator_interrupt_routing_capability(comparator)?;
+    match requested_gsi {
+        Some(gsi) => {
+            if gsi < u32::BITS && routing_capability & (1 << gsi) != 0 {
+                Ok(gsi)
+            } else {
+                Err("Comparator can't be routed to requested GSI")
+            }
+        }
+        None => {
+            if routing_capability == 0 {
+                Err("Comparator can't be routed to any GSI")
+            } else {
+                Ok(routing_capability.trailing_zeros())
+            }
+        }
+    }
+}
+
+/// Routes comparator's interrupts to IO APIC GSI
+///
+/// GSI validated using [select_comparator_gsi]
+pub fn set_comparator_gsi(comparator: u8, gsi: u32) -> Result<(), &'static str> {
+    let gsi = select_comparator_gsi(comparator, Some(gsi))?;
+    let hpet_timer = HPET_TIMER.get().unwrap().as_ref().unwrap();
+    let mut register_value =
+        hpet_timer.read_timer_configuration_and_capability_register_value(comparator);
+    register_value.set_int_route_cnf(gsi as u64);
+    hpet_timer.write_timer_configuration_and_capability_register_value(comparator, register_value);
+    Ok(())
+}
+
+/// Comparator used by [set_oneshot]
+const ONESHOT_COMPARATOR: u8 = 0;
+
+/// Shortest one-shot delay, the comparator must be written before the main counter passes it
+const ONESHOT_MIN_DELAY: Duration = Duration::from_micros(10);
+
+/// IO APIC GSI of one-shot comparator, routed once at first [set_oneshot]
+static ONESHOT_GSI: Once<Result<u8, &'static str>> = Once::new();
+
+/// One-shot set by [set_oneshot]
+#[derive(Copy, Clone)]
+struct Oneshot {
+    /// Main counter value
+    deadline_ticks: u64,
+    callback: fn(),
+}
+
+/// Pending one-shot
+static ONESHOT: Mutex<Option<Oneshot>> = Mutex::new(None);
+
+/// Calls callback from interrupt handler after the delay
+///
+/// Comparator 0 fires once in non-periodic mode, its interrupt goes through IO APIC to [idt::HPET_ONESHOT_IDT_VECTOR].
+/// Pending one-shot is replaced. Callback is called with interrupts disabled, interrupts must be enabled to get it.
+/// [timer_queue](super::timer_queue) owns the one-shot, use it for deferred work.
+pub fn set_oneshot(delay: Duration, callback: fn()) -> Result<(), &'static str> {
+    let hpet_timer = hpet_timer().ok_or("HPET is not supported")?;
+    if hpet_timer.comparators_number() < ONESHOT_COMPARATOR + 1 {
+        return Err("HPET doesn't have one-shot comparator");
+    }
+    if is_legacy_replacement_enabled() {
+        return Err("Comparator 0 is routed to IRQ 0 by legacy replacement");
+    }
+    let gsi = (*ONESHOT_GSI.call_once(route_oneshot_comparator))?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut oneshot = ONESHOT.lock();
+        let mut register_value =
+            hpet_timer.read_timer_configuration_and_capability_register_value(ONESHOT_COMPARATOR);
+        // Comparator is changed with interrupt disabled, so the old deadline doesn't fire
+        register_value.set_int_enb_cnf(false);
+        hpet_timer.write_timer_configuration_and_capability_register_value(
+            ONESHOT_COMPARATOR,
+            register_value,
+        );
+
+        let deadline_ticks = hpet_timer.read_main_counter_value_register()
+            + duration_to_ticks(delay.max(ONESHOT_MIN_DELAY));
+        *oneshot = Some(Oneshot {
+            deadline_ticks,
+            callback,
+        });
+        hpet_timer.write_timer_comparator_value_register(ONESHOT_COMPARATOR, deadline_ticks);
+
+        register_value.set_type_cnf(false); // Non-periodic
+        register_value.set_int_type_cnf(false); // Edge-triggered
+        register_value.set_mode32_cnf(false);
+        register_value.set_fsb_en_cnf(false);
+        register_value.set_int_route_cnf(gsi as u64);
+        register_value.set_int_enb_cnf(true);
+        hpet_timer.write_timer_configuration_and_capability_register_value(
+            ONESHOT_COMPARATOR,
+            register_value,
+        );
+    });
+    Ok(())
+}
+
+/// Selects GSI of one-shot comparator, redirects it to [idt::HPET_ONESHOT_IDT_VECTOR] and unmasks it
+///
+/// GSI above ISA IRQs is preferred, ISA GSI may be shared (e.g. PIT and HPET both use GSI 2 in QEMU PIIX4)
+/// and its previous vector doesn't get interrupts anymore.
+fn route_oneshot_comparator() -> Result<u8, &'static str> {
+    let routing_capability = comparator_interrupt_routing_capability(ONESHOT_COMPARATOR)?;
+    let gsi = match routing_capability & !0xFFFF {
+        0 => select_comparator_gsi(ONESHOT_COMPARATOR, None)?,
+        non_isa_routing_capability => non_isa_routing_capability.trailing_zeros(),
+    };
+    let gsi = u8::try_from(gsi).map_err(|_| "Comparator GSI is out of IO APIC")?;
+    set_comparator_gsi(ONESHOT_COMPARATOR, gsi as u32)?;
+    idt::register_handler(idt::HPET_ONESHOT_IDT_VECTOR, |_| {
+        oneshot_interrupt_handler()
+    })
+    .map_err(|_| "Failed to register HPET one-shot interrupt handler")?;
+    ioapic::redirect(gsi, idt::HPET_ONESHOT_IDT_VECTOR);
+    ioapic::set_mask(gsi, false);
+    log::info!(
+        "HPET one-shot comparator {ONESHOT_COMPARATOR} routed to GSI {gsi}, vector {}",
+        idt::HPET_ONESHOT_IDT_VECTOR
+    );
+    Ok(gsi)
+}
+
+fn oneshot_interrupt_handler() {
+    let hpet_timer = hpet_timer().unwrap();
+    let mut oneshot = ONESHOT.lock();
+    let Some(Oneshot {
+        deadline_ticks,
+        callback,
+    }) = *oneshot
+    else {
+        return;
+    };
+    // Shared GSI may deliver interrupts of other devices before the deadline
+    if hpet_timer.read_main_counter_value_register() < deadline_ticks {
+        return;
+    }
+    *oneshot = None;
+    let mut register_value =
+        hpet_timer.read_timer_configuration_and_capability_register_value(ONESHOT_COMPARATOR);
+    register_value.set_int_enb_cnf(false);
+    hpet_timer.write_timer_configuration_and_capability_register_value(
+        ONESHOT_COMPARATOR,
+        register_value,
+    );
+    drop(oneshot);
+    callback();
 }
 
 #[inline]
@@ -222,7 +606,7 @@ pub fn ticks_to_duration(ticks: u64) -> Duration {
         .as_ref()
         .unwrap()
         .period_in_nanoseconds;
-    Duration::from_nanos((ticks * nanoseconds_per_tick).to_num())
+    fixed_point::ticks_to_duration(ticks, nanoseconds_per_tick)
 }
 
 #[inline]
@@ -234,11 +618,51 @@ pub fn duration_to_ticks(duration: Duration) -> u64 {
         .as_ref()
         .unwrap()
         .period_in_nanoseconds;
-    let nanoseconds = FixedU64::<U12>::from_num(duration.as_nanos());
-    (nanoseconds / nanoseconds_per_tick).to_num()
+    fixed_point::duration_to_ticks(duration, nanoseconds_per_tick)
+}
+
+/// Waits shorter than this are always busy waits
+const SCHEDULER_SLEEP_THRESHOLD: Duration = Duration::from_millis(1);
+
+/// Scheduler sleep function, registers a wakeup after the duration and yields
+///
+/// Set when the scheduler initializes
+static SCHEDULER_SLEEP: Once<fn(Duration)> = Once::new();
+
+/// Registers scheduler sleep function, after it long [sleep]s yield instead of spinning
+pub fn set_scheduler_sleep(scheduler_sleep: fn(Duration)) {
+    SCHEDULER_SLEEP.call_once(|| scheduler_sleep);
 }
 
+/// Sleeps
+///
+/// If the scheduler is running and the wait is not shorter than [SCHEDULER_SLEEP_THRESHOLD],
+/// yields to the scheduler for whole threshold units and busy waits the rest for precision.
+/// Otherwise busy waits, see [busy_sleep].
 pub fn sleep(sleep_dutation: Duration) {
+    let Some(scheduler_sleep) = SCHEDULER_SLEEP.get() else {
+        busy_sleep(sleep_dutation);
+        return;
+    };
+    if sleep_dutation < SCHEDULER_SLEEP_THRESHOLD {
+        busy_sleep(sleep_dutation);
+        return;
+    }
+    let start_ticks = get_current_ticks();
+    let threshold_nanos = SCHEDULER_SLEEP_THRESHOLD.as_nanos();
+    let yield_duration = Duration::from_nanos(
+        (sleep_dutation.as_nanos() / threshold_nanos * threshold_nanos) as u64,
+    );
+    scheduler_sleep(yield_duration);
+    // Scheduler may wake up late or early, wait the rest from the start
+    let elapsed = ticks_to_duration(get_current_ticks() - start_ticks);
+    busy_sleep(sleep_dutation.saturating_sub(elapsed));
+}
+
+/// Busy waits, doesn't need scheduler and interrupts
+///
+/// Used in early boot (e.g. calibration) and for precise short delays
+pub fn busy_sleep(sleep_dutation: Duration) {
     let hpet_timer = HPET_TIMER.get().unwrap().as_ref().unwrap();
 
     let start_tick_value = hpet_timer.read_main_counter_value_register();
@@ -267,3 +691,20 @@ bitfield! {
     legacy_replacement_cnf, set_legacy_replacement_cnf: 1;
     enable_cnf, set_enable_cnf: 0;
 }
+
+bitfield! {
+    #[derive(Copy, Clone)]
+    struct TimerConfigurationAndCapabilityRegisterValue(u64);
+    impl Debug;
+    int_route_cap, _: 63, 32;
+    fsb_int_del_cap, _: 15;
+    fsb_en_cnf, set_fsb_en_cnf: 14;
+    int_route_cnf, set_int_route_cnf: 13, 9;
+    mode32_cnf, set_mode32_cnf: 8;
+    val_set_cnf, set_val_set_cnf: 6;
+    size_cap, _: 5;
+    per_int_cap, _: 4;
+    type_cnf, set_type_cnf: 3;
+    int_enb_cnf, set_int_enb_cnf: 2;
+    int_type_cnf, set_int_type_cnf: 1;
+}
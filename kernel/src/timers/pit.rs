@@ -2,10 +2,14 @@
 ///
 /// Only used to calibrate other timers if HPET is not available, since I'm too lazy to deal with this ancient shit.
 // http://www.brokenthorn.com/Resources/OSDev16.html
+use crate::interrupts::idt;
 use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
 const BASE_FREQ: u32 = 1193182;
 
+/// Channel 0 output is connected to ISA IRQ 0
+const PIT_ISA_IRQ: u8 = 0;
+
 const OCW_MASK_MODE: u8 = 0xE; // 00001110
 const OCW_MASK_RL: u8 = 0x30; // 00110000
 const OCW_RL_DATA: u8 = 0x30; // 110000
@@ -14,6 +18,14 @@ const OCW_COUNTER_0: u8 = 0x0; // 00000000
 const OCW_MODE_SQUAREWAVEGEN: u8 = 0x6; // 0110
 const REG_COMMAND: u16 = 0x43;
 const REG_COUNTER0: u16 = 0x40;
+const REG_COUNTER2: u16 = 0x42;
+/// Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+const OCW_COUNTER2_ONESHOT: u8 = 0xB0;
+/// NMI status and control port, channel 2 gate (bit 0), speaker (bit 1) and output (bit 5)
+const REG_CHANNEL2_GATE: u16 = 0x61;
+const CHANNEL2_GATE: u8 = 0x01;
+const CHANNEL2_SPEAKER: u8 = 0x02;
+const CHANNEL2_OUTPUT: u8 = 0x20;
 
 // Synchronization:
 // I believe atomic access will ensure valid counter operation, the LOCK prefix when writing will prevent other cores from using this variable.
@@ -57,6 +69,22 @@ pub fn init(interval_in_milliseconds: u32) {
         x86_64::instructions::port::Port::<u8>::new(REG_COUNTER0)
             .write(((divisor >> 8) & 0xFF) as u8);
     }
+
+    // Handler stays registered if PIT is reinited
+    let _ = idt::register_handler(
+        idt::IO_APIC_ISA_IRQ_VECTORS_RANGE.start() + PIT_ISA_IRQ,
+        |_| tick_interrupt_handler(),
+    );
+}
+
+/// Stops channel 0 interrupts
+///
+/// Channel 0 is switched to one-shot mode and waits for count, so its output doesn't change until [init]
+pub fn stop() {
+    // Channel 0, lobyte/hibyte, mode 0
+    unsafe {
+        x86_64::instructions::port::Port::<u8>::new(REG_COMMAND).write(OCW_RL_DATA | OCW_COUNTER_0);
+    }
 }
 
 #[inline]
@@ -73,6 +101,14 @@ pub fn get_ticks_counter() -> u64 {
     TICK_COUNTER.load(Ordering::Acquire)
 }
 
+/// Returns time counted by ticks, millisecond resolution
+#[inline]
+pub fn get_ticks_counter_as_duration() -> core::time::Duration {
+    core::time::Duration::from_millis(
+        get_ticks_counter() * MILLISECONDS_PER_TICK.load(Ordering::Acquire) as u64,
+    )
+}
+
 /// Sleeps
 pub fn sleep(milliseconds: u32) {
     let start_tick = TICK_COUNTER.load(Ordering::Acquire);
@@ -82,3 +118,30 @@ pub fn sleep(milliseconds: u32) {
         core::hint::spin_loop();
     }
 }
+
+/// Busy waits using channel 2 one-shot, doesn't need interrupts
+///
+/// Used to calibrate other timers before interrupts are enabled (1-54 ms)
+pub fn busy_sleep(milliseconds: u32) {
+    assert!(
+        (1..=54).contains(&milliseconds),
+        "Invalid PIT busy sleep interval"
+    );
+    let count = (BASE_FREQ as u64 * milliseconds as u64 / 1000) as u16;
+    unsafe {
+        let mut gate_port = x86_64::instructions::port::Port::<u8>::new(REG_CHANNEL2_GATE);
+        let mut counter2_port = x86_64::instructions::port::Port::<u8>::new(REG_COUNTER2);
+        // Gate low, speaker off
+        let gate = gate_port.read() & !(CHANNEL2_GATE | CHANNEL2_SPEAKER);
+        gate_port.write(gate);
+        x86_64::instructions::port::Port::<u8>::new(REG_COMMAND).write(OCW_COUNTER2_ONESHOT);
+        counter2_port.write((count & 0xFF) as u8);
+        counter2_port.write((count >> 8) as u8);
+        // Gate high starts counting, output goes high on terminal count
+        gate_port.write(gate | CHANNEL2_GATE);
+        while gate_port.read() & CHANNEL2_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        gate_port.write(gate);
+    }
+}
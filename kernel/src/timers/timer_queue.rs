@@ -0,0 +1,132 @@
+//! Deferred callbacks
+//!
+//! Entries are kept sorted by absolute expiry in HPET main counter ticks, HPET one-shot is programmed for
+//! the nearest one. On each one-shot interrupt all expired callbacks are called and the one-shot is
+//! programmed for the next entry.
+//!
+//! The queue owns HPET one-shot, [hpet::set_oneshot] called directly replaces the queue's deadline.
+use super::hpet;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use tinyvec::ArrayVec;
+
+/// Maximum number of pending callbacks
+pub const CAPACITY: usize = 64;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    /// HPET main counter value
+    expiry_ticks: u64,
+    id: u64,
+    callback: fn(),
+}
+
+/// Placeholder of unused queue slots
+const EMPTY_ENTRY: Entry = Entry {
+    expiry_ticks: 0,
+    id: 0,
+    callback: || {},
+};
+
+impl Default for Entry {
+    fn default() -> Self {
+        EMPTY_ENTRY
+    }
+}
+
+/// Pending entries sorted by expiry, the nearest is first
+static QUEUE: Mutex<ArrayVec<[Entry; CAPACITY]>> =
+    Mutex::new(ArrayVec::from_array_empty([EMPTY_ENTRY; CAPACITY]));
+
+/// Id of the next entry, 0 is never used
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Scheduled callback, used to cancel it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimerHandle(u64);
+
+impl TimerHandle {
+    /// Removes callback from the queue
+    ///
+    /// Returns false if it has already been called or cancelled
+    pub fn cancel(self) -> bool {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut queue = QUEUE.lock();
+            let Some(index) = queue.iter().position(|entry| entry.id == self.0) else {
+                return false;
+            };
+            // One-shot isn't reprogrammed, if the entry was the nearest the interrupt finds nothing expired
+            queue.remove(index);
+            true
+        })
+    }
+}
+
+/// Calls callback from interrupt handler after the delay
+///
+/// Callback is called with interrupts disabled, interrupts must be enabled on BSP to get it
+/// (HPET one-shot goes through IO APIC to BSP). Callback may schedule other callbacks.
+pub fn schedule(delay: Duration, callback: fn()) -> Result<TimerHandle, &'static str> {
+    if !hpet::is_supported() {
+        return Err("HPET is not supported");
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut queue = QUEUE.lock();
+        if queue.len() == CAPACITY {
+            return Err("Timer queue is full");
+        }
+        let expiry_ticks = hpet::get_current_ticks() + hpet::duration_to_ticks(delay);
+        // Entries with equal expiry fire in scheduling order
+        let index = queue.partition_point(|entry| entry.expiry_ticks <= expiry_ticks);
+        queue.insert(
+            index,
+            Entry {
+                expiry_ticks,
+                id,
+                callback,
+            },
+        );
+        if index == 0 {
+            if let Err(err) = program(expiry_ticks) {
+                queue.remove(0);
+                return Err(err);
+            }
+        }
+        Ok(TimerHandle(id))
+    })
+}
+
+/// Returns number of pending callbacks
+pub fn pending() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| QUEUE.lock().len())
+}
+
+/// Sets HPET one-shot to the expiry, expired deadline fires after the shortest one-shot delay
+fn program(expiry_ticks: u64) -> Result<(), &'static str> {
+    let delay_ticks = expiry_ticks.saturating_sub(hpet::get_current_ticks());
+    hpet::set_oneshot(hpet::ticks_to_duration(delay_ticks), fire_expired)
+}
+
+/// One-shot callback, calls expired callbacks and programs the next expiry
+fn fire_expired() {
+    loop {
+        let mut expired = ArrayVec::<[Entry; CAPACITY]>::new();
+        let mut queue = QUEUE.lock();
+        let now_ticks = hpet::get_current_ticks();
+        let expired_number = queue.partition_point(|entry| entry.expiry_ticks <= now_ticks);
+        expired.extend(queue.drain(..expired_number));
+        if expired.is_empty() {
+            if let Some(next) = queue.first() {
+                program(next.expiry_ticks).expect("Failed to program timer queue one-shot");
+            }
+            return;
+        }
+        // Callbacks may schedule, so the queue is unlocked
+        drop(queue);
+        for entry in expired {
+            (entry.callback)();
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Invariant TSC
+//!
+//! Invariant TSC runs at constant rate in all ACPI P-, C- and T-states, so it's used as a system-wide
+//! monotonic clock. Non-invariant TSC is not used.
+//!
+//! TSC frequency is in GHz range, so conversions use exact frequency-based arithmetic
+//! (see [fixed_point](super::fixed_point)), cycles per nanosecond are kept for information.
+use super::fixed_point::{self, Fixed};
+use super::{hpet, pit};
+use crate::cpu;
+use core::ops::RangeInclusive;
+use core::time::Duration;
+use raw_cpuid::CpuId;
+use spin::Once;
+
+/// Calibrated TSC, None if invariant TSC is not supported
+static TSC: Once<Option<Tsc>> = Once::new();
+
+/// Sane TSC frequencies
+const FREQUENCY_SANE_RANGE: RangeInclusive<u64> = 100_000_000..=10_000_000_000;
+
+/// How long TSC counts during measurement
+const CALIBRATION_DURATION: Duration = Duration::from_millis(10);
+
+/// Number of measurements
+const CALIBRATION_SAMPLES: usize = 5;
+
+#[derive(Debug)]
+struct Tsc {
+    /// Frequency in Hz
+    frequency_hz: u64,
+    /// Cycles per nanosecond
+    cycles_per_ns: Fixed,
+}
+
+/// Detects invariant TSC and calibrates it
+///
+/// HPET must be inited, it's used (or PIT if HPET is not supported) to measure the frequency if CPUID doesn't report it
+pub fn init() {
+    TSC.call_once(|| {
+        if !cpu::features().invariant_tsc {
+            log::info!("Invariant TSC not supported");
+            return None;
+        }
+
+        let frequency_hz = calibrate(&CpuId::new());
+        assert!(
+            FREQUENCY_SANE_RANGE.contains(&frequency_hz),
+            "TSC frequency {frequency_hz} Hz is out of sane range, bug"
+        );
+        let cycles_per_ns = Fixed::from_num(frequency_hz) / 1_000_000_000u64;
+        log::info!("Invariant TSC: frequency {frequency_hz} Hz ({cycles_per_ns} cycles per ns)");
+        Some(Tsc {
+            frequency_hz,
+            cycles_per_ns,
+        })
+    });
+}
+
+/// Whether invariant TSC is supported and calibrated
+#[inline]
+pub fn is_supported() -> bool {
+    tsc().is_some()
+}
+
+#[inline]
+fn tsc() -> Option<&'static Tsc> {
+    TSC.get()?.as_ref()
+}
+
+/// Returns TSC frequency in Hz, None if invariant TSC is not supported
+pub fn frequency_hz() -> Option<u64> {
+    tsc().map(|tsc| tsc.frequency_hz)
+}
+
+/// Returns TSC cycles per nanosecond, None if invariant TSC is not supported
+pub fn cycles_per_ns() -> Option<Fixed> {
+    tsc().map(|tsc| tsc.cycles_per_ns)
+}
+
+/// Reads TSC
+#[inline]
+pub fn read_cycles() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Returns time since TSC reset (usually power on)
+///
+/// Panics if invariant TSC is not supported
+#[inline]
+pub fn now() -> Duration {
+    cycles_to_duration(read_cycles())
+}
+
+/// Converts cycles to duration
+///
+/// Panics if invariant TSC is not supported
+#[inline]
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    let frequency_hz = frequency_hz().expect("Invariant TSC is not supported");
+    fixed_point::ticks_to_duration_by_frequency(cycles, frequency_hz)
+}
+
+/// Converts duration to cycles
+///
+/// Panics if invariant TSC is not supported
+#[inline]
+pub fn duration_to_cycles(duration: Duration) -> u64 {
+    let frequency_hz = frequency_hz().expect("Invariant TSC is not supported");
+    fixed_point::duration_to_ticks_by_frequency(duration, frequency_hz)
+}
+
+/// Busy waits
+///
+/// Panics if invariant TSC is not supported
+pub fn busy_sleep(sleep_duration: Duration) {
+    let end_cycles = read_cycles().saturating_add(duration_to_cycles(sleep_duration));
+    while read_cycles() < end_cycles {
+        core::hint::spin_loop();
+    }
+}
+
+/// Gets TSC frequency from CPUID or measures it
+fn calibrate(cpuid: &CpuId<raw_cpuid::CpuIdReaderNative>) -> u64 {
+    // Hypervisor reports TSC frequency in kHz
+    let hypervisor_frequency_hz = cpuid
+        .get_hypervisor_info()
+        .and_then(|hypervisor_info| hypervisor_info.tsc_frequency())
+        .map_or(0, |frequency_khz| frequency_khz as u64 * 1000);
+    if hypervisor_frequency_hz != 0 {
+        return hypervisor_frequency_hz;
+    }
+    // CPUID 0x15 - TSC/core crystal clock ratio and crystal frequency, usually not enumerated
+    if let Some(frequency_hz) = cpuid
+        .get_tsc_info()
+        .and_then(|tsc_info| tsc_info.tsc_frequency())
+    {
+        return frequency_hz;
+    }
+
+    let mut frequencies_hz = [0; CALIBRATION_SAMPLES];
+    for frequency_hz in frequencies_hz.iter_mut() {
+        *frequency_hz = measure_frequency();
+    }
+    // Median, a sample may be stretched by SMI
+    frequencies_hz.sort_unstable();
+    frequencies_hz[CALIBRATION_SAMPLES / 2]
+}
+
+/// Measures TSC frequency over [CALIBRATION_DURATION]
+fn measure_frequency() -> u64 {
+    let (elapsed_cycles, elapsed) = if hpet::is_supported() {
+        // HPET counter is read too, so the real elapsed time is used
+        let start_ticks = hpet::get_current_ticks();
+        let start_cycles = read_cycles();
+        hpet::busy_sleep(CALIBRATION_DURATION);
+        let end_cycles = read_cycles();
+        let end_ticks = hpet::get_current_ticks();
+        (
+            end_cycles - start_cycles,
+            hpet::ticks_to_duration(end_ticks - start_ticks),
+        )
+    } else {
+        let start_cycles = read_cycles();
+        pit::busy_sleep(CALIBRATION_DURATION.as_millis() as u32);
+        (read_cycles() - start_cycles, CALIBRATION_DURATION)
+    };
+    (elapsed_cycles as u128 * 1_000_000_000 / elapsed.as_nanos()) as u64
+}
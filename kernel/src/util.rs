@@ -0,0 +1,85 @@
+//! Debugging utilities
+use crate::com_ports::SerialChannel;
+use crate::memory_management::{virtual_memory_manager, PAGE_SIZE};
+use crate::serial_debug::serial_printer::SerialPrinterLockFree;
+use core::fmt::Write;
+use x86_64::VirtAddr;
+
+/// Bytes per hexdump line
+const HEXDUMP_LINE_SIZE: usize = 16;
+
+/// Prints memory as hexdump over serial (lock-free, so it can be used in fault handlers)
+///
+/// Lines are aligned to 16 bytes, bytes out of range are left blank.
+/// Every page is checked with [translate](virtual_memory_manager::translate) before reading,
+/// unmapped pages are reported and skipped.
+pub fn hexdump(addr: VirtAddr, len: usize) {
+    // Serial printing never fails
+    let _ = hexdump_to(&mut SerialPrinterLockFree(SerialChannel::Com1), addr, len);
+}
+
+/// Writes hexdump lines into writer, doesn't allocate
+///
+/// Line format: `address: bytes  ascii`
+pub fn hexdump_to(writer: &mut impl Write, addr: VirtAddr, len: usize) -> core::fmt::Result {
+    let start = addr.as_u64();
+    let Some(end) = start.checked_add(len as u64) else {
+        return writeln!(writer, "hexdump: range 0x{start:X} + 0x{len:X} overflows");
+    };
+    let mut line_start = start & !(HEXDUMP_LINE_SIZE as u64 - 1);
+    while line_start < end {
+        // Unmapped page is reported once and skipped
+        if !is_mapped(line_start.max(start)) {
+            let next_page = (line_start | (PAGE_SIZE as u64 - 1)).saturating_add(1);
+            writeln!(
+                writer,
+                "0x{:016X}: <unmapped up to 0x{:X}>",
+                line_start.max(start),
+                next_page.min(end)
+            )?;
+            line_start = next_page;
+            continue;
+        }
+
+        let mut bytes = [None; HEXDUMP_LINE_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let byte_addr = line_start + i as u64;
+            if (start..end).contains(&byte_addr) {
+                // Line never crosses a page, the page is mapped
+                *byte = Some(unsafe { (byte_addr as *const u8).read_volatile() });
+            }
+        }
+        write!(writer, "0x{line_start:016X}: ")?;
+        for (i, byte) in bytes.iter().enumerate() {
+            if i == HEXDUMP_LINE_SIZE / 2 {
+                writer.write_char(' ')?;
+            }
+            match byte {
+                Some(byte) => write!(writer, "{byte:02X} ")?,
+                None => writer.write_str("   ")?,
+            }
+        }
+        writer.write_str(" |")?;
+        for byte in bytes {
+            let ch = match byte {
+                Some(byte) if byte.is_ascii_graphic() || byte == b' ' => byte as char,
+                Some(_) => '.',
+                None => ' ',
+            };
+            writer.write_char(ch)?;
+        }
+        writer.write_str("|\n")?;
+
+        let Some(next_line_start) = line_start.checked_add(HEXDUMP_LINE_SIZE as u64) else {
+            break;
+        };
+        line_start = next_line_start;
+    }
+    Ok(())
+}
+
+/// Whether page containing address is mapped
+fn is_mapped(addr: u64) -> bool {
+    VirtAddr::try_new(addr)
+        .is_ok_and(|virt_addr| virtual_memory_manager::translate(virt_addr).is_some())
+}